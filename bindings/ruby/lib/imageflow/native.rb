@@ -56,6 +56,9 @@ module Imageflow
     attach_function :imageflow_context_create, [:uint32, :uint32], :pointer
     attach_function :imageflow_context_begin_terminate, [:pointer], :bool
     attach_function :imageflow_context_destroy, [:pointer], :void
+    attach_function :imageflow_context_create_cancellation_token, [:pointer], :pointer
+    attach_function :imageflow_cancellation_token_cancel, [:pointer], :void
+    attach_function :imageflow_cancellation_token_destroy, [:pointer], :void
 
     attach_function :imageflow_context_has_error, [:pointer], :bool
     attach_function :imageflow_context_error_code, [:pointer], :int32
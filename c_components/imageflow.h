@@ -85,6 +85,10 @@ typedef enum flow_status_code {
     flow_status_Image_encoding_failed = 61,
     flow_status_ErrorReportingInconsistency = 90,
     flow_status_First_rust_error = 200,
+    // 200 + the ErrorCategory of the same name in imageflow_core
+    flow_status_Operation_cancelled = 221,
+    flow_status_Operation_timed_out = 222,
+    flow_status_Size_limit_exceeded = 223,
 
     flow_status_Other_error = 1024,
     flow_status____Last_library_error,
@@ -211,6 +215,30 @@ PUB bool flow_context_print_and_exit_if_err(flow_c * c);
 
 PUB void flow_context_clear_error(flow_c * c);
 
+// Long-running operations poll *flag and fail once it is nonzero: 1 raises flow_status_Operation_cancelled, any other
+// value flow_status_Operation_timed_out. The flag must outlive its use by the context; pass NULL to detach it.
+PUB void flow_context_set_cancellation_flag(flow_c * c, const volatile size_t * flag);
+
+// flow_bitmap_bgra_create fails with flow_status_Size_limit_exceeded rather than let the pixel buffers it has
+// allocated and not yet freed exceed max_bytes. 0 (the default) means no limit.
+PUB void flow_context_set_max_bitmap_bytes(flow_c * c, size_t max_bytes);
+
+// The most pixel buffer bytes allocated at once since the context was created or the peak was last reset
+PUB size_t flow_context_bitmap_bytes_peak(flow_c * c);
+// Restarts the peak from the bytes in use now
+PUB void flow_context_reset_bitmap_bytes_peak(flow_c * c);
+
+// A pixel pool supplies the pixel buffers of flow_bitmap_bgra_create instead of the context's heap. acquire returns at
+// least byte_count bytes (zeroed if asked) allocated with malloc, or NULL; release receives the byte_count acquire was
+// called with. Contexts on different threads may share one pool, so both must be thread-safe.
+typedef void * (*flow_pixel_pool_acquire)(void * pool, size_t byte_count, bool zeroed);
+typedef void (*flow_pixel_pool_release)(void * pool, void * pixels, size_t byte_count);
+
+// Bitmaps allocated later take their pixels from pool; it must outlive the context. Buffers are released to whichever
+// pool is set when their bitmap is destroyed.
+PUB void flow_context_set_pixel_pool(flow_c * c, void * pool, flow_pixel_pool_acquire acquire,
+                                     flow_pixel_pool_release release);
+
 PUB void flow_context_print_error_to(flow_c * c, FILE * stream);
 
 PUB void flow_context_print_memory_info(flow_c * c);
@@ -62,6 +62,54 @@ static bool bitmap_allocation_hook(flow_c * context, void * ptr, size_t byte_cou
     return true;
 }
 
+// Remembers a pixel buffer and its size as allocated; crops move im->pixels and shrink im->h afterwards.
+// Freeing it returns the size to the bitmap byte count without looking the allocation up.
+struct bitmap_pixels {
+    void * pixels;
+    size_t byte_count;
+    bool pooled;
+};
+
+static bool bitmap_pixels_released(flow_c * context, void * thing)
+{
+    struct bitmap_pixels * record = (struct bitmap_pixels *)thing;
+    if (record->pooled && record->pixels != NULL) {
+        context->pixel_pool_release(context->pixel_pool, record->pixels, record->byte_count);
+    }
+    context->bitmap_bytes_in_use
+        -= record->byte_count < context->bitmap_bytes_in_use ? record->byte_count : context->bitmap_bytes_in_use;
+    return true;
+}
+
+// Zeroed, so the destructor does nothing until the buffer is in place and counted
+static struct bitmap_pixels * bitmap_pixels_record(flow_c * context, struct flow_bitmap_bgra * im)
+{
+    struct bitmap_pixels * record = (struct bitmap_pixels *)flow_context_calloc(
+        context, 1, sizeof(struct bitmap_pixels), bitmap_pixels_released, im, __FILE__, __LINE__);
+    if (record == NULL) {
+        FLOW_error(context, flow_status_Out_of_memory);
+    }
+    return record;
+}
+
+static bool bitmap_pixels_from_pool(flow_c * context, struct flow_bitmap_bgra * im, size_t byte_count, bool zeroed)
+{
+    struct bitmap_pixels * record = bitmap_pixels_record(context, im);
+    if (record == NULL) {
+        FLOW_error_return(context);
+    }
+    void * pixels = context->pixel_pool_acquire(context->pixel_pool, byte_count, zeroed);
+    if (pixels == NULL) {
+        FLOW_error(context, flow_status_Out_of_memory);
+        return false;
+    }
+    record->pixels = pixels;
+    record->byte_count = byte_count;
+    record->pooled = true;
+    im->pixels = (unsigned char *)pixels;
+    return true;
+}
+
 uint32_t flow_pixel_format_bytes_per_pixel(flow_pixel_format format)
 {
     switch (format) {
@@ -139,19 +187,44 @@ struct flow_bitmap_bgra * flow_bitmap_bgra_create(flow_c * context, int sx, int
     im->stride = unpadded_stride + padding;
 
     size_t byte_count = im->h * im->stride;
-    if (zeroed) {
-        im->pixels = (unsigned char *)FLOW_calloc_owned(context, byte_count, sizeof(unsigned char), im);
-    } else {
-        im->pixels = (unsigned char *)FLOW_malloc_owned(context, byte_count, im);
-    }
-    if (im->pixels == NULL) {
+    if (context->max_bitmap_bytes != 0 && context->bitmap_bytes_in_use + byte_count > context->max_bitmap_bytes) {
         FLOW_destroy(context, im);
-        FLOW_error(context, flow_status_Out_of_memory);
+        FLOW_error_msg(context, flow_status_Size_limit_exceeded,
+                       "Allocating a %dx%d bitmap (%zu bytes) would exceed the limit of %zu bitmap bytes (%zu in use)",
+                       sx, sy, byte_count, context->max_bitmap_bytes, context->bitmap_bytes_in_use);
         return NULL;
     }
-    if (!bitmap_allocation_hook(context, im->pixels, byte_count, allocation_kind_bgra_pixbuf)) {
-        FLOW_destroy(context, im);
-        FLOW_error_return_null(context);
+    if (context->pixel_pool_acquire != NULL) {
+        if (!bitmap_pixels_from_pool(context, im, byte_count, zeroed)) {
+            FLOW_destroy(context, im);
+            FLOW_error_return_null(context);
+        }
+    } else {
+        struct bitmap_pixels * record = bitmap_pixels_record(context, im);
+        if (record == NULL) {
+            FLOW_destroy(context, im);
+            FLOW_error_return_null(context);
+        }
+        if (zeroed) {
+            im->pixels = (unsigned char *)FLOW_calloc_owned(context, byte_count, sizeof(unsigned char), im);
+        } else {
+            im->pixels = (unsigned char *)FLOW_malloc_owned(context, byte_count, im);
+        }
+        if (im->pixels == NULL) {
+            FLOW_destroy(context, im);
+            FLOW_error(context, flow_status_Out_of_memory);
+            return NULL;
+        }
+        if (!bitmap_allocation_hook(context, im->pixels, byte_count, allocation_kind_bgra_pixbuf)) {
+            FLOW_destroy(context, im);
+            FLOW_error_return_null(context);
+        }
+        record->pixels = im->pixels;
+        record->byte_count = byte_count;
+    }
+    context->bitmap_bytes_in_use += byte_count;
+    if (context->bitmap_bytes_in_use > context->bitmap_bytes_peak) {
+        context->bitmap_bytes_peak = context->bitmap_bytes_in_use;
     }
     return im;
 }
@@ -546,6 +546,11 @@ static bool flow_codecs_jpg_decoder_FinishRead(flow_c * c, struct flow_codecs_jp
      * loop counter, so that we don't have to keep track ourselves.
      */
     while (state->cinfo->output_scanline < state->cinfo->output_height) {
+        if (flow_context_raise_if_cancelled(c)) {
+            flow_codecs_jpg_decoder_reset(c, state);
+            state->stage = flow_codecs_jpg_decoder_stage_Failed;
+            FLOW_error_return(c);
+        }
         /* jpeg_read_scanlines expects an array of pointers to scanlines.
          * Here the array is only one element long, but you could ask for
          * more than one scanline at a time if that's more convenient.
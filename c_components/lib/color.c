@@ -18,16 +18,16 @@ bool flow_bitmap_float_linear_to_luv_rows(flow_c * context, struct flow_bitmap_f
         FLOW_error(context, flow_status_Invalid_internal_state); // Don't access rows past the end of the bitmap
         return false;
     }
-    if ((bit->w * bit->channels) != bit->float_stride) {
-        FLOW_error(context, flow_status_Invalid_internal_state); // This algorithm can't handle padding, if present
+    if (bit->channels < 3) {
+        FLOW_error(context, flow_status_Invalid_internal_state);
         return false;
     }
-    float * start_at = bit->float_stride * start_row + bit->pixels;
-
-    const float * end_at = bit->float_stride * (start_row + row_count) + bit->pixels;
-
-    for (float * pix = start_at; pix < end_at; pix++) {
-        linear_to_luv(pix);
+    for (uint32_t row = start_row; row < start_row + row_count; row++) {
+        float * start_at = bit->float_stride * row + bit->pixels;
+        const float * end_at = start_at + bit->w * bit->channels;
+        for (float * pix = start_at; pix < end_at; pix += bit->channels) {
+            linear_to_luv(pix);
+        }
     }
     return true;
 }
@@ -36,19 +36,19 @@ bool flow_bitmap_float_luv_to_linear_rows(flow_c * context, struct flow_bitmap_f
                                           const uint32_t row_count)
 {
     if (!(start_row + row_count <= bit->h)) {
-        FLOW_error(context, flow_status_Invalid_internal_state);
+        FLOW_error(context, flow_status_Invalid_internal_state); // Don't access rows past the end of the bitmap
         return false;
     }
-    if ((bit->w * bit->channels) != bit->float_stride) {
+    if (bit->channels < 3) {
         FLOW_error(context, flow_status_Invalid_internal_state);
         return false;
     }
-    float * start_at = bit->float_stride * start_row + bit->pixels;
-
-    const float * end_at = bit->float_stride * (start_row + row_count) + bit->pixels;
-
-    for (float * pix = start_at; pix < end_at; pix++) {
-        luv_to_linear(pix);
+    for (uint32_t row = start_row; row < start_row + row_count; row++) {
+        float * start_at = bit->float_stride * row + bit->pixels;
+        const float * end_at = start_at + bit->w * bit->channels;
+        for (float * pix = start_at; pix < end_at; pix += bit->channels) {
+            luv_to_linear(pix);
+        }
     }
     return true;
 }
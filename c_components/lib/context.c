@@ -150,6 +150,12 @@ static const char * status_code_to_string(flow_status_code code)
             return "Image encoding failed";
         case 90:
             return "C Error Reporting Inconsistency";
+        case 221:
+            return "Operation cancelled";
+        case 222:
+            return "Operation timed out";
+        case 223:
+            return "Size limit exceeded";
         case 1024:
             return "Other error";
         default:
@@ -300,6 +306,46 @@ void flow_context_initialize(flow_c * context)
     flow_heap_set_default(context);
     flow_context_objtracking_initialize(&context->object_tracking);
     context->codec_set = flow_context_get_default_codec_set();
+    context->cancellation_flag = NULL;
+    context->max_bitmap_bytes = 0;
+    context->bitmap_bytes_in_use = 0;
+    context->bitmap_bytes_peak = 0;
+    context->pixel_pool = NULL;
+    context->pixel_pool_acquire = NULL;
+    context->pixel_pool_release = NULL;
+}
+
+void flow_context_set_max_bitmap_bytes(flow_c * c, size_t max_bytes) { c->max_bitmap_bytes = max_bytes; }
+
+size_t flow_context_bitmap_bytes_peak(flow_c * c) { return c->bitmap_bytes_peak; }
+
+void flow_context_reset_bitmap_bytes_peak(flow_c * c) { c->bitmap_bytes_peak = c->bitmap_bytes_in_use; }
+
+void flow_context_set_pixel_pool(flow_c * c, void * pool, flow_pixel_pool_acquire acquire,
+                                 flow_pixel_pool_release release)
+{
+    c->pixel_pool = pool;
+    c->pixel_pool_acquire = acquire;
+    c->pixel_pool_release = release;
+}
+
+void flow_context_set_cancellation_flag(flow_c * c, const volatile size_t * flag) { c->cancellation_flag = flag; }
+
+bool flow_context_raise_if_cancelled(flow_c * c)
+{
+    if (c->cancellation_flag == NULL) {
+        return false;
+    }
+    size_t reason = *c->cancellation_flag;
+    if (reason == 0) {
+        return false;
+    }
+    if (reason == 1) {
+        FLOW_error_msg(c, flow_status_Operation_cancelled, "The operation was cancelled");
+    } else {
+        FLOW_error_msg(c, flow_status_Operation_timed_out, "The operation ran out of time");
+    }
+    return true;
 }
 
 flow_c * flow_context_create(void)
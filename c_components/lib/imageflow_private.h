@@ -154,9 +154,20 @@ struct flow_context {
     struct flow_objtracking_info object_tracking;
     struct flow_profiling_log log;
     struct flow_error_info error;
+    const volatile size_t * cancellation_flag;
+    size_t max_bitmap_bytes;
+    size_t bitmap_bytes_in_use;
+    size_t bitmap_bytes_peak;
+    void * pixel_pool;
+    flow_pixel_pool_acquire pixel_pool_acquire;
+    flow_pixel_pool_release pixel_pool_release;
 };
 
 typedef struct flow_context flow_c;
+
+// Raises flow_status_Operation_cancelled or flow_status_Operation_timed_out and returns true if the flag set with
+// flow_context_set_cancellation_flag is up. Call between rows of long loops.
+PUB bool flow_context_raise_if_cancelled(flow_c * c);
 #include "color.h"
 
 PUB bool write_frame_to_disk(flow_c * c, const char * path, struct flow_bitmap_bgra * b);
@@ -197,6 +208,11 @@ PUB bool flow_node_execute_render_to_canvas_1d(flow_c * c, struct flow_bitmap_bg
 PUB bool flow_node_execute_scale2d_render1d(
     flow_c * c, struct flow_bitmap_bgra * input, struct flow_bitmap_bgra * canvas,
     struct flow_nodeinfo_scale2d_render_to_canvas1d * info) FLOW_HINT_HOT FLOW_HINT_UNSAFE_MATH_OPTIMIZATIONS;
+// Renders only output rows from_row..to_row of the region described by info, so bands can be scaled independently
+PUB bool flow_node_execute_scale2d_render1d_rows(
+    flow_c * c, struct flow_bitmap_bgra * input, struct flow_bitmap_bgra * canvas,
+    struct flow_nodeinfo_scale2d_render_to_canvas1d * info, uint32_t from_row,
+    uint32_t to_row) FLOW_HINT_HOT FLOW_HINT_UNSAFE_MATH_OPTIMIZATIONS;
 
 PUB struct flow_bitmap_float * flow_bitmap_float_create_header(flow_c * c, int sx, int sy, int channels);
 
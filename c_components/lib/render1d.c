@@ -66,6 +66,12 @@ static bool ScaleAndRender1D(flow_c * context, struct flow_colorcontext_info * c
     for (uint32_t source_start_row = 0; source_start_row < pSrc->h; source_start_row += buffer_row_count) {
         const uint32_t row_count = umin(pSrc->h - source_start_row, buffer_row_count);
 
+        if (flow_context_raise_if_cancelled(context)) {
+            FLOW_add_to_callstack(context);
+            success = false;
+            goto cleanup;
+        }
+
         flow_prof_start(context, "convert_srgb_to_linear", false);
         if (!flow_bitmap_float_convert_srgb_to_linear(context, colorcontext, pSrc, source_start_row, source_buf, 0,
                                                       row_count)) {
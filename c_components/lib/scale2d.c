@@ -43,7 +43,18 @@ FLOW_HINT_HOT FLOW_HINT_UNSAFE_MATH_OPTIMIZATIONS
     flow_node_execute_scale2d_render1d(flow_c * c, struct flow_bitmap_bgra * input, struct flow_bitmap_bgra * uncropped_canvas,
                                        struct flow_nodeinfo_scale2d_render_to_canvas1d * info)
 {
-    if (info->h + info->y > uncropped_canvas->h|| info->w + info->x > uncropped_canvas->w) {
+    return flow_node_execute_scale2d_render1d_rows(c, input, uncropped_canvas, info, 0, info->h);
+}
+
+FLOW_HINT_HOT FLOW_HINT_UNSAFE_MATH_OPTIMIZATIONS
+
+    bool
+    flow_node_execute_scale2d_render1d_rows(flow_c * c, struct flow_bitmap_bgra * input,
+                                            struct flow_bitmap_bgra * uncropped_canvas,
+                                            struct flow_nodeinfo_scale2d_render_to_canvas1d * info, uint32_t from_row,
+                                            uint32_t to_row)
+{
+    if (info->h + info->y > uncropped_canvas->h|| info->w + info->x > uncropped_canvas->w || from_row > to_row || to_row > info->h) {
         FLOW_error(c, flow_status_Invalid_argument);
         return false;
     }
@@ -137,7 +148,11 @@ FLOW_HINT_HOT FLOW_HINT_UNSAFE_MATH_OPTIMIZATIONS
         row_indexes[i] = -1;
     }
 
-    for (uint32_t out_row = 0; out_row < cropped_canvas->h; out_row++) {
+    for (uint32_t out_row = from_row; out_row < to_row; out_row++) {
+        if (flow_context_raise_if_cancelled(c)) {
+            FLOW_destroy(c, details);
+            FLOW_error_return(c);
+        }
         struct flow_interpolation_pixel_contributions contrib = contrib_v->ContribRow[out_row];
         // Clear output row
         memset(output_address, 0, sizeof(float) * row_floats);
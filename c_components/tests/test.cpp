@@ -79,6 +79,45 @@ TEST_CASE("Roundtrip RGB<->LUV 0,0,0,0 ", "[fastscaling]")
     CHECK(bgra[2] == 0.0f);
 }
 
+TEST_CASE("Roundtrip BGRA rows linear<->LUV, with and without padding", "[fastscaling]")
+{
+    flow_c context;
+    flow_context_initialize(&context);
+
+    const uint32_t w = 5;
+    const uint32_t h = 3;
+    for (uint32_t padding = 0; padding <= 2; padding += 2) {
+        struct flow_bitmap_float * bit = flow_bitmap_float_create(&context, w + padding, h, 4, false);
+        REQUIRE(bit != NULL);
+        // Narrow the bitmap, leaving `padding` pixels at the end of each row
+        bit->w = w;
+        for (uint32_t i = 0; i < bit->float_count; i++) {
+            bool padded = i % bit->float_stride >= w * 4;
+            bit->pixels[i] = padded ? -1.0f : (float)((i * 7) % 10 + 1) / 11.0f;
+        }
+        float * original = FLOW_calloc_array(&context, bit->float_count, float);
+        REQUIRE(original != NULL);
+        memcpy(original, bit->pixels, bit->float_count * sizeof(float));
+
+        REQUIRE(flow_bitmap_float_linear_to_luv_rows(&context, bit, 0, h));
+        for (uint32_t i = 0; i < bit->float_count; i++) {
+            uint32_t x = i % bit->float_stride;
+            if (x >= w * 4 || x % 4 == 3) {
+                // Alpha and padding are left alone
+                CHECK(bit->pixels[i] == original[i]);
+            }
+        }
+        REQUIRE(flow_bitmap_float_luv_to_linear_rows(&context, bit, 0, h));
+        for (uint32_t i = 0; i < bit->float_count; i++) {
+            CHECK(bit->pixels[i] == Approx(original[i]).epsilon(0.001));
+        }
+        flow_bitmap_float_destroy(&context, bit);
+    }
+
+    REQUIRE(flow_context_begin_terminate(&context) == true);
+    flow_context_end_terminate(&context);
+}
+
 TEST_CASE("Test guassian blur approximation.", "[fastscaling]")
 {
     flow_c context;
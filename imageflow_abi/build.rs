@@ -49,6 +49,7 @@ struct Context;
 struct JsonResponse;
 struct Job;
 struct JobIo;
+struct CancellationToken;
         "#;
 
 static DEFINE_INTS: &'static str = r#"
@@ -184,7 +185,7 @@ fn filter_enums<'a,'b>(s: String,  names: &'a[&'a str],  how: EnumModification)
 }
 
 static ENUM_NAMES: [&'static str; 4] = ["IoMode", "Direction", "Lifetime", "CleanupWith"];
-static STRUCT_NAMES: [&'static str; 4] = ["Job", "JobIo", "Context", "JsonResponse"];
+static STRUCT_NAMES: [&'static str; 5] = ["Job", "JobIo", "Context", "JsonResponse", "CancellationToken"];
 
 
 
@@ -4,5 +4,5 @@
 pub const IMAGEFLOW_ABI_VER_MAJOR: u32 = 3;
 
 /// This is incremented when a non-breaking change is made to the ABI
-pub const IMAGEFLOW_ABI_VER_MINOR: u32 = 0;
+pub const IMAGEFLOW_ABI_VER_MINOR: u32 = 1;
 
@@ -119,7 +119,7 @@ extern crate smallvec;
 extern crate backtrace;
 use c::ffi;
 
-pub use c::{Context, ErrorCategory};
+pub use c::{Context, CancellationToken, ErrorCategory};
 pub use c::ffi::ImageflowJsonResponse as JsonResponse;
 use c::IoDirection;
 use c::{ErrorKind, CodeLocation, FlowError};
@@ -298,11 +298,59 @@ pub extern "C" fn imageflow_context_destroy(context: *mut Context) {
 }
 
 
+/// Returns a handle that cancels the context's jobs from another thread, or null if the context is null.
+///
+/// Take the handle before starting a job, on the thread that uses the context; it stays valid until
+/// imageflow_cancellation_token_destroy, even after the context is destroyed.
+#[no_mangle]
+pub extern "C" fn imageflow_context_create_cancellation_token(context: *mut Context) -> *mut CancellationToken {
+    if context.is_null() {
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(context!(context).cancellation_token()))
+}
+
+/// Requests that the context's running job stop as soon as possible, or the next job if none is running;
+/// it will fail with an OperationCancelled error (exit code 75, http status 503).
+/// The request is cleared once that job ends, so later jobs on the context run normally.
+///
+/// Unlike every other function, this may be called from any thread while the context is in use.
+#[no_mangle]
+pub extern "C" fn imageflow_cancellation_token_cancel(token: *mut CancellationToken) {
+    if !token.is_null() {
+        unsafe { &*token }.cancel();
+    }
+}
+
+/// Frees a handle created with imageflow_context_create_cancellation_token
+#[no_mangle]
+pub extern "C" fn imageflow_cancellation_token_destroy(token: *mut CancellationToken) {
+    if !token.is_null() {
+        unsafe {
+            let _ = Box::from_raw(token);
+        }
+    }
+}
+
 #[test]
 fn test_create_destroy() {
     exercise_create_destroy();
 }
 
+#[test]
+fn test_cancellation_token() {
+    let c = imageflow_context_create(IMAGEFLOW_ABI_VER_MAJOR, IMAGEFLOW_ABI_VER_MINOR);
+    let token = imageflow_context_create_cancellation_token(c);
+    assert!(!token.is_null());
+    let cancelling = token as usize;
+    ::std::thread::spawn(move || imageflow_cancellation_token_cancel(cancelling as *mut CancellationToken)).join().unwrap();
+    assert!(context!(c).check_cancellation().is_err());
+    imageflow_context_destroy(c);
+    // The handle outlives the context
+    imageflow_cancellation_token_cancel(token);
+    imageflow_cancellation_token_destroy(token);
+}
+
 pub fn exercise_create_destroy() {
     let c = imageflow_context_create(IMAGEFLOW_ABI_VER_MAJOR, IMAGEFLOW_ABI_VER_MINOR);
     assert!(!c.is_null());
@@ -350,12 +398,13 @@ pub extern "C" fn imageflow_context_error_code(context: *mut Context) -> i32 {
 ///
 /// * 0 - No error
 /// * 64 - Invalid usage (graph invalid, node argument invalid, action not supported)
-/// * 65 - Invalid Json, Image malformed, Image type not supported
+/// * 65 - Invalid Json, Image malformed, Image type not supported, size limit exceeded
 /// * 66 - Primary or secondary file or resource not found.
 /// * 69 - Upstream server errored or timed out
 /// * 70 - Possible bug: internal error, custom error, unknown error, or no graph solution found
 /// * 71 - Out Of Memory condition (malloc/calloc/realloc failed).
 /// * 74 - I/O Error
+/// * 75 - Operation cancelled or timed out
 /// * 77 - Action forbidden under imageflow security policy
 /// * 402 - License error
 /// * 401 - Imageflow server authorization required
@@ -375,9 +424,10 @@ pub extern "C" fn imageflow_context_error_as_exit_code(context: *mut Context) ->
 /// * 402 - License error
 /// * 403 - Action forbidden under imageflow security policy
 /// * 404 - Primary resource/file not found
+/// * 413 - Image or bitmap size limit exceeded
 /// * 500 - Secondary resource/file not found, IO error, no solution error, unknown error, custom error, internal error
 /// * 502 - Upstream server error
-/// * 503 - Out Of Memory condition (malloc/calloc/realloc failed).
+/// * 503 - Out Of Memory condition (malloc/calloc/realloc failed), operation cancelled or timed out
 /// * 504 - Upstream timeout
 #[no_mangle]
 pub extern "C" fn imageflow_context_error_as_http_code(context: *mut Context) -> i32 {
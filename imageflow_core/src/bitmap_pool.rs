@@ -0,0 +1,190 @@
+use ::std;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use ::libc;
+use ::ffi;
+
+/// How many bytes of idle buffers a pool created by Context::create keeps for reuse
+pub const DEFAULT_MAX_IDLE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Pixel buffers of destroyed bitmaps, kept for the next bitmaps of a similar size. Clones share the buffers,
+/// so jobs run one after another, or contexts on other threads, can draw from one pool.
+#[derive(Clone)]
+pub struct BitmapPool {
+    inner: Arc<Mutex<IdleBuffers>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BitmapPoolStats {
+    /// Buffers handed out again instead of allocated
+    pub reused: u64,
+    /// Buffers allocated because no idle buffer of their size class was left
+    pub allocated: u64,
+    /// Buffers freed on release because the pool was full
+    pub freed: u64,
+    /// Bytes held for reuse now
+    pub idle_bytes: u64,
+}
+
+struct IdleBuffers {
+    /// malloc'd buffers by size class
+    by_class: BTreeMap<usize, Vec<PixelBuffer>>,
+    max_idle_bytes: usize,
+    stats: BitmapPoolStats,
+}
+
+struct PixelBuffer(*mut libc::c_void);
+
+// Idle buffers belong to no one until the pool hands them out again
+unsafe impl Send for PixelBuffer {}
+
+/// Buffers come in size classes an eighth of a power of two apart, so less than a quarter of each request is wasted
+fn size_class(byte_count: usize) -> usize {
+    if byte_count <= 4096 {
+        return 4096;
+    }
+    let step = byte_count.next_power_of_two() / 8;
+    (byte_count + step - 1) / step * step
+}
+
+impl BitmapPool {
+    pub fn new(max_idle_bytes: usize) -> BitmapPool {
+        BitmapPool {
+            inner: Arc::new(Mutex::new(IdleBuffers {
+                by_class: BTreeMap::new(),
+                max_idle_bytes: max_idle_bytes,
+                stats: BitmapPoolStats::default(),
+            })),
+        }
+    }
+
+    pub fn stats(&self) -> BitmapPoolStats {
+        self.lock().stats
+    }
+
+    /// Frees every idle buffer
+    pub fn trim(&self) {
+        let mut idle = self.lock();
+        idle.free_all();
+        idle.stats.idle_bytes = 0;
+    }
+
+    /// Makes `c` allocate its bitmaps' pixels from this pool. The pool must outlive `c`.
+    pub(crate) unsafe fn attach(&self, c: *mut ffi::ImageflowContext) {
+        let pool = &*self.inner as *const Mutex<IdleBuffers> as *mut libc::c_void;
+        ffi::flow_context_set_pixel_pool(c, pool, pool_acquire, pool_release);
+    }
+
+    fn lock(&self) -> MutexGuard<IdleBuffers> {
+        lock(&self.inner)
+    }
+
+    fn acquire(idle: &Mutex<IdleBuffers>, byte_count: usize, zeroed: bool) -> *mut libc::c_void {
+        let class = size_class(byte_count);
+        let reused = {
+            let mut idle = lock(idle);
+            let buffer = idle.by_class.get_mut(&class).and_then(|list| list.pop());
+            if buffer.is_some() {
+                idle.stats.reused += 1;
+                idle.stats.idle_bytes -= class as u64;
+            } else {
+                idle.stats.allocated += 1;
+            }
+            buffer
+        };
+        unsafe {
+            match reused {
+                Some(PixelBuffer(pixels)) => {
+                    if zeroed {
+                        std::ptr::write_bytes(pixels as *mut u8, 0, byte_count);
+                    }
+                    pixels
+                }
+                None if zeroed => libc::calloc(class, 1),
+                None => libc::malloc(class),
+            }
+        }
+    }
+
+    fn release(idle: &Mutex<IdleBuffers>, pixels: *mut libc::c_void, byte_count: usize) {
+        let class = size_class(byte_count);
+        let mut idle = lock(idle);
+        if idle.stats.idle_bytes + (class as u64) <= (idle.max_idle_bytes as u64) {
+            idle.by_class.entry(class).or_insert_with(Vec::new).push(PixelBuffer(pixels));
+            idle.stats.idle_bytes += class as u64;
+        } else {
+            idle.stats.freed += 1;
+            unsafe {
+                libc::free(pixels);
+            }
+        }
+    }
+}
+
+// A panic elsewhere can't leave the buffer lists inconsistent, and these are called from C, so poisoning is ignored
+fn lock(idle: &Mutex<IdleBuffers>) -> MutexGuard<IdleBuffers> {
+    match idle.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl IdleBuffers {
+    fn free_all(&mut self) {
+        for (_, list) in std::mem::replace(&mut self.by_class, BTreeMap::new()) {
+            for PixelBuffer(pixels) in list {
+                unsafe {
+                    libc::free(pixels);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for IdleBuffers {
+    fn drop(&mut self) {
+        self.free_all();
+    }
+}
+
+extern "C" fn pool_acquire(pool: *mut libc::c_void, byte_count: usize, zeroed: bool) -> *mut libc::c_void {
+    BitmapPool::acquire(unsafe { &*(pool as *const Mutex<IdleBuffers>) }, byte_count, zeroed)
+}
+
+extern "C" fn pool_release(pool: *mut libc::c_void, pixels: *mut libc::c_void, byte_count: usize) {
+    BitmapPool::release(unsafe { &*(pool as *const Mutex<IdleBuffers>) }, pixels, byte_count)
+}
+
+#[test]
+fn test_size_class() {
+    assert_eq!(size_class(1), 4096);
+    assert_eq!(size_class(4097), 5120);
+    assert_eq!(size_class(400 * 300 * 4), 524288);
+    assert_eq!(size_class(1 << 20), 1 << 20);
+    for &bytes in [5000usize, 65537, 1000000, 12345678].iter() {
+        assert!(size_class(bytes) >= bytes);
+        assert!(size_class(bytes) - bytes < bytes / 4);
+    }
+}
+
+#[test]
+fn test_pool_reuses_buffers_by_size_class() {
+    let pool = BitmapPool::new(1024 * 1024);
+    let a = BitmapPool::acquire(&pool.inner, 100000, true);
+    BitmapPool::release(&pool.inner, a, 100000);
+    // 99000 bytes share 100000's size class, so the buffer comes back, zeroed as asked
+    let b = BitmapPool::acquire(&pool.inner, 99000, true);
+    assert_eq!(a, b);
+    assert!(unsafe { std::slice::from_raw_parts(b as *const u8, 99000) }.iter().all(|&v| v == 0));
+    let c = BitmapPool::acquire(&pool.inner, 10000, false);
+    BitmapPool::release(&pool.inner, b, 99000);
+    BitmapPool::release(&pool.inner, c, 10000);
+    assert_eq!(pool.stats(), BitmapPoolStats { reused: 1, allocated: 2, freed: 0, idle_bytes: (size_class(100000) + size_class(10000)) as u64 });
+
+    // Past the limit buffers are freed rather than kept
+    let big = BitmapPool::acquire(&pool.inner, 2 * 1024 * 1024, false);
+    BitmapPool::release(&pool.inner, big, 2 * 1024 * 1024);
+    assert_eq!(pool.stats().freed, 1);
+    pool.trim();
+    assert_eq!(pool.stats().idle_bytes, 0);
+}
@@ -0,0 +1,135 @@
+use ::std;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use ::{ErrorKind, FlowError, Result};
+
+const RUNNING: usize = 0;
+/// Must match the C side; see flow_context_set_cancellation_flag
+const CANCELLED: usize = 1;
+const TIMED_OUT: usize = 2;
+
+/// Stops a job from another thread. Clones share one flag, which the Context's C side polls inside long loops
+/// and the execution engine checks between nodes.
+#[derive(Clone, Debug)]
+pub struct CancellationToken {
+    flag: Arc<AtomicUsize>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken { flag: Arc::new(AtomicUsize::new(RUNNING)) }
+    }
+
+    /// Requests cancellation of the running job, or of the next one to start if none is running
+    pub fn cancel(&self) {
+        self.flag.store(CANCELLED, Ordering::SeqCst);
+    }
+
+    /// Withdraws a cancellation or timeout so later jobs can run
+    pub fn clear(&self) {
+        self.flag.store(RUNNING, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst) != RUNNING
+    }
+
+    /// The error a job stopped by this token should fail with
+    pub fn check(&self) -> Result<()> {
+        match self.flag.load(Ordering::SeqCst) {
+            RUNNING => Ok(()),
+            CANCELLED => Err(nerror!(ErrorKind::OperationCancelled, "The job was cancelled")),
+            _ => Err(nerror!(ErrorKind::OperationTimedOut, "The job ran past its timeout")),
+        }
+    }
+
+    /// The address the C context polls; valid as long as any clone of the token lives
+    pub(crate) fn flag_ptr(&self) -> *const usize {
+        &*self.flag as *const AtomicUsize as *const usize
+    }
+
+    fn time_out(&self) {
+        // A cancellation takes precedence
+        let _ = self.flag.compare_exchange(RUNNING, TIMED_OUT, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    fn clear_time_out(&self) {
+        let _ = self.flag.compare_exchange(TIMED_OUT, RUNNING, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+/// Times out `token` after a delay unless dropped first. Dropping it also clears a timeout it caused,
+/// so the next job on the same context starts clean.
+pub struct Watchdog {
+    token: CancellationToken,
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn start(token: CancellationToken, timeout_ms: u64) -> Watchdog {
+        let (tx, rx) = mpsc::channel();
+        let watched = token.clone();
+        let thread = thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(Duration::from_millis(timeout_ms)) {
+                watched.time_out();
+            }
+        });
+        Watchdog { token: token, stop: Some(tx), thread: Some(thread) }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Disconnecting wakes the thread early
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.token.clear_time_out();
+    }
+}
+
+/// Clears `token` when dropped, so a cancellation stops the job it arrived during rather than every later job
+/// on the same context
+pub struct JobScope {
+    token: CancellationToken,
+}
+
+impl JobScope {
+    pub fn start(token: CancellationToken) -> JobScope {
+        JobScope { token: token }
+    }
+}
+
+impl Drop for JobScope {
+    fn drop(&mut self) {
+        self.token.clear();
+    }
+}
+
+#[test]
+fn test_cancellation_token() {
+    let token = CancellationToken::new();
+    assert!(token.check().is_ok());
+    {
+        let _watchdog = Watchdog::start(token.clone(), 1);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(token.check().unwrap_err().kind, ErrorKind::OperationTimedOut);
+    }
+    assert!(!token.is_cancelled());
+    token.clone().cancel();
+    {
+        let _watchdog = Watchdog::start(token.clone(), 1);
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(token.check().unwrap_err().kind, ErrorKind::OperationCancelled);
+    {
+        let _scope = JobScope::start(token.clone());
+        assert!(token.is_cancelled());
+    }
+    assert!(token.check().is_ok());
+}
@@ -128,7 +128,7 @@ impl LibClient {
 
 
         let (encodes, perf): (Vec<s::EncodeResult>, Option<s::BuildPerformance>) = match payload {
-            s::ResponsePayload::JobResult(s::JobResult { encodes, performance}) => (encodes, performance),
+            s::ResponsePayload::JobResult(s::JobResult { encodes, performance, .. }) => (encodes, performance),
             _ => {
                 unreachable!();
             }
@@ -25,6 +25,10 @@ pub trait DecoderFactory{
 pub trait Decoder : Any{
     fn initialize(&mut self, c: &Context) -> Result<()>;
     fn get_image_info(&mut self, c: &Context) -> Result<s::ImageInfo>;
+    /// The image's size before any downscaling the decoder was told to do during decoding
+    fn get_unscaled_size(&mut self, c: &Context) -> Result<(i32, i32)> {
+        self.get_image_info(c).map(|info| (info.image_width, info.image_height))
+    }
     fn get_exif_rotation_flag(&mut self, c: &Context) -> Result<Option<i32>>;
     fn tell_decoder(&mut self, c: &Context, tell: s::DecoderCommand) -> Result<()>;
     fn read_frame(&mut self, c: &Context) -> Result<*mut BitmapBgra>;
@@ -43,7 +47,9 @@ pub trait Encoder{
 enum CodecKind{
     EncoderPlaceholder,
     Encoder(Box<Encoder>),
-    Decoder(Box<Decoder>)
+    Decoder(Box<Decoder>),
+    /// Inputs that aren't images, such as fonts
+    Data(IoProxy)
 }
 // We need a rust-friendly codec instance, codec definition, and a way to wrap C codecs
 pub struct CodecInstanceContainer{
@@ -54,6 +60,24 @@ pub struct CodecInstanceContainer{
 
 impl CodecInstanceContainer {
 
+    /// Reads the whole of an input that isn't an image
+    pub fn read_data(&self, c: &Context) -> Result<Vec<u8>>{
+        if let CodecKind::Data(ref io) = self.codec{
+            io.seek(c, 0).map_err(|e| e.at(here!()))?;
+            let mut data = Vec::new();
+            let mut buffer = [0u8; 8192];
+            loop {
+                let read = io.read_to_buffer(c, &mut buffer).map_err(|e| e.at(here!()))?;
+                if read <= 0 {
+                    return Ok(data);
+                }
+                data.extend_from_slice(&buffer[..read as usize]);
+            }
+        }else{
+            Err(nerror!(ErrorKind::InvalidArgument, "io_id {} is an image, not a data input", self.io_id))
+        }
+    }
+
     pub fn get_decoder(&mut self) -> Result<&mut Box<Decoder>>{
         if let CodecKind::Decoder(ref mut d) = self.codec{
             Ok(d)
@@ -76,7 +100,16 @@ impl CodecInstanceContainer {
             let result = io.read_to_buffer(c, &mut buffer).map_err(|e| e.at(here!()))?;
 
             io.seek(c, 0).map_err(|e| e.at(here!()))?;
-            if buffer.starts_with(b"GIF89a") || buffer.starts_with(b"GIF87a") {
+            let font = buffer.starts_with(b"\x00\x01\x00\x00") || buffer.starts_with(b"OTTO") ||
+                buffer.starts_with(b"true") || buffer.starts_with(b"ttcf");
+            if font {
+                Ok(CodecInstanceContainer
+                    {
+                        io_id,
+                        codec: CodecKind::Data(io),
+                        encode_io: None
+                    })
+            } else if buffer.starts_with(b"GIF89a") || buffer.starts_with(b"GIF87a") {
                 return Ok(CodecInstanceContainer
                     {
                         io_id,
@@ -99,6 +132,8 @@ impl CodecInstanceContainer {
 struct ClassicDecoder{
     classic: CodecInstance,
     ignore_color_profile: bool,
+    /// Recorded before the first downscale hint, since the codec reports the downscaled size after it
+    unscaled_size: Option<(i32, i32)>,
     io: IoProxy
 }
 
@@ -119,7 +154,8 @@ impl ClassicDecoder {
                     io: io.get_io_ptr()
                 },
                 io,
-                ignore_color_profile: false
+                ignore_color_profile: false,
+                unscaled_size: None
             }))
         }
     }
@@ -213,7 +249,20 @@ impl Decoder for ClassicDecoder{
     }
 
 
+    fn get_unscaled_size(&mut self, c: &Context) -> Result<(i32, i32)> {
+        match self.unscaled_size {
+            Some(size) => Ok(size),
+            None => self.get_image_info(c).map(|info| (info.image_width, info.image_height))
+        }
+    }
+
     fn tell_decoder(&mut self, c: &Context, tell: s::DecoderCommand) -> Result<()> {
+        if let s::DecoderCommand::JpegDownscaleHints(_) = tell {
+            if self.unscaled_size.is_none() {
+                let info = self.get_image_info(c).map_err(|e| e.at(here!()))?;
+                self.unscaled_size = Some((info.image_width, info.image_height));
+            }
+        }
         let classic = &mut self.classic;
 
         match tell {
@@ -316,12 +365,7 @@ impl Encoder for ClassicEncoder{
         unsafe {
             let classic = &mut self.classic;
 
-            let (result_mime, result_ext) = match *preset {
-                s::EncoderPreset::Libpng { .. } => ("image/png", "png"),
-                s::EncoderPreset::LibjpegTurbo { .. } => ("image/jpeg", "jpg"),
-
-                s::EncoderPreset::Gif { .. } => ("image/gif", "gif"),
-            };
+            let (result_mime, result_ext) = preset.mime_type_and_extension();
 
             classic.codec_id = wanted_id;
             if !ffi::flow_codec_initialize(c.flow_c(), classic as *mut ffi::CodecInstance) {
@@ -383,7 +427,12 @@ impl CodecInstanceContainer{
 
 
          if let CodecKind::Encoder(ref mut e) = self.codec {
-             e.write_frame(c, preset, frame, decoder_io_ids).map_err(|e| e.at(here!()))
+             let start = time::precise_time_ns();
+             let result = e.write_frame(c, preset, frame, decoder_io_ids).map_err(|e| e.at(here!()));
+             if c.trace_enabled() {
+                 c.trace_span(&format!("write_frame io {}", self.io_id), "codec", start);
+             }
+             result
          }else{
              Err(unimpl!())
              //Err(FlowError::ErrNotImpl)
@@ -399,6 +448,28 @@ impl CodecInstanceContainer{
     }
 }
 
+/// Encodes `frame` into memory rather than to one of the job's outputs, for small images reported inside results
+pub fn encode_to_vec(c: &Context, preset: &s::EncoderPreset, frame: &mut BitmapBgra) -> Result<Vec<u8>>{
+    // The buffer never joins the job's io list; it only needs an id no real output uses
+    let mut io_id = i32::min_value();
+    while c.io_id_present(io_id) {
+        io_id += 1;
+    }
+    let io = IoProxy::create_output_buffer(c, io_id).map_err(|e| e.at(here!()))?;
+    let io_ptr = io.get_io_ptr();
+    let result = CodecInstanceContainer::create(c, io, io_id, IoDirection::Out)
+        .and_then(|mut codec| {
+            codec.write_frame(c, preset, frame, &[]).map_err(|e| e.at(here!()))?;
+            let bytes = codec.get_encode_io().map_err(|e| e.at(here!()))?.unwrap()
+                .get_output_buffer_bytes(c).map_err(|e| e.at(here!()))?.to_vec();
+            Ok(bytes)
+        });
+    unsafe {
+        ::ffi::flow_destroy(c.flow_c(), io_ptr as *const c_void, ptr::null(), 0);
+    }
+    result
+}
+
 
 struct ColorTransformCache{
 
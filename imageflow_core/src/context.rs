@@ -8,6 +8,12 @@ use std::any::Any;
 use ::imageflow_types::collections::AddRemoveSet;
 use ::ffi::ImageflowJsonResponse;
 use ::errors::{OutwardErrorBuffer, CErrorProxy};
+use ::cancellation::{CancellationToken, JobScope, Watchdog};
+use ::bitmap_pool::{self, BitmapPool};
+use ::trace::TraceRecorder;
+use ::frame_cache::FrameCache;
+use threadpool::ThreadPool;
+use std::collections::BTreeMap;
 
 use codecs::CodecInstanceContainer;
 use ffi::IoDirection;
@@ -20,11 +26,37 @@ pub struct Context {
     pub next_stable_node_id: i32,
     pub next_graph_version: i32,
     pub max_calc_flatten_execute_passes: i32,
+    /// How many independent nodes may execute at once; 1 (the default) executes serially
+    pub max_parallelism: usize,
+    /// How many threads one resample may split its output rows across; 1 (the default) stays on the calling thread
+    pub max_resample_threads: usize,
+    /// Whether the optimizer may ask JPEG decoders to downscale during IDCT; set by each build
+    pub enable_jpeg_block_scaling: bool,
+    /// How fast this host executes each kind of work; used by v0.1/estimate
+    pub cost_coefficients: s::CostCoefficients,
+    /// Nanoseconds spent on helper threads by the node executing now; see `add_helper_thread_ns`
+    helper_thread_ns: std::cell::Cell<u64>,
+    cancellation: CancellationToken,
+    /// Supplies the pixels of this context's bitmaps; must outlive the C context, so is dropped after it
+    bitmap_pool: BitmapPool,
+    limits: s::JobLimits,
+    /// Shared with the contexts of helper threads working on the same job
+    trace: RefCell<Option<std::sync::Arc<std::sync::Mutex<TraceRecorder>>>>,
+    /// None until a build enables it
+    frame_cache: RefCell<Option<Box<FrameCache>>>,
+    /// None until a node first splits its work across threads
+    helpers: RefCell<Option<Box<HelperThreads>>>,
     pub graph_recording: s::Build001GraphRecording,
     pub codecs: AddRemoveSet<CodecInstanceContainer>, // This loans out exclusive mutable references to items, bounding the ownership lifetime to Context
     pub io_id_list: RefCell<Vec<i32>>
 }
 
+/// Threads a node can split its work across, and idle contexts for them to work with; kept between calls
+struct HelperThreads {
+    pool: Option<ThreadPool>,
+    idle: Vec<Box<Context>>,
+}
+
 static mut JOB_ID: i32 = 0;
 impl Context {
 
@@ -37,6 +69,12 @@ impl Context {
         if inner.is_null() {
             Err(err_oom!())
         } else {
+            let cancellation = CancellationToken::new();
+            let bitmap_pool = BitmapPool::new(bitmap_pool::DEFAULT_MAX_IDLE_BYTES);
+            unsafe {
+                ffi::flow_context_set_cancellation_flag(inner, cancellation.flag_ptr());
+                bitmap_pool.attach(inner);
+            }
             Ok(Box::new(Context {
                 c_ctx: inner,
                 error: CErrorProxy::new(inner),
@@ -45,6 +83,17 @@ impl Context {
                 next_graph_version: 0,
                 next_stable_node_id: 0,
                 max_calc_flatten_execute_passes: 40,
+                max_parallelism: 1,
+                max_resample_threads: 1,
+                enable_jpeg_block_scaling: false,
+                cost_coefficients: s::CostCoefficients::default(),
+                helper_thread_ns: std::cell::Cell::new(0),
+                cancellation: cancellation,
+                bitmap_pool: bitmap_pool,
+                limits: s::JobLimits::default(),
+                trace: RefCell::new(None),
+                frame_cache: RefCell::new(None),
+                helpers: RefCell::new(None),
                 graph_recording: s::Build001GraphRecording::off(),
                 codecs: AddRemoveSet::with_capacity(4),
                 io_id_list: RefCell::new(Vec::with_capacity(2))
@@ -102,17 +151,247 @@ impl Context {
         self.c_ctx
     }
 
+    /// A handle that cancels this context's jobs from any thread
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Gives `child`, a context doing part of this context's job on a helper thread, the job's limits, cost
+    /// coefficients, trace, cancellation and bitmap pool
+    pub fn share_job_settings(&self, child: &mut Context) {
+        child.set_limits(self.limits.clone());
+        child.cost_coefficients = self.cost_coefficients.clone();
+        *child.trace.borrow_mut() = self.trace.borrow().clone();
+        child.share_cancellation(self.cancellation_token());
+        child.share_bitmap_pool(self.bitmap_pool());
+    }
+
+    /// Makes this context stop along with `token`; used by helper threads working for another context
+    pub fn share_cancellation(&mut self, token: CancellationToken) {
+        unsafe {
+            ffi::flow_context_set_cancellation_flag(self.c_ctx, token.flag_ptr());
+        }
+        self.cancellation = token;
+    }
+
+    /// Withdraws a pending cancellation; jobs started through build_1, estimate_1 and execute_1 do this
+    /// themselves once they end
+    pub fn clear_cancellation(&self) {
+        self.cancellation.clear();
+    }
+
+    /// Fails with OperationCancelled or OperationTimedOut once the job should stop
+    pub fn check_cancellation(&self) -> Result<()> {
+        self.cancellation.check()
+    }
+
+    /// The pool this context's bitmaps take their pixel buffers from and return them to
+    pub fn bitmap_pool(&self) -> BitmapPool {
+        self.bitmap_pool.clone()
+    }
+
+    /// Makes this context draw its pixel buffers from `pool`, shared with other contexts or a previous one.
+    /// Bitmaps it allocated before return their buffers to `pool` as well.
+    pub fn share_bitmap_pool(&mut self, pool: BitmapPool) {
+        unsafe {
+            pool.attach(self.c_ctx);
+        }
+        self.bitmap_pool = pool;
+    }
+
+    /// The most bytes of bitmap pixels allocated at once since the last `reset_peak_bitmap_bytes`
+    pub fn peak_bitmap_bytes(&self) -> u64 {
+        unsafe { ffi::flow_context_bitmap_bytes_peak(self.c_ctx) as u64 }
+    }
+
+    pub fn reset_peak_bitmap_bytes(&self) {
+        unsafe {
+            ffi::flow_context_reset_bitmap_bytes_peak(self.c_ctx);
+        }
+    }
+
+    pub fn limits(&self) -> &s::JobLimits {
+        &self.limits
+    }
+
+    /// Applies until changed; each build sets its own limits, or the defaults. The bitmap memory limit is also
+    /// enforced on each allocation by the C side.
+    pub fn set_limits(&mut self, limits: s::JobLimits) {
+        let max_bytes = limits.max_bitmap_bytes.map(|v| ::std::cmp::min(v, usize::max_value() as u64) as usize).unwrap_or(0);
+        unsafe {
+            ffi::flow_context_set_max_bitmap_bytes(self.c_ctx, max_bytes);
+        }
+        self.limits = limits;
+    }
+
+    /// Fails with SizeLimitExceeded if a source image of `w` x `h` may not be decoded
+    pub fn check_input_pixels(&self, w: i32, h: i32) -> Result<()> {
+        match self.limits.max_input_pixels {
+            Some(max) if w as u64 * h as u64 > max =>
+                Err(nerror!(ErrorKind::SizeLimitExceeded, "The {}x{} source image exceeds the limit of {} input pixels", w, h, max)),
+            _ => Ok(())
+        }
+    }
+
+    /// Fails with SizeLimitExceeded if an image of `w` x `h` may not be encoded
+    pub fn check_output_pixels(&self, w: i32, h: i32) -> Result<()> {
+        match self.limits.max_output_pixels {
+            Some(max) if w as u64 * h as u64 > max =>
+                Err(nerror!(ErrorKind::SizeLimitExceeded, "The {}x{} output image exceeds the limit of {} output pixels", w, h, max)),
+            _ => Ok(())
+        }
+    }
+
+    /// Fails with SizeLimitExceeded if a single `w` x `h` bitmap with `bytes_per_pixel` could never fit
+    pub fn check_bitmap_size(&self, w: i32, h: i32, bytes_per_pixel: usize) -> Result<()> {
+        let bytes = w as u64 * h as u64 * bytes_per_pixel as u64;
+        match self.limits.max_bitmap_bytes {
+            Some(max) if bytes > max =>
+                Err(nerror!(ErrorKind::SizeLimitExceeded, "A {}x{} bitmap ({} bytes) exceeds the limit of {} bitmap bytes", w, h, bytes, max)),
+            _ => Ok(())
+        }
+    }
+
+    /// Records CPU time a node spent on threads other than the one calling it, so the job's cpu_ticks include it
+    pub fn add_helper_thread_ns(&self, ns: u64) {
+        self.helper_thread_ns.set(self.helper_thread_ns.get() + ns);
+    }
+
+    /// A pool of `threads` threads for a node to split its work across. The pool is kept, and resized on later calls.
+    pub fn helper_thread_pool(&self, threads: usize) -> ThreadPool {
+        self.with_helpers(|helpers| {
+            if helpers.pool.is_none() {
+                helpers.pool = Some(ThreadPool::new(threads));
+            }
+            let pool = helpers.pool.as_mut().unwrap();
+            if pool.max_count() != threads {
+                pool.set_num_threads(threads);
+            }
+            pool.clone()
+        })
+    }
+
+    /// `count` contexts for helper threads to work with, sharing this context's job settings.
+    /// Hand them back with `return_helper_contexts` so later calls can reuse them.
+    pub fn take_helper_contexts(&self, count: usize) -> Result<Vec<Box<Context>>> {
+        let mut taken = Vec::with_capacity(count);
+        while taken.len() < count {
+            let idle = self.with_helpers(|helpers| helpers.idle.pop());
+            let mut child = match idle {
+                Some(child) => child,
+                None => Context::create().map_err(|e| e.at(here!()))?
+            };
+            self.share_job_settings(&mut child);
+            taken.push(child);
+        }
+        Ok(taken)
+    }
+
+    pub fn return_helper_contexts(&self, contexts: Vec<Box<Context>>) {
+        self.with_helpers(|helpers| helpers.idle.extend(contexts));
+    }
+
+    fn with_helpers<F, R>(&self, f: F) -> R where F: FnOnce(&mut HelperThreads) -> R {
+        let mut helpers = self.helpers.borrow_mut();
+        if helpers.is_none() {
+            *helpers = Some(Box::new(HelperThreads { pool: None, idle: Vec::new() }));
+        }
+        f(helpers.as_mut().unwrap())
+    }
+
+    /// Returns and resets the time recorded by `add_helper_thread_ns`
+    pub fn take_helper_thread_ns(&self) -> u64 {
+        let ns = self.helper_thread_ns.get();
+        self.helper_thread_ns.set(0);
+        ns
+    }
+
+    /// Starts collecting trace events, discarding any collected before
+    pub fn start_trace(&self) {
+        *self.trace.borrow_mut() = Some(std::sync::Arc::new(std::sync::Mutex::new(TraceRecorder::new())));
+    }
+
+    /// Stops tracing and returns the events collected
+    pub fn take_trace(&self) -> Option<Vec<s::TraceEvent>> {
+        // Helper contexts may still hold the recorder, so we take its events rather than the recorder itself
+        self.trace.borrow_mut().take().map(|t| {
+            let recorder = {
+                let mut guard = t.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                std::mem::replace(&mut *guard, TraceRecorder::new())
+            };
+            recorder.into_events()
+        })
+    }
+
+    pub fn trace_enabled(&self) -> bool {
+        self.trace.borrow().is_some()
+    }
+
+    /// Records a span from `start_ns` (a time::precise_time_ns value) until now, if tracing
+    pub fn trace_span(&self, name: &str, cat: &str, start_ns: u64) {
+        if self.trace_enabled() {
+            self.trace_span_with(name, cat, 0, start_ns, time::precise_time_ns(), BTreeMap::new());
+        }
+    }
+
+    pub fn trace_span_with(&self, name: &str, cat: &str, tid: u32, start_ns: u64, end_ns: u64, args: BTreeMap<String, String>) {
+        if let Some(ref t) = *self.trace.borrow() {
+            t.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).record(name, cat, tid, start_ns, end_ns, args);
+        }
+    }
+
+    /// Ends the trace a build requested, writing it to a file or returning it for the response
+    fn finish_trace(&self, destination: Option<s::TraceDestination>) -> Result<Option<Vec<s::TraceEvent>>> {
+        let events = self.take_trace();
+        match destination {
+            Some(s::TraceDestination::Response) => Ok(events),
+            Some(s::TraceDestination::File(path)) => {
+                let file = std::fs::File::create(&path).map_err(|e| nerror!(ErrorKind::EncodingIoError, "Failed to create trace file {}: {:?}", path, e))?;
+                serde_json::to_writer(file, &events.unwrap_or(vec![])).map_err(|e| nerror!(ErrorKind::EncodingIoError, "Failed to write trace file {}: {:?}", path, e))?;
+                Ok(None)
+            }
+            None => Ok(None)
+        }
+    }
+
+    /// Enables the frame cache with room for `max_bytes`, or resizes it; 0 disables it and frees the frames it held.
+    /// Cached frames count toward the bitmap memory limit.
+    pub fn configure_frame_cache(&self, max_bytes: u64) {
+        let mut cache = self.frame_cache.borrow_mut();
+        if max_bytes == 0 {
+            if let Some(mut old) = cache.take() {
+                old.clear(self);
+            }
+        } else if cache.is_some() {
+            cache.as_mut().unwrap().resize(self, max_bytes);
+        } else {
+            *cache = Some(Box::new(FrameCache::new(max_bytes)));
+        }
+    }
+
+    pub fn frame_cache_stats(&self) -> Option<s::FrameCacheStats> {
+        self.frame_cache.borrow().as_ref().map(|cache| cache.stats())
+    }
+
+    /// The frame cache, if enabled; see `configure_frame_cache`
+    pub(crate) fn frame_cache(&self) -> RefMut<Option<Box<FrameCache>>> {
+        self.frame_cache.borrow_mut()
+    }
+
     pub fn io_id_present(&self, io_id: i32) -> bool{
         self.io_id_list.borrow().iter().any(|v| *v == io_id)
     }
 
     fn add_io(&self, io: IoProxy, io_id: i32, direction: IoDirection) -> Result<()>{
-
+        let start = time::precise_time_ns();
         let codec_value = CodecInstanceContainer::create(self, io, io_id, direction).map_err(|e| e.at(here!()))?;
         let mut codec = self.codecs.add_mut(codec_value);
         if let Ok(d) = codec.get_decoder(){
             d.initialize( self).map_err(|e| e.at(here!()))?;
         }
+        if self.trace_enabled() {
+            self.trace_span(&format!("open io {}", io_id), "codec", start);
+        }
         Ok(())
     }
 
@@ -168,6 +447,11 @@ impl Context {
         self.get_codec(io_id).map_err(|e| e.at(here!()))?.get_decoder().map_err(|e| e.at(here!()))?.tell_decoder(self,  tell).map_err(|e| e.at(here!()))
     }
 
+    /// The size of an input image before any downscaling its decoder was told to do
+    pub fn get_unscaled_image_size(&mut self, io_id: i32) -> Result<(i32, i32)> {
+        self.get_codec(io_id).map_err(|e| e.at(here!()))?.get_decoder().map_err(|e| e.at(here!()))?.get_unscaled_size(self).map_err(|e| e.at(here!()))
+    }
+
     pub fn get_exif_rotation_flag(&mut self, io_id: i32) -> Result<Option<i32>>{
         self.get_codec(io_id).map_err(|e| e.at(here!()))?.get_decoder().map_err(|e| e.at(here!()))?.get_exif_rotation_flag( self).map_err(|e| e.at(here!()))
 
@@ -195,23 +479,102 @@ impl Context {
 
 
     pub fn build_1(&mut self, parsed: s::Build001) -> Result<s::ResponsePayload> {
+        let _scope = JobScope::start(self.cancellation_token());
         let mut g =::parsing::GraphTranslator::new().translate_framewise(parsed.framewise).map_err(|e| e.at(here!())) ?;
 
 
-        if let Some(s::Build001Config { graph_recording, .. }) = parsed.builder_config {
+        let mut timeout_ms = None;
+        let mut trace_to = None;
+        self.enable_jpeg_block_scaling = false;
+        // Limits apply to one build; a build without them gets the defaults, not the last build's
+        self.set_limits(s::JobLimits::default());
+        if let Some(s::Build001Config { graph_recording, job_timeout_ms, limits, trace, frame_cache, enable_jpeg_block_scaling, max_parallelism, max_resample_threads, cost_coefficients }) = parsed.builder_config {
             if let Some(r) = graph_recording {
                 self.configure_graph_recording(r);
             }
+            if let Some(l) = limits {
+                self.set_limits(l);
+            }
+            if let Some(cache) = frame_cache {
+                self.configure_frame_cache(cache.max_bytes);
+            }
+            if let Some(n) = max_parallelism {
+                self.max_parallelism = ::std::cmp::max(1, n as usize);
+            }
+            if let Some(n) = max_resample_threads {
+                self.max_resample_threads = ::std::cmp::max(1, n as usize);
+            }
+            if let Some(k) = cost_coefficients {
+                self.cost_coefficients = k;
+            }
+            timeout_ms = job_timeout_ms;
+            trace_to = trace;
+            self.enable_jpeg_block_scaling = enable_jpeg_block_scaling.unwrap_or(false);
         }
+        if trace_to.is_some() {
+            self.start_trace();
+        } else {
+            // Drop whatever a failed job left behind
+            let _ = self.take_trace();
+        }
+        let _watchdog = timeout_ms.map(|ms| Watchdog::start(self.cancellation_token(), ms));
 
         ::parsing::IoTranslator{}.add_all( self, parsed.io.clone())?;
 
-        let mut engine = ::flow::execution_engine::Engine::create(self, g);
+        if let Some(ref mut cache) = *self.frame_cache() {
+            cache.set_inputs(&parsed.io);
+        }
+        let executed = {
+            let mut engine = ::flow::execution_engine::Engine::create(self, g);
+
+            let perf = engine.execute_many();
+            perf.map(|perf| (perf, engine.collect_augmented_encode_results(&parsed.io), engine.collect_analysis_results()))
+        };
+        if let Some(ref mut cache) = *self.frame_cache() {
+            cache.forget_inputs();
+        }
+        let (perf, encodes, analyses) = executed.map_err(|e| e.at(here!()))?;
+        let trace = self.finish_trace(trace_to).map_err(|e| e.at(here!()))?;
 
-        let perf = engine.execute_many().map_err(|e| e.at(here!())) ?;
+        Ok(s::ResponsePayload::BuildResult(s::JobResult { encodes: encodes, analyses: analyses, performance: Some(perf), trace: trace, frame_cache: self.frame_cache_stats() }))
+    }
+
+    /// Dry-runs a build: inputs are read only as far as their headers, and no output is written. Unlike a build's,
+    /// the config doesn't change the context's settings.
+    pub fn estimate_1(&mut self, parsed: s::Build001) -> Result<s::ResponsePayload> {
+        let _scope = JobScope::start(self.cancellation_token());
+        let g = ::parsing::GraphTranslator::new().translate_framewise(parsed.framewise).map_err(|e| e.at(here!())) ?;
+
+        // Codecs stay registered by io_id, so the dry run gets a context of its own; a build with the same
+        // io_ids would otherwise find the estimate's codecs first
+        let mut scratch = Context::create().map_err(|e| e.at(here!()))?;
+        self.share_job_settings(&mut scratch);
+        scratch.set_limits(s::JobLimits::default());
+        scratch.graph_recording = self.graph_recording.clone();
+        if let Some(s::Build001Config { graph_recording, limits, enable_jpeg_block_scaling, cost_coefficients, .. }) = parsed.builder_config {
+            if let Some(r) = graph_recording {
+                scratch.configure_graph_recording(r);
+            }
+            if let Some(l) = limits {
+                scratch.set_limits(l);
+            }
+            scratch.enable_jpeg_block_scaling = enable_jpeg_block_scaling.unwrap_or(false);
+            if let Some(k) = cost_coefficients {
+                scratch.cost_coefficients = k;
+            }
+        }
 
+        // Encoders still need somewhere to link to, but nothing should reach the disk
+        let io = parsed.io.into_iter().map(|obj| if obj.direction == s::IoDirection::Out {
+            s::IoObject { io: s::IoEnum::OutputBuffer, ..obj }
+        } else {
+            obj
+        }).collect();
+        ::parsing::IoTranslator{}.add_all(&mut scratch, io)?;
+
+        let mut engine = ::flow::execution_engine::Engine::create(&mut scratch, g);
 
-        Ok(s::ResponsePayload::BuildResult(s::JobResult { encodes: engine.collect_augmented_encode_results(&parsed.io), performance: Some(perf) }))
+        Ok(s::ResponsePayload::JobEstimate(engine.estimate().map_err(|e| e.at(here!()))?))
     }
 
     pub fn configure_graph_recording(&mut self, recording: s::Build001GraphRecording) {
@@ -225,15 +588,18 @@ impl Context {
     }
 
     pub fn execute_1(&mut self, what: s::Execute001) -> Result<s::ResponsePayload>{
+        let _scope = JobScope::start(self.cancellation_token());
         let mut g = ::parsing::GraphTranslator::new().translate_framewise(what.framewise).map_err(|e| e.at(here!()))?;
         if let Some(r) = what.graph_recording {
             self.configure_graph_recording(r);
         }
+        self.enable_jpeg_block_scaling = false;
+        self.set_limits(s::JobLimits::default());
         let mut engine = ::flow::execution_engine::Engine::create(self, g);
 
         let perf = engine.execute_many().map_err(|e| e.at(here!()))?;
 
-        Ok(s::ResponsePayload::JobResult(s::JobResult { encodes: engine.collect_encode_results(), performance: Some(perf) }))
+        Ok(s::ResponsePayload::JobResult(s::JobResult { encodes: engine.collect_encode_results(), analyses: engine.collect_analysis_results(), performance: Some(perf), trace: None, frame_cache: self.frame_cache_stats() }))
     }
 
 
@@ -256,6 +622,25 @@ impl Drop for Context {
     }
 }
 
+#[test]
+fn test_helper_contexts_share_job_settings(){
+    let mut c = Context::create().unwrap();
+    let limits = s::JobLimits { max_bitmap_bytes: Some(1000), .. Default::default() };
+    c.set_limits(limits.clone());
+    let helpers = c.take_helper_contexts(2).unwrap();
+    for helper in helpers.iter() {
+        assert_eq!(helper.limits(), &limits);
+        assert!(helper.check_bitmap_size(100, 100, 4).is_err());
+    }
+    c.return_helper_contexts(helpers);
+
+    // Contexts handed back are reused, with the settings of the job taking them
+    c.set_limits(s::JobLimits::default());
+    let helpers = c.take_helper_contexts(3).unwrap();
+    assert!(helpers.iter().all(|helper| helper.limits() == &s::JobLimits::default()));
+    assert_eq!(c.helpers.borrow().as_ref().unwrap().idle.len(), 0);
+}
+
 #[test]
 fn test_context_size(){
     println!("std::mem::sizeof(Context) = {}", std::mem::size_of::<Context>());
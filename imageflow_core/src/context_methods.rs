@@ -20,6 +20,10 @@ fn create_context_router() -> MethodRouter<'static, Context> {
                     Box::new(move |context: &mut Context, parsed: s::Build001| {
                         context.build_1(parsed).map_err(|e| e.at(here!()))
                     }));
+    r.add_responder("v0.1/estimate",
+                    Box::new(move |context: &mut Context, parsed: s::Build001| {
+                        context.estimate_1(parsed).map_err(|e| e.at(here!()))
+                    }));
     r.add_responder("v0.1/get_image_info",
                     Box::new(move |context: &mut Context, data: s::GetImageInfo001| {
                         Ok(s::ResponsePayload::ImageInfo(context.get_image_info(data.io_id).map_err(|e| e.at(here!()))?))
@@ -74,6 +78,12 @@ fn document_message() -> String {
                                                                                    "image/png",
                                                                                    "png"))
         .unwrap();
+    s += "## v0.1/estimate \n";
+    s += "Accepts the same message body as v0.1/build, but only reads image headers and writes no output.\n";
+    s += "Encodes whose size depends on pixel data report null dimensions, and 'complete' is false.\n";
+    s += "Example response:\n";
+    s += &serde_json::to_string_pretty(&s::Response001::example_job_estimate()).unwrap();
+    s += "\n\n";
     s += "## v0.1/get_image_info \n";
     s += "Example message body:\n";
     s += &serde_json::to_string_pretty(&s::GetImageInfo001::example_get_image_info()).unwrap();
@@ -166,8 +176,9 @@ fn test_handler() {
     let build = s::Build001 {
         builder_config: Some(s::Build001Config {
             graph_recording: None,
+            enable_jpeg_block_scaling: Some(false),
 //            process_all_gif_frames: Some(false),
-//            enable_jpeg_block_scaling: Some(false)
+            .. Default::default()
         }),
         io: vec![input_io, output_io],
         framewise: s::Framewise::Steps(steps),
@@ -197,6 +197,9 @@ pub enum ErrorKind{
     ValidationNotImplemented,
     InvalidOperation,
     InvalidState,
+    OperationCancelled,
+    OperationTimedOut,
+    SizeLimitExceeded,
     Category(ErrorCategory),
     CError(CStatus)
 }
@@ -231,6 +234,9 @@ impl CategorizedError for ErrorKind{
             &ErrorKind::DecodingIoError => ErrorCategory::IoError,
             &ErrorKind::EncodingIoError => ErrorCategory::IoError,
             &ErrorKind::GifEncodingError => ErrorCategory::InternalError,
+            &ErrorKind::OperationCancelled => ErrorCategory::OperationCancelled,
+            &ErrorKind::OperationTimedOut => ErrorCategory::OperationTimedOut,
+            &ErrorKind::SizeLimitExceeded => ErrorCategory::SizeLimitExceeded,
             &ErrorKind::CError(ref e) => e.category(),
             &ErrorKind::Category(c) => c
         }
@@ -514,7 +520,14 @@ pub enum ErrorCategory{
     /// The category of the error is unknown
     Unknown,
     /// A custom error defined by a third-party plugin
-    Custom
+    Custom,
+
+    /// The job was cancelled through its cancellation token
+    OperationCancelled,
+    /// The job ran longer than its configured timeout
+    OperationTimedOut,
+    /// An image or bitmap was larger than the job's limits allow
+    SizeLimitExceeded
 
     // !!!!!!!!!!!!!!!!!!!!!!!!!!!!!!
     // NOTE - safe use of transmute in from_i32 requires that there be no numbering gaps in this list
@@ -524,7 +537,7 @@ pub enum ErrorCategory{
 
 impl ErrorCategory{
     pub fn last() -> ErrorCategory {
-        ErrorCategory::Unknown
+        ErrorCategory::SizeLimitExceeded
     }
     fn from_i32(v: i32) -> Option<ErrorCategory>{
         if v >= 0 && v <= ErrorCategory::last() as i32 {
@@ -575,6 +588,7 @@ impl ErrorCategory{
             ErrorCategory::NodeArgumentInvalid => 64, //EX_USAGE
             ErrorCategory::InvalidJson |
             ErrorCategory::ImageMalformed |
+            ErrorCategory::SizeLimitExceeded |
             ErrorCategory::ImageTypeNotSupported  => 65, //EX_DATAERR
             ErrorCategory::SecondaryResourceNotFound |
             ErrorCategory::PrimaryResourceNotFound => 66, // EX_NOINPUT
@@ -587,6 +601,8 @@ impl ErrorCategory{
             ErrorCategory::OutOfMemory => 71,// EX_TEMPFAIL 75 or EX_OSERR   71 ?
             ErrorCategory::IoError => 74, //EX_IOERR
             ErrorCategory::ActionForbidden => 77, //EX_NOPERM
+            ErrorCategory::OperationCancelled |
+            ErrorCategory::OperationTimedOut => 75, //EX_TEMPFAIL
             ErrorCategory::LicenseError => 402,
             ErrorCategory::AuthorizationRequired => 401,
             ErrorCategory::Ok => 0
@@ -608,6 +624,7 @@ impl ErrorCategory{
             ErrorCategory::LicenseError => 402,
             ErrorCategory::ActionForbidden => 403,
             ErrorCategory::PrimaryResourceNotFound => 404,
+            ErrorCategory::SizeLimitExceeded => 413,
 
             ErrorCategory::SecondaryResourceNotFound |
             ErrorCategory::InternalError |
@@ -617,7 +634,9 @@ impl ErrorCategory{
             ErrorCategory::IoError => 500,
 
             ErrorCategory::UpstreamError => 502,
-            ErrorCategory::OutOfMemory => 503,
+            ErrorCategory::OutOfMemory |
+            ErrorCategory::OperationCancelled |
+            ErrorCategory::OperationTimedOut => 503,
             ErrorCategory::UpstreamTimeout => 504,
         }
     }
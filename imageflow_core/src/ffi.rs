@@ -326,24 +326,35 @@ pub enum BitmapCompositingMode {
 #[derive(Clone,Debug,PartialEq)]
 pub struct BitmapFloat {
     /// buffer width in pixels
-    w: uint32_t,
+    pub w: uint32_t,
     /// buffer height in pixels
-    h: uint32_t,
+    pub h: uint32_t,
     /// The number of floats per pixel
-    channels: uint32_t,
+    pub channels: uint32_t,
     /// The pixel data
-    pixels: *mut c_float,
+    pub pixels: *mut c_float,
     /// If true, don't dispose the buffer with the struct
-    pixels_borrowed: bool,
+    pub pixels_borrowed: bool,
     /// The number of floats in the buffer
-    float_count: uint32_t,
+    pub float_count: uint32_t,
     /// The number of floats betwen (0,0) and (0,1)
-    float_stride: uint32_t,
+    pub float_stride: uint32_t,
 
     /// If true, alpha has been premultiplied
-    alpha_premultiplied: bool,
+    pub alpha_premultiplied: bool,
     /// If true, the alpha channel holds meaningful data
-    alpha_meaningful: bool,
+    pub alpha_meaningful: bool,
+}
+
+/// Mirrors `struct flow_colorcontext_info` (built without EXPOSE_SIGMOID); initialize with `flow_colorcontext_init`
+#[repr(C)]
+pub struct ColorContextInfo {
+    pub byte_to_float: [c_float; 256],
+    pub floatspace: Floatspace,
+    pub apply_srgb: bool,
+    pub apply_gamma: bool,
+    pub gamma: c_float,
+    pub gamma_inverse: c_float,
 }
 
 /** flow context: Heap Manager **/
@@ -623,6 +634,35 @@ pub struct Scale2dRenderToCanvas1d {
     pub interpolation_filter: Filter,
     pub scale_in_colorspace: Floatspace,
 }
+/// Mirrors `struct flow_interpolation_details`; created by `flow_interpolation_details_create_from`
+#[repr(C)]
+pub struct InterpolationDetails {
+    pub window: f64,
+    pub p1: f64,
+    pub p2: f64,
+    pub p3: f64,
+    pub q1: f64,
+    pub q2: f64,
+    pub q3: f64,
+    pub q4: f64,
+    pub blur: f64,
+    pub filter: Option<extern "C" fn(*const InterpolationDetails, f64) -> f64>,
+    pub sharpen_percent_goal: f32,
+}
+
+impl InterpolationDetails {
+    /// Distance (in source pixels) beyond which the filter weight is zero
+    pub fn support(&self) -> f64 {
+        self.window * self.blur
+    }
+    pub fn weight(&self, t: f64) -> f64 {
+        match self.filter {
+            Some(f) => f(self as *const InterpolationDetails, t),
+            None => 0f64
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone,Debug,Copy)]
 pub struct RenderToCanvas1d {
@@ -672,6 +712,10 @@ impl Rect{
     }
 }
 
+/// See flow_context_set_pixel_pool
+pub type PixelPoolAcquire = extern "C" fn(pool: *mut libc::c_void, byte_count: usize, zeroed: bool) -> *mut libc::c_void;
+pub type PixelPoolRelease = extern "C" fn(pool: *mut libc::c_void, pixels: *mut libc::c_void, byte_count: usize);
+
 mod must_replace{
     use super::*;
     use ::libc;
@@ -688,6 +732,14 @@ mod long_term{
         pub fn flow_context_create() -> *mut ImageflowContext;
         pub fn flow_context_begin_terminate(context: *mut ImageflowContext) -> bool;
         pub fn flow_context_destroy(context: *mut ImageflowContext);
+        pub fn flow_context_set_cancellation_flag(context: *mut ImageflowContext, flag: *const usize);
+        pub fn flow_context_set_max_bitmap_bytes(context: *mut ImageflowContext, max_bytes: usize);
+        pub fn flow_context_bitmap_bytes_peak(context: *mut ImageflowContext) -> usize;
+        pub fn flow_context_reset_bitmap_bytes_peak(context: *mut ImageflowContext);
+        pub fn flow_context_set_pixel_pool(context: *mut ImageflowContext,
+                                           pool: *mut libc::c_void,
+                                           acquire: PixelPoolAcquire,
+                                           release: PixelPoolRelease);
         pub fn flow_destroy(context: *mut ImageflowContext,
                             pointer: *const libc::c_void,
                             file: *const libc::c_char,
@@ -712,6 +764,13 @@ mod long_term{
                                                   canvas: *mut BitmapBgra,
                                                   info: *const Scale2dRenderToCanvas1d)
                                                   -> bool;
+        pub fn flow_node_execute_scale2d_render1d_rows(c: *mut ImageflowContext,
+                                                       input: *mut BitmapBgra,
+                                                       canvas: *mut BitmapBgra,
+                                                       info: *const Scale2dRenderToCanvas1d,
+                                                       from_row: u32,
+                                                       to_row: u32)
+                                                       -> bool;
         pub fn flow_node_execute_render_to_canvas_1d(c: *mut ImageflowContext,
                                                      input: *mut BitmapBgra,
                                                      canvas: *mut BitmapBgra,
@@ -855,6 +914,19 @@ mod mid_term {
 
         pub fn flow_bitmap_bgra_transpose(c: *mut ImageflowContext, input: *mut BitmapBgra, output: *mut BitmapBgra) -> bool;
 
+        pub fn flow_interpolation_details_create_from(c: *mut ImageflowContext, filter: Filter) -> *mut InterpolationDetails;
+        pub fn flow_interpolation_details_destroy(c: *mut ImageflowContext, details: *mut InterpolationDetails);
+
+        pub fn flow_colorcontext_init(c: *mut ImageflowContext, colorcontext: *mut ColorContextInfo, space: Floatspace, param_a: f32, param_b: f32, param_c: f32);
+        pub fn flow_bitmap_float_create(c: *mut ImageflowContext, sx: i32, sy: i32, channels: i32, zeroed: bool) -> *mut BitmapFloat;
+        pub fn flow_bitmap_float_destroy(c: *mut ImageflowContext, im: *mut BitmapFloat);
+        pub fn flow_bitmap_float_convert_srgb_to_linear(c: *mut ImageflowContext, colorcontext: *mut ColorContextInfo, src: *mut BitmapBgra, from_row: u32, dest: *mut BitmapFloat, dest_row: u32, row_count: u32) -> bool;
+        pub fn flow_bitmap_float_demultiply_alpha(c: *mut ImageflowContext, src: *mut BitmapFloat, from_row: u32, row_count: u32) -> bool;
+        pub fn flow_bitmap_float_copy_linear_over_srgb(c: *mut ImageflowContext, colorcontext: *mut ColorContextInfo, src: *mut BitmapFloat, from_row: u32, dest: *mut BitmapBgra, dest_row: u32, row_count: u32, from_col: u32, col_count: u32, transpose: bool) -> bool;
+        pub fn flow_bitmap_float_apply_color_matrix(c: *mut ImageflowContext, bmp: *mut BitmapFloat, row: u32, count: u32, matrix: *const *const f32) -> bool;
+        pub fn flow_bitmap_float_linear_to_luv_rows(c: *mut ImageflowContext, bit: *mut BitmapFloat, start_row: u32, row_count: u32) -> bool;
+        pub fn flow_bitmap_float_luv_to_linear_rows(c: *mut ImageflowContext, bit: *mut BitmapFloat, start_row: u32, row_count: u32) -> bool;
+
 
 }
 }
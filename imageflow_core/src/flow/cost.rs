@@ -0,0 +1,101 @@
+use ::internal_prelude::works_everywhere::*;
+use super::definitions::*;
+
+/// How far a filter reaches from the sample center, in source pixels at 1:1; must match weighting.c
+pub fn filter_window(filter: s::Filter) -> f64 {
+    match filter {
+        s::Filter::Box => 0.5,
+        s::Filter::Fastest => 0.74,
+        s::Filter::Triangle |
+        s::Filter::Linear |
+        s::Filter::Hermite |
+        s::Filter::CatmullRomFast |
+        s::Filter::CatmullRomFastSharp |
+        s::Filter::MitchellFast => 1.0,
+        s::Filter::RobidouxFast => 1.05,
+        s::Filter::NCubic |
+        s::Filter::NCubicSharp => 2.5,
+        s::Filter::Lanczos |
+        s::Filter::LanczosSharp |
+        s::Filter::RawLanczos3 |
+        s::Filter::RawLanczos3Sharp |
+        s::Filter::Ginseng |
+        s::Filter::GinsengSharp => 3.0,
+        s::Filter::Jinc => 6.0,
+        _ => 2.0,
+    }
+}
+
+/// Source pixels weighed for each output pixel when resampling a line of `from` pixels to `to`,
+/// as allocated by LineContributions_precompute
+pub fn taps_per_pixel(from: u32, to: u32, window: f64) -> u64 {
+    if from == 0 || to == 0 {
+        return 0;
+    }
+    let downscale_factor = f64::min(1f64, to as f64 / from as f64);
+    let half_source_window = (window + 0.5f64) / downscale_factor;
+    (2f64 * half_source_window).ceil() as u64 + 1
+}
+
+pub fn pixels(info: FrameInfo) -> u64 {
+    info.w as u64 * info.h as u64
+}
+
+pub fn bytes(info: FrameInfo) -> u64 {
+    pixels(info) * info.fmt.bytes() as u64
+}
+
+/// Wraps nanoseconds and byte counts, saturating the byte counts CostInfo can't hold
+pub fn cost(wall_ns: f64, heap_bytes: u64, peak_temp_bytes: u64) -> CostEstimate {
+    CostEstimate::Some(CostInfo {
+        wall_ns: wall_ns.max(0f64).round() as u64,
+        cpu_ticks: None,
+        heap_bytes: ::std::cmp::min(heap_bytes, u32::max_value() as u64) as u32,
+        peak_temp_bytes: ::std::cmp::min(peak_temp_bytes, u32::max_value() as u64) as u32,
+    })
+}
+
+/// scale2d.c buffers the source rows under the vertical filter as floats, then scales each blended row horizontally
+pub fn resample_cost(k: &s::CostCoefficients, from: FrameInfo, to_w: u32, to_h: u32, filter: s::Filter) -> CostEstimate {
+    let window = filter_window(filter);
+    let taps_x = taps_per_pixel(from.w as u32, to_w, window);
+    let taps_y = taps_per_pixel(from.h as u32, to_h, window);
+    let to_pixels = to_w as u64 * to_h as u64;
+    let taps = to_h as u64 * (from.w as u64 * taps_y) + to_pixels * taps_x;
+    let wall_ns = k.node_overhead_ns +
+        (pixels(from) + to_pixels) as f64 * k.scale_ns_per_pixel +
+        taps as f64 * k.scale_ns_per_tap;
+    let float_row_bytes = from.w as u64 * 4 * 4;
+    cost(wall_ns, 0, float_row_bytes * (taps_y + 1) + to_w as u64 * 4 * 4)
+}
+
+/// The estimate for nodes that touch each pixel they read or produce about once.
+/// Nodes without parents allocate the frame they produce; others work in place or draw onto their canvas.
+pub fn default_cost(ctx: &OpCtxMut, ix: NodeIndex) -> CostEstimate {
+    let k = &ctx.job.cost_coefficients;
+    let output = match ctx.weight(ix).frame_est {
+        FrameEstimate::Some(info) => info,
+        _ => return CostEstimate::Impossible,
+    };
+    if ctx.first_parent_input(ix).is_none() {
+        return cost(k.node_overhead_ns + pixels(output) as f64 * k.copy_ns_per_pixel, bytes(output), 0);
+    }
+    match ctx.first_parent_frame_info_some(ix) {
+        Some(input) => {
+            let touched = ::std::cmp::max(pixels(input), pixels(output));
+            cost(k.node_overhead_ns + touched as f64 * k.copy_ns_per_pixel, 0, 0)
+        }
+        None => CostEstimate::Impossible,
+    }
+}
+
+#[test]
+fn test_taps_per_pixel() {
+    // Robidoux at 1:1 weighs 6 source pixels
+    assert_eq!(taps_per_pixel(100, 100, filter_window(s::Filter::Robidoux)), 6);
+    // Upscaling doesn't widen the window
+    assert_eq!(taps_per_pixel(100, 400, filter_window(s::Filter::Robidoux)), 6);
+    // Downscaling 4x widens it 4x
+    assert_eq!(taps_per_pixel(400, 100, filter_window(s::Filter::Robidoux)), 21);
+    assert_eq!(taps_per_pixel(0, 100, 2f64), 0);
+}
@@ -62,6 +62,7 @@ pub enum EdgesIn {
     OneInput,
     OneOptionalInput,
     OneInputOneCanvas,
+    OneOrMoreInputs,
     Arbitrary {
         inputs: i32,
         canvases: i32,
@@ -233,6 +234,11 @@ pub trait NodeDef: ::std::fmt::Debug{
         self.as_one_input_one_canvas().is_some() || self.as_one_mutate_bitmap().is_some()
     }
 
+    /// Whether the node may render or mutate its bitmap on a worker thread, with a Context of its own
+    fn can_execute_in_parallel(&self, _p: &NodeParams) -> bool {
+        self.as_one_input_one_canvas().is_some() || self.as_one_mutate_bitmap().is_some()
+    }
+
     fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult>{
         if let Some(n) = self.as_one_input_one_canvas(){
             let input = ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
@@ -256,6 +262,15 @@ pub trait NodeDef: ::std::fmt::Debug{
         }
     }
 
+    /// Predicts the time and memory `execute` will take, from the frame estimates of this node and its parents.
+    /// Scaled by the context's CostCoefficients.
+    fn estimate_cost(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<CostEstimate>{
+        if self.can_execute() {
+            Ok(::flow::cost::default_cost(ctx, ix))
+        } else {
+            Ok(CostEstimate::NotImplemented)
+        }
+    }
 
     fn graphviz_node_label(&self, n: &Node, f: &mut std::io::Write) -> std::io::Result<()>{
         write!(f, "{}", self.name())
@@ -296,7 +311,8 @@ impl<T> NodeDefOneInputExpand for MutProtect<T> where T: NodeDef{
     fn expand(&self, ctx: &mut OpCtxMut, ix: NodeIndex, params: NodeParams, parent: FrameInfo) -> Result<()>{
         let mut new_nodes = Vec::with_capacity(2);
         if ctx.has_other_children(ctx.first_parent_input(ix).unwrap(), ix) {
-            new_nodes.push(Node::n(self.node, NodeParams::None));
+            // Siblings still need the unmodified frame
+            new_nodes.push(Node::n(&nodes::CLONE, NodeParams::None));
         }
         new_nodes.push(Node::n(&*self.node, ctx.weight(ix).params.clone()));
         ctx.replace_node(ix, new_nodes);
@@ -400,11 +416,19 @@ impl FrameEstimate{
 #[derive(Clone,Debug,PartialEq)]
 pub struct CostInfo {
     pub wall_ns: u64, // Estimated wall ticks to execute
-    pub cpu_ticks: Option<u64>, // Estimate overall CPU ticks (larger, if multi-threaded)
+    pub cpu_ticks: Option<u64>, // Overall CPU ticks, in nanoseconds of thread time (larger, if multi-threaded)
     pub heap_bytes: u32,
     pub peak_temp_bytes: u32,
 }
 
+impl CostInfo {
+    /// Adds one measured execution: `wall_ns` on the clock, `cpu_ns` summed over every thread that worked on it
+    pub fn add_measured(&mut self, wall_ns: u64, cpu_ns: u64) {
+        self.wall_ns += wall_ns;
+        self.cpu_ticks = Some(self.cpu_ticks.unwrap_or(0) + cpu_ns);
+    }
+}
+
 #[derive(Clone,Debug,PartialEq)]
 pub enum CostEstimate {
     None,
@@ -419,6 +443,7 @@ pub enum NodeResult {
     Consumed, /* Ownership has been transferred to another node for exclusive mutation. If another node tries to access, a panic will occur. Don't consume without verifying no other nodes want access. */
     Frame(*mut BitmapBgra), // Should this be boxed?
     Encoded(s::EncodeResult),
+    Analyzed(s::AnalysisResult),
 }
 #[derive(Clone,Debug,PartialEq)]
 pub enum NodeParamsInternal {
@@ -432,6 +457,9 @@ pub enum NodeParamsInternal {
         matte_color: Option<s::Color>,
         compositing_mode: ::ffi::BitmapCompositingMode,
     },
+    GridLayout(s::GridLayout),
+    /// The position of a Grid input in the order it was wired
+    GridInput(u32),
 }
 #[derive(Clone,Debug,PartialEq)]
 pub enum NodeParams {
@@ -462,6 +490,7 @@ impl From<s::Node> for Node {
     fn from(node: s::Node) -> Node {
         match node {
             s::Node::Crop { .. } => Node::n(&nodes::CROP, NodeParams::Json(node)),
+            s::Node::SmartCrop { .. } => Node::n(&nodes::SMART_CROP, NodeParams::Json(node)),
             //s::Node::CropWhitespace { .. } => Node::n(&nodes::CROP_WHITESPACE, NodeParams::Json(node)),
             s::Node::Decode { .. } => Node::n(&nodes::DECODER, NodeParams::Json(node)),
             s::Node::FlowBitmapBgraPtr { .. } => {
@@ -473,6 +502,7 @@ impl From<s::Node> for Node {
             s::Node::Rotate90 => Node::n(&nodes::ROTATE_90, NodeParams::Json(node)),
             s::Node::Rotate180 => Node::n(&nodes::ROTATE_180, NodeParams::Json(node)),
             s::Node::Rotate270 => Node::n(&nodes::ROTATE_270, NodeParams::Json(node)),
+            s::Node::Rotate { .. } => Node::n(&nodes::ROTATE, NodeParams::Json(node)),
             s::Node::ApplyOrientation { .. } => {
                 Node::n(&nodes::APPLY_ORIENTATION, NodeParams::Json(node))
             }
@@ -501,6 +531,26 @@ impl From<s::Node> for Node {
             s::Node::ColorFilterSrgb { ..} => {
                 Node::n(&nodes::COLOR_FILTER_SRGB, NodeParams::Json(node))
             },
+            s::Node::ColorMatrixLinear { ..} => {
+                Node::n(&nodes::COLOR_MATRIX_LINEAR, NodeParams::Json(node))
+            },
+            s::Node::ColorFilter { ..} => {
+                Node::n(&nodes::COLOR_FILTER, NodeParams::Json(node))
+            },
+            s::Node::AnalyzeColors { .. } => Node::n(&nodes::ANALYZE_COLORS, NodeParams::Json(node)),
+            s::Node::PerceptualHash { .. } => Node::n(&nodes::PERCEPTUAL_HASH, NodeParams::Json(node)),
+            s::Node::Compare { .. } => Node::n(&nodes::COMPARE, NodeParams::Json(node)),
+            s::Node::Histogram => Node::n(&nodes::HISTOGRAM, NodeParams::Json(node)),
+            s::Node::RedactRegions { .. } => Node::n(&nodes::REDACT_REGIONS, NodeParams::Json(node)),
+            s::Node::ApplyMask { .. } => Node::n(&nodes::APPLY_MASK, NodeParams::Json(node)),
+            s::Node::ChromaKey { .. } => Node::n(&nodes::CHROMA_KEY, NodeParams::Json(node)),
+            s::Node::Dither { .. } => Node::n(&nodes::DITHER, NodeParams::Json(node)),
+            s::Node::DrawText { .. } => Node::n(&nodes::DRAW_TEXT, NodeParams::Json(node)),
+            s::Node::Grid { .. } => Node::n(&nodes::GRID, NodeParams::Json(node)),
+            s::Node::TilePyramid { .. } => Node::n(&nodes::TILE_PYRAMID, NodeParams::Json(node)),
+            s::Node::Levels { .. } => Node::n(&nodes::LEVELS, NodeParams::Json(node)),
+            s::Node::Curves { .. } => Node::n(&nodes::CURVES, NodeParams::Json(node)),
+            s::Node::AutoLevels { .. } => Node::n(&nodes::AUTO_LEVELS, NodeParams::Json(node)),
 
         }
     }
@@ -533,4 +583,10 @@ impl Node {
     pub fn graphviz_node_label(&self, f: &mut std::io::Write) -> std::io::Result<()> {
         self.def.graphviz_node_label(self, f)
     }
+
+    /// Whether this node runs `def`, the same static definition. Statics of zero-sized definitions may share
+    /// an address, so the fqn has to match as well.
+    pub fn is(&self, def: &NodeDef) -> bool {
+        self.def as *const NodeDef as *const u8 == def as *const NodeDef as *const u8 && self.def.fqn() == def.fqn()
+    }
 }
@@ -1,6 +1,7 @@
 use ::{Context};
 use ::flow::definitions::*;
-use ::ffi::CodecInstance;
+use ::flow::nodes;
+use ::ffi::{CodecInstance, BitmapBgra};
 use ::internal_prelude::works_everywhere::*;
 use petgraph::dot::Dot;
 use std::process::Command;
@@ -8,12 +9,17 @@ use super::visualize::{notify_graph_changed, GraphRecordingUpdate, GraphRecordin
 use petgraph::EdgeDirection;
 use ::rustc_serialize::base64;
 use ::rustc_serialize::base64::ToBase64;
+use petgraph::visit::EdgeRef;
+use std::sync::mpsc;
+use ::imageflow_helpers::timeywimey;
 
 pub struct Engine<'a> {
     c: &'a Context,
     job: &'a mut Context,
     g: Graph,
     more_frames: bool,
+    /// Which frame of the inputs execute_many is on
+    frame_index: usize,
 }
 
 impl<'a> Engine<'a> {
@@ -26,6 +32,7 @@ impl<'a> Engine<'a> {
             job: split_context_2,
             g,
             more_frames: false,
+            frame_index: 0,
         }
     }
 
@@ -65,6 +72,7 @@ impl<'a> Engine<'a> {
                 EdgesIn::Arbitrary { canvases, inputs, .. } if input_count != inputs as usize || canvas_count != canvases as usize => true,
                 EdgesIn::OneInput if input_count != 1 && canvas_count != 0 => true,
                 EdgesIn::OneInputOneCanvas if input_count != 1 && canvas_count != 1 => true,
+                EdgesIn::OneOrMoreInputs if input_count == 0 || canvas_count != 0 => true,
                 EdgesIn::OneOptionalInput if canvas_count != 0 && (input_count != 0 && input_count != 1) => true,
                 _ =>                false
             };
@@ -89,7 +97,13 @@ impl<'a> Engine<'a> {
         let graph_copy = self.g.clone();
         let mut vec = Vec::with_capacity(1);
         loop {
-            match self.execute(){
+            let frame_start = time::precise_time_ns();
+            self.frame_index = vec.len();
+            let frame = self.execute();
+            if self.c.trace_enabled() {
+                self.c.trace_span(&format!("frame {}", vec.len()), "frame", frame_start);
+            }
+            match frame {
                 Err(e) => {
                     return Err(e);
                 },
@@ -98,7 +112,8 @@ impl<'a> Engine<'a> {
                     if !more{
                         return Ok(s::BuildPerformance{ frames: vec });
                     }else{
-                        //TODO: free unused bitmaps from self.g
+                        // Their buffers go back to the pool for the next frame's bitmaps
+                        self.free_frames(true);
                         self.g = graph_copy.clone();
                     }
 
@@ -110,6 +125,7 @@ impl<'a> Engine<'a> {
 
         let start = time::precise_time_ns();
         self.more_frames = false;
+        self.c.reset_peak_bitmap_bytes();
         self.validate_graph()?;
         self.notify_graph_changed()?;
 
@@ -130,38 +146,45 @@ impl<'a> Engine<'a> {
                 //            error_msg!(c, FlowStatusCode::MaximumGraphPassesExceeded);
                 //            return false;
             }
-            self.link_codecs()?;
+            let pass_start = time::precise_time_ns();
+            self.phase("link_codecs", |e| e.link_codecs())?;
 
-            self.populate_dimensions_where_certain()?;
+            self.phase("estimate", |e| e.populate_dimensions_where_certain())?;
             self.notify_graph_changed()?;
 
-            self.graph_pre_optimize_flatten()?;
+            self.phase("flatten", |e| e.graph_pre_optimize_flatten())?;
             self.notify_graph_changed()?;
 
-            self.link_codecs()?;
+            self.phase("link_codecs", |e| e.link_codecs())?;
 
-            self.graph_pre_optimize_flatten()?;
+            self.phase("flatten", |e| e.graph_pre_optimize_flatten())?;
             self.notify_graph_changed()?;
 
-            self.link_codecs()?;
+            self.phase("link_codecs", |e| e.link_codecs())?;
 
 
-            self.populate_dimensions_where_certain()?;
+            self.phase("estimate", |e| e.populate_dimensions_where_certain())?;
             self.notify_graph_changed()?;
 
-            // graph_optimize()?;
+            self.phase("optimize", |e| e.graph_optimize())?;
             self.notify_graph_changed()?;
 
-            self.populate_dimensions_where_certain()?;
+            // The optimizer may have added decoder hints
+            self.phase("link_codecs", |e| e.link_codecs())?;
+
+            self.phase("estimate", |e| e.populate_dimensions_where_certain())?;
             self.notify_graph_changed()?;
 
-            self.populate_dimensions_where_certain()?;
+            self.phase("estimate", |e| e.populate_dimensions_where_certain())?;
             self.notify_graph_changed()?;
 
             self.validate_graph()?;
 
-            self.graph_execute()?;
+            self.phase("execute", |e| e.graph_execute())?;
             passes += 1;
+            if self.c.trace_enabled() {
+                self.c.trace_span(&format!("pass {}", passes), "pass", pass_start);
+            }
 
             self.notify_graph_changed()?;
         }
@@ -170,7 +193,10 @@ impl<'a> Engine<'a> {
 
 
 
-        let mut perf : Vec<s::NodePerf> = self.g.node_weights_mut().map(|n| s::NodePerf{ wall_microseconds: ( n.cost.wall_ns as f64 / 1000f64).round() as u64, name: n.def.name().to_owned()}).collect();
+        let mut perf : Vec<s::NodePerf> = self.g.node_weights_mut().map(|n| s::NodePerf{
+            wall_microseconds: ( n.cost.wall_ns as f64 / 1000f64).round() as u64,
+            cpu_microseconds: n.cost.cpu_ticks.map(|ns| (ns as f64 / 1000f64).round() as u64),
+            name: n.def.name().to_owned()}).collect();
         perf.sort_by_key(|p| p.wall_microseconds);
         perf.reverse();
 
@@ -180,9 +206,152 @@ impl<'a> Engine<'a> {
         let wall_microseconds = (total_ns as f64 / 1000f64).round() as u64;
         let overhead_microseconds = ((total_ns as i64 - total_node_ns as i64) as f64 / 1000f64).round() as i64;
 
-        Ok((self.more_frames, s::FramePerformance{nodes: perf, wall_microseconds, overhead_microseconds}))
+        let peak_bitmap_bytes = self.c.peak_bitmap_bytes();
+
+        Ok((self.more_frames, s::FramePerformance{nodes: perf, wall_microseconds, overhead_microseconds, peak_bitmap_bytes}))
+    }
+
+
+    /// Runs one phase of a graph pass, tracing it when the job is traced
+    fn phase<F>(&mut self, name: &'static str, f: F) -> Result<()> where F: FnOnce(&mut Engine<'a>) -> Result<()> {
+        let start = time::precise_time_ns();
+        let result = f(self);
+        self.c.trace_span(name, "phase", start);
+        result
+    }
+
+    /// Traces the span of a node, on the job's thread or a worker lane. Expansion can replace a node's definition,
+    /// so callers name it as it was.
+    fn trace_node(&self, ix: NodeIndex, name: &str, cat: &str, lane: u32, start_ns: u64, end_ns: u64) {
+        if !self.c.trace_enabled() {
+            return;
+        }
+        let node = self.g.node_weight(ix).unwrap();
+        let mut args = ::std::collections::BTreeMap::new();
+        args.insert("node".to_owned(), ix.index().to_string());
+        args.insert("stable_id".to_owned(), node.stable_id.to_string());
+        if let FrameEstimate::Some(info) = node.frame_est {
+            args.insert("frame".to_owned(), format!("{}x{} {:?}", info.w, info.h, info.fmt));
+        }
+        self.c.trace_span_with(name, cat, lane, start_ns, end_ns, args);
     }
 
+    /// Expands and estimates the graph as far as is possible without executing any node, then
+    /// predicts each encode's output and the cost of the job. Decoders only read headers.
+    pub fn estimate(&mut self) -> Result<s::JobEstimate> {
+        self.validate_graph()?;
+        self.notify_graph_changed()?;
+
+        let mut passes = 0;
+        loop {
+            if passes >= self.job.max_calc_flatten_execute_passes {
+                return Err(nerror!(::ErrorKind::InvalidOperation, "Maximum graph passes exceeded during estimation"));
+            }
+            self.link_codecs()?;
+            self.populate_dimensions_where_certain()?;
+            self.notify_graph_changed()?;
+
+            let expanded = self.graph_expand_estimated()?;
+
+            self.graph_optimize()?;
+            self.link_codecs()?;
+            self.populate_dimensions_where_certain()?;
+            self.notify_graph_changed()?;
+            passes += 1;
+            if !expanded {
+                break;
+            }
+        }
+        self.validate_graph()?;
+        self.notify_graph_complete()?;
+
+        let mut complete = true;
+        let mut wall_ns = 0u64;
+        let mut heap_bytes = 0u64;
+        for ix in 0..self.g.node_count() {
+            let nix = NodeIndex::new(ix);
+            let def = self.g.node_weight(nix).unwrap().def;
+            if !def.can_execute() {
+                // Whatever is left unexpanded depends on pixels we didn't decode
+                complete = false;
+                continue;
+            }
+            let est = {
+                let mut ctx = self.op_ctx_mut();
+                def.estimate_cost(&mut ctx, nix).map_err(|e| e.with_ctx_mut(&ctx, nix).at(here!()))?
+            };
+            match est {
+                CostEstimate::Some(ref cost) => {
+                    wall_ns += cost.wall_ns;
+                    heap_bytes += cost.heap_bytes as u64;
+                }
+                _ => complete = false,
+            }
+            self.g.node_weight_mut(nix).unwrap().cost_est = est;
+        }
+
+        Ok(s::JobEstimate {
+            encodes: self.collect_encode_estimates(),
+            estimated_wall_microseconds: (wall_ns as f64 / 1000f64).round() as u64,
+            estimated_heap_bytes: heap_bytes,
+            complete: complete,
+        })
+    }
+
+    /// Like graph_pre_optimize_flatten, but expands nodes once their parents are estimated rather than executed.
+    /// Returns false if nothing could be expanded.
+    fn graph_expand_estimated(&mut self) -> Result<bool> {
+        let mut expanded = false;
+        loop {
+            let mut next = None;
+            for ix in 0..(self.g.node_count()) {
+                let nix = NodeIndex::new(ix);
+                let def = self.g.node_weight(nix).unwrap().def;
+                if def.can_expand() && self.parents_estimated(nix) {
+                    next = Some((nix, def));
+                    break;
+                }
+            }
+            match next {
+                None => return Ok(expanded),
+                Some((next_ix, def)) => {
+                    let start = time::precise_time_ns();
+                    let more_frames = {
+                        let mut ctx = self.op_ctx_mut();
+                        let _ = def.expand(&mut ctx, next_ix).map_err(|e| e.with_ctx_mut(&ctx, next_ix).at(here!()))?;
+                        ctx.more_frames.get()
+                    };
+                    self.trace_node(next_ix, def.name(), "expand", 0, start, time::precise_time_ns());
+                    self.more_frames = self.more_frames || more_frames;
+                    expanded = true;
+                }
+            }
+        }
+    }
+
+    fn collect_encode_estimates(&self) -> Vec<s::EncodeEstimate> {
+        let mut encodes = Vec::new();
+        for node in self.g.raw_nodes() {
+            if let NodeParams::Json(s::Node::Encode { io_id, ref preset }) = node.weight.params {
+                if !node.weight.is(&nodes::ENCODE) {
+                    continue;
+                }
+                let (w, h) = match node.weight.frame_est {
+                    FrameEstimate::Some(info) => (Some(info.w), Some(info.h)),
+                    _ => (None, None),
+                };
+                let (mime, ext) = preset.mime_type_and_extension();
+                encodes.push(s::EncodeEstimate {
+                    io_id: io_id,
+                    w: w,
+                    h: h,
+                    preferred_mime_type: mime.to_owned(),
+                    preferred_extension: ext.to_owned(),
+                });
+            }
+        }
+        encodes
+    }
 
     pub fn link_codecs(&mut self) -> Result<()> {
         self.notify_graph_changed()?;
@@ -393,11 +562,13 @@ impl<'a> Engine<'a> {
             match next {
                 None => return Ok(()),
                 Some((next_ix, def)) => {
+                    let start = time::precise_time_ns();
                     let more_frames = {
                         let mut ctx = self.op_ctx_mut();
                         let _ = def.expand(&mut ctx, next_ix).map_err(|e| e.with_ctx_mut(&ctx, next_ix).at(here!()))?;
                         ctx.more_frames.get()
                     };
+                    self.trace_node(next_ix, def.name(), "expand", 0, start, time::precise_time_ns());
                     self.more_frames = self.more_frames || more_frames;
                 }
             }
@@ -407,6 +578,11 @@ impl<'a> Engine<'a> {
 
 
 
+    fn graph_optimize(&mut self) -> Result<()> {
+        let mut ctx = self.op_ctx_mut();
+        super::optimize::graph_optimize(&mut ctx).map_err(|e| e.at(here!()))
+    }
+
     fn parents_complete(&self, ix: NodeIndex) -> bool{
         self.g
             .parents(ix)
@@ -432,7 +608,8 @@ impl<'a> Engine<'a> {
         // Find nodes with fn_execute, which also have been estimated, and whose parents are complete
         // AND who are not already complete
         loop {
-            let mut next = None;
+            self.job.check_cancellation().map_err(|e| e.at(here!()))?;
+            let mut ready = Vec::new();
             for ix in 0..(self.g.node_count()) {
                 let index = NodeIndex::new(ix);
                 let def = self.g.node_weight(index).unwrap().def;
@@ -447,67 +624,325 @@ impl<'a> Engine<'a> {
 
                             let _ = self.estimate_node_recursive(index,100).map_err(|e| e.at(here!()))?;
                         }
-                        next = Some((index, def));
-                        break;
+                        // Fail before allocating a bitmap that could never fit
+                        if let FrameEstimate::Some(info) = self.g.node_weight(index).unwrap().frame_est {
+                            self.job.check_bitmap_size(info.w, info.h, info.fmt.bytes()).map_err(|e| e.at(here!()))?;
+                        }
+                        ready.push((index, def));
                     }
 
                 } else if !def.can_expand(){
                     return Err(nerror!(::ErrorKind::MethodNotImplemented, "Nodes must can_execute() or can_expand(). {:?} does neither", def).into());
                 }
             }
-            match next {
-                None => return Ok(()),
-                Some((next_ix, def)) => {
-                    let more_frames = {
-                        let now = time::precise_time_ns();
-                        let mut ctx = self.op_ctx_mut();
-                        let result = def.execute(&mut ctx, next_ix).map_err(|e| e.with_ctx_mut(&ctx, next_ix).at(here!()))?;
-
-                        if result == NodeResult::None {
-                            return Err(nerror!(::ErrorKind::InvalidOperation, "Node {} execution returned {:?}", def.name(), result).into());
-                        }else{
-                            // Force update the estimate to match reality
-                            if let &NodeResult::Frame(bit) = &result{
-                                if !bit.is_null() {
-                                    unsafe {
-                                        ctx.weight_mut(next_ix).frame_est = FrameEstimate::Some((*bit).frame_info());
-                                    }
-                                }
-                            }
-                            ctx.weight_mut(next_ix).result = result;
+            if ready.is_empty() {
+                return Ok(());
+            }
+            let batch = self.parallel_batch(&ready);
+            if batch.len() > 1 {
+                let executing = self.serve_from_frame_cache(batch.clone())?;
+                if !executing.is_empty() {
+                    self.execute_detached(&executing)?;
+                }
+                for ix in executing {
+                    self.store_in_frame_cache(ix)?;
+                }
+                for ix in batch {
+                    self.record_frame_image(ix);
+                }
+            } else {
+                let (next_ix, def) = ready[0];
+                if !self.serve_from_frame_cache(vec![next_ix])?.is_empty() {
+                    self.execute_node(next_ix, def)?;
+                    self.store_in_frame_cache(next_ix)?;
+                }
+                self.record_frame_image(next_ix);
+            }
+            self.free_frames(false);
+        }
+    }
+
+    /// Identifies the frame a node will produce, if the frame cache covers it: the first frame a decoder reads from
+    /// an input whose bytes were hashed, and a Resample2D of such a frame (or of another covered resample) onto a new canvas.
+    /// Decoders given JPEG downscale hints, including those the optimizer adds, are not covered: the size they decode at
+    /// depends on the rest of the graph.
+    fn frame_cache_key(&self, ix: NodeIndex) -> Option<u64> {
+        let node = self.g.node_weight(ix).unwrap();
+        if node.is(&nodes::PRIMITIVE_DECODER) && self.frame_index == 0 {
+            // The io_id differs between builds, so only the commands given to the decoder join the input's hash
+            match node.params {
+                NodeParams::Json(s::Node::Decode { ref commands, .. }) if commands.as_ref().map_or(false, |list| list.iter().any(|command|
+                    match *command { s::DecoderCommand::JpegDownscaleHints(_) => true, _ => false })) => None,
+                NodeParams::Json(s::Node::Decode { io_id, ref commands }) => {
+                    let input = self.c.frame_cache().as_ref().and_then(|cache| cache.input_hash(io_id));
+                    input.map(|input| ::frame_cache::frame_key(&format!("decode {:?}", commands), input))
+                }
+                _ => None
+            }
+        } else if node.is(&nodes::SCALE_2D_RENDER_TO_CANVAS_1D) {
+            let parent = |kind: EdgeKind| self.g.graph().edges_directed(ix, EdgeDirection::Incoming)
+                .find(|e| *e.weight() == kind).map(|e| e.source());
+            let canvas = match parent(EdgeKind::Canvas) {
+                Some(canvas) if self.g.node_weight(canvas).unwrap().is(&nodes::CREATE_CANVAS) => self.g.node_weight(canvas).unwrap(),
+                _ => return None
+            };
+            let input = match parent(EdgeKind::Input).and_then(|input| self.frame_cache_key(input)) {
+                Some(key) => key,
+                None => return None
+            };
+            Some(::frame_cache::frame_key(&format!("{:?} {:?}", node.params, canvas.params), input))
+        } else {
+            None
+        }
+    }
+
+    /// Completes the nodes about to execute whose frames are cached with copies of them, and returns the rest
+    fn serve_from_frame_cache(&mut self, next: Vec<NodeIndex>) -> Result<Vec<NodeIndex>> {
+        if self.c.frame_cache().is_none() {
+            return Ok(next);
+        }
+        let mut rest = Vec::with_capacity(next.len());
+        for ix in next {
+            let start = time::precise_time_ns();
+            let cached = match self.frame_cache_key(ix) {
+                Some(key) => self.c.frame_cache().as_mut().unwrap().get(self.c, key).map_err(|e| e.at(here!()))?,
+                None => None
+            };
+            match cached {
+                Some(bitmap) => {
+                    let wall_ns = time::precise_time_ns() - start;
+                    {
+                        let node = self.g.node_weight_mut(ix).unwrap();
+                        unsafe {
+                            node.frame_est = FrameEstimate::Some((*bitmap).frame_info());
                         }
-                        ctx.weight_mut(next_ix).cost.wall_ns += time::precise_time_ns() - now;
-                        ctx.more_frames.get()
-                    };
+                        node.result = NodeResult::Frame(bitmap);
+                        node.cost.add_measured(wall_ns, wall_ns);
+                    }
+                    let def = self.g.node_weight(ix).unwrap().def;
+                    self.trace_node(ix, def.name(), "cache", 0, start, start + wall_ns);
+                }
+                None => rest.push(ix)
+            }
+        }
+        Ok(rest)
+    }
 
-                    self.more_frames = self.more_frames || more_frames;
+    /// Copies the frame a node just produced into the frame cache, if it covers the node. Decoders of inputs
+    /// with more frames are skipped: serving them from the cache would leave the decoder behind.
+    fn store_in_frame_cache(&mut self, ix: NodeIndex) -> Result<()> {
+        if self.c.frame_cache().is_none() {
+            return Ok(());
+        }
+        let key = match self.frame_cache_key(ix) {
+            Some(key) => key,
+            None => return Ok(())
+        };
+        let node = self.g.node_weight(ix).unwrap();
+        if node.is(&nodes::PRIMITIVE_DECODER) && self.more_frames {
+            return Ok(());
+        }
+        if let NodeResult::Frame(bitmap) = node.result {
+            if !bitmap.is_null() {
+                self.c.frame_cache().as_mut().unwrap().put(self.c, key, unsafe { &*bitmap }).map_err(|e| e.at(here!()))?;
+            }
+        }
+        Ok(())
+    }
 
-                    unsafe {
-                        if self.job.graph_recording.record_frame_images.unwrap_or(false) {
-                            if let NodeResult::Frame(ptr) = self.g
-                                .node_weight(next_ix)
-                                .unwrap()
-                                .result {
-                                let path = format!("node_frames/job_{}_node_{}.png",
-                                                   self.job.debug_job_id,
-                                                   self.g.node_weight(next_ix).unwrap().stable_id);
-                                let path_copy = path.clone();
-                                let path_cstr = std::ffi::CString::new(path).unwrap();
-                                let _ = std::fs::create_dir("node_frames");
-                                if !::ffi::flow_bitmap_bgra_save_png(self.c.flow_c(),
-                                                                     ptr,
-                                                                     path_cstr.as_ptr()) {
-                                    println!("Failed to save frame {} (from node {})",
-                                             path_copy,
-                                             next_ix.index());
-                                    cerror!(self.c).panic();
-                                }
-                            }
+    /// Destroys the bitmaps of node results no node will read again, leaving NodeResult::Consumed.
+    /// Unless `all`, a result is only spent once it has children and every one of them has executed.
+    /// Bitmaps reachable through a FlowBitmapBgraPtr belong to the caller and are never freed.
+    fn free_frames(&mut self, all: bool) {
+        let mut pinned = Vec::new();
+        for node in self.g.raw_nodes() {
+            if let NodeParams::Json(s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr }) = node.weight.params {
+                let ptr = ptr_to_flow_bitmap_bgra_ptr as *mut *mut BitmapBgra;
+                if !ptr.is_null() {
+                    pinned.push(unsafe { *ptr });
+                }
+            }
+        }
+        let mut spent = Vec::new();
+        let mut kept = Vec::new();
+        for ix in (0..self.g.node_count()).map(|i| NodeIndex::new(i)) {
+            if let NodeResult::Frame(bitmap) = self.g.node_weight(ix).unwrap().result {
+                let mut children = self.g.graph().edges_directed(ix, EdgeDirection::Outgoing).map(|e| e.target()).peekable();
+                let done = all || (children.peek().is_some() &&
+                    children.all(|child| self.g.node_weight(child).unwrap().result != NodeResult::None));
+                if done && !pinned.contains(&bitmap) {
+                    spent.push((ix, bitmap));
+                } else {
+                    kept.push(bitmap);
+                }
+            }
+        }
+        let mut destroyed = Vec::new();
+        for (ix, bitmap) in spent {
+            // Another result may still hand out the same bitmap
+            if kept.contains(&bitmap) {
+                continue;
+            }
+            if !bitmap.is_null() && !destroyed.contains(&bitmap) {
+                unsafe {
+                    ::ffi::flow_destroy(self.c.flow_c(), bitmap as *const c_void, ptr::null(), 0);
+                }
+                destroyed.push(bitmap);
+            }
+            self.g.node_weight_mut(ix).unwrap().result = NodeResult::Consumed;
+        }
+    }
+
+    fn execute_node(&mut self, next_ix: NodeIndex, def: &'static NodeDef) -> Result<()> {
+        let (more_frames, start, end) = {
+            let now = time::precise_time_ns();
+            let cpu_start = timeywimey::thread_cpu_ns();
+            let mut ctx = self.op_ctx_mut();
+            ctx.c.take_helper_thread_ns();
+            let result = def.execute(&mut ctx, next_ix).map_err(|e| e.with_ctx_mut(&ctx, next_ix).at(here!()))?;
+
+            if result == NodeResult::None {
+                return Err(nerror!(::ErrorKind::InvalidOperation, "Node {} execution returned {:?}", def.name(), result).into());
+            }else{
+                // Force update the estimate to match reality
+                if let &NodeResult::Frame(bit) = &result{
+                    if !bit.is_null() {
+                        unsafe {
+                            ctx.weight_mut(next_ix).frame_est = FrameEstimate::Some((*bit).frame_info());
                         }
                     }
                 }
+                ctx.weight_mut(next_ix).result = result;
+            }
+            let wall_ns = time::precise_time_ns() - now;
+            // Helper threads report their own CPU time; without a thread clock we count wall time
+            let cpu_ns = timeywimey::thread_cpu_ns_since(cpu_start).unwrap_or(wall_ns) + ctx.c.take_helper_thread_ns();
+            ctx.weight_mut(next_ix).cost.add_measured(wall_ns, cpu_ns);
+            (ctx.more_frames.get(), now, now + wall_ns)
+        };
+        self.trace_node(next_ix, def.name(), "node", 0, start, end);
+
+        self.more_frames = self.more_frames || more_frames;
+        Ok(())
+    }
+
+    /// Picks the ready nodes, in index order, that can run at once on worker threads.
+    /// A node is left for later if it would write a bitmap another picked node reads or writes, or read one being written,
+    /// so the outcome is the same as executing serially in index order.
+    fn parallel_batch(&self, ready: &[(NodeIndex, &'static NodeDef)]) -> Vec<NodeIndex> {
+        let mut batch = Vec::new();
+        if self.job.max_parallelism < 2 {
+            return batch;
+        }
+        let mut read = Vec::new();
+        let mut written = Vec::new();
+        for &(ix, def) in ready {
+            if !def.can_execute_in_parallel(&self.g.node_weight(ix).unwrap().params) {
+                continue;
+            }
+            let writes_to = if def.as_one_input_one_canvas().is_some() { EdgeKind::Canvas } else { EdgeKind::Input };
+            let parents = self.g.graph().edges_directed(ix, EdgeDirection::Incoming)
+                .map(|e| (e.source(), *e.weight() == writes_to))
+                .collect::<Vec<(NodeIndex, bool)>>();
+            let conflict = parents.iter().any(|&(parent, writes)| {
+                written.contains(&parent) || (writes && read.contains(&parent))
+            });
+            if !conflict {
+                for &(parent, writes) in parents.iter() {
+                    if writes { written.push(parent) } else { read.push(parent) }
+                }
+                batch.push(ix);
+            }
+        }
+        batch
+    }
+
+    /// Claims the bitmaps of each node in `batch`, renders or mutates them on the context's helper threads (up to
+    /// `max_parallelism` of them), then stores the results in index order
+    fn execute_detached(&mut self, batch: &[NodeIndex]) -> Result<()> {
+        let mut jobs = Vec::with_capacity(batch.len());
+        // Share the resample thread budget between the nodes rather than multiplying it
+        let resample_threads = ::std::cmp::max(1, self.job.max_resample_threads / batch.len());
+        let mut contexts = self.c.take_helper_contexts(batch.len()).map_err(|e| e.at(here!()))?;
+        {
+            let mut ctx = self.op_ctx_mut();
+            for &ix in batch {
+                let def = ctx.weight(ix).def;
+                let params = ctx.weight(ix).params.clone();
+                let mut c = contexts.pop().unwrap();
+                c.max_resample_threads = resample_threads;
+                let input = ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+                let canvas = if def.as_one_input_one_canvas().is_some() {
+                    let canvas = ctx.bitmap_bgra_from(ix, EdgeKind::Canvas).map_err(|e| e.at(here!()))?;
+                    ctx.consume_parent_result(ix, EdgeKind::Canvas).map_err(|e| e.at(here!()))?;
+                    Some(canvas)
+                } else {
+                    ctx.consume_parent_result(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+                    None
+                };
+                jobs.push(DetachedNode { ix: ix, def: def, params: params, input: input, canvas: canvas, c: c });
+            }
+        }
+
+        let pool = self.c.helper_thread_pool(::std::cmp::min(self.job.max_parallelism, jobs.len()));
+        let (tx, rx) = mpsc::channel();
+        for job in jobs {
+            let tx = tx.clone();
+            pool.execute(move || {
+                let _ = tx.send(job.run());
+            });
+        }
+        drop(tx);
+        // Every job must finish before we touch the graph again; a job that panics drops its sender unused
+        let mut outcomes = rx.iter().collect::<Vec<DetachedOutcome>>();
+        pool.join();
+        self.c.return_helper_contexts(outcomes.iter_mut().filter_map(|o| o.c.take()).collect());
+        if outcomes.len() != batch.len() {
+            return Err(nerror!(::ErrorKind::InternalError, "{} of {} nodes panicked on worker threads", batch.len() - outcomes.len(), batch.len()));
+        }
+        outcomes.sort_by_key(|o| o.ix.index());
+        // Each node gets a lane of its own, so the overlap shows
+        for (lane, outcome) in outcomes.iter().enumerate() {
+            let def = self.g.node_weight(outcome.ix).unwrap().def;
+            self.trace_node(outcome.ix, def.name(), "node", lane as u32 + 1, outcome.start_ns, outcome.start_ns + outcome.wall_ns);
+        }
+
+        let mut ctx = self.op_ctx_mut();
+        for outcome in outcomes {
+            let ix = outcome.ix;
+            ctx.weight_mut(ix).cost.add_measured(outcome.wall_ns, outcome.cpu_ns);
+            let bitmap = outcome.result.map_err(|e| e.at(here!()).with_ctx_mut(&ctx, ix))?;
+            unsafe {
+                ctx.weight_mut(ix).frame_est = FrameEstimate::Some((*bitmap).frame_info());
             }
+            ctx.weight_mut(ix).result = NodeResult::Frame(bitmap);
+        }
+        Ok(())
+    }
 
+    fn record_frame_image(&self, next_ix: NodeIndex) {
+        unsafe {
+            if self.job.graph_recording.record_frame_images.unwrap_or(false) {
+                if let NodeResult::Frame(ptr) = self.g
+                    .node_weight(next_ix)
+                    .unwrap()
+                    .result {
+                    let path = format!("node_frames/job_{}_node_{}.png",
+                                       self.job.debug_job_id,
+                                       self.g.node_weight(next_ix).unwrap().stable_id);
+                    let path_copy = path.clone();
+                    let path_cstr = std::ffi::CString::new(path).unwrap();
+                    let _ = std::fs::create_dir("node_frames");
+                    if !::ffi::flow_bitmap_bgra_save_png(self.c.flow_c(),
+                                                         ptr,
+                                                         path_cstr.as_ptr()) {
+                        println!("Failed to save frame {} (from node {})",
+                                 path_copy,
+                                 next_ix.index());
+                        cerror!(self.c).panic();
+                    }
+                }
+            }
         }
     }
     fn op_ctx_mut(&mut self) -> OpCtxMut{
@@ -542,6 +977,15 @@ impl<'a> Engine<'a> {
         }
         encodes
     }
+    pub fn collect_analysis_results(&self) -> Vec<s::AnalysisResult>{
+        let mut analyses = Vec::new();
+        for node in self.g.raw_nodes() {
+            if let ::flow::definitions::NodeResult::Analyzed(ref r) = node.weight.result {
+                analyses.push((*r).clone());
+            }
+        }
+        analyses
+    }
     pub fn collect_augmented_encode_results(&self, io: &[s::IoObject]) -> Vec<s::EncodeResult>{
         self.collect_encode_results().into_iter().map(|r: s::EncodeResult|{
             if r.bytes == s::ResultBytes::Elsewhere {
@@ -603,3 +1047,50 @@ pub fn inputs_estimates(g: &Graph, node_id: NodeIndex) -> Vec<FrameEstimate> {
         .filter_map(|(_, node_index)| g.node_weight(node_index).map(|w| w.frame_est))
         .collect()
 }
+
+
+/// A bitmap-only node taken out of the graph to run on a worker thread, with a Context of its own that shares
+/// the job's settings
+struct DetachedNode {
+    ix: NodeIndex,
+    def: &'static NodeDef,
+    params: NodeParams,
+    input: *mut BitmapBgra,
+    /// Present for nodes that render into a canvas; otherwise the input is mutated
+    canvas: Option<*mut BitmapBgra>,
+    c: Box<Context>,
+}
+
+struct DetachedOutcome {
+    ix: NodeIndex,
+    start_ns: u64,
+    wall_ns: u64,
+    cpu_ns: u64,
+    result: Result<*mut BitmapBgra>,
+    /// Handed back for later batches
+    c: Option<Box<Context>>,
+}
+
+// Each bitmap is claimed by one detached node (see Engine::parallel_batch), node definitions are stateless statics,
+// each node's Context is used by its worker alone, and the engine waits for every job to finish before using the
+// graph again.
+unsafe impl Send for DetachedNode {}
+unsafe impl Send for DetachedOutcome {}
+
+impl DetachedNode {
+    fn run(self) -> DetachedOutcome {
+        let c = self.c;
+        let now = time::precise_time_ns();
+        let cpu_start = timeywimey::thread_cpu_ns();
+        let result = unsafe {
+            match (self.canvas, self.def.as_one_input_one_canvas(), self.def.as_one_mutate_bitmap()) {
+                (Some(canvas), Some(n), _) => n.render(&c, &mut *canvas, &mut *self.input, &self.params).map(|_| canvas),
+                (None, _, Some(n)) => n.mutate(&c, &mut *self.input, &self.params).map(|_| self.input),
+                _ => Err(unimpl!())
+            }
+        };
+        let wall_ns = time::precise_time_ns() - now;
+        let cpu_ns = timeywimey::thread_cpu_ns_since(cpu_start).unwrap_or(wall_ns) + c.take_helper_thread_ns();
+        DetachedOutcome { ix: self.ix, start_ns: now, wall_ns: wall_ns, cpu_ns: cpu_ns, result: result, c: Some(c) }
+    }
+}
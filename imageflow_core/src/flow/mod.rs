@@ -3,6 +3,8 @@ use ::internal_prelude::external::*;
 #[macro_use]
 pub mod definitions;
 pub mod execution_engine;
+pub mod optimize;
+pub mod cost;
 pub mod visualize;
 pub mod nodes;
 use self::definitions::*;
@@ -0,0 +1,280 @@
+use super::internal_prelude::*;
+use ::rustc_serialize::base64;
+use ::rustc_serialize::base64::ToBase64;
+use std::f32::consts::PI;
+use imageflow_helpers::colors;
+
+pub static ANALYZE_COLORS: AnalyzeColorsDef = AnalyzeColorsDef{};
+
+/// BlurHash and dominant colors are computed from a box-filtered copy at most this large
+const SAMPLE_MAX: u32 = 64;
+/// The preview is at most this large
+const PREVIEW_MAX: u32 = 16;
+
+const BASE83_CHARS: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Reads the pixel at (x, y) as straight (non-premultiplied) RGBA
+pub fn read_rgba(bitmap: &BitmapBgra, x: u32, y: u32) -> [u8; 4] {
+    unsafe {
+        let p = bitmap.pixels.offset((y * bitmap.stride) as isize + (x as usize * bitmap.fmt.bytes()) as isize);
+        match bitmap.fmt {
+            PixelFormat::Gray8 => [*p, *p, *p, 255],
+            PixelFormat::Bgra32 => [*p.offset(2), *p.offset(1), *p, *p.offset(3)],
+            _ => [*p.offset(2), *p.offset(1), *p, 255],
+        }
+    }
+}
+
+/// Averages `bitmap` down to fit within `max` x `max` pixels (never upscaling).
+/// Colors are weighted by alpha so transparent pixels don't darken their neighbors.
+/// Returns the width, height and row-major RGBA values in 0..255.
+pub fn box_downsample(bitmap: &BitmapBgra, max: u32) -> (u32, u32, Vec<[f32; 4]>) {
+    let longest = cmp::max(bitmap.w, bitmap.h);
+    let (w, h) = if longest <= max {
+        (bitmap.w, bitmap.h)
+    } else {
+        (cmp::max(1, (bitmap.w as u64 * max as u64 / longest as u64) as u32),
+         cmp::max(1, (bitmap.h as u64 * max as u64 / longest as u64) as u32))
+    };
+    // r * a, g * a, b * a, a, count
+    let mut sums = vec![[0f64; 5]; (w * h) as usize];
+    for y in 0..bitmap.h {
+        let row = (y as u64 * h as u64 / bitmap.h as u64) as u32 * w;
+        for x in 0..bitmap.w {
+            let p = read_rgba(bitmap, x, y);
+            let a = p[3] as f64;
+            let sum = &mut sums[(row + (x as u64 * w as u64 / bitmap.w as u64) as u32) as usize];
+            sum[0] += p[0] as f64 * a;
+            sum[1] += p[1] as f64 * a;
+            sum[2] += p[2] as f64 * a;
+            sum[3] += a;
+            sum[4] += 1f64;
+        }
+    }
+    let pixels = sums.iter().map(|sum| {
+        if sum[3] > 0f64 {
+            [(sum[0] / sum[3]) as f32, (sum[1] / sum[3]) as f32, (sum[2] / sum[3]) as f32, (sum[3] / sum[4]) as f32]
+        } else {
+            [0f32; 4]
+        }
+    }).collect::<Vec<[f32; 4]>>();
+    (w, h, pixels)
+}
+
+fn byte_to_linear(v: f32) -> f32 {
+    colors::srgb_to_linear(v / 255f32)
+}
+
+fn linear_to_byte(v: f32) -> u32 {
+    (colors::linear_to_srgb(v.max(0f32).min(1f32)) * 255f32 + 0.5f32) as u32
+}
+
+fn encode_base83(value: u32, length: u32, to: &mut String) {
+    for i in 0..length {
+        let digit = (value / 83u32.pow(length - 1 - i)) % 83;
+        to.push(BASE83_CHARS[digit as usize] as char);
+    }
+}
+
+/// Encodes row-major RGBA values (0..255) as a BlurHash (https://blurha.sh)
+pub fn blurhash(w: u32, h: u32, pixels: &[[f32; 4]], x_components: u32, y_components: u32) -> String {
+    let linear = pixels.iter().map(|p| [byte_to_linear(p[0]), byte_to_linear(p[1]), byte_to_linear(p[2])]).collect::<Vec<[f32; 3]>>();
+
+    let mut factors = Vec::with_capacity((x_components * y_components) as usize);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let mut sum = [0f32; 3];
+            for y in 0..h {
+                let basis_y = (PI * j as f32 * y as f32 / h as f32).cos();
+                for x in 0..w {
+                    let basis = (PI * i as f32 * x as f32 / w as f32).cos() * basis_y;
+                    let p = linear[(y * w + x) as usize];
+                    sum[0] += basis * p[0];
+                    sum[1] += basis * p[1];
+                    sum[2] += basis * p[2];
+                }
+            }
+            let normalization = if i == 0 && j == 0 { 1f32 } else { 2f32 };
+            let scale = normalization / (w * h) as f32;
+            factors.push([sum[0] * scale, sum[1] * scale, sum[2] * scale]);
+        }
+    }
+
+    let mut hash = String::new();
+    encode_base83((x_components - 1) + (y_components - 1) * 9, 1, &mut hash);
+
+    let ac = &factors[1..];
+    let maximum_value = if ac.len() > 0 {
+        let actual_max = ac.iter().flat_map(|f| f.iter()).fold(0f32, |max, &v| max.max(v.abs()));
+        let quantised = (actual_max * 166f32 - 0.5f32).floor().max(0f32).min(82f32) as u32;
+        encode_base83(quantised, 1, &mut hash);
+        (quantised + 1) as f32 / 166f32
+    } else {
+        encode_base83(0, 1, &mut hash);
+        1f32
+    };
+
+    let dc = factors[0];
+    encode_base83((linear_to_byte(dc[0]) << 16) + (linear_to_byte(dc[1]) << 8) + linear_to_byte(dc[2]), 4, &mut hash);
+
+    let quantise = |v: f32| {
+        let v = v / maximum_value;
+        let sign_pow = v.abs().sqrt() * v.signum();
+        (sign_pow * 9f32 + 9.5f32).floor().max(0f32).min(18f32) as u32
+    };
+    for f in ac {
+        encode_base83(quantise(f[0]) * 19 * 19 + quantise(f[1]) * 19 + quantise(f[2]), 2, &mut hash);
+    }
+    hash
+}
+
+/// Encodes row-major RGBA values (0..255) as a PNG through the libpng encoder
+fn encode_preview_png(c: &Context, w: u32, h: u32, pixels: &[[f32; 4]]) -> Result<Vec<u8>> {
+    unsafe {
+        let bitmap = ::ffi::flow_bitmap_bgra_create(c.flow_c(), w as i32, h as i32, false, PixelFormat::Bgra32);
+        if bitmap.is_null() {
+            return Err(cerror!(c, "Failed to allocate a {}x{} preview", w, h));
+        }
+        for (i, p) in pixels.iter().enumerate() {
+            let (x, y) = (i as u32 % w, i as u32 / w);
+            let to = (*bitmap).pixels.offset((y * (*bitmap).stride + x * 4) as isize);
+            for (ch, &from) in [2usize, 1, 0, 3].iter().enumerate() {
+                *to.offset(ch as isize) = p[from].round().max(0f32).min(255f32) as u8;
+            }
+        }
+        let preset = s::EncoderPreset::Libpng { depth: Some(s::PngBitDepth::Png32), matte: None, zlib_compression: None };
+        let result = ::codecs::encode_to_vec(c, &preset, &mut *bitmap);
+        ::ffi::flow_destroy(c.flow_c(), bitmap as *const c_void, ptr::null(), 0);
+        result
+    }
+}
+
+/// Buckets mostly-opaque pixels by their top 4 bits per channel, then merges similar buckets.
+/// Returns up to `count` colors, most common first.
+pub fn dominant_colors(pixels: &[[f32; 4]], count: usize) -> Vec<s::DominantColor> {
+    // r, g, b sums and pixel count
+    let mut buckets = vec![[0f64; 4]; 4096];
+    let mut total = 0f64;
+    for p in pixels.iter().filter(|p| p[3] >= 128f32) {
+        let key = ((p[0] as usize >> 4) << 8) | ((p[1] as usize >> 4) << 4) | (p[2] as usize >> 4);
+        let bucket = &mut buckets[cmp::min(key, 4095)];
+        bucket[0] += p[0] as f64;
+        bucket[1] += p[1] as f64;
+        bucket[2] += p[2] as f64;
+        bucket[3] += 1f64;
+        total += 1f64;
+    }
+    let mut sorted = buckets.into_iter().filter(|b| b[3] > 0f64)
+        .map(|b| ([b[0] / b[3], b[1] / b[3], b[2] / b[3]], b[3]))
+        .collect::<Vec<([f64; 3], f64)>>();
+    sorted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+    // Neighboring buckets usually belong to the same perceived color
+    let mut chosen: Vec<([f64; 3], f64)> = Vec::new();
+    for (color, weight) in sorted {
+        if let Some(existing) = chosen.iter_mut().find(|c| (0..3).all(|i| (c.0[i] - color[i]).abs() < 24f64)) {
+            existing.1 += weight;
+            continue;
+        }
+        if chosen.len() < count {
+            chosen.push((color, weight));
+        }
+    }
+    chosen.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+    chosen.into_iter().map(|(color, weight)| s::DominantColor {
+        color: s::Color::Srgb(s::ColorSrgb::Hex(format!("{:02X}{:02X}{:02X}FF", color[0].round() as u8, color[1].round() as u8, color[2].round() as u8))),
+        fraction: (weight / total) as f32,
+    }).collect()
+}
+
+fn analyze_colors(c: &Context, bitmap: &BitmapBgra, x_components: u32, y_components: u32, color_count: u32) -> Result<s::ColorAnalysis> {
+    let (sample_w, sample_h, sample) = box_downsample(bitmap, SAMPLE_MAX);
+    let (preview_w, preview_h, preview) = box_downsample(bitmap, PREVIEW_MAX);
+    let preview_png = encode_preview_png(c, preview_w, preview_h, &preview).map_err(|e| e.at(here!()))?;
+    Ok(s::ColorAnalysis {
+        blurhash: blurhash(sample_w, sample_h, &sample, x_components, y_components),
+        preview_png_base64: preview_png.to_base64(base64::STANDARD),
+        preview_w: preview_w,
+        preview_h: preview_h,
+        dominant_colors: dominant_colors(&sample, color_count as usize),
+    })
+}
+
+#[derive(Debug,Clone)]
+pub struct AnalyzeColorsDef;
+
+impl AnalyzeColorsDef {
+    fn get(&self, p: &NodeParams) -> Result<(u32, u32, u32)> {
+        if let &NodeParams::Json(s::Node::AnalyzeColors { blurhash_x_components, blurhash_y_components, dominant_colors }) = p {
+            let x = blurhash_x_components.unwrap_or(4);
+            let y = blurhash_y_components.unwrap_or(3);
+            if x < 1 || x > 9 || y < 1 || y > 9 {
+                Err(nerror!(::ErrorKind::InvalidNodeParams, "BlurHash components must be between 1 and 9, got {}x{}", x, y))
+            } else {
+                Ok((x, y, dominant_colors.unwrap_or(5)))
+            }
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need AnalyzeColors, got {:?}", p))
+        }
+    }
+}
+
+impl NodeDef for AnalyzeColorsDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.analyze_colors"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInput, EdgesOut::None))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        self.get(p).map_err(|e| e.at(here!())).map(|_| ())
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+    }
+    fn can_execute(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        let (x_components, y_components, color_count) = self.get(&ctx.weight(ix).params).map_err(|e| e.at(here!()))?;
+        let input = ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+
+        let analysis = analyze_colors(ctx.c, unsafe { &*input }, x_components, y_components, color_count).map_err(|e| e.at(here!()))?;
+        Ok(NodeResult::Analyzed(s::AnalysisResult::Colors(analysis)))
+    }
+}
+
+#[test]
+fn test_blurhash() {
+    let white = vec![[255f32, 255f32, 255f32, 255f32]; 16];
+    assert_eq!(blurhash(4, 4, &white, 1, 1), "00TSUA");
+    // Flag, maximum, DC and 11 AC components
+    assert_eq!(blurhash(4, 4, &white, 4, 3).len(), 1 + 1 + 4 + 2 * 11);
+
+    let mut encoded = String::new();
+    encode_base83(83 * 83 + 1, 3, &mut encoded);
+    assert_eq!(encoded, "101");
+}
+
+#[test]
+fn test_dominant_colors() {
+    let mut pixels = vec![[200f32, 10f32, 10f32, 255f32]; 30];
+    pixels.extend(vec![[202f32, 12f32, 8f32, 255f32]; 10]);
+    pixels.extend(vec![[10f32, 10f32, 200f32, 255f32]; 20]);
+    pixels.extend(vec![[0f32, 0f32, 0f32, 0f32]; 100]);
+    let colors = dominant_colors(&pixels, 5);
+    assert_eq!(colors.len(), 2);
+    assert_eq!(colors[0].fraction, 40f32 / 60f32);
+    assert_eq!(colors[1].color, s::Color::Srgb(s::ColorSrgb::Hex("0A0AC8FF".to_owned())));
+}
+
+#[test]
+fn test_preview_is_png() {
+    let c = Context::create().unwrap();
+    let png = encode_preview_png(&c, 2, 1, &[[255f32, 0f32, 0f32, 255f32], [0f32, 0f32, 255f32, 128f32]]).unwrap();
+    assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+}
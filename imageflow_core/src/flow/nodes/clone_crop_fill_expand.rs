@@ -84,6 +84,11 @@ impl NodeDefOneInputOneCanvas for CopyRectNodeDef{
 }
 
 
+/// True if the rectangle is non-empty and lies within a w x h bitmap
+pub fn rect_within(w: u32, h: u32, x1: u32, y1: u32, x2: u32, y2: u32) -> bool {
+    !(x2 <= x1 || y2 <= y1 || (x1 as i32) < 0 || (y1 as i32) < 0 || x2 > w || y2 > h)
+}
+
 #[derive(Debug, Clone)]
 pub struct FillRectNodeDef;
 impl NodeDef for FillRectNodeDef{
@@ -101,7 +106,7 @@ impl NodeDefMutateBitmap for FillRectNodeDef {
     fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra,  p: &NodeParams) -> Result<()>{
         if let &NodeParams::Json(s::Node::FillRect { x1, x2, y1, y2, ref color }) = p{
 
-            if x2 <= x1 || y2 <= y1 || (x1 as i32) < 0 || (y1 as i32) < 0 || x2 > bitmap.w || y2 > bitmap.h{
+            if !rect_within(bitmap.w, bitmap.h, x1, y1, x2, y2) {
                return Err(nerror!(::ErrorKind::InvalidCoordinates, "Invalid coordinates for {}x{} bitmap: {:?}", bitmap.w, bitmap.h, p));
             }
 
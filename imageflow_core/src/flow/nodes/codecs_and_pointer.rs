@@ -57,6 +57,11 @@ impl NodeDef for BitmapBgraDef {
         }
     }
 
+    fn estimate_cost(&self, ctx: &mut OpCtxMut, _ix: NodeIndex) -> Result<CostEstimate> {
+        // Either the bitmap exists already or it is handed through
+        Ok(::flow::cost::cost(ctx.job.cost_coefficients.node_overhead_ns, 0, 0))
+    }
+
     fn can_execute(&self) -> bool {
         true
     }
@@ -93,6 +98,7 @@ fn decoder_get_io_id(params: &NodeParams) -> Result<i32> {
 fn decoder_estimate(ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
     let io_id = decoder_get_io_id(&ctx.weight(ix).params).map_err(|e| e.at(here!()))?;
     let frame_info = ctx.job.get_image_info(io_id).map_err(|e| e.at(here!()))?;
+    ctx.job.check_input_pixels(frame_info.image_width, frame_info.image_height).map_err(|e| e.at(here!()))?;
 
     Ok(FrameEstimate::Some(FrameInfo {
         fmt: frame_info.frame_decodes_into,
@@ -191,6 +197,24 @@ impl NodeDef for DecoderPrimitiveDef {
     fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
         decoder_estimate(ctx, ix).map_err(|e| e.at(here!()))
     }
+
+    fn estimate_cost(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<CostEstimate> {
+        let output = match ctx.weight(ix).frame_est {
+            FrameEstimate::Some(info) => info,
+            _ => return Ok(CostEstimate::Impossible)
+        };
+        let io_id = decoder_get_io_id(&ctx.weight(ix).params)?;
+        let mime = ctx.job.get_image_info(io_id).map_err(|e| e.at(here!()))?.preferred_mime_type;
+        let k = &ctx.job.cost_coefficients;
+        let ns_per_pixel = match mime.as_str() {
+            "image/jpeg" => k.decode_jpeg_ns_per_pixel,
+            "image/gif" => k.decode_gif_ns_per_pixel,
+            _ => k.decode_png_ns_per_pixel
+        };
+        let pixels = ::flow::cost::pixels(output);
+        Ok(::flow::cost::cost(k.node_overhead_ns + pixels as f64 * ns_per_pixel, ::flow::cost::bytes(output), 0))
+    }
+
     fn can_execute(&self) -> bool {
         true
     }
@@ -201,7 +225,11 @@ impl NodeDef for DecoderPrimitiveDef {
         let mut codec = ctx.c.get_codec(io_id).map_err(|e| e.at(here!()))?;
         let mut decoder = codec.get_decoder().map_err(|e| e.at(here!()))?;
 
+        let start = time::precise_time_ns();
         let result = decoder.read_frame(ctx.c).map_err(|e| e.at(here!()))?;
+        if ctx.c.trace_enabled() {
+            ctx.c.trace_span(&format!("read_frame io {}", io_id), "codec", start);
+        }
 
         if decoder.has_more_frames()?{
             ctx.set_more_frames(true);
@@ -241,7 +269,26 @@ impl NodeDef for EncoderDef {
     }
 
     fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
-        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+        let estimate = ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+        if let FrameEstimate::Some(info) = estimate {
+            ctx.job.check_output_pixels(info.w, info.h).map_err(|e| e.at(here!()))?;
+        }
+        Ok(estimate)
+    }
+
+    fn estimate_cost(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<CostEstimate> {
+        let input = match ctx.weight(ix).frame_est {
+            FrameEstimate::Some(info) => info,
+            _ => return Ok(CostEstimate::Impossible)
+        };
+        let (_, preset) = self.get(&ctx.weight(ix).params)?;
+        let k = &ctx.job.cost_coefficients;
+        let ns_per_pixel = match preset {
+            s::EncoderPreset::LibjpegTurbo { .. } => k.encode_jpeg_ns_per_pixel,
+            s::EncoderPreset::Libpng { .. } => k.encode_png_ns_per_pixel,
+            s::EncoderPreset::Gif => k.encode_gif_ns_per_pixel
+        };
+        Ok(::flow::cost::cost(k.node_overhead_ns + ::flow::cost::pixels(input) as f64 * ns_per_pixel, 0, 0))
     }
     fn can_execute(&self) -> bool {
         true
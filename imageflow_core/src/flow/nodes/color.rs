@@ -6,6 +6,13 @@ use super::internal_prelude::*;
 pub static COLOR_MATRIX_SRGB: MutProtect<ColorMatrixSrgbMutDef> = MutProtect{ node: &COLOR_MATRIX_SRGB_MUTATE, fqn: "imazen.color_matrix_srgb"};
 pub static COLOR_FILTER_SRGB: ColorFilterSrgb = ColorFilterSrgb{};
 pub static COLOR_MATRIX_SRGB_MUTATE: ColorMatrixSrgbMutDef = ColorMatrixSrgbMutDef{};
+pub static COLOR_MATRIX_LINEAR: MutProtect<ColorMatrixLinearMutDef> = MutProtect{ node: &COLOR_MATRIX_LINEAR_MUTATE, fqn: "imazen.color_matrix_linear"};
+pub static COLOR_MATRIX_LINEAR_MUTATE: ColorMatrixLinearMutDef = ColorMatrixLinearMutDef{};
+pub static COLOR_FILTER: ColorFilter = ColorFilter{};
+pub static COLOR_FILTER_LUV_MUTATE: ColorFilterLuvMutDef = ColorFilterLuvMutDef{};
+
+/// How many rows are converted to floating point at a time
+const FLOAT_ROWS_PER_PASS: u32 = 64;
 
 
 
@@ -53,18 +60,7 @@ impl NodeDefOneInputExpand for ColorFilterSrgb{
     }
     fn expand(&self, ctx: &mut OpCtxMut, ix: NodeIndex, p: NodeParams, parent: FrameInfo) -> Result<()> {
         if let NodeParams::Json(s::Node::ColorFilterSrgb(filter))= p {
-            let matrix = match filter as s::ColorFilterSrgb {
-                s::ColorFilterSrgb::Sepia => sepia(),
-                s::ColorFilterSrgb::GrayscaleNtsc => grayscale_ntsc(),
-                s::ColorFilterSrgb::GrayscaleRy => grayscale_ry(),
-                s::ColorFilterSrgb::GrayscaleFlat => grayscale_flat(),
-                s::ColorFilterSrgb::GrayscaleBt709 => grayscale_bt709(),
-                s::ColorFilterSrgb::Invert => invert(),
-                s::ColorFilterSrgb::Alpha(a) => alpha(a),
-                s::ColorFilterSrgb::Contrast(a) => contrast(a),
-                s::ColorFilterSrgb::Saturation(a) => saturation(a),
-                s::ColorFilterSrgb::Brightness(a) => brightness(a),
-            };
+            let matrix = filter_matrix(filter);
             ctx.replace_node(ix, vec![Node::n(&COLOR_MATRIX_SRGB_MUTATE,
                                                 NodeParams::Json(s::Node::ColorMatrixSrgb { matrix: matrix }))]);
             Ok(())
@@ -74,6 +70,198 @@ impl NodeDefOneInputExpand for ColorFilterSrgb{
     }
 }
 
+fn filter_matrix(filter: s::ColorFilterSrgb) -> [[f32;5];5] {
+    match filter {
+        s::ColorFilterSrgb::Sepia => sepia(),
+        s::ColorFilterSrgb::GrayscaleNtsc => grayscale_ntsc(),
+        s::ColorFilterSrgb::GrayscaleRy => grayscale_ry(),
+        s::ColorFilterSrgb::GrayscaleFlat => grayscale_flat(),
+        s::ColorFilterSrgb::GrayscaleBt709 => grayscale_bt709(),
+        s::ColorFilterSrgb::Invert => invert(),
+        s::ColorFilterSrgb::Alpha(a) => alpha(a),
+        s::ColorFilterSrgb::Contrast(a) => contrast(a),
+        s::ColorFilterSrgb::Saturation(a) => saturation(a),
+        s::ColorFilterSrgb::Brightness(a) => brightness(a),
+    }
+}
+
+/// Runs `f` over `bitmap` converted to linear light (or L*u*v*), FLOAT_ROWS_PER_PASS rows at a time.
+/// `f` receives the float buffer and the number of rows filled; alpha is not premultiplied.
+fn mutate_as_float<F>(c: &Context, bitmap: &mut BitmapBgra, luv: bool, mut f: F) -> Result<()>
+    where F: FnMut(*mut ffi::BitmapFloat, u32) -> Result<()> {
+    if bitmap.fmt == PixelFormat::Gray8 {
+        return Err(nerror!(::ErrorKind::InvalidOperation, "Linear-light color operations require a BGR(A) bitmap, got Gray8"));
+    }
+    let flow_c = c.flow_c();
+    let rows = cmp::max(1, cmp::min(FLOAT_ROWS_PER_PASS, bitmap.h));
+    unsafe {
+        let mut colorcontext: ffi::ColorContextInfo = mem::zeroed();
+        ffi::flow_colorcontext_init(flow_c, &mut colorcontext, ffi::Floatspace::Linear, 0f32, 0f32, 0f32);
+
+        let buffer = ffi::flow_bitmap_float_create(flow_c, bitmap.w as i32, rows as i32, 4, true);
+        if buffer.is_null() {
+            return Err(cerror!(c, "Failed to allocate float buffer"));
+        }
+        let mut result = Ok(());
+        let mut y = 0;
+        while y < bitmap.h && result.is_ok() {
+            let count = cmp::min(rows, bitmap.h - y);
+            result = float_pass(c, &mut colorcontext, bitmap, buffer, y, count, luv, &mut f);
+            y += count;
+        }
+        ffi::flow_bitmap_float_destroy(flow_c, buffer);
+        result
+    }
+}
+
+unsafe fn float_pass<F>(c: &Context, colorcontext: *mut ffi::ColorContextInfo, bitmap: *mut BitmapBgra, buffer: *mut ffi::BitmapFloat,
+                        y: u32, count: u32, luv: bool, f: &mut F) -> Result<()>
+    where F: FnMut(*mut ffi::BitmapFloat, u32) -> Result<()> {
+    let flow_c = c.flow_c();
+    if !ffi::flow_bitmap_float_convert_srgb_to_linear(flow_c, colorcontext, bitmap, y, buffer, 0, count) {
+        return Err(cerror!(c, "Failed to convert sRGB to linear"));
+    }
+    if (*bitmap).fmt == PixelFormat::Bgra32 && !ffi::flow_bitmap_float_demultiply_alpha(flow_c, buffer, 0, count) {
+        return Err(cerror!(c, "Failed to demultiply alpha"));
+    }
+    if luv && !ffi::flow_bitmap_float_linear_to_luv_rows(flow_c, buffer, 0, count) {
+        return Err(cerror!(c, "Failed to convert linear to L*u*v*"));
+    }
+    f(buffer, count).map_err(|e| e.at(here!()))?;
+    if luv && !ffi::flow_bitmap_float_luv_to_linear_rows(flow_c, buffer, 0, count) {
+        return Err(cerror!(c, "Failed to convert L*u*v* to linear"));
+    }
+    if !ffi::flow_bitmap_float_copy_linear_over_srgb(flow_c, colorcontext, buffer, 0, bitmap, y, count, 0, (*bitmap).w, false) {
+        return Err(cerror!(c, "Failed to convert linear to sRGB"));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ColorMatrixLinearMutDef;
+impl NodeDef for ColorMatrixLinearMutDef{
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap>{
+        Some(self)
+    }
+}
+impl NodeDefMutateBitmap for ColorMatrixLinearMutDef{
+    fn fqn(&self) -> &'static str{
+        "imazen.color_matrix_linear_mut"
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra,  p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::ColorMatrixLinear { ref matrix }) = p {
+            let color_matrix_ptrs = matrix.iter().map(|row| row as *const f32).collect::<Vec<*const f32>>();
+            mutate_as_float(c, bitmap, false, |buffer, count| {
+                if unsafe { ffi::flow_bitmap_float_apply_color_matrix(c.flow_c(), buffer, 0, count, color_matrix_ptrs.as_ptr()) } {
+                    Ok(())
+                } else {
+                    Err(cerror!(c, "Failed to apply color matrix"))
+                }
+            }).map_err(|e| e.at(here!()))
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need ColorMatrixLinear, got {:?}", p))
+        }
+    }
+}
+
+/// Filters with a perceptual definition in L*u*v*; the rest fall back to linear light
+fn luv_supports(filter: s::ColorFilterSrgb) -> bool {
+    match filter {
+        s::ColorFilterSrgb::Brightness(_) |
+        s::ColorFilterSrgb::Contrast(_) |
+        s::ColorFilterSrgb::Saturation(_) |
+        s::ColorFilterSrgb::GrayscaleNtsc |
+        s::ColorFilterSrgb::GrayscaleRy |
+        s::ColorFilterSrgb::GrayscaleFlat |
+        s::ColorFilterSrgb::GrayscaleBt709 => true,
+        _ => false
+    }
+}
+
+/// Adjusts a single pixel in the layout used by the C L*u*v* conversion:
+/// L is 0..100 and u, v are offset by 100, so neutral gray has u = v = 100.
+fn luv_adjust(filter: s::ColorFilterSrgb, pixel: &mut [f32]) {
+    match filter {
+        s::ColorFilterSrgb::Brightness(f) => pixel[0] += f * 100f32,
+        s::ColorFilterSrgb::Contrast(f) => pixel[0] = (pixel[0] - 50f32) * (f + 1f32).max(0f32) + 50f32,
+        s::ColorFilterSrgb::Saturation(f) => {
+            let factor = (f + 1f32).max(0f32);
+            pixel[1] = (pixel[1] - 100f32) * factor + 100f32;
+            pixel[2] = (pixel[2] - 100f32) * factor + 100f32;
+        }
+        s::ColorFilterSrgb::GrayscaleNtsc |
+        s::ColorFilterSrgb::GrayscaleRy |
+        s::ColorFilterSrgb::GrayscaleFlat |
+        s::ColorFilterSrgb::GrayscaleBt709 => {
+            pixel[1] = 100f32;
+            pixel[2] = 100f32;
+        }
+        _ => {}
+    }
+    pixel[0] = pixel[0].max(0f32).min(100f32);
+}
+
+#[derive(Debug, Clone)]
+pub struct ColorFilterLuvMutDef;
+impl NodeDef for ColorFilterLuvMutDef{
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap>{
+        Some(self)
+    }
+}
+impl NodeDefMutateBitmap for ColorFilterLuvMutDef{
+    fn fqn(&self) -> &'static str{
+        "imazen.color_filter_luv_mut"
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra,  p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::ColorFilter { filter, .. }) = p {
+            mutate_as_float(c, bitmap, true, |buffer, count| {
+                let buffer = unsafe { &*buffer };
+                let channels = buffer.channels as usize;
+                for row in 0..count {
+                    let pixels = unsafe {
+                        slice::from_raw_parts_mut(buffer.pixels.offset((row * buffer.float_stride) as isize), buffer.w as usize * channels)
+                    };
+                    for pixel in pixels.chunks_mut(channels) {
+                        luv_adjust(filter, pixel);
+                    }
+                }
+                Ok(())
+            }).map_err(|e| e.at(here!()))
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need ColorFilter, got {:?}", p))
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct ColorFilter;
+impl NodeDef for ColorFilter{
+    fn as_one_input_expand(&self) -> Option<&NodeDefOneInputExpand>{
+        Some(self)
+    }
+}
+impl NodeDefOneInputExpand for ColorFilter{
+    fn fqn(&self) -> &'static str{
+        "imazen.color_filter"
+    }
+    fn expand(&self, ctx: &mut OpCtxMut, ix: NodeIndex, p: NodeParams, parent: FrameInfo) -> Result<()> {
+        if let NodeParams::Json(s::Node::ColorFilter { filter, space }) = p {
+            let node = match space.unwrap_or(s::ColorFilterSpace::Srgb) {
+                s::ColorFilterSpace::Srgb => Node::n(&COLOR_MATRIX_SRGB_MUTATE,
+                                                     NodeParams::Json(s::Node::ColorMatrixSrgb { matrix: filter_matrix(filter) })),
+                s::ColorFilterSpace::Luv if luv_supports(filter) => Node::n(&COLOR_FILTER_LUV_MUTATE,
+                                                                            NodeParams::Json(s::Node::ColorFilter { filter: filter, space: space })),
+                _ => Node::n(&COLOR_MATRIX_LINEAR_MUTATE,
+                             NodeParams::Json(s::Node::ColorMatrixLinear { matrix: filter_matrix(filter) })),
+            };
+            ctx.replace_node(ix, vec![node]);
+            Ok(())
+        }else{
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need ColorFilter, got {:?}", p))
+        }
+    }
+}
+
 
 fn sepia() -> [[f32;5];5] {
     [
@@ -212,3 +400,24 @@ fn saturation(saturation: f32) -> [[f32;5];5] {
         [0.0f32, 0.0f32, 0.0f32, 0.0f32, 1.0f32],
     ]
 }
+
+#[test]
+fn test_luv_adjust() {
+    let mut pixel = [60f32, 130f32, 80f32, 1f32];
+    luv_adjust(s::ColorFilterSrgb::GrayscaleBt709, &mut pixel);
+    assert_eq!(pixel, [60f32, 100f32, 100f32, 1f32]);
+
+    let mut pixel = [60f32, 130f32, 80f32, 1f32];
+    luv_adjust(s::ColorFilterSrgb::Saturation(-0.5f32), &mut pixel);
+    assert_eq!(pixel, [60f32, 115f32, 90f32, 1f32]);
+
+    let mut pixel = [75f32, 100f32, 100f32, 1f32];
+    luv_adjust(s::ColorFilterSrgb::Contrast(1f32), &mut pixel);
+    assert_eq!(pixel[0], 100f32);
+
+    let mut pixel = [10f32, 100f32, 100f32, 1f32];
+    luv_adjust(s::ColorFilterSrgb::Brightness(-0.2f32), &mut pixel);
+    assert_eq!(pixel[0], 0f32);
+
+    assert!(!luv_supports(s::ColorFilterSrgb::Sepia));
+}
@@ -0,0 +1,273 @@
+use super::internal_prelude::*;
+use super::analyze_colors::read_rgba;
+
+pub static COMPARE: CompareDef = CompareDef{};
+pub static COMPARE_PRIMITIVE: ComparePrimitiveDef = ComparePrimitiveDef{};
+pub static DIFF_HEATMAP: DiffHeatmapDef = DiffHeatmapDef{};
+
+/// SSIM is averaged over square windows of this size, overlapping by half
+const SSIM_WINDOW: u32 = 8;
+
+fn get_diff_io_id(p: &NodeParams) -> Result<Option<i32>> {
+    if let &NodeParams::Json(s::Node::Compare { diff_io_id }) = p {
+        Ok(diff_io_id)
+    } else {
+        Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Compare, got {:?}", p))
+    }
+}
+
+/// Returns the input and canvas bitmaps, which must have the same dimensions
+fn get_pair(ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<(*mut BitmapBgra, *mut BitmapBgra)> {
+    let input = ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+    let canvas = ctx.bitmap_bgra_from(ix, EdgeKind::Canvas).map_err(|e| e.at(here!()))?;
+    let (a, b) = unsafe { (&*input, &*canvas) };
+    if a.w != b.w || a.h != b.h {
+        Err(nerror!(::ErrorKind::InvalidNodeConnections, "Compare requires images of the same size, got {}x{} and {}x{}", a.w, a.h, b.w, b.h))
+    } else {
+        Ok((input, canvas))
+    }
+}
+
+/// Rec. 601 luma, composited over white
+fn luma(p: [u8; 4]) -> f64 {
+    let l = 0.299f64 * p[0] as f64 + 0.587f64 * p[1] as f64 + 0.114f64 * p[2] as f64;
+    let a = p[3] as f64 / 255f64;
+    l * a + 255f64 * (1f64 - a)
+}
+
+fn max_channel_delta(a: [u8; 4], b: [u8; 4], channels: usize) -> u8 {
+    (0..channels).map(|c| (a[c] as i32 - b[c] as i32).abs() as u8).max().unwrap_or(0)
+}
+
+fn window_ssim(a: &[f64], b: &[f64], stride: usize, x: usize, y: usize, w: usize, h: usize) -> f64 {
+    let c1 = (0.01f64 * 255f64).powi(2);
+    let c2 = (0.03f64 * 255f64).powi(2);
+    let n = (w * h) as f64;
+    let (mut sum_a, mut sum_b) = (0f64, 0f64);
+    for row in y..y + h {
+        for ix in row * stride + x..row * stride + x + w {
+            sum_a += a[ix];
+            sum_b += b[ix];
+        }
+    }
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let (mut var_a, mut var_b, mut covariance) = (0f64, 0f64, 0f64);
+    for row in y..y + h {
+        for ix in row * stride + x..row * stride + x + w {
+            var_a += (a[ix] - mean_a) * (a[ix] - mean_a);
+            var_b += (b[ix] - mean_b) * (b[ix] - mean_b);
+            covariance += (a[ix] - mean_a) * (b[ix] - mean_b);
+        }
+    }
+    let (var_a, var_b, covariance) = (var_a / n, var_b / n, covariance / n);
+    ((2f64 * mean_a * mean_b + c1) * (2f64 * covariance + c2)) /
+        ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2))
+}
+
+fn mean_ssim(a: &[f64], b: &[f64], w: u32, h: u32) -> f64 {
+    let window_w = cmp::min(SSIM_WINDOW, w) as usize;
+    let window_h = cmp::min(SSIM_WINDOW, h) as usize;
+    let (step_x, step_y) = (cmp::max(1, window_w / 2), cmp::max(1, window_h / 2));
+    let (mut total, mut count) = (0f64, 0f64);
+    let mut y = 0;
+    while y + window_h <= h as usize {
+        let mut x = 0;
+        while x + window_w <= w as usize {
+            total += window_ssim(a, b, w as usize, x, y, window_w, window_h);
+            count += 1f64;
+            x += step_x;
+        }
+        y += step_y;
+    }
+    total / count
+}
+
+pub fn compare_bitmaps(a: &BitmapBgra, b: &BitmapBgra) -> s::ComparisonResult {
+    let channels = if a.fmt == PixelFormat::Bgra32 || b.fmt == PixelFormat::Bgra32 { 4 } else { 3 };
+    let mut squared_error = 0f64;
+    let mut max_delta = 0u8;
+    let mut pixels_different = 0u64;
+    let mut luma_a = Vec::with_capacity((a.w * a.h) as usize);
+    let mut luma_b = Vec::with_capacity((a.w * a.h) as usize);
+    for y in 0..a.h {
+        for x in 0..a.w {
+            let (pa, pb) = (read_rgba(a, x, y), read_rgba(b, x, y));
+            for c in 0..channels {
+                let d = pa[c] as f64 - pb[c] as f64;
+                squared_error += d * d;
+            }
+            let delta = max_channel_delta(pa, pb, channels);
+            if delta > 0 {
+                pixels_different += 1;
+            }
+            max_delta = cmp::max(max_delta, delta);
+            luma_a.push(luma(pa));
+            luma_b.push(luma(pb));
+        }
+    }
+    let mse = squared_error / (a.w as f64 * a.h as f64 * channels as f64);
+    // Anti-correlated images can have an SSIM at or below zero; they're as dissimilar as it gets
+    let ssim = mean_ssim(&luma_a, &luma_b, a.w, a.h).max(1e-6f64);
+    s::ComparisonResult {
+        dssim: 1f64 / ssim - 1f64,
+        psnr: if mse > 0f64 { Some(10f64 * (255f64 * 255f64 / mse).log10()) } else { None },
+        max_channel_delta: max_delta,
+        pixels_different: pixels_different,
+    }
+}
+
+/// Unchanged pixels are a dimmed grayscale copy of the input; changed pixels run from red to yellow as the difference grows
+fn render_heatmap(a: &BitmapBgra, b: &BitmapBgra, canvas: &mut BitmapBgra) {
+    for y in 0..a.h {
+        for x in 0..a.w {
+            let (pa, pb) = (read_rgba(a, x, y), read_rgba(b, x, y));
+            let delta = max_channel_delta(pa, pb, 4) as u32;
+            let bgra = if delta == 0 {
+                let gray = (luma(pa) / 4f64) as u8;
+                [gray, gray, gray, 255]
+            } else {
+                [0, cmp::min(255, delta * 2) as u8, 255, 255]
+            };
+            unsafe {
+                let p = canvas.pixels.offset((y * canvas.stride + x * 4) as isize);
+                for i in 0..4 {
+                    *p.offset(i as isize) = bgra[i];
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct CompareDef;
+
+impl NodeDef for CompareDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.compare"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInputOneCanvas, EdgesOut::None))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        get_diff_io_id(p).map_err(|e| e.at(here!())).map(|_| ())
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+    }
+    fn can_expand(&self) -> bool {
+        true
+    }
+
+    fn expand(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<()> {
+        let diff_io_id = get_diff_io_id(&ctx.weight(ix).params).map_err(|e| e.at(here!()))?;
+        // The heat map is rendered by a sibling node from the same parents, then encoded
+        if let Some(io_id) = diff_io_id {
+            let params = ctx.weight(ix).params.clone();
+            let heatmap = ctx.graph.add_node(Node::n(&DIFF_HEATMAP, params));
+            ctx.copy_edges_to(ix, heatmap, EdgeDirection::Incoming);
+            let encode = ctx.graph.add_node(Node::n(&ENCODE, NodeParams::Json(s::Node::Encode {
+                io_id: io_id,
+                preset: s::EncoderPreset::libpng32(),
+            })));
+            ctx.graph.add_edge(heatmap, encode, EdgeKind::Input).unwrap();
+        }
+        // Mutate instead of replace
+        ctx.weight_mut(ix).def = &COMPARE_PRIMITIVE;
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct ComparePrimitiveDef;
+
+impl NodeDef for ComparePrimitiveDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.compare_primitive"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInputOneCanvas, EdgesOut::None))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        get_diff_io_id(p).map_err(|e| e.at(here!())).map(|_| ())
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+    }
+    fn can_execute(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        let (input, canvas) = get_pair(ctx, ix).map_err(|e| e.at(here!()))?;
+        let result = compare_bitmaps(unsafe { &*input }, unsafe { &*canvas });
+        Ok(NodeResult::Analyzed(s::AnalysisResult::Comparison(result)))
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct DiffHeatmapDef;
+
+impl NodeDef for DiffHeatmapDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.diff_heatmap"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInputOneCanvas, EdgesOut::Any))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        get_diff_io_id(p).map_err(|e| e.at(here!())).map(|_| ())
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        let input = ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+        input.map_frame(|info| Ok(FrameInfo { fmt: PixelFormat::Bgra32, ..info }))
+    }
+    fn can_execute(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        let (input, canvas) = get_pair(ctx, ix).map_err(|e| e.at(here!()))?;
+        let (a, b) = unsafe { (&*input, &*canvas) };
+        unsafe {
+            let heatmap = ::ffi::flow_bitmap_bgra_create(ctx.flow_c(), a.w as i32, a.h as i32, false, PixelFormat::Bgra32);
+            if heatmap.is_null() {
+                return Err(cerror!(ctx.c, "Failed to allocate {}x{} heat map", a.w, a.h));
+            }
+            (*heatmap).compositing_mode = ::ffi::BitmapCompositingMode::ReplaceSelf;
+            render_heatmap(a, b, &mut *heatmap);
+            Ok(NodeResult::Frame(heatmap))
+        }
+    }
+}
+
+#[test]
+fn test_compare_bitmaps() {
+    let mut expected = vec![100u8; 16];
+    let mut actual = expected.clone();
+    actual[5] = 116;
+    let gray = |pixels: &mut Vec<u8>| BitmapBgra {
+        w: 4,
+        h: 4,
+        stride: 4,
+        pixels: pixels.as_mut_ptr(),
+        fmt: PixelFormat::Gray8,
+        matte_color: [0; 4],
+        compositing_mode: ::ffi::BitmapCompositingMode::ReplaceSelf,
+    };
+    let (a, b) = (gray(&mut actual), gray(&mut expected));
+
+    let same = compare_bitmaps(&b, &b);
+    assert_eq!((same.dssim, same.psnr, same.max_channel_delta, same.pixels_different), (0f64, None, 0, 0));
+
+    let different = compare_bitmaps(&a, &b);
+    assert_eq!((different.max_channel_delta, different.pixels_different), (16, 1));
+    // MSE is 16^2 / 16 pixels
+    assert!((different.psnr.unwrap() - 10f64 * (255f64 * 255f64 / 16f64).log10()).abs() < 1e-9);
+    assert!(different.dssim > 0f64);
+}
@@ -0,0 +1,263 @@
+use super::internal_prelude::*;
+
+pub static APPLY_MASK: ApplyMaskDef = ApplyMaskDef{};
+pub static CHROMA_KEY: MutProtect<ChromaKeyMutDef> = MutProtect{node: &CHROMA_KEY_MUTATE, fqn: "imazen.chroma_key"};
+pub static CHROMA_KEY_MUTATE: ChromaKeyMutDef = ChromaKeyMutDef{};
+
+/// Gives a Bgr32 bitmap an opaque alpha channel so it can become transparent
+fn ensure_alpha(bitmap: &mut BitmapBgra) -> Result<()> {
+    match bitmap.fmt {
+        PixelFormat::Bgra32 => Ok(()),
+        PixelFormat::Bgr32 => {
+            for y in 0..bitmap.h {
+                let row = unsafe { slice::from_raw_parts_mut(bitmap.pixels.offset((y * bitmap.stride) as isize), (bitmap.w * 4) as usize) };
+                for pixel in row.chunks_mut(4) {
+                    pixel[3] = 255;
+                }
+            }
+            bitmap.fmt = PixelFormat::Bgra32;
+            Ok(())
+        }
+        other => Err(nerror!(::ErrorKind::InvalidNodeConnections, "Transparency requires a Bgr32 or Bgra32 bitmap, not {:?}", other))
+    }
+}
+
+fn row<'a>(bitmap: &'a BitmapBgra, y: u32) -> &'a [u8] {
+    unsafe { slice::from_raw_parts(bitmap.pixels.offset((y * bitmap.stride) as isize), (bitmap.w as usize) * bitmap.fmt.bytes()) }
+}
+
+fn row_mut<'a>(bitmap: &'a mut BitmapBgra, y: u32) -> &'a mut [u8] {
+    unsafe { slice::from_raw_parts_mut(bitmap.pixels.offset((y * bitmap.stride) as isize), (bitmap.w as usize) * bitmap.fmt.bytes()) }
+}
+
+/// The mask value (0..255) of a pixel in `fmt`
+fn mask_value(pixel: &[u8], fmt: PixelFormat, channel: s::MaskChannel) -> u8 {
+    match (fmt, channel) {
+        (PixelFormat::Gray8, _) => pixel[0],
+        (PixelFormat::Bgra32, s::MaskChannel::Alpha) => pixel[3],
+        (_, s::MaskChannel::Alpha) => 255,
+        (_, s::MaskChannel::Luminance) => {
+            let luma = 0.114f32 * pixel[0] as f32 + 0.587f32 * pixel[1] as f32 + 0.299f32 * pixel[2] as f32;
+            let alpha = if fmt == PixelFormat::Bgra32 { pixel[3] as f32 / 255f32 } else { 1f32 };
+            (luma * alpha).round() as u8
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApplyMaskDef;
+impl NodeDef for ApplyMaskDef {
+    fn as_one_input_one_canvas(&self) -> Option<&NodeDefOneInputOneCanvas> {
+        Some(self)
+    }
+
+    fn estimate_cost(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<CostEstimate> {
+        match ctx.first_parent_frame_info_some(ix) {
+            Some(mask) => {
+                let k = &ctx.job.cost_coefficients;
+                Ok(::flow::cost::cost(k.node_overhead_ns + ::flow::cost::pixels(mask) as f64 * k.blend_ns_per_pixel, 0, 0))
+            }
+            None => Ok(CostEstimate::Impossible)
+        }
+    }
+}
+impl NodeDefOneInputOneCanvas for ApplyMaskDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.apply_mask"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::ApplyMask { .. }) = p {
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need ApplyMask, got {:?}", p))
+        }
+    }
+    fn render(&self, c: &Context, canvas: &mut BitmapBgra, input: &mut BitmapBgra, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::ApplyMask { channel, invert }) = p {
+            if canvas.w != input.w || canvas.h != input.h {
+                return Err(nerror!(::ErrorKind::InvalidNodeConnections, "The mask ({}x{}) must be the same size as the image ({}x{})", input.w, input.h, canvas.w, canvas.h));
+            }
+            ensure_alpha(canvas).map_err(|e| e.at(here!()))?;
+            let bpp = input.fmt.bytes();
+            for y in 0..canvas.h {
+                let mask = row(input, y);
+                let image = row_mut(canvas, y);
+                for (pixel, m) in image.chunks_mut(4).zip(mask.chunks(bpp)) {
+                    let mut m = mask_value(m, input.fmt, channel);
+                    if invert == Some(true) {
+                        m = 255 - m;
+                    }
+                    pixel[3] = ((pixel[3] as u32 * m as u32 + 127) / 255) as u8;
+                }
+            }
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need ApplyMask, got {:?}", p))
+        }
+    }
+}
+
+/// BT.601 full-range chroma of a BGR pixel
+fn chroma(b: f32, g: f32, r: f32) -> (f32, f32) {
+    (128f32 - 0.168736f32 * r - 0.331264f32 * g + 0.5f32 * b,
+     128f32 + 0.5f32 * r - 0.418688f32 * g - 0.081312f32 * b)
+}
+
+/// How much of a pixel to keep (0..1) given its chroma distance from the key color
+fn key_opacity(distance: f32, tolerance: f32, feather: f32) -> f32 {
+    if distance <= tolerance {
+        0f32
+    } else if distance >= tolerance + feather {
+        1f32
+    } else {
+        (distance - tolerance) / feather
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChromaKeyMutDef;
+impl NodeDef for ChromaKeyMutDef {
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap> {
+        Some(self)
+    }
+}
+impl NodeDefMutateBitmap for ChromaKeyMutDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.chroma_key_mutate"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::ChromaKey { tolerance, feather, .. }) = p {
+            if !(tolerance >= 0f32) || !(feather.unwrap_or(0f32) >= 0f32) {
+                Err(nerror!(::ErrorKind::InvalidNodeParams, "tolerance and feather cannot be negative"))
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need ChromaKey, got {:?}", p))
+        }
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra, p: &NodeParams) -> Result<()> {
+        self.validate_params(p).map_err(|e| e.at(here!()))?;
+        if let &NodeParams::Json(s::Node::ChromaKey { ref color, tolerance, feather }) = p {
+            let key = color.to_color_32().map_err(|e| nerror!(::ErrorKind::InvalidNodeParams, "Invalid key color: {:?}", e))?.to_bgra_le();
+            let (key_cb, key_cr) = chroma(key as u8 as f32, (key >> 8) as u8 as f32, (key >> 16) as u8 as f32);
+            let feather = feather.unwrap_or(0f32);
+
+            ensure_alpha(bitmap).map_err(|e| e.at(here!()))?;
+            for y in 0..bitmap.h {
+                for pixel in row_mut(bitmap, y).chunks_mut(4) {
+                    let (cb, cr) = chroma(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                    let distance = ((cb - key_cb) * (cb - key_cb) + (cr - key_cr) * (cr - key_cr)).sqrt();
+                    pixel[3] = (pixel[3] as f32 * key_opacity(distance, tolerance, feather)).round() as u8;
+                }
+            }
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need ChromaKey, got {:?}", p))
+        }
+    }
+}
+
+/// The W3C separable blend function B(backdrop, source), on 0..1 sRGB values
+fn blend_channel(mode: s::CompositingMode, cb: f32, cs: f32) -> f32 {
+    match mode {
+        s::CompositingMode::Multiply => cb * cs,
+        s::CompositingMode::Screen => cb + cs - cb * cs,
+        s::CompositingMode::Overlay => {
+            // Hard light with the layers swapped
+            if cb <= 0.5f32 {
+                2f32 * cb * cs
+            } else {
+                let cb = 2f32 * cb - 1f32;
+                cb + cs - cb * cs
+            }
+        }
+        s::CompositingMode::Compose => cs,
+        s::CompositingMode::Overwrite => cs,
+    }
+}
+
+/// Blends a straight-alpha BGRA source pixel over a backdrop pixel, then composites source-over
+fn blend_pixel(mode: s::CompositingMode, source: &[u8], backdrop: &mut [u8], backdrop_alpha: bool) {
+    let alpha_s = source[3] as f32 / 255f32;
+    let alpha_b = if backdrop_alpha { backdrop[3] as f32 / 255f32 } else { 1f32 };
+    let alpha_o = alpha_s + alpha_b * (1f32 - alpha_s);
+    if alpha_o <= 0f32 {
+        return;
+    }
+    for c in 0..3 {
+        let cs = source[c] as f32 / 255f32;
+        let cb = backdrop[c] as f32 / 255f32;
+        // Where the backdrop is transparent, the source shows unmodified
+        let mixed = (1f32 - alpha_b) * cs + alpha_b * blend_channel(mode, cb, cs);
+        let premultiplied = alpha_s * mixed + (1f32 - alpha_s) * alpha_b * cb;
+        backdrop[c] = (premultiplied / alpha_o * 255f32).round().max(0f32).min(255f32) as u8;
+    }
+    if backdrop_alpha {
+        backdrop[3] = (alpha_o * 255f32).round() as u8;
+    }
+}
+
+/// Scales `input` into a scratch bitmap, then blends it onto `canvas` at the given rectangle with `mode`
+pub fn render_blended(c: &Context, canvas: &mut BitmapBgra, input: &mut BitmapBgra, target: &ffi::Scale2dRenderToCanvas1d, mode: s::CompositingMode) -> Result<()> {
+    let scratch = unsafe { ::ffi::flow_bitmap_bgra_create(c.flow_c(), target.w as i32, target.h as i32, true, PixelFormat::Bgra32) };
+    if scratch.is_null() {
+        return Err(cerror!(c, "Failed to allocate blend buffer"));
+    }
+    let scaled = ffi::Scale2dRenderToCanvas1d {
+        x: 0,
+        y: 0,
+        .. *target
+    };
+    unsafe {
+        (*scratch).compositing_mode = ::ffi::BitmapCompositingMode::ReplaceSelf;
+        if let Err(e) = super::scale_render::scale2d_render1d(c, input, scratch, &scaled) {
+            ::ffi::flow_destroy(c.flow_c(), scratch as *const c_void, ptr::null(), 0);
+            return Err(e.at(here!()));
+        }
+        let source = &*scratch;
+        let backdrop_alpha = canvas.fmt == PixelFormat::Bgra32;
+        for y in 0..target.h {
+            let source_row = row(source, y);
+            let canvas_row = &mut row_mut(canvas, target.y + y)[(target.x * 4) as usize..((target.x + target.w) * 4) as usize];
+            for (s, b) in source_row.chunks(4).zip(canvas_row.chunks_mut(4)) {
+                blend_pixel(mode, s, b, backdrop_alpha);
+            }
+        }
+        ::ffi::flow_destroy(c.flow_c(), scratch as *const c_void, ptr::null(), 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_blend_pixel() {
+    let blend = |mode, source: [u8; 4], backdrop: [u8; 4]| {
+        let mut out = backdrop;
+        blend_pixel(mode, &source, &mut out, true);
+        out
+    };
+    let gray = [128, 128, 128, 255];
+    assert_eq!(blend(s::CompositingMode::Multiply, [255, 255, 255, 255], gray), gray);
+    assert_eq!(blend(s::CompositingMode::Multiply, [0, 0, 0, 255], gray), [0, 0, 0, 255]);
+    assert_eq!(blend(s::CompositingMode::Screen, [0, 0, 0, 255], gray), gray);
+    assert_eq!(blend(s::CompositingMode::Screen, gray, [128, 128, 128, 255]), [192, 192, 192, 255]);
+    // Overlay keeps a black backdrop black and a white one white
+    assert_eq!(blend(s::CompositingMode::Overlay, gray, [0, 0, 0, 255]), [0, 0, 0, 255]);
+    assert_eq!(blend(s::CompositingMode::Overlay, gray, [255, 255, 255, 255]), [255, 255, 255, 255]);
+    // A transparent source changes nothing; a transparent backdrop takes the source as is
+    assert_eq!(blend(s::CompositingMode::Multiply, [0, 0, 0, 0], gray), gray);
+    assert_eq!(blend(s::CompositingMode::Multiply, [10, 20, 30, 255], [0, 0, 0, 0]), [10, 20, 30, 255]);
+}
+
+#[test]
+fn test_masks_and_keys() {
+    assert_eq!(mask_value(&[255, 255, 255, 128], PixelFormat::Bgra32, s::MaskChannel::Alpha), 128);
+    assert_eq!(mask_value(&[255, 255, 255, 128], PixelFormat::Bgra32, s::MaskChannel::Luminance), 128);
+    assert_eq!(mask_value(&[0, 255, 0, 0], PixelFormat::Bgr32, s::MaskChannel::Luminance), 150);
+
+    let (cb, cr) = chroma(128f32, 128f32, 128f32);
+    assert!((cb - 128f32).abs() < 0.01f32 && (cr - 128f32).abs() < 0.01f32);
+    assert_eq!(key_opacity(5f32, 10f32, 0f32), 0f32);
+    assert_eq!(key_opacity(15f32, 10f32, 10f32), 0.5f32);
+    assert_eq!(key_opacity(25f32, 10f32, 10f32), 1f32);
+}
@@ -1,4 +1,7 @@
 use super::internal_prelude::*;
+use ::imageflow_riapi::sizing::{steps, AspectRatio, BoxTarget, Layout, IdentityCropProvider, FocalPointCropProvider, PartialCropProvider};
+use ::imageflow_riapi::ir4::layout::Ir4Layout;
+use ::imageflow_riapi::ir4::parsing::{Anchor1D, FitMode, ScaleMode};
 
 
 pub static CONSTRAIN: ConstrainDef = ConstrainDef{};
@@ -107,10 +110,10 @@ impl NodeDefOneInputExpand for ConstrainDef{
     fn estimate(&self, params: &NodeParams, input: FrameEstimate) -> Result<FrameEstimate>{
         if let &NodeParams::Json(s::Node::Constrain(ref constraint)) = params {
             input.map_frame(|input| {
-                let (w, h, _) = constrain(input.w as u32, input.h as u32, constraint);
+                let layout = layout_constraint(input.w as u32, input.h as u32, constraint).map_err(|e| e.at(here!()))?;
                 Ok(FrameInfo {
-                    w: w as i32,
-                    h: h as i32,
+                    w: layout.canvas.0 as i32,
+                    h: layout.canvas.1 as i32,
                     fmt: ffi::PixelFormat::from(input.fmt),
                 })
             })
@@ -124,12 +127,18 @@ impl NodeDefOneInputExpand for ConstrainDef{
             let input_w = parent.w as u32;
             let input_h = parent.h as u32;
 
-            let (new_w, new_h, hints_val) = constrain(input_w, input_h, &constraint);
+            let layout = layout_constraint(input_w, input_h, &constraint).map_err(|e| e.at(here!()))?;
 
-            let hints = &hints_val;
+            let hints = &layout.hints;
+
+            let (crop_w, crop_h) = match layout.crop {
+                Some(c) => (c[2] - c[0], c[3] - c[1]),
+                None => (input_w, input_h)
+            };
+            let (new_w, new_h) = layout.image;
 
             let resample_when = hints.and_then(|ref h| h.resample_when).unwrap_or(s::ResampleWhen::SizeDiffers);
-            let size_differs = new_w != input_w || new_h != input_h;
+            let size_differs = new_w != crop_w || new_h != crop_h;
             let sharpen_requested = hints.and_then(|h| h.sharpen_percent).unwrap_or(0f32) > 0f32;
 
             let resample = match resample_when {
@@ -139,8 +148,16 @@ impl NodeDefOneInputExpand for ConstrainDef{
                 _ => false
             };
 
+            let mut nodes = Vec::new();
+            if let Some(c) = layout.crop {
+                if layout.smart_crop {
+                    nodes.push(Node::from(s::Node::SmartCrop { w: crop_w, h: crop_h }));
+                } else {
+                    nodes.push(Node::from(s::Node::Crop { x1: c[0], y1: c[1], x2: c[2], y2: c[3] }));
+                }
+            }
             if resample {
-                let scale2d_params = s::Node::Resample2D {
+                nodes.push(Node::n(&super::SCALE, NodeParams::Json(s::Node::Resample2D {
                     w: new_w,
                     h: new_h,
                     up_filter: hints.and_then(|h| h.up_filter),
@@ -149,15 +166,20 @@ impl NodeDefOneInputExpand for ConstrainDef{
                     hints: hints.map(|h| s::ResampleHints {
                         sharpen_percent: h.sharpen_percent,
                     }),
-                };
-
-                let scale2d = ctx.graph
-                    .add_node(Node::n(&super::SCALE,
-                                        NodeParams::Json(scale2d_params)));
-                ctx.replace_node_with_existing(ix, scale2d);
-            } else {
-                ctx.delete_node_and_snap_together(ix);
+                })));
+            }
+            let (canvas_w, canvas_h) = layout.canvas;
+            let (left, top) = layout.image_at;
+            if canvas_w != new_w || canvas_h != new_h {
+                nodes.push(Node::from(s::Node::ExpandCanvas {
+                    left: left,
+                    top: top,
+                    right: canvas_w - new_w - left,
+                    bottom: canvas_h - new_h - top,
+                    color: layout.background.unwrap_or_else(|| s::Color::Srgb(s::ColorSrgb::Hex("FFFFFF00".to_owned()))),
+                }));
             }
+            ctx.replace_node(ix, nodes);
             Ok(())
         } else {
             Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Constrain, got {:?}", params))
@@ -165,6 +187,124 @@ impl NodeDefOneInputExpand for ConstrainDef{
     }
 }
 
+/// The result of evaluating a constraint against an input size: an optional source crop,
+/// the size to scale the cropped image to, and the canvas it is placed on.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ConstraintLayout {
+    pub(crate) crop: Option<[u32; 4]>,
+    /// Position the crop by content at execution time instead of using the crop's coordinates
+    pub(crate) smart_crop: bool,
+    pub(crate) image: (u32, u32),
+    pub(crate) canvas: (u32, u32),
+    pub(crate) image_at: (u32, u32),
+    pub(crate) background: Option<s::Color>,
+    pub(crate) hints: Option<s::ConstraintResamplingHints>,
+}
+
+fn gravity_anchors(gravity: Option<s::ConstraintGravity>) -> (Anchor1D, Anchor1D) {
+    match gravity.unwrap_or(s::ConstraintGravity::Center) {
+        s::ConstraintGravity::TopLeft => (Anchor1D::Near, Anchor1D::Near),
+        s::ConstraintGravity::Top => (Anchor1D::Center, Anchor1D::Near),
+        s::ConstraintGravity::TopRight => (Anchor1D::Far, Anchor1D::Near),
+        s::ConstraintGravity::Left => (Anchor1D::Near, Anchor1D::Center),
+        s::ConstraintGravity::Center => (Anchor1D::Center, Anchor1D::Center),
+        s::ConstraintGravity::Right => (Anchor1D::Far, Anchor1D::Center),
+        s::ConstraintGravity::BottomLeft => (Anchor1D::Near, Anchor1D::Far),
+        s::ConstraintGravity::Bottom => (Anchor1D::Center, Anchor1D::Far),
+        s::ConstraintGravity::BottomRight => (Anchor1D::Far, Anchor1D::Far),
+        s::ConstraintGravity::FocalPoint { .. } |
+        s::ConstraintGravity::Auto => (Anchor1D::Center, Anchor1D::Center),
+    }
+}
+
+fn scale_mode(scale: Option<s::ConstraintScaling>) -> ScaleMode {
+    match scale.unwrap_or(s::ConstraintScaling::DownscaleOnly) {
+        s::ConstraintScaling::DownscaleOnly => ScaleMode::DownscaleOnly,
+        s::ConstraintScaling::UpscaleOnly => ScaleMode::UpscaleOnly,
+        s::ConstraintScaling::Both => ScaleMode::Both,
+        s::ConstraintScaling::UpscaleCanvas => ScaleMode::UpscaleCanvas,
+    }
+}
+
+/// Runs the IR4 sizing steps for `constraint`, so that JSON constraints behave exactly like `mode=` and `scale=`
+pub(crate) fn layout_constraint(old_w: u32, old_h: u32, constraint: &s::Constraint) -> Result<ConstraintLayout> {
+    let source = AspectRatio::create(old_w as i32, old_h as i32).map_err(|e| FlowError::from_layout(e).at(here!()))?;
+
+    let (target, layout_steps, gravity, background, hints) = match *constraint {
+        s::Constraint::Within { .. } => {
+            let (w, h, hints) = constrain(old_w, old_h, constraint);
+            return Ok(ConstraintLayout {
+                crop: None,
+                smart_crop: false,
+                image: (w, h),
+                canvas: (w, h),
+                image_at: (0, 0),
+                background: None,
+                hints: hints,
+            });
+        }
+        s::Constraint::AspectCrop { w, h, gravity } => {
+            let target = AspectRatio::create(w as i32, h as i32).map_err(|e| FlowError::from_layout(e).at(here!()))?;
+            (target, steps().crop_aspect().into_vec(), gravity, None, None)
+        }
+        s::Constraint::Fit(ref b) |
+        s::Constraint::FitPad(ref b) |
+        s::Constraint::FitCrop(ref b) |
+        s::Constraint::Distort(ref b) => {
+            let mode = match *constraint {
+                s::Constraint::FitPad(_) => FitMode::Pad,
+                s::Constraint::FitCrop(_) => FitMode::Crop,
+                s::Constraint::Distort(_) => FitMode::Stretch,
+                _ => FitMode::Max
+            };
+            let target = match (b.w, b.h) {
+                (Some(w), Some(h)) => AspectRatio::create(w as i32, h as i32),
+                (Some(w), None) => source.height_for(w as i32, None).and_then(|h| AspectRatio::create(w as i32, h)),
+                (None, Some(h)) => source.width_for(h as i32, None).and_then(|w| AspectRatio::create(w, h as i32)),
+                (None, None) => Ok(source)
+            }.map_err(|e| FlowError::from_layout(e).at(here!()))?;
+            // Like IR4, a missing box means mode=max
+            let mode = if b.w.is_none() && b.h.is_none() { FitMode::Max } else { mode };
+            (target, Ir4Layout::steps_for(mode, scale_mode(b.scale)), b.gravity, b.background.clone(), b.hints)
+        }
+    };
+
+    let layout = Layout::create(source, target).execute_all(&layout_steps, &IdentityCropProvider::new())
+        .map_err(|e| FlowError::from_layout(e).at(here!()))?;
+
+    let anchors = gravity_anchors(gravity);
+    let source_crop = layout.get_source_crop();
+    let image = layout.get_box(BoxTarget::CurrentImage);
+    let canvas = layout.get_box(BoxTarget::CurrentCanvas);
+
+    let crop_origin = match gravity {
+        Some(s::ConstraintGravity::FocalPoint { x, y }) => FocalPointCropProvider::new(x as f64, y as f64).crop_origin(source_crop, source),
+        _ => Ir4Layout::align(anchors, source_crop, source).ok()
+    };
+    let (crop_x1, crop_y1) = crop_origin
+        .ok_or_else(|| nerror!(::ErrorKind::LayoutError, "Source crop {:?} does not fit within {:?}", source_crop, source))?;
+    let (left, top) = Ir4Layout::align(anchors, image, canvas)
+        .map_err(|_| nerror!(::ErrorKind::LayoutError, "Image {:?} does not fit within canvas {:?}", image, canvas))?;
+    if left < 0 || top < 0 || canvas.width() < image.width() + left || canvas.height() < image.height() + top {
+        return Err(nerror!(::ErrorKind::LayoutError, "Image {:?} does not fit within canvas {:?}", image, canvas));
+    }
+
+    let crop = if source_crop == source {
+        None
+    } else {
+        Some([crop_x1 as u32, crop_y1 as u32, (crop_x1 + source_crop.width()) as u32, (crop_y1 + source_crop.height()) as u32])
+    };
+
+    Ok(ConstraintLayout {
+        crop: crop,
+        smart_crop: gravity == Some(s::ConstraintGravity::Auto),
+        image: (image.width() as u32, image.height() as u32),
+        canvas: (canvas.width() as u32, canvas.height() as u32),
+        image_at: (left as u32, top as u32),
+        background: background,
+        hints: hints,
+    })
+}
 
 
 fn scale_b_to(aspect_ratio_a_over_b: f32, a_from: u32, a_to: u32, b_from: u32) -> u32{
@@ -194,6 +334,8 @@ fn constrain(old_w: u32, old_h: u32, constraint: &s::Constraint) -> (u32,u32, Op
             }
         }
         s::Constraint::Within{ ref hints, ..} => (old_w, old_h, *hints),
+        // Other constraints are evaluated by layout_constraint
+        _ => (old_w, old_h, None),
     }
 }
 
@@ -223,6 +365,42 @@ fn test_constrain(){
 
 }
 
+#[test]
+fn test_layout_constraint(){
+    fn b(w: u32, h: u32) -> s::ConstraintBox {
+        s::ConstraintBox { w: Some(w), h: Some(h), scale: None, gravity: None, background: None, hints: None }
+    }
+    {
+        // Matches ir4::layout::test_crop_and_scale
+        let l = layout_constraint(768, 433, &s::Constraint::FitCrop(b(100, 200))).unwrap();
+        assert_eq!((l.crop, l.image, l.canvas), (Some([275, 0, 492, 433]), (100, 200), (100, 200)));
+    }
+    {
+        let l = layout_constraint(200, 100, &s::Constraint::FitPad(b(100, 100))).unwrap();
+        assert_eq!((l.crop, l.image, l.canvas, l.image_at), (None, (100, 50), (100, 100), (0, 25)));
+    }
+    {
+        let l = layout_constraint(200, 100, &s::Constraint::Distort(b(100, 100))).unwrap();
+        assert_eq!((l.crop, l.image, l.canvas), (None, (100, 100), (100, 100)));
+    }
+    {
+        let l = layout_constraint(50, 40, &s::Constraint::Fit(b(100, 100))).unwrap();
+        assert_eq!((l.crop, l.image, l.canvas), (None, (50, 40), (50, 40)));
+    }
+    {
+        let l = layout_constraint(200, 100, &s::Constraint::AspectCrop { w: 1, h: 1, gravity: Some(s::ConstraintGravity::Left) }).unwrap();
+        assert_eq!((l.crop, l.image, l.canvas), (Some([0, 0, 100, 100]), (100, 100), (100, 100)));
+    }
+    {
+        let l = layout_constraint(200, 100, &s::Constraint::AspectCrop { w: 1, h: 1, gravity: Some(s::ConstraintGravity::FocalPoint { x: 75f32, y: 0f32 }) }).unwrap();
+        assert_eq!((l.crop, l.smart_crop), (Some([100, 0, 200, 100]), false));
+    }
+    {
+        let l = layout_constraint(200, 100, &s::Constraint::AspectCrop { w: 1, h: 1, gravity: Some(s::ConstraintGravity::Auto) }).unwrap();
+        assert_eq!((l.crop, l.smart_crop), (Some([50, 0, 150, 100]), true));
+    }
+}
+
 #[derive(Debug,Clone)]
 pub struct CommandStringDef;
 impl NodeDef for CommandStringDef{
@@ -0,0 +1,248 @@
+use super::internal_prelude::*;
+
+pub static DITHER: MutProtect<DitherMutDef> = MutProtect{node: &DITHER_MUTATE, fqn: "imazen.dither"};
+pub static DITHER_MUTATE: DitherMutDef = DitherMutDef{};
+
+/// Floyd-Steinberg: (dx, dy, weight) of the neighbors that receive a share of each pixel's error
+static FLOYD_STEINBERG: [(i32, usize, f32); 4] = [(1, 0, 7f32 / 16f32), (-1, 1, 3f32 / 16f32), (0, 1, 5f32 / 16f32), (1, 1, 1f32 / 16f32)];
+/// Atkinson passes on 6/8 of the error
+static ATKINSON: [(i32, usize, f32); 6] = [(1, 0, 0.125f32), (2, 0, 0.125f32), (-1, 1, 0.125f32), (0, 1, 0.125f32), (1, 1, 0.125f32), (0, 2, 0.125f32)];
+
+/// Palette colors as b, g, r (0..255), and the typical step between neighboring colors on one channel
+struct Palette {
+    colors: Vec<[f32; 3]>,
+    spread: f32,
+}
+
+impl Palette {
+    fn new(palette: &s::DitherPalette) -> Result<Palette> {
+        match *palette {
+            s::DitherPalette::WebSafe => {
+                let mut colors = Vec::with_capacity(216);
+                for b in 0..6 {
+                    for g in 0..6 {
+                        for r in 0..6 {
+                            colors.push([(b * 51) as f32, (g * 51) as f32, (r * 51) as f32]);
+                        }
+                    }
+                }
+                Ok(Palette { colors: colors, spread: 51f32 })
+            }
+            s::DitherPalette::Grayscale { levels } => {
+                if levels < 2 || levels > 256 {
+                    return Err(nerror!(::ErrorKind::InvalidNodeParams, "Grayscale dithering needs 2 to 256 levels, got {}", levels));
+                }
+                let step = 255f32 / (levels - 1) as f32;
+                let colors = (0..levels).map(|i| {
+                    let v = (i as f32 * step).round();
+                    [v, v, v]
+                }).collect();
+                Ok(Palette { colors: colors, spread: step })
+            }
+            s::DitherPalette::Custom { ref colors } => {
+                if colors.is_empty() || colors.len() > 256 {
+                    return Err(nerror!(::ErrorKind::InvalidNodeParams, "A custom dither palette needs 1 to 256 colors, got {}", colors.len()));
+                }
+                let mut list = Vec::with_capacity(colors.len());
+                for color in colors.iter() {
+                    let bgra = color.to_color_32().map_err(|e| nerror!(::ErrorKind::InvalidNodeParams, "Invalid palette color: {:?}", e))?.to_bgra_le();
+                    list.push([bgra as u8 as f32, (bgra >> 8) as u8 as f32, (bgra >> 16) as u8 as f32]);
+                }
+                // As if the colors were a cube spread evenly over each channel, like web_safe
+                let per_channel = (list.len() as f32).cbrt() - 1f32;
+                let spread = 255f32 / per_channel.max(1f32);
+                Ok(Palette { colors: list, spread: spread })
+            }
+        }
+    }
+
+    /// The index of the color closest to `bgr`
+    fn nearest(&self, bgr: &[f32; 3]) -> usize {
+        let mut best = 0;
+        let mut best_distance = ::std::f32::MAX;
+        for (ix, color) in self.colors.iter().enumerate() {
+            let distance = (color[0] - bgr[0]) * (color[0] - bgr[0]) +
+                (color[1] - bgr[1]) * (color[1] - bgr[1]) +
+                (color[2] - bgr[2]) * (color[2] - bgr[2]);
+            if distance < best_distance {
+                best = ix;
+                best_distance = distance;
+            }
+        }
+        best
+    }
+}
+
+/// A size x size Bayer matrix of thresholds in 0..size*size, built by recursive subdivision
+fn bayer_matrix(size: u32) -> Vec<u32> {
+    let mut matrix = vec![0u32];
+    let mut n = 1;
+    while n < size {
+        let mut next = vec![0u32; (n * n * 4) as usize];
+        for y in 0..n * 2 {
+            for x in 0..n * 2 {
+                let offset = match (y / n, x / n) {
+                    (0, 0) => 0,
+                    (0, _) => 2,
+                    (_, 0) => 3,
+                    _ => 1
+                };
+                next[(y * n * 2 + x) as usize] = 4 * matrix[((y % n) * n + x % n) as usize] + offset;
+            }
+        }
+        matrix = next;
+        n *= 2;
+    }
+    matrix
+}
+
+/// Reads pixels as b, g, r floats and writes palette colors back, for the formats dithering supports
+fn read_pixel(pixel: &[u8]) -> [f32; 3] {
+    if pixel.len() == 1 {
+        [pixel[0] as f32, pixel[0] as f32, pixel[0] as f32]
+    } else {
+        [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]
+    }
+}
+
+fn write_pixel(pixel: &mut [u8], bgr: &[f32; 3]) {
+    if pixel.len() == 1 {
+        pixel[0] = (0.114f32 * bgr[0] + 0.587f32 * bgr[1] + 0.299f32 * bgr[2]).round() as u8;
+    } else {
+        pixel[0] = bgr[0] as u8;
+        pixel[1] = bgr[1] as u8;
+        pixel[2] = bgr[2] as u8;
+    }
+}
+
+/// Maps each row of `rows` (packed pixels of `bytes` each) to `palette`, diffusing error through `kernel`.
+/// With `alpha`, fully transparent pixels are left alone and neither take nor pass on error.
+fn diffuse(rows: &mut [&mut [u8]], bytes: usize, alpha: bool, palette: &Palette, kernel: &[(i32, usize, f32)]) {
+    let w = rows.first().map(|row| row.len() / bytes).unwrap_or(0);
+    let depth = kernel.iter().map(|&(_, dy, _)| dy).max().unwrap_or(0) + 1;
+    // Ring of error rows, padded by two pixels each side so the kernel never leaves it
+    let mut errors = vec![vec![[0f32; 3]; w + 4]; depth];
+    for (y, row) in rows.iter_mut().enumerate() {
+        for x in 0..w {
+            let pixel = &mut row[x * bytes..(x + 1) * bytes];
+            if alpha && pixel[3] == 0 {
+                continue;
+            }
+            let source = read_pixel(pixel);
+            let error = errors[y % depth][x + 2];
+            let wanted = [source[0] + error[0], source[1] + error[1], source[2] + error[2]];
+            let chosen = palette.colors[palette.nearest(&wanted)];
+            write_pixel(pixel, &chosen);
+            for &(dx, dy, weight) in kernel.iter() {
+                let target = &mut errors[(y + dy) % depth][(x as i32 + 2 + dx) as usize];
+                for ch in 0..3 {
+                    target[ch] += (wanted[ch] - chosen[ch]) * weight;
+                }
+            }
+        }
+        for e in errors[y % depth].iter_mut() {
+            *e = [0f32; 3];
+        }
+    }
+}
+
+/// Maps each row of `rows` to `palette` after nudging pixels by a tiled Bayer threshold matrix
+fn ordered(rows: &mut [&mut [u8]], bytes: usize, alpha: bool, palette: &Palette, size: u32) {
+    let matrix = bayer_matrix(size);
+    let cells = (size * size) as f32;
+    for (y, row) in rows.iter_mut().enumerate() {
+        for (x, pixel) in row.chunks_mut(bytes).enumerate() {
+            if alpha && pixel[3] == 0 {
+                continue;
+            }
+            let threshold = matrix[((y as u32 % size) * size + x as u32 % size) as usize] as f32;
+            let offset = ((threshold + 0.5f32) / cells - 0.5f32) * palette.spread;
+            let source = read_pixel(pixel);
+            let chosen = palette.colors[palette.nearest(&[source[0] + offset, source[1] + offset, source[2] + offset])];
+            write_pixel(pixel, &chosen);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DitherMutDef;
+impl NodeDef for DitherMutDef {
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap> {
+        Some(self)
+    }
+}
+impl NodeDefMutateBitmap for DitherMutDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.dither_mutate"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::Dither { ref palette, method }) = p {
+            if let s::DitherMethod::Bayer { size } = method {
+                if size != 2 && size != 4 && size != 8 {
+                    return Err(nerror!(::ErrorKind::InvalidNodeParams, "Bayer matrix size must be 2, 4 or 8, got {}", size));
+                }
+            }
+            Palette::new(palette).map(|_| ())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Dither, got {:?}", p))
+        }
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra, p: &NodeParams) -> Result<()> {
+        self.validate_params(p).map_err(|e| e.at(here!()))?;
+        if let &NodeParams::Json(s::Node::Dither { ref palette, method }) = p {
+            let palette = Palette::new(palette).map_err(|e| e.at(here!()))?;
+            let bytes = match bitmap.fmt {
+                PixelFormat::Bgra32 => 4,
+                PixelFormat::Bgr32 => 4,
+                PixelFormat::Gray8 => 1,
+                other => return Err(nerror!(::ErrorKind::InvalidNodeConnections, "Dither does not support {:?} bitmaps", other))
+            };
+            let alpha = bitmap.fmt == PixelFormat::Bgra32;
+            let mut rows = (0..bitmap.h).map(|y| unsafe {
+                slice::from_raw_parts_mut(bitmap.pixels.offset((y * bitmap.stride) as isize), bitmap.w as usize * bytes)
+            }).collect::<Vec<&mut [u8]>>();
+            match method {
+                s::DitherMethod::FloydSteinberg => diffuse(&mut rows, bytes, alpha, &palette, &FLOYD_STEINBERG),
+                s::DitherMethod::Atkinson => diffuse(&mut rows, bytes, alpha, &palette, &ATKINSON),
+                s::DitherMethod::Bayer { size } => ordered(&mut rows, bytes, alpha, &palette, size),
+            }
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Dither, got {:?}", p))
+        }
+    }
+}
+
+#[test]
+fn test_bayer_matrix() {
+    assert_eq!(bayer_matrix(2), vec![0, 2, 3, 1]);
+    let m = bayer_matrix(4);
+    assert_eq!(&m[0..4], &[0, 8, 2, 10]);
+    let mut sorted = bayer_matrix(8);
+    sorted.sort();
+    assert_eq!(sorted, (0..64).collect::<Vec<u32>>());
+}
+
+#[test]
+fn test_dither_gray_ramp() {
+    let palette = Palette::new(&s::DitherPalette::Grayscale { levels: 2 }).unwrap();
+    assert_eq!(palette.colors, vec![[0f32; 3], [255f32; 3]]);
+    assert_eq!(Palette::new(&s::DitherPalette::WebSafe).unwrap().nearest(&[50f32, 110f32, 250f32]), 36 + 2 * 6 + 5);
+
+    // A flat 50% gray over two levels should come out about half white, whatever the method
+    let methods: [&Fn(&mut [&mut [u8]]); 3] = [
+        &|rows| diffuse(rows, 1, false, &palette, &FLOYD_STEINBERG),
+        &|rows| diffuse(rows, 1, false, &palette, &ATKINSON),
+        &|rows| ordered(rows, 1, false, &palette, 4),
+    ];
+    for method in methods.iter() {
+        let mut pixels = vec![128u8; 16 * 16];
+        {
+            let mut rows = pixels.chunks_mut(16).collect::<Vec<&mut [u8]>>();
+            method(&mut rows[..]);
+        }
+        assert!(pixels.iter().all(|&v| v == 0 || v == 255));
+        let white = pixels.iter().filter(|&&v| v == 255).count();
+        assert!(white > 100 && white < 156, "{} of 256 pixels were white", white);
+    }
+}
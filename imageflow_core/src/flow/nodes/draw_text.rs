@@ -0,0 +1,283 @@
+use super::internal_prelude::*;
+use ::rusttype::{Font, FontCollection, Scale, point};
+
+pub static DRAW_TEXT: MutProtect<DrawTextMutDef> = MutProtect{node: &DRAW_TEXT_MUTATE, fqn: "imazen.draw_text"};
+pub static DRAW_TEXT_MUTATE: DrawTextMutDef = DrawTextMutDef{};
+
+/// DejaVu Sans; see fonts/LICENSE.txt
+static BUNDLED_FONT: &'static [u8] = include_bytes!("../../../fonts/DejaVuSans.ttf");
+
+/// The largest em size, in pixels, text may be drawn at
+const MAX_SIZE: f32 = 2048f32;
+
+/// Greedily breaks each paragraph of `text` between words so lines measure at most `max_width`.
+/// A single word wider than `max_width` gets a line to itself.
+fn wrap_lines<F>(text: &str, max_width: f32, measure: F) -> Vec<String> where F: Fn(&str) -> f32 {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let candidate = if line.is_empty() { word.to_owned() } else { format!("{} {}", line, word) };
+            if !line.is_empty() && measure(&candidate) > max_width {
+                lines.push(mem::replace(&mut line, word.to_owned()));
+            } else {
+                line = candidate;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn line_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    let mut width = 0f32;
+    let mut last = None;
+    for glyph in text.chars().filter_map(|c| font.glyph(c)) {
+        let glyph = glyph.scaled(scale);
+        if let Some(last) = last {
+            width += font.pair_kerning(scale, last, glyph.id());
+        }
+        width += glyph.h_metrics().advance_width;
+        last = Some(glyph.id());
+    }
+    width
+}
+
+/// Where the block of text goes, as fractions of the free space (or, for a focal point, of the image)
+/// and whether it's centered on that point
+fn anchor_fractions(anchor: s::ConstraintGravity) -> Result<(f32, f32, bool)> {
+    Ok(match anchor {
+        s::ConstraintGravity::TopLeft => (0f32, 0f32, false),
+        s::ConstraintGravity::Top => (0.5f32, 0f32, false),
+        s::ConstraintGravity::TopRight => (1f32, 0f32, false),
+        s::ConstraintGravity::Left => (0f32, 0.5f32, false),
+        s::ConstraintGravity::Center => (0.5f32, 0.5f32, false),
+        s::ConstraintGravity::Right => (1f32, 0.5f32, false),
+        s::ConstraintGravity::BottomLeft => (0f32, 1f32, false),
+        s::ConstraintGravity::Bottom => (0.5f32, 1f32, false),
+        s::ConstraintGravity::BottomRight => (1f32, 1f32, false),
+        s::ConstraintGravity::FocalPoint { x, y } => (x / 100f32, y / 100f32, true),
+        s::ConstraintGravity::Auto => return Err(nerror!(::ErrorKind::InvalidNodeParams, "DrawText does not support the auto anchor")),
+    })
+}
+
+/// The width and height in pixels of the block of `lines`
+fn block_size(font: &Font, scale: Scale, lines: &[String]) -> (i64, i64) {
+    let v = font.v_metrics(scale);
+    let line_height = v.ascent - v.descent + v.line_gap;
+    let w = lines.iter().map(|line| line_width(font, scale, line)).fold(0f32, |a, b| a.max(b));
+    let h = line_height * (lines.len() - 1) as f32 + v.ascent - v.descent;
+    (w.ceil() as i64, h.ceil() as i64)
+}
+
+/// Rasterizes the `clip` rectangle (x1, y1, x2, y2) of the `block_w` wide block of `lines` into a coverage mask (0..1),
+/// aligning each line by `align` (0 left .. 1 right). Only the clipped part is allocated, so it can't outgrow the image.
+fn rasterize(font: &Font, scale: Scale, lines: &[String], align: f32, block_w: i64, clip: [i64; 4]) -> Vec<f32> {
+    let v = font.v_metrics(scale);
+    let line_height = v.ascent - v.descent + v.line_gap;
+    let (clip_w, clip_h) = ((clip[2] - clip[0]) as usize, (clip[3] - clip[1]) as usize);
+    let mut mask = vec![0f32; clip_w * clip_h];
+
+    for (i, line) in lines.iter().enumerate() {
+        let baseline = v.ascent + line_height * i as f32;
+        let mut caret = (block_w as f32 - line_width(font, scale, line)) * align;
+        let mut last = None;
+        for glyph in line.chars().filter_map(|c| font.glyph(c)) {
+            let glyph = glyph.scaled(scale);
+            if let Some(last) = last {
+                caret += font.pair_kerning(scale, last, glyph.id());
+            }
+            last = Some(glyph.id());
+            if caret - scale.x > clip[2] as f32 {
+                // The rest of the line starts right of the clip
+                break;
+            }
+            let advance = glyph.h_metrics().advance_width;
+            let glyph = glyph.positioned(point(caret, baseline));
+            caret += advance;
+            if let Some(bb) = glyph.pixel_bounding_box() {
+                if (bb.max.x as i64) <= clip[0] || (bb.min.x as i64) >= clip[2] ||
+                    (bb.max.y as i64) <= clip[1] || (bb.min.y as i64) >= clip[3] {
+                    continue;
+                }
+                glyph.draw(|gx, gy, coverage| {
+                    let (x, y) = (bb.min.x as i64 + gx as i64, bb.min.y as i64 + gy as i64);
+                    if x >= clip[0] && y >= clip[1] && x < clip[2] && y < clip[3] {
+                        let ix = (y - clip[1]) as usize * clip_w + (x - clip[0]) as usize;
+                        // Glyphs may overlap slightly (kerning, accents)
+                        mask[ix] = (mask[ix] + coverage).min(1f32);
+                    }
+                });
+            }
+        }
+    }
+    mask
+}
+
+#[derive(Debug, Clone)]
+pub struct DrawTextMutDef;
+impl NodeDef for DrawTextMutDef{
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap>{
+        Some(self)
+    }
+    fn can_execute_in_parallel(&self, p: &NodeParams) -> bool {
+        // Fonts from an io_id are read through the job's codecs
+        match *p {
+            NodeParams::Json(s::Node::DrawText { font: Some(s::TextFont::Io { .. }), .. }) => false,
+            _ => true
+        }
+    }
+}
+impl NodeDefMutateBitmap for DrawTextMutDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.draw_text_mutate"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::DrawText { size, anchor, max_width, .. }) = p {
+            if !(size > 0f32) || !(size <= MAX_SIZE) {
+                return Err(nerror!(::ErrorKind::InvalidNodeParams, "Text size must be a positive number no larger than {}, got {}", MAX_SIZE, size));
+            }
+            if max_width == Some(0) {
+                return Err(nerror!(::ErrorKind::InvalidNodeParams, "max_width must be at least 1"));
+            }
+            anchor_fractions(anchor.unwrap_or(s::ConstraintGravity::TopLeft)).map(|_| ())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need DrawText, got {:?}", p))
+        }
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra, p: &NodeParams) -> Result<()> {
+        self.validate_params(p).map_err(|e| e.at(here!()))?;
+        if let &NodeParams::Json(s::Node::DrawText { ref text, font, size, ref color, anchor, max_width, wrap }) = p {
+            if bitmap.fmt.bytes() != 4 {
+                return Err(nerror!(::ErrorKind::InvalidNodeConnections, "DrawText can only draw on Bgr32 and Bgra32 bitmaps, not {:?}", bitmap.fmt));
+            }
+            let collection = match font.unwrap_or(s::TextFont::Bundled) {
+                s::TextFont::Bundled => FontCollection::from_bytes(BUNDLED_FONT),
+                s::TextFont::Io { io_id } => {
+                    let data = c.get_codec(io_id).map_err(|e| e.at(here!()))?.read_data(c).map_err(|e| e.at(here!()))?;
+                    FontCollection::from_bytes(data)
+                }
+            };
+            let font = collection.into_font().ok_or_else(|| nerror!(::ErrorKind::InvalidNodeParams, "The font could not be read"))?;
+            let (ax, ay, centered) = anchor_fractions(anchor.unwrap_or(s::ConstraintGravity::TopLeft))?;
+            let max_width = max_width.unwrap_or(bitmap.w) as f32;
+
+            let mut scale = Scale::uniform(size);
+            let lines = if wrap.unwrap_or(true) {
+                wrap_lines(text, max_width, |line| line_width(&font, scale, line))
+            } else {
+                let lines = text.split('\n').map(|line| line.to_owned()).collect::<Vec<String>>();
+                let widest = lines.iter().map(|line| line_width(&font, scale, line)).fold(0f32, |a, b| a.max(b));
+                if widest > max_width {
+                    scale = Scale::uniform(size * max_width / widest);
+                }
+                lines
+            };
+            let (w, h) = block_size(&font, scale, &lines);
+            if w <= 0 || h <= 0 {
+                return Ok(());
+            }
+            // Glyphs are positioned in i32 pixels
+            if w > i32::max_value() as i64 || h > i32::max_value() as i64 {
+                return Err(nerror!(::ErrorKind::InvalidNodeParams, "The text is too long to draw ({}x{} pixels)", w, h));
+            }
+
+            let (left, top) = if centered {
+                ((bitmap.w as f64 * ax as f64 - w as f64 / 2f64).round() as i64, (bitmap.h as f64 * ay as f64 - h as f64 / 2f64).round() as i64)
+            } else {
+                (((bitmap.w as f64 - w as f64) * ax as f64).round() as i64, ((bitmap.h as f64 - h as f64) * ay as f64).round() as i64)
+            };
+            // Clip to the bitmap
+            let (x1, y1) = (cmp::max(0, left), cmp::max(0, top));
+            let (x2, y2) = (cmp::min(bitmap.w as i64, left + w), cmp::min(bitmap.h as i64, top + h));
+            if x2 <= x1 || y2 <= y1 {
+                return Ok(());
+            }
+            let mask = rasterize(&font, scale, &lines, if centered { 0.5f32 } else { ax }, w, [x1 - left, y1 - top, x2 - left, y2 - top]);
+
+            let bgra = color.to_color_32().map_err(|e| nerror!(::ErrorKind::InvalidNodeParams, "Invalid text color: {:?}", e))?.to_bgra_le();
+            let alpha = (bgra >> 24) as f32;
+            let overlay = unsafe { ::ffi::flow_bitmap_bgra_create(c.flow_c(), (x2 - x1) as i32, (y2 - y1) as i32, false, PixelFormat::Bgra32) };
+            if overlay.is_null() {
+                return Err(cerror!(c, "Failed to allocate text overlay"));
+            }
+            unsafe {
+                let o = &mut *overlay;
+                for y in 0..o.h {
+                    let row = slice::from_raw_parts_mut(o.pixels.offset((y * o.stride) as isize), (o.w * 4) as usize);
+                    for x in 0..o.w as usize {
+                        let coverage = mask[y as usize * o.w as usize + x];
+                        row[x * 4] = bgra as u8;
+                        row[x * 4 + 1] = (bgra >> 8) as u8;
+                        row[x * 4 + 2] = (bgra >> 16) as u8;
+                        row[x * 4 + 3] = (alpha * coverage).round() as u8;
+                    }
+                }
+            }
+
+            // Blend it like DrawImageExact; the triangle filter is an exact copy at 1:1
+            bitmap.compositing_mode = ::ffi::BitmapCompositingMode::BlendWithSelf;
+            let render = ffi::Scale2dRenderToCanvas1d {
+                interpolation_filter: ffi::Filter::from(s::Filter::Triangle),
+                x: x1 as u32,
+                y: y1 as u32,
+                w: (x2 - x1) as u32,
+                h: (y2 - y1) as u32,
+                sharpen_percent_goal: 0f32,
+                scale_in_colorspace: ::ffi::Floatspace::Linear,
+            };
+            unsafe {
+                let rendered = ::ffi::flow_node_execute_scale2d_render1d(c.flow_c(), overlay, bitmap as *mut BitmapBgra, &render as *const ffi::Scale2dRenderToCanvas1d);
+                ::ffi::flow_destroy(c.flow_c(), overlay as *const c_void, ptr::null(), 0);
+                if !rendered {
+                    return Err(cerror!(c, "Failed to composite text"));
+                }
+            }
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need DrawText, got {:?}", p))
+        }
+    }
+}
+
+#[test]
+fn test_wrap_lines() {
+    let measure = |line: &str| line.chars().count() as f32;
+    assert_eq!(wrap_lines("the quick brown fox", 10f32, &measure), vec!["the quick", "brown fox"]);
+    assert_eq!(wrap_lines("a\nextraordinarily long", 5f32, &measure), vec!["a", "extraordinarily", "long"]);
+    assert_eq!(wrap_lines("", 5f32, &measure), vec![""]);
+}
+
+#[test]
+fn test_rasterize_bundled_font() {
+    let font = FontCollection::from_bytes(BUNDLED_FONT).into_font().unwrap();
+    let scale = Scale::uniform(32f32);
+    let lines = ["Hi".to_owned(), "there".to_owned()];
+    let (w, h) = block_size(&font, scale, &lines);
+    assert!(line_width(&font, scale, "there") > line_width(&font, scale, "Hi"));
+    assert_eq!(w, line_width(&font, scale, "there").ceil() as i64);
+    assert!(h > 64 && h < 96);
+    let mask = rasterize(&font, scale, &lines, 0.5f32, w, [0, 0, w, h]);
+    assert_eq!(mask.len(), (w * h) as usize);
+    assert!(mask.iter().any(|&v| v > 0.99f32) && mask.iter().all(|&v| v >= 0f32 && v <= 1f32));
+
+    // Clipping to the second line gives the same coverage there
+    let second = rasterize(&font, scale, &lines, 0.5f32, w, [0, h / 2, w, h]);
+    assert_eq!(&second[..], &mask[(h / 2 * w) as usize..]);
+}
+
+#[test]
+fn test_huge_text_is_clipped_to_the_image() {
+    let font = FontCollection::from_bytes(BUNDLED_FONT).into_font().unwrap();
+    let scale = Scale::uniform(MAX_SIZE);
+    let lines = vec!["W".repeat(100000)];
+    let (w, h) = block_size(&font, scale, &lines);
+    assert!(w * h > 1i64 << 32);
+    assert_eq!(rasterize(&font, scale, &lines, 0f32, w, [0, 0, 64, 32]).len(), 64 * 32);
+
+    let params = |size: f32| NodeParams::Json(s::Node::DrawText {
+        text: "W".to_owned(), font: None, size: size, color: s::Color::Black, anchor: None, max_width: None, wrap: None });
+    assert!(NodeDef::validate_params(&DRAW_TEXT_MUTATE, &params(MAX_SIZE)).is_ok());
+    assert!(NodeDef::validate_params(&DRAW_TEXT_MUTATE, &params(MAX_SIZE * 2f32)).is_err());
+    assert!(NodeDef::validate_params(&DRAW_TEXT_MUTATE, &params(::std::f32::INFINITY)).is_err());
+}
@@ -0,0 +1,293 @@
+use super::internal_prelude::*;
+use super::constrain::layout_constraint;
+
+pub static GRID: GridDef = GridDef{};
+pub static GRID_LAYOUT: GridLayoutDef = GridLayoutDef{};
+pub static GRID_INPUT: MutProtect<GridInputMutDef> = MutProtect{node: &GRID_INPUT_MUTATE, fqn: "imazen.grid_input"};
+pub static GRID_INPUT_MUTATE: GridInputMutDef = GridInputMutDef{};
+
+struct GridParams {
+    columns: u32,
+    cell_w: u32,
+    cell_h: u32,
+    gap: u32,
+    background: s::Color,
+    fit: s::GridFit,
+}
+
+fn get_params(p: &NodeParams) -> Result<GridParams> {
+    if let &NodeParams::Json(s::Node::Grid { columns, cell_w, cell_h, gap, ref background, fit }) = p {
+        if columns < 1 || cell_w < 1 || cell_h < 1 {
+            return Err(nerror!(::ErrorKind::InvalidNodeParams, "Grid columns, cell_w and cell_h must be at least 1; got {}, {} and {}", columns, cell_w, cell_h));
+        }
+        Ok(GridParams {
+            columns: columns,
+            cell_w: cell_w,
+            cell_h: cell_h,
+            gap: gap.unwrap_or(0),
+            background: background.clone().unwrap_or(s::Color::Transparent),
+            fit: fit.unwrap_or(s::GridFit::FitPad),
+        })
+    } else {
+        Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Grid, got {:?}", p))
+    }
+}
+
+/// The length of `cells` cells of `size` with `gap` between them
+fn span(cells: u32, size: u32, gap: u32) -> Option<u32> {
+    size.checked_add(gap).and_then(|step| step.checked_mul(cells)).and_then(|total| total.checked_sub(gap))
+}
+
+impl GridParams {
+    fn canvas_size(&self, count: u32) -> Result<(u32, u32)> {
+        let columns = cmp::min(self.columns, count);
+        let rows = count / self.columns + if count % self.columns > 0 { 1 } else { 0 };
+        match (span(columns, self.cell_w, self.gap), span(rows, self.cell_h, self.gap)) {
+            (Some(w), Some(h)) if w <= i32::max_value() as u32 && h <= i32::max_value() as u32 => Ok((w, h)),
+            _ => Err(nerror!(::ErrorKind::SizeLimitExceeded, "A grid of {} cells in {} columns of {}x{} with a gap of {} is too large", count, self.columns, self.cell_w, self.cell_h, self.gap))
+        }
+    }
+    /// The column, row and top-left corner of cell `index`
+    fn cell(&self, index: u32) -> Result<(u32, u32, u32, u32)> {
+        let (column, row) = (index % self.columns, index / self.columns);
+        let x = self.cell_w.checked_add(self.gap).and_then(|step| step.checked_mul(column));
+        let y = self.cell_h.checked_add(self.gap).and_then(|step| step.checked_mul(row));
+        match (x, y) {
+            (Some(x), Some(y)) => Ok((column, row, x, y)),
+            _ => Err(nerror!(::ErrorKind::SizeLimitExceeded, "Grid cell {} lies outside any possible canvas", index))
+        }
+    }
+    fn constraint(&self) -> s::Constraint {
+        let b = s::ConstraintBox {
+            w: Some(self.cell_w),
+            h: Some(self.cell_h),
+            scale: Some(s::ConstraintScaling::Both),
+            gravity: None,
+            background: None,
+            hints: None,
+        };
+        match self.fit {
+            s::GridFit::Fit => s::Constraint::Fit(b),
+            s::GridFit::FitPad => s::Constraint::FitPad(b),
+            s::GridFit::FitCrop => s::Constraint::FitCrop(b),
+            s::GridFit::Distort => s::Constraint::Distort(b),
+        }
+    }
+}
+
+/// The Input parents of `ix`, in placement order, with the io_id each was decoded from
+fn ordered_inputs(ctx: &OpCtxMut, ix: NodeIndex) -> Vec<(NodeIndex, Option<i32>)> {
+    let mut inputs = ctx.graph.raw_edges().iter()
+        .filter(|e| e.target() == ix && e.weight == EdgeKind::Input)
+        .map(|e| {
+            let position = match ctx.weight(e.source()).params {
+                NodeParams::Internal(NodeParamsInternal::GridInput(position)) => Some(position),
+                _ => None
+            };
+            let own = match ctx.weight(e.source()).params {
+                NodeParams::Json(s::Node::Decode { io_id, .. }) => Some(io_id),
+                _ => None
+            };
+            let io_id = own.or_else(|| ctx.get_decoder_io_ids_and_indexes(e.source()).first().map(|&(io_id, _)| io_id));
+            (position, e.source(), io_id)
+        }).collect::<Vec<(Option<u32>, NodeIndex, Option<i32>)>>();
+    // Graphs built without the parser have no positions; their inputs follow in edge order
+    inputs.sort_by_key(|&(position, _, _)| (position.is_none(), position));
+    inputs.into_iter().map(|(_, input, io_id)| (input, io_id)).collect()
+}
+
+#[derive(Debug,Clone)]
+pub struct GridDef;
+
+impl NodeDef for GridDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.grid"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneOrMoreInputs, EdgesOut::Any))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        get_params(p).map_err(|e| e.at(here!())).map(|_| ())
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        let params = get_params(&ctx.weight(ix).params).map_err(|e| e.at(here!()))?;
+        let count = ctx.graph.graph().edges_directed(ix, EdgeDirection::Incoming).filter(|e| e.weight() == &EdgeKind::Input).count() as u32;
+        let (w, h) = params.canvas_size(cmp::max(1, count)).map_err(|e| e.at(here!()))?;
+        Ok(FrameEstimate::Some(FrameInfo {
+            w: w as i32,
+            h: h as i32,
+            fmt: PixelFormat::Bgra32,
+        }))
+    }
+    fn can_expand(&self) -> bool {
+        true
+    }
+
+    fn expand(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<()> {
+        let params = get_params(&ctx.weight(ix).params).map_err(|e| e.at(here!()))?;
+        let inputs = ordered_inputs(ctx, ix);
+        let (w, h) = params.canvas_size(inputs.len() as u32).map_err(|e| e.at(here!()))?;
+        let constraint = params.constraint();
+
+        let mut last = ctx.graph.add_node(Node::from(s::Node::CreateCanvas {
+            format: s::PixelFormat::Bgra32,
+            w: w as usize,
+            h: h as usize,
+            color: params.background.clone(),
+        }));
+        let mut cells = Vec::with_capacity(inputs.len());
+        for (index, &(input, io_id)) in inputs.iter().enumerate() {
+            let info = match ctx.weight(input).frame_est {
+                FrameEstimate::Some(info) => info,
+                other => return Err(nerror!(::ErrorKind::InvalidNodeConnections, "Grid input {} has no known size ({:?})", index, other))
+            };
+            let layout = layout_constraint(info.w as u32, info.h as u32, &constraint).map_err(|e| e.at(here!()))?;
+            let (column, row, cell_x, cell_y) = params.cell(index as u32).map_err(|e| e.at(here!()))?;
+            // Fit leaves the canvas smaller than the cell; center it. Rounding can leave it a pixel larger, which starts at the cell edge.
+            let x = cell_x + params.cell_w.saturating_sub(layout.canvas.0) / 2 + layout.image_at.0;
+            let y = cell_y + params.cell_h.saturating_sub(layout.canvas.1) / 2 + layout.image_at.1;
+
+            let source = match layout.crop {
+                Some(c) => {
+                    let crop = ctx.graph.add_node(Node::from(s::Node::Crop { x1: c[0], y1: c[1], x2: c[2], y2: c[3] }));
+                    ctx.graph.add_edge(input, crop, EdgeKind::Input).unwrap();
+                    crop
+                }
+                None => input
+            };
+            let draw = ctx.graph.add_node(Node::from(s::Node::DrawImageExact {
+                x: x,
+                y: y,
+                w: layout.image.0,
+                h: layout.image.1,
+                blend: Some(s::CompositingMode::Compose),
+                hints: layout.hints,
+            }));
+            ctx.graph.add_edge(source, draw, EdgeKind::Input).unwrap();
+            ctx.graph.add_edge(last, draw, EdgeKind::Canvas).unwrap();
+            last = draw;
+
+            cells.push(s::GridCell {
+                io_id: io_id,
+                column: column,
+                row: row,
+                x: x,
+                y: y,
+                w: layout.image.0,
+                h: layout.image.1,
+            });
+        }
+        // The coordinate map is reported by a child of the finished grid
+        let report = ctx.graph.add_node(Node::n(&GRID_LAYOUT, NodeParams::Internal(NodeParamsInternal::GridLayout(s::GridLayout {
+            w: w,
+            h: h,
+            cells: cells,
+        }))));
+        ctx.graph.add_edge(last, report, EdgeKind::Input).unwrap();
+
+        // The inputs now feed their cells, so only the grid's children move to the last cell
+        ctx.delete_parent_edges_for(ix);
+        ctx.replace_node_with_existing(ix, last);
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct GridLayoutDef;
+
+impl NodeDef for GridLayoutDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.grid_layout"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInput, EdgesOut::None))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Internal(NodeParamsInternal::GridLayout(_)) = p {
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need GridLayout, got {:?}", p))
+        }
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+    }
+    fn can_execute(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        if let NodeParams::Internal(NodeParamsInternal::GridLayout(ref layout)) = ctx.weight(ix).params {
+            Ok(NodeResult::Analyzed(s::AnalysisResult::GridLayout(layout.clone())))
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need GridLayout, got {:?}", ctx.weight(ix).params))
+        }
+    }
+}
+
+/// Passes a Grid input through unchanged; its params hold the input's wired position
+#[derive(Debug,Clone)]
+pub struct GridInputMutDef;
+
+impl NodeDef for GridInputMutDef {
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap> {
+        Some(self)
+    }
+}
+
+impl NodeDefMutateBitmap for GridInputMutDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.grid_input_mutate"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Internal(NodeParamsInternal::GridInput(_)) = p {
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need GridInput, got {:?}", p))
+        }
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra, p: &NodeParams) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_grid_cells() {
+    let params = GridParams {
+        columns: 3,
+        cell_w: 100,
+        cell_h: 50,
+        gap: 10,
+        background: s::Color::Transparent,
+        fit: s::GridFit::FitPad,
+    };
+    assert_eq!(params.canvas_size(1).unwrap(), (100, 50));
+    assert_eq!(params.canvas_size(3).unwrap(), (320, 50));
+    assert_eq!(params.canvas_size(7).unwrap(), (320, 170));
+    assert_eq!(params.cell(4).unwrap(), (1, 1, 110, 60));
+
+    let layout = layout_constraint(400, 100, &params.constraint()).unwrap();
+    assert_eq!((layout.image, layout.canvas, layout.image_at), ((100, 25), (100, 50), (0, 12)));
+}
+
+#[test]
+fn test_grid_size_overflow() {
+    let params = |columns: u32, cell_w: u32, gap: u32| GridParams {
+        columns: columns,
+        cell_w: cell_w,
+        cell_h: 50,
+        gap: gap,
+        background: s::Color::Transparent,
+        fit: s::GridFit::FitPad,
+    };
+    assert_eq!(params(u32::max_value(), 100, 0).canvas_size(2).unwrap(), (200, 50));
+    for &(columns, cell_w, gap) in [(3, u32::max_value(), 0), (3, 100, u32::max_value()), (3, 1 << 30, 0), (3, 1 << 31, 1 << 31)].iter() {
+        let e = params(columns, cell_w, gap).canvas_size(3).unwrap_err();
+        assert_eq!(e.kind, ::ErrorKind::SizeLimitExceeded);
+    }
+    assert_eq!(params(3, u32::max_value(), 0).cell(2).unwrap_err().kind, ::ErrorKind::SizeLimitExceeded);
+}
@@ -0,0 +1,140 @@
+use super::internal_prelude::*;
+use super::analyze_colors::read_rgba;
+
+pub static HISTOGRAM: HistogramDef = HistogramDef{};
+
+/// Channel differences at or below this are treated as noise (e.g. JPEG artifacts)
+/// when deciding whether an image is solid or grayscale
+const STATS_TOLERANCE: u8 = 3;
+
+fn channel_stats(histogram: &[u64], count: u64) -> s::ChannelStats {
+    let sum = histogram.iter().enumerate().map(|(v, &n)| v as f64 * n as f64).sum::<f64>();
+    let mean = sum / count as f64;
+    let variance = histogram.iter().enumerate().map(|(v, &n)| (v as f64 - mean) * (v as f64 - mean) * n as f64).sum::<f64>() / count as f64;
+    s::ChannelStats {
+        histogram: histogram.to_vec(),
+        mean: mean,
+        stddev: variance.sqrt(),
+        min: histogram.iter().position(|&n| n > 0).unwrap_or(0) as u8,
+        max: histogram.iter().rposition(|&n| n > 0).unwrap_or(0) as u8,
+    }
+}
+
+pub fn image_stats(bitmap: &BitmapBgra) -> s::ImageStats {
+    // Red, green, blue and alpha, 256 bins each
+    let mut histograms = vec![0u64; 1024];
+    let mut max_chroma = 0u8;
+    // Red, green and blue ranges of pixels that aren't fully transparent
+    let mut visible_min = [255u8; 3];
+    let mut visible_max = [0u8; 3];
+    for y in 0..bitmap.h {
+        for x in 0..bitmap.w {
+            let p = read_rgba(bitmap, x, y);
+            for c in 0..4 {
+                histograms[c * 256 + p[c] as usize] += 1;
+            }
+            // Transparent pixels often hold leftover color that nobody sees
+            if p[3] > 0 {
+                let chroma = cmp::max(p[0], cmp::max(p[1], p[2])) - cmp::min(p[0], cmp::min(p[1], p[2]));
+                max_chroma = cmp::max(max_chroma, chroma);
+                for c in 0..3 {
+                    visible_min[c] = cmp::min(visible_min[c], p[c]);
+                    visible_max[c] = cmp::max(visible_max[c], p[c]);
+                }
+            }
+        }
+    }
+    let count = bitmap.w as u64 * bitmap.h as u64;
+    let channels = histograms.chunks(256).map(|h| channel_stats(h, count)).collect::<Vec<s::ChannelStats>>();
+    let transparent = histograms[768];
+
+    let fully_transparent = transparent == count;
+    let solid = fully_transparent || (channels[3].max - channels[3].min <= STATS_TOLERANCE &&
+        (0..3).all(|c| visible_max[c] - visible_min[c] <= STATS_TOLERANCE));
+    let blank = fully_transparent || {
+        let near = |target: u8| channels[0..3].iter().all(|c| (c.mean - target as f64).abs() <= STATS_TOLERANCE as f64);
+        solid && channels[3].min == 255 && (near(0) || near(255))
+    };
+
+    let mut channels = channels.into_iter();
+    s::ImageStats {
+        w: bitmap.w,
+        h: bitmap.h,
+        red: channels.next().unwrap(),
+        green: channels.next().unwrap(),
+        blue: channels.next().unwrap(),
+        alpha: channels.next().unwrap(),
+        alpha_coverage: (count - transparent) as f64 / count as f64,
+        solid: solid,
+        blank: blank,
+        grayscale: max_chroma <= STATS_TOLERANCE,
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct HistogramDef;
+
+impl NodeDef for HistogramDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.histogram"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInput, EdgesOut::None))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::Histogram) = p {
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Histogram, got {:?}", p))
+        }
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+    }
+    fn can_execute(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        let input = ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+        Ok(NodeResult::Analyzed(s::AnalysisResult::ImageStats(image_stats(unsafe { &*input }))))
+    }
+}
+
+#[test]
+fn test_image_stats() {
+    let bgra = |pixels: &mut Vec<u8>| BitmapBgra {
+        w: 2,
+        h: 2,
+        stride: 8,
+        pixels: pixels.as_mut_ptr(),
+        fmt: PixelFormat::Bgra32,
+        matte_color: [0; 4],
+        compositing_mode: ::ffi::BitmapCompositingMode::ReplaceSelf,
+    };
+
+    let mut white = vec![255u8; 16];
+    let stats = image_stats(&bgra(&mut white));
+    assert!(stats.solid && stats.blank && stats.grayscale);
+    assert_eq!((stats.red.mean, stats.red.stddev, stats.alpha_coverage), (255f64, 0f64, 1f64));
+    assert_eq!(stats.green.histogram[255], 4);
+
+    // Two opaque gray pixels (one slightly off-gray), one opaque red, one transparent green
+    let mut mixed = vec![100, 100, 100, 255, 100, 101, 102, 255, 0, 0, 255, 255, 0, 255, 0, 0];
+    let stats = image_stats(&bgra(&mut mixed));
+    assert!(!stats.solid && !stats.blank && !stats.grayscale);
+    assert_eq!(stats.alpha_coverage, 0.75f64);
+    assert_eq!((stats.red.min, stats.red.max), (0, 255));
+    assert_eq!(stats.blue.mean, 50f64);
+
+    mixed[8..12].copy_from_slice(&[128, 128, 128, 255]);
+    assert!(image_stats(&bgra(&mut mixed)).grayscale);
+
+    // Nearly transparent red, plus fully transparent pixels whose hidden colors don't count
+    let mut hidden = vec![0, 0, 255, 2, 0, 0, 255, 2, 255, 0, 0, 0, 30, 200, 10, 0];
+    let stats = image_stats(&bgra(&mut hidden));
+    assert!(stats.solid && !stats.blank);
+    assert_eq!(stats.alpha_coverage, 0.5f64);
+}
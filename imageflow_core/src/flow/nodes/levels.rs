@@ -0,0 +1,168 @@
+use super::internal_prelude::*;
+use super::white_balance::{area_threshold, create_byte_mapping, apply_mappings};
+
+pub static LEVELS: MutProtect<ToneMappingMutDef> = MutProtect{node: &TONE_MAPPING_MUTATE, fqn: "imazen.levels"};
+pub static CURVES: MutProtect<ToneMappingMutDef> = MutProtect{node: &TONE_MAPPING_MUTATE, fqn: "imazen.curves"};
+pub static AUTO_LEVELS: MutProtect<ToneMappingMutDef> = MutProtect{node: &TONE_MAPPING_MUTATE, fqn: "imazen.auto_levels"};
+pub static TONE_MAPPING_MUTATE: ToneMappingMutDef = ToneMappingMutDef{};
+
+
+fn identity_lut() -> Vec<u8> {
+    (0..256usize).map(|v| v as u8).collect()
+}
+
+fn levels_lut(black: u8, white: u8, gamma: f32) -> Vec<u8> {
+    let range = (white - black) as f64;
+    (0..256usize).map(|v| {
+        let t = ((v as f64 - black as f64) / range).max(0f64).min(1f64);
+        (t.powf(1f64 / gamma as f64) * 255f64).round() as u8
+    }).collect()
+}
+
+/// Monotone cubic (Fritsch-Carlson) interpolation, so the curve never overshoots between points
+fn curve_lut(points: &[(u8, u8)]) -> Vec<u8> {
+    let xs = points.iter().map(|&(x, _)| x as f64).collect::<Vec<f64>>();
+    let ys = points.iter().map(|&(_, y)| y as f64).collect::<Vec<f64>>();
+    let n = points.len();
+
+    let secants = (0..n - 1).map(|i| (ys[i + 1] - ys[i]) / (xs[i + 1] - xs[i])).collect::<Vec<f64>>();
+    let mut tangents = vec![0f64; n];
+    tangents[0] = secants[0];
+    tangents[n - 1] = secants[n - 2];
+    for i in 1..n - 1 {
+        tangents[i] = if secants[i - 1] * secants[i] <= 0f64 { 0f64 } else { (secants[i - 1] + secants[i]) / 2f64 };
+    }
+    for i in 0..n - 1 {
+        if secants[i] == 0f64 {
+            tangents[i] = 0f64;
+            tangents[i + 1] = 0f64;
+        } else {
+            let a = tangents[i] / secants[i];
+            let b = tangents[i + 1] / secants[i];
+            let magnitude = a * a + b * b;
+            if magnitude > 9f64 {
+                let scale = 3f64 / magnitude.sqrt();
+                tangents[i] = scale * a * secants[i];
+                tangents[i + 1] = scale * b * secants[i];
+            }
+        }
+    }
+
+    (0..256usize).map(|v| {
+        let x = v as f64;
+        let y = if x <= xs[0] {
+            ys[0]
+        } else if x >= xs[n - 1] {
+            ys[n - 1]
+        } else {
+            let i = xs.iter().rposition(|&start| start <= x).unwrap();
+            let h = xs[i + 1] - xs[i];
+            let t = (x - xs[i]) / h;
+            let (t2, t3) = (t * t, t * t * t);
+            (2f64 * t3 - 3f64 * t2 + 1f64) * ys[i] + (t3 - 2f64 * t2 + t) * h * tangents[i] +
+                (-2f64 * t3 + 3f64 * t2) * ys[i + 1] + (t3 - t2) * h * tangents[i + 1]
+        };
+        y.round().max(0f64).min(255f64) as u8
+    }).collect()
+}
+
+fn apply_to_channel(bitmap: *mut BitmapBgra, lut: Vec<u8>, channel: Option<s::ColorChannel>) -> Result<()> {
+    match channel {
+        None => apply_mappings(bitmap, &lut, &lut, &lut),
+        Some(s::ColorChannel::Red) => apply_mappings(bitmap, &lut, &identity_lut(), &identity_lut()),
+        Some(s::ColorChannel::Green) => apply_mappings(bitmap, &identity_lut(), &lut, &identity_lut()),
+        Some(s::ColorChannel::Blue) => apply_mappings(bitmap, &identity_lut(), &identity_lut(), &lut),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ToneMappingMutDef;
+impl NodeDef for ToneMappingMutDef{
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap>{
+        Some(self)
+    }
+}
+impl NodeDefMutateBitmap for ToneMappingMutDef{
+    fn fqn(&self) -> &'static str{
+        "imazen.tone_mapping_mut"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        match *p {
+            NodeParams::Json(s::Node::Levels { black, white, gamma, .. }) => {
+                if white <= black {
+                    Err(nerror!(::ErrorKind::InvalidNodeParams, "Levels white ({}) must be greater than black ({})", white, black))
+                } else if !gamma.is_finite() || gamma <= 0f32 {
+                    Err(nerror!(::ErrorKind::InvalidNodeParams, "Levels gamma must be a positive number, got {}", gamma))
+                } else {
+                    Ok(())
+                }
+            }
+            NodeParams::Json(s::Node::Curves { ref points, .. }) => {
+                if points.len() < 2 {
+                    Err(nerror!(::ErrorKind::InvalidNodeParams, "Curves requires at least 2 points, got {:?}", points))
+                } else if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                    Err(nerror!(::ErrorKind::InvalidNodeParams, "Curves points must be sorted by strictly increasing input value, got {:?}", points))
+                } else {
+                    Ok(())
+                }
+            }
+            NodeParams::Json(s::Node::AutoLevels { threshold }) => {
+                match threshold {
+                    Some(t) if !(t >= 0f32 && t < 0.5f32) =>
+                        Err(nerror!(::ErrorKind::InvalidNodeParams, "AutoLevels threshold must be between 0 and 0.5, got {}", t)),
+                    _ => Ok(())
+                }
+            }
+            _ => Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Levels, Curves, or AutoLevels, got {:?}", p))
+        }
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra,  p: &NodeParams) -> Result<()> {
+        self.validate_params(p).map_err(|e| e.at(here!()))?;
+        match *p {
+            NodeParams::Json(s::Node::Levels { black, white, gamma, channel }) => {
+                apply_to_channel(bitmap, levels_lut(black, white, gamma), channel)
+            }
+            NodeParams::Json(s::Node::Curves { ref points, channel }) => {
+                apply_to_channel(bitmap, curve_lut(points), channel)
+            }
+            NodeParams::Json(s::Node::AutoLevels { threshold }) => {
+                let mut histograms: [u64; 768] = [0; 768];
+                let mut pixels_sampled: u64 = 0;
+                unsafe {
+                    if !::ffi::flow_bitmap_bgra_populate_histogram(c.flow_c(), bitmap as *mut BitmapBgra, histograms.as_mut_ptr(), 256, 3, &mut pixels_sampled as *mut u64) {
+                        return Err(cerror!(c, "Failed to populate histogram"))
+                    }
+                }
+                let combined = (0..256usize).map(|v| histograms[v] + histograms[256 + v] + histograms[512 + v]).collect::<Vec<u64>>();
+                let threshold = threshold.unwrap_or(0.006) as f64;
+                let (low, high) = area_threshold(&combined, pixels_sampled * 3, threshold, threshold);
+                if high > low {
+                    apply_to_channel(bitmap, create_byte_mapping(low, high), None)
+                } else {
+                    // Solid images have no range to stretch
+                    Ok(())
+                }
+            }
+            _ => Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Levels, Curves, or AutoLevels, got {:?}", p))
+        }
+    }
+}
+
+#[test]
+fn test_tone_luts() {
+    assert_eq!(levels_lut(0, 255, 1f32), identity_lut());
+    let l = levels_lut(50, 200, 1f32);
+    assert_eq!((l[0], l[50], l[125], l[200], l[255]), (0, 0, 128, 255, 255));
+    let l = levels_lut(0, 255, 2f32);
+    assert!(l[64] > 64 && l[0] == 0 && l[255] == 255);
+
+    assert_eq!(curve_lut(&[(0, 0), (255, 255)]), identity_lut());
+    let inverted = curve_lut(&[(0, 255), (255, 0)]);
+    assert_eq!((inverted[0], inverted[255]), (255, 0));
+
+    let s_curve = curve_lut(&[(0, 0), (64, 40), (192, 215), (255, 255)]);
+    assert_eq!((s_curve[64], s_curve[192]), (40, 215));
+    assert!(s_curve.windows(2).all(|pair| pair[0] <= pair[1]));
+    // Clamped outside the first and last points
+    assert_eq!(curve_lut(&[(16, 20), (235, 230)])[0], 20);
+}
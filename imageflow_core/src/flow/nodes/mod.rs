@@ -14,6 +14,18 @@ mod codecs_and_pointer;
 mod constrain;
 mod white_balance;
 mod color;
+mod smart_crop;
+mod levels;
+mod analyze_colors;
+mod perceptual_hash;
+mod compare;
+mod image_stats;
+mod redact;
+mod tile_pyramid;
+mod grid;
+mod draw_text;
+mod compositing;
+mod dither;
 
 mod internal_prelude {
     pub use ::ffi;
@@ -36,6 +48,8 @@ pub use self::clone_crop_fill_expand::CROP;
 pub use self::clone_crop_fill_expand::CROP_MUTATE;
 pub use self::clone_crop_fill_expand::EXPAND_CANVAS;
 pub use self::clone_crop_fill_expand::FILL_RECT;
+pub use self::smart_crop::SMART_CROP;
+pub use self::smart_crop::SMART_CROP_MUTATE;
 pub use self::codecs_and_pointer::BITMAP_BGRA_POINTER;
 pub use self::codecs_and_pointer::DECODER;
 pub use self::codecs_and_pointer::ENCODE;
@@ -51,7 +65,9 @@ pub use self::rotate_flip_transpose::ROTATE_180;
 pub use self::rotate_flip_transpose::ROTATE_270;
 pub use self::rotate_flip_transpose::ROTATE_90;
 pub use self::rotate_flip_transpose::TRANSPOSE;
+pub use self::rotate_flip_transpose::ROTATE;
 pub use self::scale_render::SCALE;
+pub use self::scale_render::SCALE_2D_RENDER_TO_CANVAS_1D;
 pub use self::scale_render::DRAW_IMAGE_EXACT;
 //pub use self::scale_render::SCALE_1D;
 //pub use self::scale_render::SCALE_1D_TO_CANVAS_1D;
@@ -62,6 +78,35 @@ pub use self::white_balance::WHITE_BALANCE_SRGB;
 pub use self::color::COLOR_MATRIX_SRGB_MUTATE;
 pub use self::color::COLOR_MATRIX_SRGB;
 pub use self::color::COLOR_FILTER_SRGB;
+pub use self::color::COLOR_MATRIX_LINEAR_MUTATE;
+pub use self::color::COLOR_MATRIX_LINEAR;
+pub use self::color::COLOR_FILTER_LUV_MUTATE;
+pub use self::color::COLOR_FILTER;
+pub use self::levels::LEVELS;
+pub use self::levels::CURVES;
+pub use self::levels::AUTO_LEVELS;
+pub use self::levels::TONE_MAPPING_MUTATE;
+pub use self::analyze_colors::ANALYZE_COLORS;
+pub use self::perceptual_hash::PERCEPTUAL_HASH;
+pub use self::compare::COMPARE;
+pub use self::compare::COMPARE_PRIMITIVE;
+pub use self::compare::DIFF_HEATMAP;
+pub use self::image_stats::HISTOGRAM;
+pub use self::redact::REDACT_REGIONS;
+pub use self::redact::REDACT_REGIONS_MAPPED;
+pub use self::redact::REDACT_REGIONS_MUTATE;
+pub use self::tile_pyramid::TILE_PYRAMID;
+pub use self::grid::GRID;
+pub use self::grid::GRID_LAYOUT;
+pub use self::grid::GRID_INPUT;
+pub use self::grid::GRID_INPUT_MUTATE;
+pub use self::draw_text::DRAW_TEXT;
+pub use self::draw_text::DRAW_TEXT_MUTATE;
+pub use self::compositing::APPLY_MASK;
+pub use self::compositing::CHROMA_KEY;
+pub use self::compositing::CHROMA_KEY_MUTATE;
+pub use self::dither::DITHER;
+pub use self::dither::DITHER_MUTATE;
 
 #[macro_use]
 use super::definitions::*;
@@ -282,6 +327,19 @@ impl<'c> OpCtxMut<'c> {
         }
     }
 
+    pub fn delete_parent_edges_for(&mut self, to_node: NodeIndex) {
+        loop {
+            if self.graph
+                .raw_edges()
+                .iter()
+                .position(|e| e.target() == to_node)
+                .and_then(|ix| self.graph.remove_edge(EdgeIndex::new(ix)))
+                .is_none(){
+                break;
+            }
+        }
+    }
+
     pub fn delete_node_and_snap_together(&mut self, node_to_delete: NodeIndex) {
         // Prefer EdgeKind=Input
         let input = self.graph
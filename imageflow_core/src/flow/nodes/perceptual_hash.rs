@@ -0,0 +1,173 @@
+use super::internal_prelude::*;
+use super::analyze_colors::read_rgba;
+use std::f32::consts::PI;
+
+pub static PERCEPTUAL_HASH: PerceptualHashDef = PerceptualHashDef{};
+
+/// Rec. 601 luma, composited over white so transparent areas hash like a blank page
+fn luma(bitmap: &BitmapBgra, x: u32, y: u32) -> f32 {
+    let p = read_rgba(bitmap, x, y);
+    let l = 0.299f32 * p[0] as f32 + 0.587f32 * p[1] as f32 + 0.114f32 * p[2] as f32;
+    let a = p[3] as f32 / 255f32;
+    l * a + 255f32 * (1f32 - a)
+}
+
+/// The source pixels covered by cell `index` of `cells`; never empty, even when upscaling
+fn cell_range(index: u32, cells: u32, size: u32) -> (u32, u32) {
+    let start = (index as u64 * size as u64 / cells as u64) as u32;
+    let end = ((index as u64 + 1) * size as u64 / cells as u64) as u32;
+    (start, cmp::max(end, start + 1))
+}
+
+/// Averages luma into exactly `w` x `h` cells, ignoring aspect ratio
+pub fn luma_grid(bitmap: &BitmapBgra, w: u32, h: u32) -> Vec<f32> {
+    let mut grid = Vec::with_capacity((w * h) as usize);
+    for gy in 0..h {
+        let (y1, y2) = cell_range(gy, h, bitmap.h);
+        for gx in 0..w {
+            let (x1, x2) = cell_range(gx, w, bitmap.w);
+            let mut sum = 0f32;
+            for y in y1..y2 {
+                for x in x1..x2 {
+                    sum += luma(bitmap, x, y);
+                }
+            }
+            grid.push(sum / ((x2 - x1) * (y2 - y1)) as f32);
+        }
+    }
+    grid
+}
+
+/// Unnormalized DCT-II
+fn dct_1d(input: &[f32]) -> Vec<f32> {
+    let n = input.len();
+    (0..n).map(|k| {
+        input.iter().enumerate().map(|(i, &v)| v * (PI * (2 * i + 1) as f32 * k as f32 / (2 * n) as f32).cos()).sum::<f32>()
+    }).collect()
+}
+
+fn dct_2d(grid: &[f32], size: usize) -> Vec<f32> {
+    let rows = grid.chunks(size).flat_map(|row| dct_1d(row)).collect::<Vec<f32>>();
+    let mut out = vec![0f32; size * size];
+    for x in 0..size {
+        let column = (0..size).map(|y| rows[y * size + x]).collect::<Vec<f32>>();
+        for (y, v) in dct_1d(&column).into_iter().enumerate() {
+            out[y * size + x] = v;
+        }
+    }
+    out
+}
+
+/// Returns `side` * `side` bits, row-major
+pub fn perceptual_hash_bits(bitmap: &BitmapBgra, algorithm: s::PerceptualHashAlgorithm, side: u32) -> Vec<bool> {
+    match algorithm {
+        s::PerceptualHashAlgorithm::AHash => {
+            let grid = luma_grid(bitmap, side, side);
+            let mean = grid.iter().sum::<f32>() / grid.len() as f32;
+            grid.iter().map(|&v| v > mean).collect()
+        }
+        s::PerceptualHashAlgorithm::DHash => {
+            let grid = luma_grid(bitmap, side + 1, side);
+            grid.chunks((side + 1) as usize)
+                .flat_map(|row| row.windows(2).map(|pair| pair[0] > pair[1]).collect::<Vec<bool>>())
+                .collect()
+        }
+        s::PerceptualHashAlgorithm::PHash => {
+            let size = (side * 4) as usize;
+            let side = side as usize;
+            let coefficients = dct_2d(&luma_grid(bitmap, size as u32, size as u32), size);
+            let coefficients = &coefficients;
+            let low = (0..side).flat_map(|y| (0..side).map(move |x| coefficients[y * size + x])).collect::<Vec<f32>>();
+            // The DC term only reflects overall brightness, so it's left out of the median
+            let mut sorted = low[1..].to_vec();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            let median = sorted[sorted.len() / 2];
+            low.iter().map(|&v| v > median).collect()
+        }
+    }
+}
+
+fn to_hex(bits: &[bool]) -> String {
+    bits.chunks(4).map(|nibble| {
+        let v = nibble.iter().fold(0u32, |acc, &bit| (acc << 1) | bit as u32);
+        ::std::char::from_digit(v, 16).unwrap()
+    }).collect()
+}
+
+#[derive(Debug,Clone)]
+pub struct PerceptualHashDef;
+
+impl PerceptualHashDef {
+    fn get(&self, p: &NodeParams) -> Result<(s::PerceptualHashAlgorithm, u32)> {
+        if let &NodeParams::Json(s::Node::PerceptualHash { algorithm, bits }) = p {
+            match bits.unwrap_or(64) {
+                64 => Ok((algorithm, 8)),
+                256 => Ok((algorithm, 16)),
+                other => Err(nerror!(::ErrorKind::InvalidNodeParams, "Perceptual hash bits must be 64 or 256, got {}", other))
+            }
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need PerceptualHash, got {:?}", p))
+        }
+    }
+}
+
+impl NodeDef for PerceptualHashDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.perceptual_hash"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInput, EdgesOut::None))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        self.get(p).map_err(|e| e.at(here!())).map(|_| ())
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+    }
+    fn can_execute(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        let (algorithm, side) = self.get(&ctx.weight(ix).params).map_err(|e| e.at(here!()))?;
+        let input = ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+
+        let bits = perceptual_hash_bits(unsafe { &*input }, algorithm, side);
+        Ok(NodeResult::Analyzed(s::AnalysisResult::PerceptualHash(s::PerceptualHashResult {
+            algorithm: algorithm,
+            bits: bits.len() as u32,
+            hash: to_hex(&bits),
+        })))
+    }
+}
+
+#[test]
+fn test_perceptual_hash() {
+    let gray = |w: u32, h: u32, pixels: &mut Vec<u8>| BitmapBgra {
+        w: w,
+        h: h,
+        stride: w,
+        pixels: pixels.as_mut_ptr(),
+        fmt: PixelFormat::Gray8,
+        matte_color: [0; 4],
+        compositing_mode: ::ffi::BitmapCompositingMode::ReplaceSelf,
+    };
+    let hash = |bitmap: &BitmapBgra, algorithm, side| to_hex(&perceptual_hash_bits(bitmap, algorithm, side));
+
+    // Brightens left to right
+    let mut gradient = (0..8).flat_map(|_| (0..90).map(|x| (x * 2) as u8)).collect::<Vec<u8>>();
+    assert_eq!(hash(&gray(90, 8, &mut gradient), s::PerceptualHashAlgorithm::DHash, 8), "0000000000000000");
+    let mut reversed = gradient.iter().map(|v| 255 - v).collect::<Vec<u8>>();
+    assert_eq!(hash(&gray(90, 8, &mut reversed), s::PerceptualHashAlgorithm::DHash, 8), "ffffffffffffffff");
+
+    // Dark left half, bright right half
+    let mut halves = (0..16 * 16).map(|i| if i % 16 < 8 { 0 } else { 255 }).collect::<Vec<u8>>();
+    let bitmap = gray(16, 16, &mut halves);
+    assert_eq!(hash(&bitmap, s::PerceptualHashAlgorithm::AHash, 8), "0f0f0f0f0f0f0f0f");
+
+    let phash = hash(&bitmap, s::PerceptualHashAlgorithm::PHash, 16);
+    assert_eq!(phash.len(), 64);
+    assert_eq!(s::hamming_distance(&phash, &hash(&bitmap, s::PerceptualHashAlgorithm::PHash, 16)), Some(0));
+}
@@ -0,0 +1,381 @@
+use super::internal_prelude::*;
+use super::clone_crop_fill_expand::{rect_within, COPY_RECT};
+use super::rotate_flip_transpose::{quarter_turns, APPLY_ORIENTATION, FLIP_H, FLIP_H_PRIMITIVE, FLIP_V, FLIP_V_PRIMITIVE, NO_OP,
+                                   ROTATE, ROTATE_180, ROTATE_270, ROTATE_90, TRANSPOSE, TRANSPOSE_MUT};
+
+pub static REDACT_REGIONS: RedactRegionsDef = RedactRegionsDef{};
+pub static REDACT_REGIONS_MAPPED: MutProtect<RedactRegionsMutDef> = MutProtect{node: &REDACT_REGIONS_MUTATE, fqn: "imazen.redact_regions_mapped"};
+pub static REDACT_REGIONS_MUTATE: RedactRegionsMutDef = RedactRegionsMutDef{};
+
+/// An orientation change between the source and the node being expanded
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Reorientation {
+    FlipH,
+    FlipV,
+    Transpose,
+}
+
+/// Maps `r` through `op` applied to a w x h image
+fn reorient(r: s::RedactRegion, op: Reorientation, w: u32, h: u32) -> s::RedactRegion {
+    match op {
+        Reorientation::FlipH => s::RedactRegion { x1: w - r.x2, x2: w - r.x1, ..r },
+        Reorientation::FlipV => s::RedactRegion { y1: h - r.y2, y2: h - r.y1, ..r },
+        Reorientation::Transpose => s::RedactRegion { x1: r.y1, y1: r.x1, x2: r.y2, y2: r.x2 },
+    }
+}
+
+/// Maps `r` from a `from`-sized image to the `to`-sized frame a decoder downscaled it to, rounding outward
+/// so no pixel of the region escapes
+fn scale_region(r: s::RedactRegion, from: (u32, u32), to: (u32, u32)) -> s::RedactRegion {
+    let floor = |v: u32, from: u32, to: u32| (v as u64 * to as u64 / from as u64) as u32;
+    let ceil = |v: u32, from: u32, to: u32| ((v as u64 * to as u64 + from as u64 - 1) / from as u64) as u32;
+    s::RedactRegion {
+        x1: floor(r.x1, from.0, to.0),
+        y1: floor(r.y1, from.1, to.1),
+        x2: ceil(r.x2, from.0, to.0),
+        y2: ceil(r.y2, from.1, to.1),
+    }
+}
+
+/// The orientation changes `node` makes, in order, whether or not it has been expanded yet; None if it makes none.
+/// Mirrors the expansions in rotate_flip_transpose.rs.
+fn reorientations_of(node: &Node, parent: (u32, u32)) -> Result<Option<Vec<Reorientation>>> {
+    use self::Reorientation::*;
+    let rotate_90 = || vec![Transpose, FlipV];
+    let rotate_180 = || vec![FlipV, FlipH];
+    let rotate_270 = || vec![FlipV, Transpose];
+    let ops = if node.is(&FLIP_H) || node.is(&FLIP_H_PRIMITIVE) {
+        vec![FlipH]
+    } else if node.is(&FLIP_V) || node.is(&FLIP_V_PRIMITIVE) {
+        vec![FlipV]
+    } else if node.is(&TRANSPOSE) || node.is(&TRANSPOSE_MUT) {
+        vec![Transpose]
+    } else if node.is(&ROTATE_90) {
+        rotate_90()
+    } else if node.is(&ROTATE_180) {
+        rotate_180()
+    } else if node.is(&ROTATE_270) {
+        rotate_270()
+    } else if node.is(&NO_OP) {
+        vec![]
+    } else if node.is(&APPLY_ORIENTATION) {
+        match node.params {
+            NodeParams::Json(s::Node::ApplyOrientation { flag: 7 }) => vec![FlipV, FlipH, Transpose],
+            NodeParams::Json(s::Node::ApplyOrientation { flag: 8 }) => rotate_90(),
+            NodeParams::Json(s::Node::ApplyOrientation { flag: 6 }) => rotate_270(),
+            NodeParams::Json(s::Node::ApplyOrientation { flag: 5 }) => vec![Transpose],
+            NodeParams::Json(s::Node::ApplyOrientation { flag: 4 }) => vec![FlipV],
+            NodeParams::Json(s::Node::ApplyOrientation { flag: 3 }) => rotate_180(),
+            NodeParams::Json(s::Node::ApplyOrientation { flag: 2 }) => vec![FlipH],
+            _ => vec![]
+        }
+    } else if node.is(&ROTATE) {
+        let square = parent.0 == parent.1;
+        match node.params {
+            NodeParams::Json(s::Node::Rotate { degrees, expand_canvas, .. }) => match quarter_turns(degrees) {
+                Some(0) => vec![],
+                Some(2) => rotate_180(),
+                Some(1) if expand_canvas || square => rotate_90(),
+                Some(3) if expand_canvas || square => rotate_270(),
+                _ => return Err(nerror!(::ErrorKind::InvalidNodeConnections, "RedactRegions cannot map source coordinates through a {} degree rotation; redact before rotating", degrees))
+            },
+            ref other => return Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Rotate, got {:?}", other))
+        }
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(ops))
+}
+
+fn frame_size(ctx: &OpCtxMut, ix: NodeIndex) -> Result<(u32, u32)> {
+    match ctx.weight(ix).frame_est {
+        FrameEstimate::Some(info) => Ok((info.w as u32, info.h as u32)),
+        other => Err(nerror!(::ErrorKind::InvalidState, "Node {:?} lacks a frame estimate: {:?}", ix, other))
+    }
+}
+
+/// Walks up the input chain from `ix` to the source, which may still hold unexpanded nodes when estimating.
+/// Returns the source node, the size of its frame and the orientation changes applied since, in order.
+fn orientation_since_source(ctx: &OpCtxMut, ix: NodeIndex) -> Result<(NodeIndex, (u32, u32), Vec<Reorientation>)> {
+    let mut ops = Vec::new();
+    let mut current = ctx.first_parent_input(ix).ok_or_else(|| nerror!(::ErrorKind::InvalidNodeConnections, "RedactRegions requires an input"))?;
+    loop {
+        let size = frame_size(ctx, current)?;
+        let parent = match ctx.first_parent_of_kind(current, EdgeKind::Input) {
+            Some(parent) => parent,
+            None => {
+                ops.reverse();
+                return Ok((current, size, ops));
+            }
+        };
+        let parent_size = frame_size(ctx, parent)?;
+        let node = ctx.weight(current);
+        let has_canvas = ctx.first_parent_of_kind(current, EdgeKind::Canvas).is_some();
+
+        let full_copy = match node.params {
+            NodeParams::Json(s::Node::CopyRectToCanvas { from_x: 0, from_y: 0, x: 0, y: 0, width, height }) =>
+                (width, height) == parent_size && size == parent_size,
+            _ => false
+        };
+        if let Some(changes) = reorientations_of(node, parent_size)? {
+            // We're walking upstream, so ops end up reversed
+            ops.extend(changes.into_iter().rev());
+        } else if node.is(&COPY_RECT) && full_copy {
+            // What Clone expands to
+        } else if has_canvas || size != parent_size {
+            return Err(nerror!(::ErrorKind::InvalidNodeConnections, "RedactRegions cannot map source coordinates through {}; redact before cropping, resizing or compositing", node.def.fqn()));
+        }
+        current = parent;
+    }
+}
+
+fn get_params(p: &NodeParams) -> Result<(&Vec<s::RedactRegion>, &s::RedactionStyle)> {
+    if let &NodeParams::Json(s::Node::RedactRegions { ref regions, ref style }) = p {
+        match *style {
+            s::RedactionStyle::Pixelate { block } if block < 1 =>
+                Err(nerror!(::ErrorKind::InvalidNodeParams, "Pixelate block size must be at least 1")),
+            s::RedactionStyle::Blur { sigma } if !(sigma > 0f32) || !sigma.is_finite() =>
+                Err(nerror!(::ErrorKind::InvalidNodeParams, "Blur sigma must be a positive number, got {}", sigma)),
+            _ => Ok((regions, style))
+        }
+    } else {
+        Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need RedactRegions, got {:?}", p))
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct RedactRegionsDef;
+impl NodeDef for RedactRegionsDef{
+    fn as_one_input_expand(&self) -> Option<&NodeDefOneInputExpand>{
+        Some(self)
+    }
+}
+impl NodeDefOneInputExpand for RedactRegionsDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.redact_regions"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        get_params(p).map(|_| ()).map_err(|e| e.at(here!()))
+    }
+    fn expand(&self, ctx: &mut OpCtxMut, ix: NodeIndex, p: NodeParams, parent: FrameInfo) -> Result<()> {
+        let (source, (mut w, mut h), ops) = orientation_since_source(ctx, ix).map_err(|e| e.at(here!()))?;
+        let (regions, style) = {
+            let (regions, style) = get_params(&p).map_err(|e| e.at(here!()))?;
+            (regions.clone(), style.clone())
+        };
+        // Regions are in the image's own pixels, even if the decoder was told to downscale it
+        let io_id = match ctx.weight(source).params {
+            NodeParams::Json(s::Node::Decode { io_id, .. }) => Some(io_id),
+            _ => None
+        };
+        let (source_w, source_h) = match io_id {
+            Some(io_id) => {
+                let (w, h) = ctx.job.get_unscaled_image_size(io_id).map_err(|e| e.at(here!()))?;
+                (w as u32, h as u32)
+            }
+            None => (w, h)
+        };
+        for r in regions.iter() {
+            if !rect_within(source_w, source_h, r.x1, r.y1, r.x2, r.y2) {
+                return Err(nerror!(::ErrorKind::InvalidCoordinates, "Invalid coordinates for {}x{} source image: {:?}", source_w, source_h, r));
+            }
+        }
+        let mut mapped = regions.into_iter().map(|r| scale_region(r, (source_w, source_h), (w, h))).collect::<Vec<s::RedactRegion>>();
+        for op in ops {
+            mapped = mapped.into_iter().map(|r| reorient(r, op, w, h)).collect();
+            if op == Reorientation::Transpose {
+                mem::swap(&mut w, &mut h);
+            }
+        }
+        ctx.replace_node(ix, vec![Node::n(&REDACT_REGIONS_MAPPED, NodeParams::Json(s::Node::RedactRegions {
+            regions: mapped,
+            style: style,
+        }))]);
+        Ok(())
+    }
+}
+
+/// Reads a region into floats, premultiplying alpha for Bgra32 so transparent pixels don't bleed color
+fn read_region(bitmap: &BitmapBgra, r: &s::RedactRegion) -> Vec<[f32; 4]> {
+    let bpp = bitmap.fmt.bytes();
+    let premultiply = bitmap.fmt == PixelFormat::Bgra32;
+    let mut pixels = Vec::with_capacity(((r.x2 - r.x1) * (r.y2 - r.y1)) as usize);
+    for y in r.y1..r.y2 {
+        for x in r.x1..r.x2 {
+            let mut v = [0f32; 4];
+            unsafe {
+                let p = bitmap.pixels.offset((y * bitmap.stride) as isize + (x as usize * bpp) as isize);
+                for c in 0..bpp {
+                    v[c] = *p.offset(c as isize) as f32;
+                }
+            }
+            if premultiply {
+                for c in 0..3 {
+                    v[c] = v[c] * v[3] / 255f32;
+                }
+            }
+            pixels.push(v);
+        }
+    }
+    pixels
+}
+
+fn write_region(bitmap: &mut BitmapBgra, r: &s::RedactRegion, pixels: &[[f32; 4]]) {
+    let bpp = bitmap.fmt.bytes();
+    let premultiplied = bitmap.fmt == PixelFormat::Bgra32;
+    let mut values = pixels.iter();
+    for y in r.y1..r.y2 {
+        for x in r.x1..r.x2 {
+            let mut v = *values.next().unwrap();
+            if premultiplied {
+                for c in 0..3 {
+                    v[c] = if v[3] > 0f32 { v[c] * 255f32 / v[3] } else { 0f32 };
+                }
+            }
+            unsafe {
+                let p = bitmap.pixels.offset((y * bitmap.stride) as isize + (x as usize * bpp) as isize);
+                for c in 0..bpp {
+                    *p.offset(c as isize) = v[c].round().max(0f32).min(255f32) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// Replaces each block x block square (aligned to the region's corner) with its average
+fn pixelate(pixels: &mut [[f32; 4]], w: usize, h: usize, block: usize) {
+    for by in (0..(h + block - 1) / block).map(|i| i * block) {
+        for bx in (0..(w + block - 1) / block).map(|i| i * block) {
+            let (x2, y2) = (cmp::min(bx + block, w), cmp::min(by + block, h));
+            let mut sum = [0f32; 4];
+            for y in by..y2 {
+                for x in bx..x2 {
+                    for c in 0..4 {
+                        sum[c] += pixels[y * w + x][c];
+                    }
+                }
+            }
+            let count = ((x2 - bx) * (y2 - by)) as f32;
+            let average = [sum[0] / count, sum[1] / count, sum[2] / count, sum[3] / count];
+            for y in by..y2 {
+                for x in bx..x2 {
+                    pixels[y * w + x] = average;
+                }
+            }
+        }
+    }
+}
+
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3f32).ceil() as i32;
+    let weights = (-radius..radius + 1).map(|i| (-((i * i) as f32) / (2f32 * sigma * sigma)).exp()).collect::<Vec<f32>>();
+    let sum = weights.iter().sum::<f32>();
+    weights.into_iter().map(|weight| weight / sum).collect()
+}
+
+/// One pass of a separable convolution; samples past the region's edge are clamped to it
+fn convolve(pixels: &mut [[f32; 4]], w: usize, h: usize, kernel: &[f32], horizontal: bool) {
+    let radius = (kernel.len() / 2) as isize;
+    let source = pixels.to_vec();
+    for y in 0..h {
+        for x in 0..w {
+            let mut sum = [0f32; 4];
+            for (k, weight) in kernel.iter().enumerate() {
+                let offset = k as isize - radius;
+                let ix = if horizontal {
+                    y * w + cmp::max(0, cmp::min(w as isize - 1, x as isize + offset)) as usize
+                } else {
+                    cmp::max(0, cmp::min(h as isize - 1, y as isize + offset)) as usize * w + x
+                };
+                for c in 0..4 {
+                    sum[c] += source[ix][c] * weight;
+                }
+            }
+            pixels[y * w + x] = sum;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedactRegionsMutDef;
+impl NodeDef for RedactRegionsMutDef{
+    fn as_one_mutate_bitmap(&self) -> Option<&NodeDefMutateBitmap>{
+        Some(self)
+    }
+}
+impl NodeDefMutateBitmap for RedactRegionsMutDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.redact_regions_mutate"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        get_params(p).map(|_| ()).map_err(|e| e.at(here!()))
+    }
+    fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra, p: &NodeParams) -> Result<()> {
+        let (regions, style) = get_params(p).map_err(|e| e.at(here!()))?;
+        for r in regions.iter() {
+            if !rect_within(bitmap.w, bitmap.h, r.x1, r.y1, r.x2, r.y2) {
+                return Err(nerror!(::ErrorKind::InvalidCoordinates, "Invalid coordinates for {}x{} bitmap: {:?}", bitmap.w, bitmap.h, r));
+            }
+            let (w, h) = ((r.x2 - r.x1) as usize, (r.y2 - r.y1) as usize);
+            match *style {
+                s::RedactionStyle::Fill { ref color } => {
+                    let color = color.clone().to_u32_bgra().map_err(|e| nerror!(::ErrorKind::InvalidNodeParams, "Invalid fill color: {:?}", e))?;
+                    unsafe {
+                        if !ffi::flow_bitmap_bgra_fill_rect(c.flow_c(), bitmap as *mut BitmapBgra, r.x1, r.y1, r.x2, r.y2, color) {
+                            return Err(cerror!(c, "Failed to fill rectangle"));
+                        }
+                    }
+                }
+                s::RedactionStyle::Pixelate { block } => {
+                    let mut pixels = read_region(bitmap, r);
+                    pixelate(&mut pixels, w, h, block as usize);
+                    write_region(bitmap, r, &pixels);
+                }
+                s::RedactionStyle::Blur { sigma } => {
+                    let kernel = gaussian_kernel(sigma);
+                    let mut pixels = read_region(bitmap, r);
+                    convolve(&mut pixels, w, h, &kernel, true);
+                    convolve(&mut pixels, w, h, &kernel, false);
+                    write_region(bitmap, r, &pixels);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_reorient() {
+    let r = s::RedactRegion { x1: 10, y1: 20, x2: 30, y2: 25 };
+    assert_eq!(reorient(r, Reorientation::FlipH, 100, 50), s::RedactRegion { x1: 70, y1: 20, x2: 90, y2: 25 });
+    assert_eq!(reorient(r, Reorientation::FlipV, 100, 50), s::RedactRegion { x1: 10, y1: 25, x2: 30, y2: 30 });
+    assert_eq!(reorient(r, Reorientation::Transpose, 100, 50), s::RedactRegion { x1: 20, y1: 10, x2: 25, y2: 30 });
+}
+
+#[test]
+fn test_scale_region_rounds_outward() {
+    let r = s::RedactRegion { x1: 10, y1: 20, x2: 30, y2: 25 };
+    assert_eq!(scale_region(r, (100, 50), (100, 50)), r);
+    assert_eq!(scale_region(r, (100, 50), (50, 25)), s::RedactRegion { x1: 5, y1: 10, x2: 15, y2: 13 });
+    // Even a one pixel region keeps covering a pixel
+    let dot = s::RedactRegion { x1: 7, y1: 7, x2: 8, y2: 8 };
+    assert_eq!(scale_region(dot, (16, 16), (2, 2)), s::RedactRegion { x1: 0, y1: 0, x2: 1, y2: 1 });
+}
+
+#[test]
+fn test_pixelate_and_blur() {
+    // A 3x2 region holding 0..6
+    let mut pixels = (0..6).map(|v| [v as f32; 4]).collect::<Vec<[f32; 4]>>();
+    pixelate(&mut pixels, 3, 2, 2);
+    // Blocks are {0,1,3,4} and the partial column {2,5}
+    assert_eq!(pixels.iter().map(|p| p[0]).collect::<Vec<f32>>(), vec![2f32, 2f32, 3.5f32, 2f32, 2f32, 3.5f32]);
+
+    let kernel = gaussian_kernel(1f32);
+    assert_eq!(kernel.len(), 7);
+    assert!((kernel.iter().sum::<f32>() - 1f32).abs() < 1e-5);
+
+    let mut flat = vec![[100f32; 4]; 12];
+    convolve(&mut flat, 4, 3, &kernel, true);
+    convolve(&mut flat, 4, 3, &kernel, false);
+    assert!(flat.iter().all(|p| (p[0] - 100f32).abs() < 1e-3));
+}
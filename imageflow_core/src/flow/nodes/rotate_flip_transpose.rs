@@ -1,4 +1,5 @@
 use super::internal_prelude::*;
+use imageflow_helpers::colors;
 
 pub static FLIP_V_PRIMITIVE: FlipVerticalMutNodeDef = FlipVerticalMutNodeDef{} ;
 pub static FLIP_H_PRIMITIVE: FlipHorizontalMutNodeDef = FlipHorizontalMutNodeDef{};
@@ -11,6 +12,8 @@ pub static ROTATE_180: Rotate180Def = Rotate180Def{};
 pub static ROTATE_270: Rotate270Def = Rotate270Def{};
 pub static TRANSPOSE: TransposeDef = TransposeDef{};
 pub static TRANSPOSE_MUT: TransposeMutDef = TransposeMutDef{};
+pub static ROTATE: RotateDef = RotateDef{};
+pub static ROTATE_RENDER: RotateRenderDef = RotateRenderDef{};
 
 
 
@@ -186,7 +189,7 @@ impl NodeDef for FlipHorizontalMutNodeDef{
 }
 impl NodeDefMutateBitmap for FlipHorizontalMutNodeDef{
     fn fqn(&self) -> &'static str{
-        "imazen.flip_vertical_mutate"
+        "imazen.flip_horizontal_mutate"
     }
     fn mutate(&self, c: &Context, bitmap: &mut BitmapBgra,  p: &NodeParams) -> Result<()>{
         unsafe {
@@ -273,3 +276,272 @@ impl NodeDefOneInputExpand for Rotate180Def {
         Ok(())
     }
 }
+
+
+/// Returns the number of counter-clockwise quarter turns if `degrees` is (nearly) a multiple of 90
+pub fn quarter_turns(degrees: f32) -> Option<i32> {
+    let normalized = ((degrees as f64 % 360f64) + 360f64) % 360f64;
+    let turns = (normalized / 90f64).round();
+    if (normalized - turns * 90f64).abs() < 0.001 {
+        Some(turns as i32 % 4)
+    } else {
+        None
+    }
+}
+
+/// The canvas size required to hold a w x h image rotated by `degrees`
+fn rotated_size(w: i32, h: i32, degrees: f32, expand_canvas: bool) -> (i32, i32) {
+    if !expand_canvas {
+        return (w, h);
+    }
+    let radians = (degrees as f64).to_radians();
+    let (sin, cos) = (radians.sin().abs(), radians.cos().abs());
+    // Shave off a little so that floating-point error doesn't add a pixel at right angles
+    let new_w = (w as f64 * cos + h as f64 * sin - 0.001).ceil().max(1f64);
+    let new_h = (w as f64 * sin + h as f64 * cos - 0.001).ceil().max(1f64);
+    (new_w as i32, new_h as i32)
+}
+
+/// Shared by Rotate and the render node it expands into, which carries the same params
+fn validate_rotate(p: &NodeParams) -> Result<()> {
+    if let &NodeParams::Json(s::Node::Rotate { degrees, ref background, .. }) = p {
+        if !degrees.is_finite() {
+            return Err(nerror!(::ErrorKind::InvalidNodeParams, "Rotate degrees must be a finite number, got {}", degrees));
+        }
+        background.to_color_32().map_err(|e| nerror!(::ErrorKind::InvalidNodeParams, "Invalid Rotate background color: {:?}", e))?;
+        Ok(())
+    } else {
+        Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Rotate, got {:?}", p))
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct RotateDef;
+impl NodeDef for RotateDef{
+    fn as_one_input_expand(&self) -> Option<&NodeDefOneInputExpand>{
+        Some(self)
+    }
+}
+impl NodeDefOneInputExpand for RotateDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.rotate"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        validate_rotate(p)
+    }
+    fn estimate(&self, p: &NodeParams, input: FrameEstimate) -> Result<FrameEstimate> {
+        if let &NodeParams::Json(s::Node::Rotate { degrees, expand_canvas, .. }) = p {
+            input.map_frame(|info| {
+                match quarter_turns(degrees) {
+                    Some(0) | Some(2) => Ok(info),
+                    Some(_) if expand_canvas || info.w == info.h => Ok(FrameInfo { w: info.h, h: info.w, ..info }),
+                    _ => {
+                        let (w, h) = rotated_size(info.w, info.h, degrees, expand_canvas);
+                        Ok(FrameInfo { w: w, h: h, fmt: PixelFormat::Bgra32 })
+                    }
+                }
+            })
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Rotate, got {:?}", p))
+        }
+    }
+
+    fn expand(&self, ctx: &mut OpCtxMut, ix: NodeIndex, p: NodeParams, parent: FrameInfo) -> Result<()> {
+        if let NodeParams::Json(s::Node::Rotate { degrees, background, filter, expand_canvas }) = p {
+            // Right angles are lossless; don't resample them
+            let lossless: Option<Vec<Node>> = match quarter_turns(degrees) {
+                Some(0) => Some(vec![]),
+                Some(2) => Some(vec![Node::n(&ROTATE_180, NodeParams::None)]),
+                Some(1) if expand_canvas || parent.w == parent.h => Some(vec![Node::n(&ROTATE_90, NodeParams::None)]),
+                Some(3) if expand_canvas || parent.w == parent.h => Some(vec![Node::n(&ROTATE_270, NodeParams::None)]),
+                _ => None
+            };
+            if let Some(nodes) = lossless {
+                ctx.replace_node(ix, nodes);
+                return Ok(());
+            }
+
+            let (w, h) = rotated_size(parent.w, parent.h, degrees, expand_canvas);
+            let canvas_params = s::Node::CreateCanvas {
+                w: w as usize,
+                h: h as usize,
+                format: s::PixelFormat::Bgra32,
+                color: background.clone(),
+            };
+            let render_params = s::Node::Rotate {
+                degrees: degrees,
+                background: background,
+                filter: filter,
+                expand_canvas: expand_canvas,
+            };
+            let canvas = ctx.graph
+                .add_node(Node::n(&CREATE_CANVAS,
+                                  NodeParams::Json(canvas_params)));
+            let render = ctx.graph
+                .add_node(Node::n(&ROTATE_RENDER, NodeParams::Json(render_params)));
+            ctx.graph.add_edge(canvas, render, EdgeKind::Canvas).unwrap();
+            ctx.replace_node_with_existing(ix, render);
+            Ok(())
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Rotate, got {:?}", p))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RotateRenderDef;
+
+impl NodeDef for RotateRenderDef {
+    fn as_one_input_one_canvas(&self) -> Option<&NodeDefOneInputOneCanvas> {
+        Some(self)
+    }
+}
+
+impl NodeDefOneInputOneCanvas for RotateRenderDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.rotate_to_canvas"
+    }
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        validate_rotate(p)
+    }
+
+    fn render(&self, c: &Context, canvas: &mut BitmapBgra, input: &mut BitmapBgra, p: &NodeParams) -> Result<()> {
+        if let &NodeParams::Json(s::Node::Rotate { degrees, ref background, filter, .. }) = p {
+            if input.fmt.bytes() != 4 || canvas.fmt.bytes() != 4 {
+                return Err(nerror!(::ErrorKind::InvalidNodeConnections, "Rotate can only operate on Rgb32 and Rgba32 bitmaps. Input pixel format {:?}. Canvas pixel format {:?}.", input.fmt, canvas.fmt));
+            }
+            let background = background.clone().to_u32_bgra()
+                .map_err(|e| nerror!(::ErrorKind::InvalidNodeParams, "Invalid Rotate background color: {:?}", e))?;
+
+            unsafe {
+                let details = ::ffi::flow_interpolation_details_create_from(c.flow_c(), filter.unwrap_or(s::Filter::Robidoux));
+                if details.is_null() {
+                    return Err(cerror!(c, "Failed to create interpolation details"));
+                }
+                let result = rotate_into(&*details, input, canvas, degrees, background);
+                ::ffi::flow_interpolation_details_destroy(c.flow_c(), details);
+                result
+            }
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Rotate, got {:?}", p))
+        }
+    }
+}
+
+/// Premultiplied linear-light [b, g, r, a] for a BGRA pixel
+fn to_premultiplied_linear(lut: &[f32], pixel: &[u8], has_alpha: bool) -> [f32; 4] {
+    let a = if has_alpha { pixel[3] as f32 / 255f32 } else { 1f32 };
+    [lut[pixel[0] as usize] * a, lut[pixel[1] as usize] * a, lut[pixel[2] as usize] * a, a]
+}
+
+/// Inverse-maps each canvas pixel into the input and resamples it with the given filter.
+/// Taps falling outside the input take the background color, which anti-aliases the edges.
+fn rotate_into(details: &::ffi::InterpolationDetails, input: &BitmapBgra, canvas: &mut BitmapBgra, degrees: f32, background: u32) -> Result<()> {
+    let to_linear = (0..256).map(|v| colors::srgb_to_linear(v as f32 / 255f32)).collect::<Vec<f32>>();
+    let to_srgb = (0..4096).map(|v| (colors::linear_to_srgb(v as f32 / 4095f32) * 255f32).round() as u8).collect::<Vec<u8>>();
+
+    let bg_bytes = [background as u8, (background >> 8) as u8, (background >> 16) as u8, (background >> 24) as u8];
+    let bg = to_premultiplied_linear(&to_linear, &bg_bytes, true);
+    let input_has_alpha = input.fmt == PixelFormat::Bgra32;
+
+    let radius = details.support().max(0.5f64);
+    let radians = (degrees as f64).to_radians();
+    let (sin, cos) = (radians.sin(), radians.cos());
+    let (in_w, in_h) = (input.w as i32, input.h as i32);
+    let (in_cx, in_cy) = (input.w as f64 / 2f64, input.h as f64 / 2f64);
+    let (out_cx, out_cy) = (canvas.w as f64 / 2f64, canvas.h as f64 / 2f64);
+
+    let src: &[u8] = unsafe { slice::from_raw_parts(input.pixels, (input.stride * input.h) as usize) };
+    let dst: &mut [u8] = unsafe { slice::from_raw_parts_mut(canvas.pixels, (canvas.stride * canvas.h) as usize) };
+
+    let taps = (radius.ceil() as usize) * 2 + 1;
+    let mut weights_x = vec![0f64; taps];
+    let mut weights_y = vec![0f64; taps];
+
+    for (y, row) in dst.chunks_mut(canvas.stride as usize).take(canvas.h as usize).enumerate() {
+        let dy = y as f64 + 0.5f64 - out_cy;
+        for (x, pixel) in row.chunks_mut(4).take(canvas.w as usize).enumerate() {
+            let dx = x as f64 + 0.5f64 - out_cx;
+            let sx = dx * cos - dy * sin + in_cx - 0.5f64;
+            let sy = dx * sin + dy * cos + in_cy - 0.5f64;
+
+            let x1 = (sx - radius).ceil() as i32;
+            let y1 = (sy - radius).ceil() as i32;
+            let count_x = (((sx + radius).floor() as i32 - x1 + 1).max(0) as usize).min(taps);
+            let count_y = (((sy + radius).floor() as i32 - y1 + 1).max(0) as usize).min(taps);
+
+            let mut sum = [0f64; 4];
+            let mut weight_sum = 0f64;
+
+            if x1 + (count_x as i32) <= 0 || y1 + (count_y as i32) <= 0 || x1 >= in_w || y1 >= in_h {
+                // Entirely outside the source
+                pixel.copy_from_slice(&bg_bytes);
+                continue;
+            }
+
+            for i in 0..count_x {
+                weights_x[i] = details.weight((x1 + i as i32) as f64 - sx);
+            }
+            for j in 0..count_y {
+                weights_y[j] = details.weight((y1 + j as i32) as f64 - sy);
+            }
+            for j in 0..count_y {
+                let src_y = y1 + j as i32;
+                for i in 0..count_x {
+                    let src_x = x1 + i as i32;
+                    let w = weights_x[i] * weights_y[j];
+                    if w == 0f64 {
+                        continue;
+                    }
+                    let value = if src_x < 0 || src_y < 0 || src_x >= in_w || src_y >= in_h {
+                        bg
+                    } else {
+                        let offset = src_y as usize * input.stride as usize + src_x as usize * 4;
+                        to_premultiplied_linear(&to_linear, &src[offset..offset + 4], input_has_alpha)
+                    };
+                    for ch in 0..4 {
+                        sum[ch] += value[ch] as f64 * w;
+                    }
+                    weight_sum += w;
+                }
+            }
+            if weight_sum == 0f64 {
+                pixel.copy_from_slice(&bg_bytes);
+                continue;
+            }
+            let alpha = (sum[3] / weight_sum).max(0f64).min(1f64);
+            for ch in 0..3 {
+                let linear = if alpha > 0f64 { sum[ch] / weight_sum / alpha } else { 0f64 };
+                pixel[ch] = to_srgb[(linear.max(0f64).min(1f64) * 4095f64).round() as usize];
+            }
+            pixel[3] = (alpha * 255f64).round() as u8;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rotated_size() {
+    assert_eq!(rotated_size(400, 300, 90f32, true), (300, 400));
+    assert_eq!(rotated_size(400, 300, -270f32, true), (300, 400));
+    assert_eq!(rotated_size(400, 300, 180f32, true), (400, 300));
+    assert_eq!(rotated_size(100, 100, 45f32, true), (142, 142));
+    assert_eq!(rotated_size(400, 300, 30f32, false), (400, 300));
+    assert_eq!(quarter_turns(-90f32), Some(3));
+    assert_eq!(quarter_turns(450f32), Some(1));
+    assert_eq!(quarter_turns(1.5f32), None);
+}
+
+#[test]
+fn test_rotate_render_validates_params() {
+    let params = |degrees: f32, color: &str| NodeParams::Json(s::Node::Rotate {
+        degrees: degrees,
+        background: s::Color::Srgb(s::ColorSrgb::Hex(color.to_owned())),
+        filter: None,
+        expand_canvas: true,
+    });
+    assert!(NodeDef::validate_params(&ROTATE_RENDER, &params(30f32, "ffffff")).is_ok());
+    assert!(NodeDef::validate_params(&ROTATE_RENDER, &params(::std::f32::NAN, "ffffff")).is_err());
+    assert!(NodeDef::validate_params(&ROTATE_RENDER, &params(30f32, "nope")).is_err());
+    assert!(NodeDef::validate_params(&ROTATE_RENDER, &NodeParams::None).is_err());
+}
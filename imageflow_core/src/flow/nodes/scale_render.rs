@@ -7,6 +7,101 @@ pub static SCALE: ScaleDef = ScaleDef{};
 
 pub static DRAW_IMAGE_EXACT: DrawImageDef = DrawImageDef{};
 //pub static SCALE_1D: Render1DDef  =Render1DDef{};
+use ::imageflow_helpers::timeywimey;
+use std::sync::mpsc;
+
+/// Bands shorter than this cost more in setup (filter weights, row buffers) than they save
+const MIN_BAND_ROWS: u32 = 32;
+
+/// One band of output rows, scaled on a helper thread with a context sharing the job's settings
+struct ScaleBand {
+    input: *mut BitmapBgra,
+    canvas: *mut BitmapBgra,
+    info: ffi::Scale2dRenderToCanvas1d,
+    from_row: u32,
+    to_row: u32,
+    c: Box<Context>,
+}
+
+// Bands write disjoint canvas rows, only read the input, and are joined before scale2d_render1d returns
+unsafe impl Send for ScaleBand {}
+
+/// The band's context, to be handed back, with the CPU time the band took
+struct BandOutcome {
+    c: Box<Context>,
+    cpu_ns: u64,
+    result: Result<()>,
+}
+
+unsafe impl Send for BandOutcome {}
+
+fn scale_rows(c: &Context, input: *mut BitmapBgra, canvas: *mut BitmapBgra, info: &ffi::Scale2dRenderToCanvas1d, from_row: u32, to_row: u32) -> Result<()> {
+    unsafe {
+        if ::ffi::flow_node_execute_scale2d_render1d_rows(c.flow_c(), input, canvas, info as *const ffi::Scale2dRenderToCanvas1d, from_row, to_row) {
+            Ok(())
+        } else {
+            Err(cerror!(c, "Failed to execute Scale2D:  "))
+        }
+    }
+}
+
+impl ScaleBand {
+    fn run(self) -> BandOutcome {
+        let cpu_start = timeywimey::thread_cpu_ns();
+        let now = time::precise_time_ns();
+        let result = scale_rows(&self.c, self.input, self.canvas, &self.info, self.from_row, self.to_row);
+        let wall_ns = time::precise_time_ns() - now;
+        BandOutcome { c: self.c, cpu_ns: timeywimey::thread_cpu_ns_since(cpu_start).unwrap_or(wall_ns), result: result }
+    }
+}
+
+/// Scales `input` into the `info` rectangle of `canvas`, splitting the output rows into bands
+/// across up to `c.max_resample_threads` threads. The calling thread scales the first band;
+/// the others go to the context's helper threads.
+pub fn scale2d_render1d(c: &Context, input: *mut BitmapBgra, canvas: *mut BitmapBgra, info: &ffi::Scale2dRenderToCanvas1d) -> Result<()> {
+    let threads = ::std::cmp::max(1, c.max_resample_threads) as u32;
+    let band_rows = ::std::cmp::max(MIN_BAND_ROWS, (info.h + threads - 1) / threads);
+    let bands = (info.h + band_rows - 1) / band_rows;
+    if bands < 2 {
+        return scale_rows(c, input, canvas, info, 0, info.h);
+    }
+    let pool = c.helper_thread_pool(bands as usize - 1);
+    let contexts = c.take_helper_contexts(bands as usize - 1).map_err(|e| e.at(here!()))?;
+    let (tx, rx) = mpsc::channel();
+    for (index, helper) in contexts.into_iter().enumerate() {
+        let from_row = (index as u32 + 1) * band_rows;
+        let band = ScaleBand {
+            input: input,
+            canvas: canvas,
+            info: *info,
+            from_row: from_row,
+            to_row: ::std::cmp::min(info.h, from_row + band_rows),
+            c: helper,
+        };
+        let tx = tx.clone();
+        pool.execute(move || {
+            let _ = tx.send(band.run());
+        });
+    }
+    drop(tx);
+    let mut result = scale_rows(c, input, canvas, info, 0, band_rows);
+    // Every band must finish before the bitmaps can be touched again, even if one failed;
+    // a band that panics drops its sender unused
+    let outcomes = rx.iter().collect::<Vec<BandOutcome>>();
+    if outcomes.len() + 1 != bands as usize && result.is_ok() {
+        result = Err(nerror!(::ErrorKind::InternalError, "{} Scale2D bands panicked on helper threads", bands as usize - 1 - outcomes.len()));
+    }
+    let mut helpers = Vec::with_capacity(outcomes.len());
+    for outcome in outcomes {
+        c.add_helper_thread_ns(outcome.cpu_ns);
+        if result.is_ok() {
+            result = outcome.result;
+        }
+        helpers.push(outcome.c);
+    }
+    c.return_helper_contexts(helpers);
+    result
+}
 
 
 #[derive(Debug,Clone)]
@@ -227,6 +322,30 @@ impl NodeDef for DrawImageDef {
     fn as_one_input_one_canvas(&self) -> Option<&NodeDefOneInputOneCanvas> {
         Some(self)
     }
+
+    fn estimate_cost(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<CostEstimate> {
+        let input = match ctx.first_parent_frame_info_some(ix) {
+            Some(info) => info,
+            None => return Ok(CostEstimate::Impossible)
+        };
+        if let NodeParams::Json(s::Node::DrawImageExact { w, h, hints, blend, .. }) = ctx.weight(ix).params {
+            let filter = if w > input.w as u32 || h > input.h as u32 {
+                hints.and_then(|h| h.up_filter).unwrap_or(s::Filter::Ginseng)
+            } else {
+                hints.and_then(|h| h.down_filter).unwrap_or(s::Filter::Robidoux)
+            };
+            let k = &ctx.job.cost_coefficients;
+            let mut est = ::flow::cost::resample_cost(k, input, w, h, filter);
+            if blend != Some(s::CompositingMode::Overwrite) {
+                if let CostEstimate::Some(ref mut info) = est {
+                    info.wall_ns += (w as f64 * h as f64 * k.blend_ns_per_pixel).round() as u64;
+                }
+            }
+            Ok(est)
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need DrawImageExact, got {:?}", ctx.weight(ix).params))
+        }
+    }
 }
 
 impl NodeDefOneInputOneCanvas for DrawImageDef {
@@ -261,7 +380,8 @@ impl NodeDefOneInputOneCanvas for DrawImageDef {
 
             let floatspace = hints.and_then(|h| h.scaling_colorspace).unwrap_or(s::ScalingFloatspace::Linear); //  if downscaling { ffi::Floatspace::Linear} else {ffi::Floatspace::Srgb}
 
-            let compose = blend.unwrap_or(::imageflow_types::CompositingMode::Compose) == s::CompositingMode::Compose;
+            let mode = blend.unwrap_or(::imageflow_types::CompositingMode::Compose);
+            let compose = mode == s::CompositingMode::Compose;
 
             if canvas.compositing_mode == ::ffi::BitmapCompositingMode::ReplaceSelf && compose{
                 canvas.compositing_mode = ::ffi::BitmapCompositingMode::BlendWithSelf;
@@ -277,14 +397,11 @@ impl NodeDefOneInputOneCanvas for DrawImageDef {
                 scale_in_colorspace: ::ffi::Floatspace::from(floatspace)
             };
 
-            unsafe {
-                if !::ffi::flow_node_execute_scale2d_render1d(c.flow_c(),
-                                                              input, canvas, &ffi_struct as *const ffi::Scale2dRenderToCanvas1d) {
-                    return Err(cerror!(c, "Failed to execute Scale2D:  "));
-                }
+            if !compose && mode != s::CompositingMode::Overwrite {
+                return super::compositing::render_blended(c, canvas, input, &ffi_struct, mode).map_err(|e| e.at(here!()));
             }
 
-            Ok(())
+            scale2d_render1d(c, input, canvas, &ffi_struct).map_err(|e| e.at(here!()))
         } else {
             Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Resample2D, got {:?}",p))
         }
@@ -300,6 +417,23 @@ impl NodeDef for Scale2dDef {
     fn as_one_input_one_canvas(&self) -> Option<&NodeDefOneInputOneCanvas> {
         Some(self)
     }
+
+    fn estimate_cost(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<CostEstimate> {
+        let input = match ctx.first_parent_frame_info_some(ix) {
+            Some(info) => info,
+            None => return Ok(CostEstimate::Impossible)
+        };
+        if let NodeParams::Json(s::Node::Resample2D { w, h, down_filter, up_filter, .. }) = ctx.weight(ix).params {
+            let filter = if w > input.w as u32 || h > input.h as u32 {
+                up_filter
+            } else {
+                down_filter
+            };
+            Ok(::flow::cost::resample_cost(&ctx.job.cost_coefficients, input, w, h, filter.unwrap_or(s::Filter::Robidoux)))
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Resample2D, got {:?}", ctx.weight(ix).params))
+        }
+    }
 }
 
 impl NodeDefOneInputOneCanvas for Scale2dDef {
@@ -351,15 +485,7 @@ impl NodeDefOneInputOneCanvas for Scale2dDef {
                 }
             };
 
-            unsafe {
-                //preconditions
-                if !::ffi::flow_node_execute_scale2d_render1d(c.flow_c(),
-                                                              input, canvas, &ffi_struct as *const ffi::Scale2dRenderToCanvas1d) {
-                    return Err(cerror!(c, "Failed to execute Scale2D:  "));
-                }
-            }
-
-            Ok(())
+            scale2d_render1d(c, input, canvas, &ffi_struct).map_err(|e| e.at(here!()))
         } else {
             Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Resample2D, got {:?}",p))
         }
@@ -0,0 +1,164 @@
+use super::internal_prelude::*;
+use ::imageflow_riapi::sizing::{AspectRatio, PartialCropProvider, SalientCropProvider};
+
+pub static SMART_CROP: MutProtect<SmartCropMutNodeDef> = MutProtect{node: &SMART_CROP_MUTATE, fqn: "imazen.smart_crop"};
+pub static SMART_CROP_MUTATE: SmartCropMutNodeDef = SmartCropMutNodeDef{};
+
+/// Saliency is measured over a grid of at most this many cells along the longest side
+const SALIENCY_GRID_MAX: u32 = 64;
+
+/// Scores each cell of a grid laid over `bitmap` by mean luma gradient plus luma entropy.
+/// Returns the row-major weights with the grid width and height.
+pub fn saliency_grid(bitmap: &BitmapBgra) -> (Vec<f32>, usize, usize) {
+    let cell = cmp::max(1, (cmp::max(bitmap.w, bitmap.h) + SALIENCY_GRID_MAX - 1) / SALIENCY_GRID_MAX);
+    let grid_w = ((bitmap.w + cell - 1) / cell) as usize;
+    let grid_h = ((bitmap.h + cell - 1) / cell) as usize;
+
+    let bytes_pp = bitmap.fmt.bytes();
+    let has_alpha = bitmap.fmt == PixelFormat::Bgra32;
+    let luma = |x: u32, y: u32| -> i32 {
+        unsafe {
+            let p = bitmap.pixels.offset((y * bitmap.stride) as isize + (x as usize * bytes_pp) as isize);
+            let l = if bytes_pp == 1 {
+                *p as i32
+            } else {
+                (*p as i32 * 29 + *p.offset(1) as i32 * 150 + *p.offset(2) as i32 * 77) >> 8
+            };
+            // Transparent areas carry no detail
+            if has_alpha { l * *p.offset(3) as i32 / 255 } else { l }
+        }
+    };
+
+    let mut gradients = vec![0u64; grid_w * grid_h];
+    let mut histograms = vec![[0u32; 16]; grid_w * grid_h];
+    for y in 0..bitmap.h {
+        for x in 0..bitmap.w {
+            let l = luma(x, y);
+            let dx = if x + 1 < bitmap.w { (luma(x + 1, y) - l).abs() } else { 0 };
+            let dy = if y + 1 < bitmap.h { (luma(x, y + 1) - l).abs() } else { 0 };
+            let ix = (y / cell) as usize * grid_w + (x / cell) as usize;
+            gradients[ix] += (dx + dy) as u64;
+            histograms[ix][(l >> 4) as usize] += 1;
+        }
+    }
+
+    let weights = (0..grid_w * grid_h).map(|ix| {
+        let count = histograms[ix].iter().sum::<u32>() as f32;
+        if count == 0f32 {
+            return 0f32;
+        }
+        let entropy = histograms[ix].iter().filter(|&&n| n > 0).map(|&n| {
+            let p = n as f32 / count;
+            -p * p.log2()
+        }).sum::<f32>();
+        // Both terms fall roughly within 0..1
+        (gradients[ix] as f32 / count) / 255f32 + entropy / 4f32
+    }).collect::<Vec<f32>>();
+    (weights, grid_w, grid_h)
+}
+
+#[derive(Debug, Clone)]
+pub struct SmartCropMutNodeDef;
+
+impl SmartCropMutNodeDef {
+    fn est_validate(&self, p: &NodeParams, input_est: FrameEstimate) -> Result<FrameEstimate> {
+        if let &NodeParams::Json(s::Node::SmartCrop { w, h }) = p {
+            if w < 1 || h < 1 {
+                Err(nerror!(::ErrorKind::InvalidNodeParams, "Smart crop size {}x{} must be at least 1x1", w, h))
+            } else if let FrameEstimate::Some(input) = input_est {
+                if w > input.w as u32 || h > input.h as u32 {
+                    Err(nerror!(::ErrorKind::InvalidNodeParams, "Smart crop size {}x{} exceeds {}x{} bitmap", w, h, input.w, input.h))
+                } else {
+                    Ok(FrameEstimate::Some(FrameInfo {
+                        w: w as i32,
+                        h: h as i32,
+                        fmt: ffi::PixelFormat::from(input.fmt),
+                    }))
+                }
+            } else {
+                Ok(FrameEstimate::None)
+            }
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need SmartCrop, got {:?}", p))
+        }
+    }
+}
+
+impl NodeDef for SmartCropMutNodeDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.smart_crop_mutate"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInput, EdgesOut::Any))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        self.est_validate(p, FrameEstimate::None).map(|_| ()).map_err(|e| e.at(here!()))
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        let input_est = ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+        let p = &ctx.weight(ix).params;
+        self.est_validate(p, input_est).map_err(|e| e.at(here!()))
+    }
+
+    fn can_execute(&self) -> bool { true }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        let mut input = unsafe {
+            &mut *ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()).with_ctx_mut(ctx, ix))?
+        };
+        ctx.consume_parent_result(ix, EdgeKind::Input)?;
+
+        let _ = self.est_validate(&ctx.weight(ix).params, FrameEstimate::Some(input.frame_info())).map_err(|e| e.at(here!()))?;
+
+        if let &NodeParams::Json(s::Node::SmartCrop { w, h }) = &ctx.weight(ix).params {
+            let (weights, grid_w, grid_h) = saliency_grid(input);
+            let inner = AspectRatio::create(w as i32, h as i32).map_err(|e| FlowError::from_layout(e).at(here!()))?;
+            let outer = AspectRatio::create(input.w as i32, input.h as i32).map_err(|e| FlowError::from_layout(e).at(here!()))?;
+            let (x1, y1) = SalientCropProvider::new(weights, grid_w, grid_h).crop_origin(inner, outer)
+                .ok_or_else(|| nerror!(::ErrorKind::InvalidNodeParams, "Smart crop size {}x{} exceeds {}x{} bitmap", w, h, input.w, input.h))?;
+
+            unsafe {
+                let offset = input.stride as isize * y1 as isize +
+                    input.fmt.bytes() as isize * x1 as isize;
+                input.pixels = input.pixels.offset(offset);
+            }
+            input.w = w;
+            input.h = h;
+
+            Ok(NodeResult::Frame(input))
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need SmartCrop, got {:?}", &ctx.weight(ix).params))
+        }
+    }
+}
+
+#[test]
+fn test_saliency_grid() {
+    // A flat gray 128x64 image with a checkerboard in its right quarter
+    let (w, h) = (128u32, 64u32);
+    let mut pixels = vec![128u8; (w * h) as usize];
+    for y in 0..h {
+        for x in 96..w {
+            pixels[(y * w + x) as usize] = if (x + y) % 2 == 0 { 0 } else { 255 };
+        }
+    }
+    let bitmap = BitmapBgra {
+        w: w,
+        h: h,
+        stride: w,
+        pixels: pixels.as_mut_ptr(),
+        fmt: PixelFormat::Gray8,
+        matte_color: [0; 4],
+        compositing_mode: ::ffi::BitmapCompositingMode::ReplaceSelf,
+    };
+    let (weights, grid_w, grid_h) = saliency_grid(&bitmap);
+    assert_eq!((grid_w, grid_h), (64, 32));
+    assert_eq!(weights[0], 0f32);
+
+    let inner = AspectRatio::create(32, 64).unwrap();
+    let outer = AspectRatio::create(128, 64).unwrap();
+    let (x, y) = SalientCropProvider::new(weights, grid_w, grid_h).crop_origin(inner, outer).unwrap();
+    assert_eq!((x, y), (96, 0));
+}
@@ -0,0 +1,274 @@
+use super::internal_prelude::*;
+use ::codecs::CodecInstanceContainer;
+use ::io::IoProxy;
+
+pub static TILE_PYRAMID: TilePyramidDef = TilePyramidDef{};
+
+/// The [start, end) pixel ranges of each tile along one axis of a level
+fn tile_spans(size: u32, tile_size: u32, overlap: u32) -> Vec<(u32, u32)> {
+    (0..(size + tile_size - 1) / tile_size).map(|i| {
+        let start = i * tile_size;
+        (if i > 0 { start - cmp::min(start, overlap) } else { 0 },
+         cmp::min(size, start + tile_size + overlap))
+    }).collect()
+}
+
+/// The highest DeepZoom level, where the image is full size; level 0 is 1x1
+fn dzi_max_level(w: u32, h: u32) -> u32 {
+    let mut level = 0;
+    while (1u64 << level) < cmp::max(w, h) as u64 {
+        level += 1;
+    }
+    level
+}
+
+/// Averages each 2x2 block of `src` into `dst`, which must be ceil(w/2) x ceil(h/2) and share its format
+fn halve(src: &BitmapBgra, dst: &mut BitmapBgra) {
+    let bpp = src.fmt.bytes();
+    let weighted = src.fmt == PixelFormat::Bgra32;
+    for y in 0..dst.h {
+        for x in 0..dst.w {
+            let mut sum = [0u32; 4];
+            let mut weight = 0u32;
+            for sy in (y * 2)..cmp::min(src.h, y * 2 + 2) {
+                for sx in (x * 2)..cmp::min(src.w, x * 2 + 2) {
+                    let p = unsafe { slice::from_raw_parts(src.pixels.offset((sy * src.stride) as isize + (sx as usize * bpp) as isize), bpp) };
+                    // Weight color by alpha so transparent pixels don't bleed into their neighbors
+                    let w = if weighted { p[3] as u32 } else { 1 };
+                    for c in 0..cmp::min(bpp, 3) {
+                        sum[c] += p[c] as u32 * w;
+                    }
+                    if bpp > 3 {
+                        sum[3] += p[3] as u32;
+                    }
+                    weight += w;
+                }
+            }
+            let count = (cmp::min(src.h, y * 2 + 2) - y * 2) * (cmp::min(src.w, x * 2 + 2) - x * 2);
+            let out = unsafe { slice::from_raw_parts_mut(dst.pixels.offset((y * dst.stride) as isize + (x as usize * bpp) as isize), bpp) };
+            for c in 0..cmp::min(bpp, 3) {
+                out[c] = if weight > 0 { ((sum[c] + weight / 2) / weight) as u8 } else { 0 };
+            }
+            if bpp > 3 {
+                out[3] = ((sum[3] + count / 2) / count) as u8;
+            }
+        }
+    }
+}
+
+fn io_error(path: &Path, e: io::Error) -> FlowError {
+    nerror!(::ErrorKind::EncodingIoError, "Failed to write {}: {}", path.display(), e)
+}
+
+struct TileWriter<'a> {
+    c: &'a Context,
+    io_id: i32,
+    preset: &'a s::EncoderPreset,
+    decoders: &'a [i32],
+}
+
+impl<'a> TileWriter<'a> {
+    /// Encodes the given rectangle of `level` to its own file, closing the file afterwards
+    fn write(&self, level: &BitmapBgra, x1: u32, y1: u32, x2: u32, y2: u32, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).map_err(|e| io_error(dir, e))?;
+        }
+        let mut tile = BitmapBgra {
+            w: x2 - x1,
+            h: y2 - y1,
+            stride: level.stride,
+            pixels: unsafe { level.pixels.offset((y1 * level.stride) as isize + (x1 as usize * level.fmt.bytes()) as isize) },
+            fmt: level.fmt,
+            matte_color: level.matte_color,
+            compositing_mode: level.compositing_mode.clone(),
+        };
+        let io = IoProxy::file_with_mode(self.c, self.io_id, path, ::IoMode::WriteSeekable).map_err(|e| e.at(here!()))?;
+        let io_ptr = io.get_io_ptr();
+        let result = CodecInstanceContainer::create(self.c, io, self.io_id, s::IoDirection::Out)
+            .and_then(|mut codec| codec.write_frame(self.c, self.preset, &mut tile, self.decoders));
+        // Pyramids can have many thousands of tiles; don't hold their files open until the context is destroyed
+        unsafe {
+            ::ffi::flow_destroy(self.c.flow_c(), io_ptr as *const c_void, ptr::null(), 0);
+        }
+        result.map(|_| ()).map_err(|e| e.at(here!()))
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct TilePyramidDef;
+
+impl TilePyramidDef {
+    fn get(&self, p: &NodeParams) -> Result<(i32, s::TileLayout, u32, u32, s::EncoderPreset)> {
+        if let &NodeParams::Json(s::Node::TilePyramid { io_id, ref layout, tile_size, overlap, ref preset }) = p {
+            let (tile_size, overlap) = match *layout {
+                s::TileLayout::Dzi => (tile_size.unwrap_or(254), overlap.unwrap_or(1)),
+                s::TileLayout::Iiif { .. } => {
+                    if overlap.unwrap_or(0) != 0 {
+                        return Err(nerror!(::ErrorKind::InvalidNodeParams, "IIIF tiles cannot overlap"));
+                    }
+                    (tile_size.unwrap_or(512), 0)
+                }
+            };
+            if tile_size < 1 || overlap >= tile_size {
+                return Err(nerror!(::ErrorKind::InvalidNodeParams, "Tile size must be at least 1 and larger than the overlap; got {} and {}", tile_size, overlap));
+            }
+            Ok((io_id, layout.clone(), tile_size, overlap, preset.clone()))
+        } else {
+            Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need TilePyramid, got {:?}", p))
+        }
+    }
+}
+
+fn extension(preset: &s::EncoderPreset) -> &'static str {
+    match *preset {
+        s::EncoderPreset::LibjpegTurbo { .. } => "jpg",
+        s::EncoderPreset::Libpng { .. } => "png",
+        s::EncoderPreset::Gif => "gif",
+    }
+}
+
+impl NodeDef for TilePyramidDef {
+    fn fqn(&self) -> &'static str {
+        "imazen.tile_pyramid"
+    }
+    fn edges_required(&self, p: &NodeParams) -> Result<(EdgesIn, EdgesOut)> {
+        Ok((EdgesIn::OneInput, EdgesOut::None))
+    }
+
+    fn validate_params(&self, p: &NodeParams) -> Result<()> {
+        self.get(p).map_err(|e| e.at(here!())).map(|_| ())
+    }
+
+    fn estimate(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<FrameEstimate> {
+        ctx.frame_est_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))
+    }
+    fn can_execute(&self) -> bool {
+        true
+    }
+
+    fn execute(&self, ctx: &mut OpCtxMut, ix: NodeIndex) -> Result<NodeResult> {
+        let (io_id, layout, tile_size, overlap, preset) = self.get(&ctx.weight(ix).params).map_err(|e| e.at(here!()))?;
+        let input = ctx.bitmap_bgra_from(ix, EdgeKind::Input).map_err(|e| e.at(here!()))?;
+        let (w, h) = unsafe { ((*input).w, (*input).h) };
+        let ext = extension(&preset);
+
+        let descriptor_path = {
+            let codec = ctx.job.get_codec(io_id).map_err(|e| e.at(here!()))?;
+            let io = codec.get_encode_io().map_err(|e| e.at(here!()))?;
+            io.and_then(|io| io.path()).map(|p| p.to_path_buf())
+                .ok_or_else(|| nerror!(::ErrorKind::InvalidNodeParams, "TilePyramid io_id {} must be a Filename output", io_id))?
+        };
+        let stem = descriptor_path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let dzi_dir = descriptor_path.with_file_name(format!("{}_files", stem));
+        let iiif_dir = descriptor_path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+
+        let decoders = ctx.get_decoder_io_ids_and_indexes(ix).into_iter().map(|(io_id, ix)| io_id).collect::<Vec<i32>>();
+        let writer = TileWriter { c: ctx.c, io_id: io_id, preset: &preset, decoders: &decoders };
+
+        let dzi_top = dzi_max_level(w, h);
+        let mut scale_factors = Vec::new();
+        // Each level is produced from the one above it, which is then released
+        let mut level: *mut BitmapBgra = input;
+        let mut scale = 1u32;
+        loop {
+            let (lw, lh) = unsafe { ((*level).w, (*level).h) };
+            let columns = tile_spans(lw, tile_size, overlap);
+            let rows = tile_spans(lh, tile_size, overlap);
+            for (row, &(y1, y2)) in rows.iter().enumerate() {
+                for (column, &(x1, x2)) in columns.iter().enumerate() {
+                    let path = match layout {
+                        s::TileLayout::Dzi => dzi_dir.join(format!("{}", dzi_top - scale.trailing_zeros()))
+                            .join(format!("{}_{}.{}", column, row, ext)),
+                        s::TileLayout::Iiif { .. } => {
+                            let region = if x2 - x1 == lw && y2 - y1 == lh {
+                                "full".to_owned()
+                            } else {
+                                // In full-size coordinates, clipped to the image
+                                let (rx, ry) = (x1 * scale, y1 * scale);
+                                format!("{},{},{},{}", rx, ry, cmp::min(w - rx, (x2 - x1) * scale), cmp::min(h - ry, (y2 - y1) * scale))
+                            };
+                            iiif_dir.join(region).join(format!("{},", x2 - x1)).join("0").join(format!("default.{}", ext))
+                        }
+                    };
+                    writer.write(unsafe { &*level }, x1, y1, x2, y2, &path).map_err(|e| e.at(here!()))?;
+                }
+            }
+            scale_factors.push(scale);
+
+            let done = match layout {
+                s::TileLayout::Dzi => lw == 1 && lh == 1,
+                s::TileLayout::Iiif { .. } => columns.len() == 1 && rows.len() == 1,
+            };
+            if done {
+                break;
+            }
+            let next = unsafe { ::ffi::flow_bitmap_bgra_create(ctx.flow_c(), ((lw + 1) / 2) as i32, ((lh + 1) / 2) as i32, false, (*level).fmt) };
+            if next.is_null() {
+                return Err(cerror!(ctx.c, "Failed to allocate pyramid level"));
+            }
+            unsafe {
+                halve(&*level, &mut *next);
+                if level != input {
+                    ::ffi::flow_destroy(ctx.flow_c(), level as *const c_void, ptr::null(), 0);
+                }
+            }
+            level = next;
+            scale *= 2;
+        }
+        if level != input {
+            unsafe {
+                ::ffi::flow_destroy(ctx.flow_c(), level as *const c_void, ptr::null(), 0);
+            }
+        }
+
+        let (descriptor, mime, descriptor_ext) = match layout {
+            s::TileLayout::Dzi => (format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\"><Size Width=\"{}\" Height=\"{}\"/></Image>\n",
+                ext, overlap, tile_size, w, h), "application/xml", "dzi"),
+            s::TileLayout::Iiif { ref id } => (format!(
+                "{{\"@context\":\"http://iiif.io/api/image/2/context.json\",\"@id\":{},\"protocol\":\"http://iiif.io/api/image\",\"width\":{},\"height\":{},\"profile\":[\"http://iiif.io/api/image/2/level0.json\"],\"tiles\":[{{\"width\":{},\"scaleFactors\":[{}]}}]}}\n",
+                ::serde_json::to_string(id).unwrap(), w, h, tile_size,
+                scale_factors.iter().map(|s| s.to_string()).collect::<Vec<String>>().join(",")), "application/json", "json"),
+        };
+        {
+            let codec = ctx.job.get_codec(io_id).map_err(|e| e.at(here!()))?;
+            let io = codec.get_encode_io().map_err(|e| e.at(here!()))?.unwrap();
+            io.write_from_buffer(ctx.c, descriptor.as_bytes()).map_err(|e| e.at(here!()))?;
+        }
+
+        Ok(NodeResult::Encoded(s::EncodeResult {
+            w: w as i32,
+            h: h as i32,
+            preferred_mime_type: mime.to_owned(),
+            preferred_extension: descriptor_ext.to_owned(),
+            io_id: io_id,
+            bytes: s::ResultBytes::Elsewhere,
+        }))
+    }
+}
+
+#[test]
+fn test_tile_spans() {
+    assert_eq!(tile_spans(600, 254, 1), vec![(0, 255), (253, 509), (507, 600)]);
+    assert_eq!(tile_spans(512, 512, 0), vec![(0, 512)]);
+    assert_eq!(tile_spans(1, 254, 1), vec![(0, 1)]);
+    assert_eq!((dzi_max_level(1, 1), dzi_max_level(600, 300), dzi_max_level(1024, 10)), (0, 10, 10));
+}
+
+#[test]
+fn test_halve() {
+    let bitmap = |w: u32, h: u32, pixels: &mut Vec<u8>| BitmapBgra {
+        w: w,
+        h: h,
+        stride: w * 4,
+        pixels: pixels.as_mut_ptr(),
+        fmt: PixelFormat::Bgra32,
+        matte_color: [0; 4],
+        compositing_mode: ::ffi::BitmapCompositingMode::ReplaceSelf,
+    };
+    // An opaque red pixel and a transparent green one, then a lone opaque blue column
+    let mut src = vec![0, 0, 255, 255, 0, 255, 0, 0, 255, 0, 0, 255];
+    let mut dst = vec![0u8; 8];
+    halve(&bitmap(3, 1, &mut src), &mut bitmap(2, 1, &mut dst));
+    assert_eq!(dst, vec![0, 0, 255, 128, 255, 0, 0, 255]);
+}
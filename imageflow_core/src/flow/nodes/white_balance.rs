@@ -10,7 +10,7 @@ use super::internal_prelude::*;
 
 
 
-fn area_threshold(histogram: &[u64], total_pixels: u64, low_threshold: f64, high_threshold: f64) -> (usize, usize){
+pub fn area_threshold(histogram: &[u64], total_pixels: u64, low_threshold: f64, high_threshold: f64) -> (usize, usize){
     let mut low = 0;
     let mut high = histogram.len() - 1;
     let mut area = 0u64;
@@ -30,18 +30,17 @@ fn area_threshold(histogram: &[u64], total_pixels: u64, low_threshold: f64, high
             break;
         }
     }
-    eprintln!("{},{}", low,high);
     (low, high)
 }
 
-fn create_byte_mapping(low: usize, high: usize) -> Vec<u8>{
+pub fn create_byte_mapping(low: usize, high: usize) -> Vec<u8>{
     let scale = 255.0 / ((high - low) as f64);
 
     (0..256usize).map(|v| (v.saturating_sub(low) as f64 * scale).round().min(255f64).max(0f64) as u8).collect()
 }
 
 
-fn apply_mappings(bitmap: *mut BitmapBgra, map_red: &[u8], map_green: &[u8], map_blue: &[u8]) -> Result<()>{
+pub fn apply_mappings(bitmap: *mut BitmapBgra, map_red: &[u8], map_green: &[u8], map_blue: &[u8]) -> Result<()>{
 
     let input: &BitmapBgra = unsafe{ &*bitmap };
     let bytes: &mut [u8] = unsafe { slice::from_raw_parts_mut::<u8>(input.pixels, (input.stride * input.h) as usize) };
@@ -0,0 +1,344 @@
+use ::internal_prelude::works_everywhere::*;
+use ::flow::definitions::*;
+use ::flow::nodes;
+use petgraph::EdgeDirection;
+use petgraph::visit::EdgeRef;
+
+/// Below this ratio we don't ask the JPEG decoder to downscale; same default as IR4's decoder.min_precise_scaling_ratio
+const MIN_PRECISE_SCALING_RATIO: f64 = 2.1f64;
+
+/// An orientation op as a 2x2 matrix [a, b, c, d] acting on centered pixel coordinates
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Orientation([i8; 4]);
+
+const IDENTITY: Orientation = Orientation([1, 0, 0, 1]);
+const FLIP_H: Orientation = Orientation([-1, 0, 0, 1]);
+const FLIP_V: Orientation = Orientation([1, 0, 0, -1]);
+const TRANSPOSE: Orientation = Orientation([0, 1, 1, 0]);
+
+impl Orientation {
+    /// This op followed by `next`
+    fn then(self, next: Orientation) -> Orientation {
+        let (a, b) = (next.0, self.0);
+        Orientation([a[0] * b[0] + a[1] * b[2], a[0] * b[1] + a[1] * b[3],
+                     a[2] * b[0] + a[3] * b[2], a[2] * b[1] + a[3] * b[3]])
+    }
+
+    /// Mirrors the expansions in rotate_flip_transpose.rs
+    fn from_exif_flag(flag: i32) -> Orientation {
+        match flag {
+            2 => FLIP_H,
+            3 => FLIP_V.then(FLIP_H),
+            4 => FLIP_V,
+            5 => TRANSPOSE,
+            6 => FLIP_V.then(TRANSPOSE),
+            7 => FLIP_V.then(FLIP_H).then(TRANSPOSE),
+            8 => TRANSPOSE.then(FLIP_V),
+            _ => IDENTITY
+        }
+    }
+
+    fn exif_flag(self) -> i32 {
+        (1..9).find(|&flag| Orientation::from_exif_flag(flag) == self).unwrap_or(1)
+    }
+
+    fn swaps_axes(self) -> bool {
+        self.0[0] == 0
+    }
+
+    fn of(node: &Node) -> Option<Orientation> {
+        if node.is(&nodes::NO_OP) {
+            Some(IDENTITY)
+        } else if node.is(&nodes::FLIP_H) {
+            Some(FLIP_H)
+        } else if node.is(&nodes::FLIP_V) {
+            Some(FLIP_V)
+        } else if node.is(&nodes::TRANSPOSE) {
+            Some(TRANSPOSE)
+        } else if node.is(&nodes::ROTATE_90) {
+            Some(Orientation::from_exif_flag(8))
+        } else if node.is(&nodes::ROTATE_180) {
+            Some(Orientation::from_exif_flag(3))
+        } else if node.is(&nodes::ROTATE_270) {
+            Some(Orientation::from_exif_flag(6))
+        } else if node.is(&nodes::APPLY_ORIENTATION) {
+            match node.params {
+                NodeParams::Json(s::Node::ApplyOrientation { flag }) => Some(Orientation::from_exif_flag(flag)),
+                _ => None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+fn crop_of(node: &Node) -> Option<[u32; 4]> {
+    match node.params {
+        NodeParams::Json(s::Node::Crop { x1, y1, x2, y2 }) if node.is(&nodes::CROP) => Some([x1, y1, x2, y2]),
+        _ => None
+    }
+}
+
+fn resample_of(node: &Node) -> Option<(u32, u32)> {
+    match node.params {
+        NodeParams::Json(s::Node::Resample2D { w, h, .. }) if node.is(&nodes::SCALE) => Some((w, h)),
+        _ => None
+    }
+}
+
+/// `inner` applied to the output of `outer`, as one crop; None if `inner` isn't a valid crop of it
+fn merge_crops(outer: [u32; 4], inner: [u32; 4]) -> Option<[u32; 4]> {
+    let (w, h) = (outer[2].saturating_sub(outer[0]), outer[3].saturating_sub(outer[1]));
+    if inner[0] < inner[2] && inner[1] < inner[3] && inner[2] <= w && inner[3] <= h {
+        Some([outer[0] + inner[0], outer[1] + inner[1], outer[0] + inner[2], outer[1] + inner[3]])
+    } else {
+        None
+    }
+}
+
+/// How far, in output pixels, a downscaling filter reaches beyond an output pixel; enough for every filter we have
+const RESAMPLE_SUPPORT: u32 = 4;
+
+/// Widens `[start, end)` of an axis resampled from `src` to `dst` pixels by the filter's reach, then until both
+/// ends land on source pixel edges. Returns the widened range and the source range it is resampled from.
+fn widen_axis(src: u32, dst: u32, start: u32, end: u32) -> ([u32; 2], [u32; 2]) {
+    // Upscaling stretches the filter's reach in output pixels
+    let margin = RESAMPLE_SUPPORT * ::std::cmp::max(1, (dst + src - 1) / src) + 1;
+    let on_edge = |v: u32| (v as u64 * src as u64) % dst as u64 == 0;
+    let to_src = |v: u32| (v as u64 * src as u64 / dst as u64) as u32;
+    // 0 and dst are always on an edge
+    let mut a = start.saturating_sub(margin);
+    while !on_edge(a) {
+        a -= 1;
+    }
+    let mut b = ::std::cmp::min(end.saturating_add(margin), dst);
+    while !on_edge(b) {
+        b += 1;
+    }
+    ([a, b], [to_src(a), to_src(b)])
+}
+
+/// Lets us crop before resampling `from` to `to` and then cropping `crop`: returns the source region to crop
+/// first, the size to resample it to, and the crop that remains. The source region is wider than `crop` by the
+/// filter's reach, so every remaining pixel is resampled from the same source pixels as before.
+/// None unless that saves resampling some of the source.
+fn crop_before_resample(from: (u32, u32), to: (u32, u32), crop: [u32; 4]) -> Option<([u32; 4], (u32, u32), [u32; 4])> {
+    if crop[0] >= crop[2] || crop[1] >= crop[3] || crop[2] > to.0 || crop[3] > to.1 || from.0 == 0 || from.1 == 0 {
+        return None;
+    }
+    let (x, source_x) = widen_axis(from.0, to.0, crop[0], crop[2]);
+    let (y, source_y) = widen_axis(from.1, to.1, crop[1], crop[3]);
+    if (source_x[1] - source_x[0]) as u64 * (source_y[1] - source_y[0]) as u64 >= from.0 as u64 * from.1 as u64 {
+        return None;
+    }
+    Some(([source_x[0], source_y[0], source_x[1], source_y[1]],
+          (x[1] - x[0], y[1] - y[0]),
+          [crop[0] - x[0], crop[1] - y[0], crop[2] - x[0], crop[3] - y[0]]))
+}
+
+/// The smallest size we want the decoder to produce for a source image ultimately resampled to `to`
+fn downscale_hint(from: (i32, i32), to: (u32, u32)) -> Option<(i64, i64)> {
+    if to.0 == 0 || to.1 == 0 {
+        return None;
+    }
+    let ratio = (from.0 as f64 / to.0 as f64).min(from.1 as f64 / to.1 as f64);
+    if ratio > MIN_PRECISE_SCALING_RATIO {
+        Some(((from.0 as f64 * MIN_PRECISE_SCALING_RATIO / ratio).floor() as i64,
+              (from.1 as f64 * MIN_PRECISE_SCALING_RATIO / ratio).floor() as i64))
+    } else {
+        None
+    }
+}
+
+fn unexecuted(ctx: &OpCtxMut, ix: NodeIndex) -> bool {
+    ctx.weight(ix).result == NodeResult::None
+}
+
+/// The only node `ix` feeds, if it is also that node's only parent
+fn sole_child(ctx: &OpCtxMut, ix: NodeIndex) -> Option<NodeIndex> {
+    let children = ctx.graph.graph().edges_directed(ix, EdgeDirection::Outgoing).map(|e| (e.target(), *e.weight())).collect::<Vec<_>>();
+    if children.len() == 1 && children[0].1 == EdgeKind::Input &&
+        ctx.graph.graph().edges_directed(children[0].0, EdgeDirection::Incoming).count() == 1 {
+        Some(children[0].0)
+    } else {
+        None
+    }
+}
+
+fn has_one_input(ctx: &OpCtxMut, ix: NodeIndex) -> bool {
+    let parents = ctx.graph.graph().edges_directed(ix, EdgeDirection::Incoming).map(|e| *e.weight()).collect::<Vec<_>>();
+    parents == vec![EdgeKind::Input]
+}
+
+/// Replaces the definition and parameters of a not-yet-expanded node, keeping its place and stable id
+fn redefine(ctx: &mut OpCtxMut, ix: NodeIndex, def: &'static NodeDef, params: NodeParams) {
+    let weight = ctx.weight_mut(ix);
+    weight.def = def;
+    weight.params = params;
+    weight.frame_est = FrameEstimate::None;
+}
+
+/// Forgets the estimates of `ix` and of every node downstream of it
+fn invalidate_estimates_from(ctx: &mut OpCtxMut, ix: NodeIndex) {
+    let mut pending = vec![ix];
+    while let Some(next) = pending.pop() {
+        ctx.weight_mut(next).frame_est = FrameEstimate::None;
+        pending.extend(ctx.graph.graph().neighbors_directed(next, EdgeDirection::Outgoing));
+    }
+}
+
+/// Each pass makes at most one rewrite, since removing a node can move another to its index
+fn optimize_once(ctx: &mut OpCtxMut) -> Result<bool> {
+    for index in 0..ctx.graph.node_count() {
+        let ix = NodeIndex::new(index);
+        if !unexecuted(ctx, ix) || !has_one_input(ctx, ix) {
+            continue;
+        }
+        let child = sole_child(ctx, ix).filter(|&child| unexecuted(ctx, child));
+
+        if let Some(orientation) = Orientation::of(ctx.weight(ix)) {
+            if orientation == IDENTITY {
+                ctx.delete_node_and_snap_together(ix);
+                return Ok(true);
+            }
+            // Compose consecutive flips, rotations and transpositions into one; inverses cancel to identity
+            if let Some((child, next)) = child.and_then(|child| Orientation::of(ctx.weight(child)).map(|o| (child, o))) {
+                let flag = orientation.then(next).exif_flag();
+                redefine(ctx, ix, &nodes::APPLY_ORIENTATION, NodeParams::Json(s::Node::ApplyOrientation { flag: flag }));
+                ctx.delete_node_and_snap_together(child);
+                return Ok(true);
+            }
+        }
+
+        if let Some(outer) = crop_of(ctx.weight(ix)) {
+            if let Some((child, merged)) = child.and_then(|child| crop_of(ctx.weight(child)).and_then(|inner| merge_crops(outer, inner)).map(|m| (child, m))) {
+                redefine(ctx, ix, &nodes::CROP, NodeParams::Json(s::Node::Crop { x1: merged[0], y1: merged[1], x2: merged[2], y2: merged[3] }));
+                ctx.delete_node_and_snap_together(child);
+                return Ok(true);
+            }
+        }
+
+        // Crop first so we resample fewer pixels; the resample keeps its place between the two crops
+        if let (Some(to), Some(child)) = (resample_of(ctx.weight(ix)), child) {
+            if let (Some(crop), Some(from)) = (crop_of(ctx.weight(child)), ctx.first_parent_frame_info_some(ix)) {
+                if let Some((source, (w, h), rest)) = crop_before_resample((from.w as u32, from.h as u32), to, crop) {
+                    let resample = match ctx.weight(ix).params.clone() {
+                        NodeParams::Json(s::Node::Resample2D { down_filter, up_filter, scaling_colorspace, hints, .. }) => s::Node::Resample2D {
+                            w: w,
+                            h: h,
+                            down_filter: down_filter,
+                            up_filter: up_filter,
+                            scaling_colorspace: scaling_colorspace,
+                            hints: hints,
+                        },
+                        other => return Err(nerror!(::ErrorKind::NodeParamsMismatch, "Need Resample2D, got {:?}", other))
+                    };
+                    redefine(ctx, ix, &nodes::CROP, NodeParams::Json(s::Node::Crop { x1: source[0], y1: source[1], x2: source[2], y2: source[3] }));
+                    redefine(ctx, child, &nodes::SCALE, NodeParams::Json(resample));
+                    let last = ctx.graph.add_node(Node::n(&nodes::CROP, NodeParams::Json(s::Node::Crop { x1: rest[0], y1: rest[1], x2: rest[2], y2: rest[3] })));
+                    ctx.copy_edges_to(child, last, EdgeDirection::Outgoing);
+                    ctx.delete_child_edges_for(child);
+                    ctx.graph.add_edge(child, last, EdgeKind::Input).unwrap();
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Asks JPEG decoders to downscale during IDCT when all they feed is (reorientation and) a large downscale.
+/// Decoders given commands explicitly are left alone. This changes output pixels, so is opt-in.
+fn add_downscale_hints(ctx: &mut OpCtxMut) -> Result<()> {
+    for index in 0..ctx.graph.node_count() {
+        let ix = NodeIndex::new(index);
+        let io_id = match ctx.weight(ix).params {
+            NodeParams::Json(s::Node::Decode { io_id, commands: None }) if unexecuted(ctx, ix) => io_id,
+            _ => continue
+        };
+        // Walk down to the resample, tracking whether width and height trade places on the way
+        let mut current = ix;
+        let mut swapped = false;
+        let mut target = None;
+        while let Some(next) = sole_child(ctx, current).filter(|&next| unexecuted(ctx, next)) {
+            if let Some(orientation) = Orientation::of(ctx.weight(next)) {
+                swapped = swapped != orientation.swaps_axes();
+                current = next;
+            } else {
+                if let (Some(size), &NodeParams::Json(s::Node::Resample2D { scaling_colorspace, .. })) = (resample_of(ctx.weight(next)), &ctx.weight(next).params) {
+                    // Like IR4, stay gamma-correct unless resampling in sRGB was asked for
+                    target = Some((size, scaling_colorspace != Some(s::ScalingFloatspace::Srgb)));
+                }
+                break;
+            }
+        }
+        if let Some(((w, h), gamma_correct)) = target {
+            let info = ctx.job.get_image_info(io_id).map_err(|e| e.at(here!()))?;
+            if info.preferred_mime_type != "image/jpeg" {
+                continue;
+            }
+            let to = if swapped { (h, w) } else { (w, h) };
+            if let Some((width, height)) = downscale_hint((info.image_width, info.image_height), to) {
+                let hints = s::DecoderCommand::JpegDownscaleHints(s::JpegIDCTDownscaleHints {
+                    width: width,
+                    height: height,
+                    scale_luma_spatially: Some(gamma_correct),
+                    gamma_correct_for_srgb_during_spatial_luma_scaling: Some(gamma_correct),
+                });
+                ctx.weight_mut(ix).params = NodeParams::Json(s::Node::Decode { io_id: io_id, commands: Some(vec![hints]) });
+                // The decoder's frame, and everything estimated from it, is about to shrink
+                invalidate_estimates_from(ctx, ix);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites nodes that haven't been expanded or executed into cheaper equivalents:
+/// orientation ops are composed (cancelling inverses), adjacent crops are merged, crops move ahead of
+/// resampling, and (with `enable_jpeg_block_scaling`) JPEG decoders feeding a large downscale are told to scale
+/// during IDCT.
+pub fn graph_optimize(ctx: &mut OpCtxMut) -> Result<()> {
+    while optimize_once(ctx).map_err(|e| e.at(here!()))? {}
+    if ctx.c.enable_jpeg_block_scaling {
+        add_downscale_hints(ctx).map_err(|e| e.at(here!()))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_orientation_composition() {
+    let all = (1..9).map(Orientation::from_exif_flag).collect::<Vec<Orientation>>();
+    for (i, a) in all.iter().enumerate() {
+        assert_eq!(a.exif_flag(), i as i32 + 1);
+        // Every orientation has an inverse
+        assert!(all.iter().any(|b| a.then(*b) == IDENTITY));
+    }
+    assert_eq!(FLIP_H.then(FLIP_H), IDENTITY);
+    assert_eq!(FLIP_H.then(FLIP_V).exif_flag(), 3);
+    assert_eq!(Orientation::from_exif_flag(8).then(Orientation::from_exif_flag(6)), IDENTITY);
+    assert_eq!(Orientation::from_exif_flag(8).then(Orientation::from_exif_flag(8)).exif_flag(), 3);
+    assert!(Orientation::from_exif_flag(6).swaps_axes() && !FLIP_V.swaps_axes());
+}
+
+#[test]
+fn test_crop_rewrites() {
+    assert_eq!(merge_crops([10, 10, 110, 60], [5, 5, 50, 50]), Some([15, 15, 60, 60]));
+    assert_eq!(merge_crops([10, 10, 110, 60], [5, 5, 50, 51]), None);
+
+    // Widened by 5 output pixels each way, which already land on source pixel edges
+    assert_eq!(crop_before_resample((800, 600), (400, 300), [10, 20, 110, 220]),
+               Some(([10, 30, 230, 450], (110, 210), [5, 5, 105, 205])));
+    // 800 -> 300 only lands on a source edge every 3 output pixels
+    assert_eq!(crop_before_resample((800, 600), (300, 300), [10, 20, 110, 220]),
+               Some(([8, 30, 312, 450], (114, 210), [7, 5, 107, 205])));
+    // Upscaling widens by more output pixels
+    assert_eq!(widen_axis(100, 300, 100, 200), ([87, 213], [29, 71]));
+    assert_eq!(crop_before_resample((800, 600), (400, 300), [10, 20, 410, 220]), None);
+    // Nothing left to save once the margins reach the edges
+    assert_eq!(crop_before_resample((800, 600), (400, 300), [2, 2, 398, 298]), None);
+    assert_eq!(crop_before_resample((220, 420), (110, 210), [5, 5, 105, 205]), None);
+
+    assert_eq!(downscale_hint((4000, 3000), (400, 300)), Some((840, 630)));
+    assert_eq!(downscale_hint((800, 600), (400, 300)), None);
+}
@@ -0,0 +1,214 @@
+use ::std;
+use std::collections::HashMap;
+use ::internal_prelude::works_everywhere::*;
+use ::ffi::{self, BitmapBgra};
+use ::Context;
+use imageflow_helpers::hashing::hash_64;
+
+/// Copies of decoded and resampled frames, kept across the builds of one context and keyed by a hash of
+/// the input bytes and node parameters that produced them. The copies are bitmaps the context owns.
+pub struct FrameCache {
+    entries: HashMap<u64, CachedFrame>,
+    max_bytes: u64,
+    /// Hashes of the bytes of each input of the build executing now, by io_id
+    inputs: HashMap<i32, u64>,
+    clock: u64,
+    stats: s::FrameCacheStats,
+}
+
+struct CachedFrame {
+    bitmap: *mut BitmapBgra,
+    bytes: u64,
+    last_used: u64,
+}
+
+impl FrameCache {
+    pub fn new(max_bytes: u64) -> FrameCache {
+        FrameCache {
+            entries: HashMap::new(),
+            max_bytes: max_bytes,
+            inputs: HashMap::new(),
+            clock: 0,
+            stats: s::FrameCacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> s::FrameCacheStats {
+        s::FrameCacheStats {
+            entries: self.entries.len() as u64,
+            bytes: self.bytes(),
+            .. self.stats
+        }
+    }
+
+    fn bytes(&self) -> u64 {
+        self.entries.values().map(|e| e.bytes).sum::<u64>()
+    }
+
+    /// Evicts frames until those left fit in `max_bytes`
+    pub fn resize(&mut self, c: &Context, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+        self.evict_to(c, max_bytes);
+    }
+
+    /// Destroys every cached frame
+    pub fn clear(&mut self, c: &Context) {
+        self.evict_to(c, 0);
+    }
+
+    /// Hashes the inputs of the build about to execute; files by their path, length and modification time rather than
+    /// their bytes. Inputs that can't be read up front, like URLs, are left out, so nothing decoded from them is cached.
+    pub fn set_inputs(&mut self, io: &[s::IoObject]) {
+        self.inputs.clear();
+        for obj in io.iter().filter(|obj| obj.direction == s::IoDirection::In) {
+            if let Some(hash) = hash_input(&obj.io) {
+                self.inputs.insert(obj.io_id, hash);
+            }
+        }
+    }
+
+    /// Called once the build ends, so later calls can't match frames by io_id alone
+    pub fn forget_inputs(&mut self) {
+        self.inputs.clear();
+    }
+
+    pub fn input_hash(&self, io_id: i32) -> Option<u64> {
+        self.inputs.get(&io_id).cloned()
+    }
+
+    /// A copy of the frame cached under `key`, which the caller owns
+    pub fn get(&mut self, c: &Context, key: u64) -> Result<Option<*mut BitmapBgra>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let cached = match self.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.last_used = clock;
+                Some(entry.bitmap)
+            }
+            None => None
+        };
+        match cached {
+            Some(bitmap) => {
+                self.stats.hits += 1;
+                copy_bitmap(c, unsafe { &*bitmap }).map(Some).map_err(|e| e.at(here!()))
+            }
+            None => {
+                self.stats.misses += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Keeps a copy of `bitmap` under `key`, evicting the least recently used frames to make room.
+    /// Frames larger than the whole cache are not kept.
+    pub fn put(&mut self, c: &Context, key: u64, bitmap: &BitmapBgra) -> Result<()> {
+        let bytes = bitmap.stride as u64 * bitmap.h as u64;
+        if bytes > self.max_bytes || self.entries.contains_key(&key) {
+            return Ok(());
+        }
+        let max_bytes = self.max_bytes;
+        self.evict_to(c, max_bytes - bytes);
+        let copy = copy_bitmap(c, bitmap).map_err(|e| e.at(here!()))?;
+        self.clock += 1;
+        self.entries.insert(key, CachedFrame { bitmap: copy, bytes: bytes, last_used: self.clock });
+        Ok(())
+    }
+
+    fn evict_to(&mut self, c: &Context, max_bytes: u64) {
+        let mut bytes = self.bytes();
+        while bytes > max_bytes {
+            let oldest = match self.entries.iter().min_by_key(|&(_, e)| e.last_used) {
+                Some((&key, _)) => key,
+                None => return
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                unsafe {
+                    ffi::flow_destroy(c.flow_c(), entry.bitmap as *const c_void, ptr::null(), 0);
+                }
+                bytes -= entry.bytes;
+                self.stats.evictions += 1;
+            }
+        }
+    }
+}
+
+/// Keys a frame by what produced it; `what` names the kind of node and the parameters it ran with
+pub fn frame_key(what: &str, upstream: u64) -> u64 {
+    hash_64(format!("{} {:016x}", what, upstream).as_bytes())
+}
+
+fn hash_input(io: &s::IoEnum) -> Option<u64> {
+    match *io {
+        s::IoEnum::ByteArray(ref bytes) => Some(hash_64(bytes)),
+        s::IoEnum::Base64(ref text) | s::IoEnum::BytesHex(ref text) => Some(hash_64(text.as_bytes())),
+        // Reading the file here would read it twice, so it is known by its path, length and modification time
+        s::IoEnum::Filename(ref path) => {
+            let modified = std::fs::metadata(path).and_then(|meta| meta.modified().map(|time| (meta.len(), time)));
+            match modified {
+                Ok((len, time)) => match time.duration_since(std::time::UNIX_EPOCH) {
+                    Ok(since) => Some(hash_64(format!("file {} {} {}.{:09}", path, len, since.as_secs(), since.subsec_nanos()).as_bytes())),
+                    Err(_) => None
+                },
+                Err(_) => None
+            }
+        }
+        _ => None
+    }
+}
+
+/// A new bitmap with the pixels and compositing settings of `from`
+fn copy_bitmap(c: &Context, from: &BitmapBgra) -> Result<*mut BitmapBgra> {
+    unsafe {
+        let copy = ffi::flow_bitmap_bgra_create(c.flow_c(), from.w as i32, from.h as i32, false, from.fmt);
+        if copy.is_null() {
+            return Err(cerror!(c, "Failed to allocate a {}x{} frame cache copy", from.w, from.h));
+        }
+        let row_bytes = from.w as usize * from.fmt.bytes();
+        for row in 0..from.h as isize {
+            ptr::copy_nonoverlapping(from.pixels.offset(row * from.stride as isize),
+                                     (*copy).pixels.offset(row * (*copy).stride as isize),
+                                     row_bytes);
+        }
+        (*copy).compositing_mode = from.compositing_mode;
+        (*copy).matte_color = from.matte_color;
+        Ok(copy)
+    }
+}
+
+#[test]
+fn test_frame_cache_evicts_least_recently_used() {
+    let c = Context::create().unwrap();
+    let frame = |w: i32| unsafe { &*ffi::flow_bitmap_bgra_create(c.flow_c(), w, 10, true, ffi::PixelFormat::Bgra32) };
+    // Rows of 16 pixels are 64 bytes, so each frame is 640 bytes
+    let mut cache = FrameCache::new(1300);
+    cache.put(&c, 1, frame(16)).unwrap();
+    cache.put(&c, 2, frame(16)).unwrap();
+    assert!(cache.get(&c, 1).unwrap().is_some());
+    cache.put(&c, 3, frame(16)).unwrap();
+    assert!(cache.get(&c, 2).unwrap().is_none());
+    assert!(cache.get(&c, 1).unwrap().is_some());
+    assert!(cache.get(&c, 3).unwrap().is_some());
+    // Too large to keep at all
+    cache.put(&c, 4, frame(64)).unwrap();
+    assert_eq!(cache.stats(), s::FrameCacheStats { hits: 3, misses: 1, evictions: 1, entries: 2, bytes: 1280 });
+    cache.clear(&c);
+    assert_eq!(cache.stats().entries, 0);
+}
+
+#[test]
+fn test_files_are_hashed_by_length_and_modification_time() {
+    let path = std::env::temp_dir().join("imageflow_frame_cache_input.bin");
+    let io = s::IoEnum::Filename(path.to_str().unwrap().to_owned());
+    let write = |bytes: &[u8]| {
+        use std::io::Write;
+        std::fs::File::create(&path).and_then(|mut f| f.write_all(bytes)).unwrap();
+    };
+    write(b"first");
+    let first = hash_input(&io);
+    assert!(first.is_some());
+    assert_eq!(hash_input(&io), first);
+    write(b"second");
+    assert!(hash_input(&io) != first);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(hash_input(&io), None);
+}
@@ -89,6 +89,11 @@ impl IoProxy {
         }
     }
 
+    /// The file this reads or writes, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|p| p.as_path())
+    }
+
     pub fn get_io_ptr(&self) -> *mut ::ffi::ImageflowJobIo {
         self.classic
     }
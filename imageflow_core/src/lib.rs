@@ -48,6 +48,8 @@ extern crate core;
 extern crate chashmap;
 extern crate lcms2_sys;
 extern crate rgb;
+extern crate rusttype;
+extern crate threadpool;
 
 #[macro_use]
 pub mod errors;
@@ -56,6 +58,10 @@ pub use errors::*;
 
 
 mod json;
+mod cancellation;
+mod bitmap_pool;
+mod frame_cache;
+mod trace;
 mod flow;
 mod context_methods;
 mod context;
@@ -63,9 +69,12 @@ mod codecs;
 mod io;
 
 pub use context::{Context};
+pub use cancellation::CancellationToken;
+pub use bitmap_pool::{BitmapPool, BitmapPoolStats};
 pub use io::IoProxy;
 pub use ::ffi::{IoDirection, IoMode};
 pub use ::flow::definitions::Graph;
+pub use ::flow::cost::{filter_window, taps_per_pixel};
 pub use json::JsonResponse;
 pub use json::MethodRouter;
 // use std::ops::DerefMut;
@@ -1,4 +1,4 @@
-use flow::definitions::{Graph, Node, NodeParams, EdgeKind};
+use flow::definitions::{Graph, Node, NodeParams, NodeParamsInternal, EdgeKind};
 use flow::nodes;
 use internal_prelude::works_everywhere::*;
 use ::ffi;
@@ -53,14 +53,24 @@ impl GraphTranslator {
             node_id_map.insert(old_id.parse::<i32>().unwrap(), new_id);
         }
 
+        // Edge order doesn't survive expansion, so each Grid input passes through a node recording its position
+        let mut grid_inputs: HashMap<NodeIndex, u32> = HashMap::new();
         for edge in from.edges {
             let from_id = node_id_map[&edge.from];
-            let to_id = node_id_map[&edge.to];
+            let mut to_id = node_id_map[&edge.to];
             let new_edge_kind = match edge.kind {
                 s::EdgeKind::Input => EdgeKind::Input,
                 s::EdgeKind::Canvas => EdgeKind::Canvas,
             };
 
+            if new_edge_kind == EdgeKind::Input && g.node_weight(to_id).unwrap().is(&nodes::GRID) {
+                let position = grid_inputs.entry(to_id).or_insert(0);
+                let marker = g.add_node(Node::n(&nodes::GRID_INPUT, NodeParams::Internal(NodeParamsInternal::GridInput(*position))));
+                *position += 1;
+                g.add_edge(marker, to_id, EdgeKind::Input).unwrap();
+                to_id = marker;
+            }
+
             if let  Err(daggy::WouldCycle(_)) = g.add_edge(from_id, to_id, new_edge_kind) {
                 return Err(nerror!(ErrorKind::GraphCyclic));
             }
@@ -0,0 +1,55 @@
+use ::std;
+use std::collections::BTreeMap;
+use ::internal_prelude::works_everywhere::*;
+
+/// Collects the Chrome trace events of one job. Timestamps come from time::precise_time_ns,
+/// which every thread shares, so spans measured on worker threads line up with the rest.
+pub struct TraceRecorder {
+    start_ns: u64,
+    events: Vec<s::TraceEvent>,
+}
+
+impl TraceRecorder {
+    pub fn new() -> TraceRecorder {
+        TraceRecorder { start_ns: time::precise_time_ns(), events: Vec::new() }
+    }
+
+    pub fn record(&mut self, name: &str, cat: &str, tid: u32, start_ns: u64, end_ns: u64, args: BTreeMap<String, String>) {
+        // Spans that began before tracing did are clipped to its start
+        let start_ns = std::cmp::max(start_ns, self.start_ns);
+        self.events.push(s::TraceEvent {
+            name: name.to_owned(),
+            cat: cat.to_owned(),
+            ph: "X".to_owned(),
+            ts: (start_ns - self.start_ns) as f64 / 1000f64,
+            dur: end_ns.saturating_sub(start_ns) as f64 / 1000f64,
+            pid: 1,
+            tid: tid,
+            args: args,
+        });
+    }
+
+    /// The events in start order, as trace viewers expect nested spans to be
+    pub fn into_events(self) -> Vec<s::TraceEvent> {
+        let mut events = self.events;
+        // Longer spans first when they start together, so they enclose the shorter ones
+        events.sort_by(|a, b| a.ts.partial_cmp(&b.ts).unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.dur.partial_cmp(&a.dur).unwrap_or(std::cmp::Ordering::Equal)));
+        events
+    }
+}
+
+#[test]
+fn test_trace_recorder() {
+    let mut r = TraceRecorder::new();
+    let start = r.start_ns;
+    r.record("inner", "node", 0, start + 2000, start + 3000, BTreeMap::new());
+    r.record("outer", "pass", 0, start + 2000, start + 9000, BTreeMap::new());
+    r.record("early", "codec", 1, start - 5000, start + 1000, BTreeMap::new());
+    let events = r.into_events();
+    assert_eq!(events.iter().map(|e| e.name.as_str()).collect::<Vec<&str>>(), vec!["early", "outer", "inner"]);
+    assert_eq!(events[0].ts, 0f64);
+    assert_eq!(events[0].dur, 1f64);
+    assert_eq!(events[1].ts, 2f64);
+    assert_eq!(events[1].dur, 7f64);
+}
@@ -13,6 +13,7 @@ extern crate serde_json;
 extern crate smallvec;
 
 extern crate twox_hash;
+extern crate gif;
 
 use std::ffi::CString;
 use std::path::Path;
@@ -21,6 +22,7 @@ use imageflow_core::{Context, ErrorKind, FlowError, CodeLocation};
 
 fn default_build_config(debug: bool) -> s::Build001Config {
     s::Build001Config{graph_recording: match debug{ true => Some(s::Build001GraphRecording::debug_defaults()), false => None} ,
+        .. Default::default()
     }
 }
 
@@ -82,6 +84,7 @@ fn compare(input: Option<s::IoEnum>, allowed_off_by_one_bytes: usize, checksum_n
                 true => Some(s::Build001GraphRecording::debug_defaults()),
                 false => None
             },
+            .. Default::default()
         }),
         io: inputs,
         framewise: s::Framewise::Steps(steps)
@@ -391,6 +394,647 @@ fn test_encode_png32_smoke() {
     );
 }
 
+#[test]
+fn test_limits_fail_oversized_jobs() {
+    // Each build gets an io_id of its own
+    let build = |io_id: i32, limits: Option<s::JobLimits>| s::Build001 {
+        builder_config: Some(s::Build001Config { limits: limits, .. default_build_config(false) }),
+        io: vec![s::IoObject { io_id: io_id, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::CreateCanvas {w: 400, h: 300, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+            s::Node::Encode{ io_id: io_id, preset: s::EncoderPreset::Libpng {depth: Some(s::PngBitDepth::Png32), matte: None,  zlib_compression: None}}
+        ])
+    };
+    let limited = vec![
+        s::JobLimits { max_output_pixels: Some(400 * 300 - 1), .. Default::default() },
+        s::JobLimits { max_bitmap_bytes: Some(400 * 300 * 4 - 1), .. Default::default() },
+    ];
+    for limits in limited {
+        let mut context = Context::create().unwrap();
+        let e = context.build_1(build(1, Some(limits.clone()))).err().expect("The job should exceed its limits");
+        assert_eq!(e.category(), imageflow_core::ErrorCategory::SizeLimitExceeded);
+
+        // The limits were the last build's alone
+        let _ = context.build_1(build(2, None)).unwrap();
+
+        // Nor do they outlast it into an execute
+        let e = context.build_1(build(3, Some(limits))).err().expect("The job should exceed its limits");
+        assert_eq!(e.category(), imageflow_core::ErrorCategory::SizeLimitExceeded);
+        let mut bitmap: *mut BitmapBgra = std::ptr::null_mut();
+        let _ = context.execute_1(s::Execute001 {
+            graph_recording: None,
+            framewise: s::Framewise::Steps(vec![
+                s::Node::CreateCanvas {w: 400, h: 300, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+                s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: &mut bitmap as *mut *mut BitmapBgra as usize }
+            ])
+        }).unwrap();
+        assert!(!bitmap.is_null());
+    }
+}
+
+#[test]
+fn test_estimate_reports_encode_dimensions() {
+    let build = s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: vec![s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::CreateCanvas {w: 400, h: 300, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+            s::Node::Constrain(s::Constraint::Within{w: Some(200), h: Some(200), hints: None}),
+            s::Node::Encode{ io_id: 1, preset: s::EncoderPreset::libpng32()}
+        ])
+    };
+    let mut context = Context::create().unwrap();
+    let estimate = match context.estimate_1(build).unwrap() {
+        s::ResponsePayload::JobEstimate(e) => e,
+        other => panic!("Expected a job estimate, got {:?}", other)
+    };
+    assert!(estimate.complete);
+    assert_eq!(estimate.encodes.len(), 1);
+    assert_eq!((estimate.encodes[0].w, estimate.encodes[0].h), (Some(200), Some(150)));
+    assert_eq!(estimate.encodes[0].preferred_mime_type, "image/png");
+    assert!(estimate.estimated_heap_bytes >= 400 * 300 * 4 + 200 * 150 * 4);
+    assert!(estimate.estimated_wall_microseconds > 0);
+    // Nothing was encoded, and the estimate's io didn't stay registered on the context
+    assert!(context.get_output_buffer_slice(1).is_err());
+}
+
+#[test]
+fn test_estimate_uses_configured_cost_coefficients() {
+    let mut context = Context::create().unwrap();
+    let mut estimate = |k: Option<s::CostCoefficients>| {
+        let build = s::Build001 {
+            builder_config: Some(s::Build001Config { cost_coefficients: k, .. default_build_config(false) }),
+            io: vec![s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+            framewise: s::Framewise::Steps(vec![
+                s::Node::CreateCanvas {w: 400, h: 300, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+                s::Node::Encode{ io_id: 1, preset: s::EncoderPreset::libpng32()}
+            ])
+        };
+        match context.estimate_1(build).unwrap() {
+            s::ResponsePayload::JobEstimate(e) => e.estimated_wall_microseconds,
+            other => panic!("Expected a job estimate, got {:?}", other)
+        }
+    };
+    let default = estimate(None);
+    // A host ten times slower at encoding PNGs
+    let slow = s::CostCoefficients { encode_png_ns_per_pixel: s::CostCoefficients::default().encode_png_ns_per_pixel * 10f64, .. Default::default() };
+    assert!(estimate(Some(slow)) > default * 5);
+    // The coefficients were that estimate's alone
+    assert_eq!(estimate(None), default);
+}
+
+#[test]
+fn test_build_after_estimate_writes_real_output() {
+    let path = std::env::temp_dir().join("imageflow_build_after_estimate.png");
+    let _ = std::fs::remove_file(&path);
+    let build = || s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: vec![s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::Filename(path.to_str().unwrap().to_owned()) }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::CreateCanvas {w: 40, h: 30, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+            s::Node::Encode{ io_id: 1, preset: s::EncoderPreset::libpng32()}
+        ])
+    };
+    let mut context = Context::create().unwrap();
+    let _ = context.estimate_1(build()).unwrap();
+    assert!(!path.exists());
+    let _ = context.build_1(build()).unwrap();
+    // Closes the file
+    drop(context);
+    let mut bytes = Vec::new();
+    {
+        use std::io::Read;
+        std::fs::File::open(&path).and_then(|mut f| f.read_to_end(&mut bytes)).unwrap();
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert!(bytes.starts_with(&[0x89, b'P', b'N', b'G']), "The build wrote {} bytes that aren't a PNG", bytes.len());
+}
+
+/// The BGRA bytes of one pixel of a Bgra32 bitmap
+fn bgra_at(bitmap: *mut BitmapBgra, x: u32, y: u32) -> [u8; 4] {
+    unsafe {
+        let p = (*bitmap).pixels.offset((y * (*bitmap).stride + x * 4) as isize);
+        [*p, *p.offset(1), *p.offset(2), *p.offset(3)]
+    }
+}
+
+#[test]
+fn test_mutating_a_shared_frame_clones_it() {
+    let mut flipped_h: *mut BitmapBgra = std::ptr::null_mut();
+    let mut flipped_v: *mut BitmapBgra = std::ptr::null_mut();
+    let mut nodes = HashMap::new();
+    nodes.insert("0".to_owned(), s::Node::CreateCanvas {w: 4, h: 2, format: s::PixelFormat::Bgra32, color: s::Color::Black});
+    nodes.insert("1".to_owned(), s::Node::FillRect {x1: 0, y1: 0, x2: 1, y2: 1, color: s::Color::Srgb(s::ColorSrgb::Hex("FF0000".to_owned()))});
+    nodes.insert("2".to_owned(), s::Node::FlipH);
+    nodes.insert("3".to_owned(), s::Node::FlipV);
+    nodes.insert("4".to_owned(), s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: &mut flipped_h as *mut *mut BitmapBgra as usize });
+    nodes.insert("5".to_owned(), s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: &mut flipped_v as *mut *mut BitmapBgra as usize });
+    let edge = |from: i32, to: i32| s::Edge { from: from, to: to, kind: s::EdgeKind::Input };
+    let build = s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: vec![],
+        framewise: s::Framewise::Graph(s::Graph { nodes: nodes, edges: vec![edge(0, 1), edge(1, 2), edge(1, 3), edge(2, 4), edge(3, 5)] })
+    };
+    let mut context = Context::create().unwrap();
+    let perf = match context.build_1(build).unwrap() {
+        s::ResponsePayload::BuildResult(s::JobResult { performance: Some(perf), .. }) => perf,
+        other => panic!("Expected a build result, got {:?}", other)
+    };
+    // Both flips share the red-cornered frame, so each works on a clone of it
+    assert_eq!(perf.frames[0].nodes.iter().filter(|n| n.name == "copy_rect_to_canvas").count(), 2);
+    let red = [0, 0, 255, 255];
+    let black = [0, 0, 0, 255];
+    assert_eq!((bgra_at(flipped_h, 0, 0), bgra_at(flipped_h, 3, 0), bgra_at(flipped_h, 0, 1)), (black, red, black));
+    assert_eq!((bgra_at(flipped_v, 0, 0), bgra_at(flipped_v, 3, 0), bgra_at(flipped_v, 0, 1)), (black, black, red));
+}
+
+#[test]
+fn test_redaction_maps_source_coordinates_through_jpeg_downscaling() {
+    let mut context = Context::create().unwrap();
+    let jpeg = {
+        let _ = context.build_1(s::Build001 {
+            builder_config: None,
+            io: vec![s::IoObject { io_id: 0, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+            framewise: s::Framewise::Steps(vec![
+                s::Node::CreateCanvas {w: 1600, h: 1200, format: s::PixelFormat::Bgr32, color: s::Color::Srgb(s::ColorSrgb::Hex("FFFFFFFF".to_owned()))},
+                s::Node::Encode{ io_id: 0, preset: s::EncoderPreset::LibjpegTurbo { quality: Some(90), progressive: None, optimize_huffman_coding: None }}
+            ])
+        }).unwrap();
+        context.get_output_buffer_slice(0).unwrap().to_vec()
+    };
+    let mut bitmap: *mut BitmapBgra = std::ptr::null_mut();
+    let hints = s::DecoderCommand::JpegDownscaleHints(s::JpegIDCTDownscaleHints {
+        width: 420,
+        height: 315,
+        scale_luma_spatially: None,
+        gamma_correct_for_srgb_during_spatial_luma_scaling: None,
+    });
+    let _ = context.build_1(s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: vec![s::IoObject { io_id: 1, direction: s::IoDirection::In, io: s::IoEnum::ByteArray(jpeg) }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::Decode { io_id: 1, commands: Some(vec![hints]) },
+            s::Node::FlipH,
+            // The top right quadrant of the 1600x1200 source
+            s::Node::RedactRegions {
+                regions: vec![s::RedactRegion { x1: 800, y1: 0, x2: 1600, y2: 600 }],
+                style: s::RedactionStyle::Fill { color: s::Color::Srgb(s::ColorSrgb::Hex("000000".to_owned())) },
+            },
+            s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: &mut bitmap as *mut *mut BitmapBgra as usize },
+        ])
+    }).unwrap();
+    // Decoded at 3/8 scale and flipped, the quadrant is the frame's top left
+    assert_eq!(unsafe { ((*bitmap).w, (*bitmap).h) }, (600, 450));
+    let black = |x: u32, y: u32| bgra_at(bitmap, x, y)[..3] == [0u8, 0, 0][..];
+    let white = |x: u32, y: u32| bgra_at(bitmap, x, y)[..3].iter().all(|&v| v > 240);
+    assert!(black(0, 0) && black(299, 0) && black(0, 224) && black(299, 224));
+    assert!(white(300, 0) && white(0, 225) && white(450, 337));
+}
+
+#[test]
+fn test_grid_places_inputs_in_wired_order() {
+    let tinypng = vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52, 0x00,
+        0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1F, 0x15, 0xC4, 0x89, 0x00,
+        0x00, 0x00, 0x0A, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9C, 0x63, 0x00, 0x01, 0x00, 0x00, 0x05, 0x00, 0x01,
+        0x0D, 0x0A, 0x2D, 0xB4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82 ];
+    // Two branches of one decoder and a canvas, wired canvas first and the branches in reverse
+    let mut nodes = HashMap::new();
+    nodes.insert("0".to_owned(), s::Node::Decode { io_id: 0, commands: None });
+    nodes.insert("1".to_owned(), s::Node::Resample2D { w: 40, h: 20, down_filter: None, up_filter: None, hints: None, scaling_colorspace: None });
+    nodes.insert("2".to_owned(), s::Node::Resample2D { w: 20, h: 40, down_filter: None, up_filter: None, hints: None, scaling_colorspace: None });
+    nodes.insert("3".to_owned(), s::Node::CreateCanvas { w: 30, h: 30, format: s::PixelFormat::Bgra32, color: s::Color::Black });
+    nodes.insert("4".to_owned(), s::Node::Grid { columns: 3, cell_w: 50, cell_h: 50, gap: None, background: None, fit: None });
+    nodes.insert("5".to_owned(), s::Node::Encode { io_id: 1, preset: s::EncoderPreset::libpng32() });
+    let edge = |from: i32, to: i32| s::Edge { from: from, to: to, kind: s::EdgeKind::Input };
+    let build = s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: vec![s::IoObject { io_id: 0, direction: s::IoDirection::In, io: s::IoEnum::ByteArray(tinypng) },
+                 s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+        framewise: s::Framewise::Graph(s::Graph { nodes: nodes, edges: vec![edge(0, 1), edge(0, 2), edge(3, 4), edge(2, 4), edge(1, 4), edge(4, 5)] })
+    };
+    let mut context = Context::create().unwrap();
+    let layout = match context.build_1(build).unwrap() {
+        s::ResponsePayload::BuildResult(s::JobResult { mut analyses, .. }) => match analyses.pop() {
+            Some(s::AnalysisResult::GridLayout(layout)) => layout,
+            other => panic!("Expected a grid layout, got {:?}", other)
+        },
+        other => panic!("Expected a build result, got {:?}", other)
+    };
+    let cells = layout.cells.iter().map(|c| (c.io_id, c.column, c.w, c.h)).collect::<Vec<_>>();
+    assert_eq!(cells, vec![(None, 0, 50, 50), (Some(0), 1, 25, 50), (Some(0), 2, 50, 25)]);
+}
+
+#[test]
+fn test_resample_bands_match_one_thread() {
+    let mut context = Context::create().unwrap();
+    let mut next_io_id = 0;
+    let mut resample = |h: u32, threads: u32| {
+        next_io_id += 1;
+        let _ = context.build_1(s::Build001 {
+            builder_config: Some(s::Build001Config { max_resample_threads: Some(threads), .. default_build_config(false) }),
+            io: vec![s::IoObject { io_id: next_io_id, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+            framewise: s::Framewise::Steps(vec![
+                s::Node::CreateCanvas {w: 301, h: 403, format: s::PixelFormat::Bgra32, color: s::Color::Srgb(s::ColorSrgb::Hex("FFFFFF".to_owned()))},
+                s::Node::FillRect {x1: 30, y1: 17, x2: 200, y2: 290, color: s::Color::Srgb(s::ColorSrgb::Hex("CC3366".to_owned()))},
+                s::Node::Resample2D { w: 157, h: h, down_filter: Some(s::Filter::Robidoux), up_filter: Some(s::Filter::Robidoux), hints: None, scaling_colorspace: None },
+                s::Node::Encode { io_id: next_io_id, preset: s::EncoderPreset::libpng32() }
+            ])
+        }).unwrap();
+        context.get_output_buffer_slice(next_io_id).unwrap().to_vec()
+    };
+    // Shorter than one band, odd heights that leave a short last band, and an upscale
+    for &h in [1u32, 7, 31, 33, 65, 97, 201, 511].iter() {
+        let serial = resample(h, 1);
+        for &threads in [2u32, 3, 8].iter() {
+            assert!(resample(h, threads) == serial, "{} rows on {} threads differ from one thread", h, threads);
+        }
+    }
+}
+
+#[test]
+fn test_draw_text_with_font_from_io() {
+    let render = |font: s::TextFont| {
+        let mut bitmap: *mut BitmapBgra = std::ptr::null_mut();
+        let build = s::Build001 {
+            builder_config: Some(default_build_config(false)),
+            io: vec![s::IoObject { io_id: 0, direction: s::IoDirection::In, io: s::IoEnum::ByteArray(include_bytes!("../fonts/DejaVuSans.ttf").to_vec()) }],
+            framewise: s::Framewise::Steps(vec![
+                s::Node::CreateCanvas {w: 120, h: 40, format: s::PixelFormat::Bgra32, color: s::Color::Srgb(s::ColorSrgb::Hex("FFFFFF".to_owned()))},
+                s::Node::DrawText { text: "Hi".to_owned(), font: Some(font), size: 32f32, color: s::Color::Black, anchor: None, max_width: None, wrap: None },
+                s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: &mut bitmap as *mut *mut BitmapBgra as usize }
+            ])
+        };
+        let mut context = Context::create().unwrap();
+        let _ = context.build_1(build).unwrap();
+        unsafe { std::slice::from_raw_parts((*bitmap).pixels, ((*bitmap).stride * (*bitmap).h) as usize).to_vec() }
+    };
+    let from_io = render(s::TextFont::Io { io_id: 0 });
+    assert!(from_io.chunks(4).any(|p| p[0] < 64 && p[1] < 64 && p[2] < 64));
+    // The input holds the same font that is bundled
+    assert_eq!(from_io, render(s::TextFont::Bundled));
+}
+
+/// Builds `steps` into a bitmap; returns the names of the nodes the job ended with, the bitmap's size and the
+/// pixels at `points`
+fn build_and_sample(steps: Vec<s::Node>, points: &[(u32, u32)]) -> (Vec<String>, (u32, u32), Vec<[u8; 4]>) {
+    let mut bitmap: *mut BitmapBgra = std::ptr::null_mut();
+    let mut steps = steps;
+    steps.push(s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: &mut bitmap as *mut *mut BitmapBgra as usize });
+    let build = s::Build001 {
+        builder_config: Some(default_build_config(DEBUG_GRAPH)),
+        io: vec![],
+        framewise: s::Framewise::Steps(steps)
+    };
+    let mut context = Context::create().unwrap();
+    let names = match context.build_1(build).unwrap() {
+        s::ResponsePayload::BuildResult(s::JobResult { performance: Some(perf), .. }) => perf.frames[0].nodes.iter().map(|n| n.name.clone()).collect(),
+        other => panic!("Expected a build result, got {:?}", other)
+    };
+    let size = unsafe { ((*bitmap).w, (*bitmap).h) };
+    (names, size, points.iter().map(|&(x, y)| bgra_at(bitmap, x, y)).collect())
+}
+
+#[test]
+fn test_optimizer_cancels_stacked_flips() {
+    let (names, size, pixels) = build_and_sample(vec![
+        s::Node::CreateCanvas {w: 4, h: 2, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+        s::Node::FillRect {x1: 0, y1: 0, x2: 1, y2: 1, color: s::Color::Srgb(s::ColorSrgb::Hex("FF0000FF".to_owned()))},
+        s::Node::FlipH,
+        s::Node::FlipV,
+        s::Node::FlipH,
+        s::Node::FlipV,
+    ], &[(0, 0), (3, 1)]);
+    assert!(names.iter().all(|n| !n.contains("flip") && !n.contains("orientation") && !n.contains("transpose")), "{:?}", names);
+    assert_eq!(size, (4, 2));
+    assert_eq!(pixels, vec![[0, 0, 255, 255], [0, 0, 0, 255]]);
+}
+
+#[test]
+fn test_optimizer_merges_crops() {
+    let (names, size, pixels) = build_and_sample(vec![
+        s::Node::CreateCanvas {w: 100, h: 80, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+        s::Node::FillRect {x1: 15, y1: 15, x2: 16, y2: 16, color: s::Color::Srgb(s::ColorSrgb::Hex("FF0000FF".to_owned()))},
+        s::Node::Crop {x1: 10, y1: 10, x2: 90, y2: 70},
+        s::Node::Crop {x1: 5, y1: 5, x2: 45, y2: 35},
+    ], &[(0, 0), (1, 1)]);
+    assert_eq!(names.iter().filter(|n| n.starts_with("crop")).count(), 1, "{:?}", names);
+    assert_eq!(size, (40, 30));
+    assert_eq!(pixels, vec![[0, 0, 255, 255], [0, 0, 0, 255]]);
+}
+
+#[test]
+fn test_rotate_90_and_exif_orientation_6() {
+    let rotate = |orientation: s::Node| build_and_sample(vec![
+        s::Node::CreateCanvas {w: 4, h: 2, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+        s::Node::FillRect {x1: 0, y1: 0, x2: 1, y2: 1, color: s::Color::Srgb(s::ColorSrgb::Hex("FF0000FF".to_owned()))},
+        orientation,
+    ], &[(0, 0), (1, 0), (0, 3)]);
+    // Counter-clockwise, so the top-left corner ends up bottom-left
+    let (_, size, pixels) = rotate(s::Node::Rotate90);
+    assert_eq!(size, (2, 4));
+    assert_eq!(pixels, vec![[0, 0, 0, 255], [0, 0, 0, 255], [0, 0, 255, 255]]);
+    // Displaying EXIF orientation 6 turns the image clockwise, so the top-left corner ends up top-right
+    let (_, size, pixels) = rotate(s::Node::ApplyOrientation { flag: 6 });
+    assert_eq!(size, (2, 4));
+    assert_eq!(pixels, vec![[0, 0, 0, 255], [0, 0, 255, 255], [0, 0, 0, 255]]);
+}
+
+#[test]
+fn test_jpeg_block_scaling_is_opt_in() {
+    let mut context = Context::create().unwrap();
+    let jpeg = {
+        let _ = context.build_1(s::Build001 {
+            builder_config: None,
+            io: vec![s::IoObject { io_id: 0, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+            framewise: s::Framewise::Steps(vec![
+                s::Node::CreateCanvas {w: 1600, h: 1200, format: s::PixelFormat::Bgr32, color: s::Color::Srgb(s::ColorSrgb::Hex("336699FF".to_owned()))},
+                s::Node::Encode{ io_id: 0, preset: s::EncoderPreset::LibjpegTurbo { quality: Some(90), progressive: None, optimize_huffman_coding: None }}
+            ])
+        }).unwrap();
+        context.get_output_buffer_slice(0).unwrap().to_vec()
+    };
+    // Returns the size the decoder reports once the build is done, and the size encoded
+    let mut decoded_size = |io_id: i32, enable: Option<bool>| {
+        let result = context.build_1(s::Build001 {
+            builder_config: Some(s::Build001Config { enable_jpeg_block_scaling: enable, .. default_build_config(false) }),
+            io: vec![
+                s::IoObject { io_id: io_id, direction: s::IoDirection::In, io: s::IoEnum::ByteArray(jpeg.clone()) },
+                s::IoObject { io_id: io_id + 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer },
+            ],
+            framewise: s::Framewise::Steps(vec![
+                s::Node::Decode { io_id: io_id, commands: None },
+                s::Node::Resample2D{ w: 200, h: 150, down_filter: Some(s::Filter::Robidoux), up_filter: Some(s::Filter::Robidoux), hints: None, scaling_colorspace: None },
+                s::Node::Encode{ io_id: io_id + 1, preset: s::EncoderPreset::libpng32()}
+            ])
+        }).unwrap();
+        let encoded = match result {
+            s::ResponsePayload::BuildResult(s::JobResult { encodes, .. }) => (encodes[0].w, encodes[0].h),
+            other => panic!("Expected a build result, got {:?}", other)
+        };
+        let info = context.get_image_info(io_id).unwrap();
+        ((info.image_width, info.image_height), encoded)
+    };
+    assert_eq!(decoded_size(10, None), ((1600, 1200), (200, 150)));
+    // Asked for at least 420x315 (2.1x the output), the decoder picks 3/8 scale; the estimate follows it
+    assert_eq!(decoded_size(20, Some(true)), ((600, 450), (200, 150)));
+    // Not remembered by later builds
+    assert_eq!(decoded_size(30, None), ((1600, 1200), (200, 150)));
+}
+
+#[test]
+fn test_parallel_execution_matches_serial() {
+    // A canvas fanned out to four branches, each encoded to a buffer of its own
+    let run = |max_parallelism: Option<u32>| {
+        let mut nodes = HashMap::new();
+        nodes.insert("0".to_owned(), s::Node::CreateCanvas {w: 300, h: 200, format: s::PixelFormat::Bgra32, color: s::Color::Srgb(s::ColorSrgb::Hex("FFFFFF".to_owned()))});
+        nodes.insert("1".to_owned(), s::Node::FillRect {x1: 20, y1: 20, x2: 120, y2: 90, color: s::Color::Srgb(s::ColorSrgb::Hex("3366CC".to_owned()))});
+        nodes.insert("2".to_owned(), s::Node::Resample2D { w: 150, h: 100, down_filter: Some(s::Filter::Robidoux), up_filter: Some(s::Filter::Robidoux), hints: None, scaling_colorspace: None });
+        nodes.insert("3".to_owned(), s::Node::Resample2D { w: 450, h: 250, down_filter: Some(s::Filter::Lanczos), up_filter: Some(s::Filter::Lanczos), hints: None, scaling_colorspace: Some(s::ScalingFloatspace::Linear) });
+        nodes.insert("4".to_owned(), s::Node::FlipV);
+        nodes.insert("5".to_owned(), s::Node::Crop {x1: 10, y1: 10, x2: 200, y2: 150});
+        let mut edges = vec![s::Edge { from: 0, to: 1, kind: s::EdgeKind::Input }];
+        for branch in 0..4 {
+            nodes.insert((6 + branch).to_string(), s::Node::Encode { io_id: branch + 1, preset: s::EncoderPreset::libpng32() });
+            edges.push(s::Edge { from: 1, to: 2 + branch, kind: s::EdgeKind::Input });
+            edges.push(s::Edge { from: 2 + branch, to: 6 + branch, kind: s::EdgeKind::Input });
+        }
+        let build = s::Build001 {
+            builder_config: Some(s::Build001Config { max_parallelism: max_parallelism, .. default_build_config(false) }),
+            io: (1..5).map(|io_id| s::IoObject { io_id: io_id, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }).collect(),
+            framewise: s::Framewise::Graph(s::Graph { nodes: nodes, edges: edges })
+        };
+        let mut context = Context::create().unwrap();
+        let mut names = match context.build_1(build).unwrap() {
+            s::ResponsePayload::BuildResult(s::JobResult { performance: Some(perf), .. }) => perf.frames[0].nodes.iter().map(|n| n.name.clone()).collect::<Vec<String>>(),
+            other => panic!("Expected a build result, got {:?}", other)
+        };
+        names.sort();
+        let outputs = (1..5).map(|io_id| context.get_output_buffer_slice(io_id).unwrap().to_vec()).collect::<Vec<Vec<u8>>>();
+        (names, outputs)
+    };
+    let (serial_names, serial_outputs) = run(None);
+    let (parallel_names, parallel_outputs) = run(Some(4));
+    assert_eq!(parallel_names, serial_names);
+    for (parallel, serial) in parallel_outputs.iter().zip(serial_outputs.iter()) {
+        assert!(parallel.len() > 0 && parallel == serial);
+    }
+}
+
+#[test]
+fn test_cancellation_stops_one_job() {
+    let build = |io_id| s::Build001 {
+        builder_config: None,
+        io: vec![s::IoObject { io_id: io_id, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::CreateCanvas {w: 400, h: 300, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+            s::Node::Encode{ io_id: io_id, preset: s::EncoderPreset::libpng32()}
+        ])
+    };
+    let mut context = Context::create().unwrap();
+    let token = context.cancellation_token();
+    std::thread::spawn(move || token.cancel()).join().unwrap();
+    let e = context.build_1(build(1)).err().expect("The job should be cancelled");
+    assert_eq!(e.category(), imageflow_core::ErrorCategory::OperationCancelled);
+
+    // The next job on the same context runs normally
+    let _ = context.build_1(build(2)).unwrap();
+}
+
+#[test]
+fn test_trace_in_response() {
+    let build = s::Build001 {
+        builder_config: Some(s::Build001Config { trace: Some(s::TraceDestination::Response), .. default_build_config(false) }),
+        io: vec![s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::CreateCanvas {w: 40, h: 30, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+            s::Node::Encode{ io_id: 1, preset: s::EncoderPreset::libpng32()}
+        ])
+    };
+    let mut context = Context::create().unwrap();
+    let events = match context.build_1(build).unwrap() {
+        s::ResponsePayload::BuildResult(s::JobResult { trace: Some(events), .. }) => events,
+        other => panic!("Expected a traced build result, got {:?}", other)
+    };
+    let has = |cat: &str, name: &str| events.iter().any(|e| e.cat == cat && e.name == name);
+    assert!(has("frame", "frame 0"));
+    assert!(has("pass", "pass 1"));
+    assert!(has("phase", "execute"));
+    assert!(has("node", "create_canvas"));
+    assert!(has("codec", "write_frame io 1"));
+    assert!(events.iter().all(|e| e.ph == "X" && e.ts >= 0f64 && e.dur >= 0f64));
+}
+
+#[test]
+fn test_bitmaps_return_to_pool() {
+    let build = || s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: vec![s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::CreateCanvas {w: 400, h: 300, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+            s::Node::Resample2D{ w: 300, h: 200, down_filter: Some(s::Filter::Robidoux), up_filter: Some(s::Filter::Robidoux), hints: None, scaling_colorspace: None },
+            s::Node::Encode{ io_id: 1, preset: s::EncoderPreset::libpng32()}
+        ])
+    };
+    let mut context = Context::create().unwrap();
+    let peak = match context.build_1(build()).unwrap() {
+        s::ResponsePayload::BuildResult(s::JobResult { performance: Some(perf), .. }) => perf.frames[0].peak_bitmap_bytes,
+        other => panic!("Expected a build result, got {:?}", other)
+    };
+    // The canvas and the resampled frame (rows padded to 64 bytes) exist at once
+    assert!(peak >= 400 * 4 * 300 + 1216 * 200);
+
+    // Both bitmaps were freed once encoded, so the second job gets their buffers back
+    let _ = context.build_1(build()).unwrap();
+    let stats = context.bitmap_pool().stats();
+    assert!(stats.reused >= 2, "{:?}", stats);
+}
+
+#[test]
+fn test_fan_out_survives_early_frees() {
+    let white = || s::Node::CreateCanvas {w: 200, h: 150, format: s::PixelFormat::Bgra32, color: s::Color::Srgb(s::ColorSrgb::Hex("FFFFFF".to_owned()))};
+    let rect = || s::Node::FillRect {x1: 10, y1: 20, x2: 90, y2: 100, color: s::Color::Srgb(s::ColorSrgb::Hex("3366CC".to_owned()))};
+    let branches = || vec![
+        s::Node::FlipH,
+        s::Node::Crop {x1: 5, y1: 5, x2: 150, y2: 120},
+        s::Node::Resample2D { w: 100, h: 75, down_filter: Some(s::Filter::Robidoux), up_filter: Some(s::Filter::Robidoux), hints: None, scaling_colorspace: None },
+    ];
+    let encode = |io_id: i32| s::Node::Encode { io_id: io_id, preset: s::EncoderPreset::libpng32() };
+    let output = |io_id: i32| s::IoObject { io_id: io_id, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer };
+
+    // The canvas is read by every branch, so it must outlive the first ones to finish
+    let mut nodes = HashMap::new();
+    nodes.insert("0".to_owned(), white());
+    nodes.insert("1".to_owned(), rect());
+    let mut edges = vec![s::Edge { from: 0, to: 1, kind: s::EdgeKind::Input }];
+    for (ix, branch) in branches().into_iter().enumerate() {
+        let ix = ix as i32;
+        nodes.insert((2 + ix).to_string(), branch);
+        nodes.insert((5 + ix).to_string(), encode(ix + 1));
+        edges.push(s::Edge { from: 1, to: 2 + ix, kind: s::EdgeKind::Input });
+        edges.push(s::Edge { from: 2 + ix, to: 5 + ix, kind: s::EdgeKind::Input });
+    }
+    let mut context = Context::create().unwrap();
+    let _ = context.build_1(s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: (1..4).map(|io_id| output(io_id)).collect(),
+        framewise: s::Framewise::Graph(s::Graph { nodes: nodes, edges: edges })
+    }).unwrap();
+
+    for (ix, branch) in branches().into_iter().enumerate() {
+        let io_id = ix as i32 + 1;
+        let mut alone = Context::create().unwrap();
+        let _ = alone.build_1(s::Build001 {
+            builder_config: Some(default_build_config(false)),
+            io: vec![output(io_id)],
+            framewise: s::Framewise::Steps(vec![white(), rect(), branch, encode(io_id)])
+        }).unwrap();
+        let expected = alone.get_output_buffer_slice(io_id).unwrap();
+        assert!(context.get_output_buffer_slice(io_id).unwrap() == expected, "Branch {} differs from running it alone", ix);
+    }
+}
+
+#[test]
+fn test_gif_frames_survive_early_frees() {
+    // Two 4x2 frames: red then blue columns, and the reverse
+    let gif_bytes = vec![
+        0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x04, 0x00, 0x02, 0x00, 0x80, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF,
+        0x2C, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x02, 0x07, 0x04, 0xC1, 0x30, 0x04, 0xC1, 0x30, 0x05, 0x00,
+        0x2C, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x02, 0x07, 0x0C, 0x43, 0x10, 0x0C, 0x43, 0x10, 0x05, 0x00,
+        0x3B];
+    let mut context = Context::create().unwrap();
+    let frames = match context.build_1(s::Build001 {
+        builder_config: Some(default_build_config(false)),
+        io: vec![
+            s::IoObject { io_id: 0, direction: s::IoDirection::In, io: s::IoEnum::ByteArray(gif_bytes) },
+            s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer },
+        ],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::Decode { io_id: 0, commands: None },
+            s::Node::FlipH,
+            s::Node::Encode { io_id: 1, preset: s::EncoderPreset::Gif },
+        ])
+    }).unwrap() {
+        s::ResponsePayload::BuildResult(s::JobResult { performance: Some(perf), .. }) => perf.frames.len(),
+        other => panic!("Expected a build result, got {:?}", other)
+    };
+    assert_eq!(frames, 2);
+
+    // Each frame was freed after encoding, yet the next one is flipped from its own pixels
+    let output = context.get_output_buffer_slice(1).unwrap().to_vec();
+    let mut decoder = gif::Decoder::new(&output[..]);
+    decoder.set(gif::ColorOutput::RGBA);
+    let mut reader = decoder.read_info().unwrap();
+    let mut first_pixels = Vec::new();
+    while let Some(frame) = reader.read_next_frame().unwrap() {
+        let reddish = |offset: usize| frame.buffer[offset] > 200 && frame.buffer[offset + 2] < 50;
+        first_pixels.push((reddish(0), reddish(12)));
+    }
+    assert_eq!(first_pixels, vec![(false, true), (true, false)]);
+}
+
+#[test]
+fn test_frame_cache_across_builds() {
+    let mut context = Context::create().unwrap();
+    let (png, jpeg) = {
+        let mut encode_canvas = |io_id: i32, w: usize, h: usize, preset: s::EncoderPreset| {
+            let _ = context.build_1(s::Build001 {
+                builder_config: None,
+                io: vec![s::IoObject { io_id: io_id, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+                framewise: s::Framewise::Steps(vec![
+                    s::Node::CreateCanvas {w: w, h: h, format: s::PixelFormat::Bgra32, color: s::Color::Black},
+                    s::Node::Encode{ io_id: io_id, preset: preset}
+                ])
+            }).unwrap();
+            context.get_output_buffer_slice(io_id).unwrap().to_vec()
+        };
+        (encode_canvas(0, 400, 300, s::EncoderPreset::libpng32()),
+         encode_canvas(100, 800, 600, s::EncoderPreset::LibjpegTurbo { quality: Some(90), progressive: None, optimize_huffman_coding: None }))
+    };
+    // Each build gets io_ids of its own; frames are matched by the input's bytes
+    let mut build = |input: &Vec<u8>, io_id: i32, w: u32, h: u32, block_scaling: Option<bool>| {
+        let result = context.build_1(s::Build001 {
+            builder_config: Some(s::Build001Config {
+                frame_cache: Some(s::FrameCacheConfig { max_bytes: 16 * 1024 * 1024 }),
+                enable_jpeg_block_scaling: block_scaling,
+                .. default_build_config(false)
+            }),
+            io: vec![
+                s::IoObject { io_id: io_id, direction: s::IoDirection::In, io: s::IoEnum::ByteArray(input.clone()) },
+                s::IoObject { io_id: io_id + 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer },
+            ],
+            framewise: s::Framewise::Steps(vec![
+                s::Node::Decode { io_id: io_id, commands: None },
+                s::Node::Resample2D{ w: w, h: h, down_filter: Some(s::Filter::Robidoux), up_filter: Some(s::Filter::Robidoux), hints: None, scaling_colorspace: None },
+                s::Node::Encode{ io_id: io_id + 1, preset: s::EncoderPreset::libpng32()}
+            ])
+        }).unwrap();
+        match result {
+            s::ResponsePayload::BuildResult(s::JobResult { frame_cache: Some(stats), encodes, .. }) => {
+                assert_eq!((encodes[0].w, encodes[0].h), (w as i32, h as i32));
+                stats
+            }
+            other => panic!("Expected a build result with frame cache stats, got {:?}", other)
+        }
+    };
+    let first = build(&png, 1, 200, 150, None);
+    assert_eq!((first.hits, first.misses, first.entries), (0, 2, 2));
+    // The decoded frame and the resample both come from the cache
+    let second = build(&png, 3, 200, 150, None);
+    assert_eq!((second.hits, second.misses, second.entries), (2, 2, 2));
+    // Only the decoded frame does
+    let third = build(&png, 5, 100, 75, None);
+    assert_eq!((third.hits, third.misses, third.entries), (3, 3, 3));
+    // JPEG frames decoded at full size are cached like any other
+    let fourth = build(&jpeg, 7, 200, 150, None);
+    assert_eq!((fourth.hits, fourth.misses, fourth.entries), (3, 5, 5));
+    // Block scaled decodes depend on the target size, so neither they nor their resamples are looked up or kept,
+    // and each target still gets its own size
+    let fifth = build(&jpeg, 9, 100, 75, Some(true));
+    assert_eq!((fifth.hits, fifth.misses, fifth.entries), (3, 5, 5));
+    let sixth = build(&jpeg, 11, 200, 150, Some(true));
+    assert_eq!((sixth.hits, sixth.misses, sixth.entries), (3, 5, 5));
+}
+
 fn get_result_dimensions(steps: Vec<s::Node>, io: Vec<s::IoObject>, debug: bool) -> (u32, u32) {
     let mut steps = steps.clone();
 
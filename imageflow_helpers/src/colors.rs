@@ -88,6 +88,34 @@ impl Color32{
 
 }
 
+/// Converts an sRGB channel value in 0..1 to linear light
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045f32 {
+        v / 12.92f32
+    } else {
+        ((v + 0.055f32) / 1.055f32).powf(2.4f32)
+    }
+}
+
+/// Converts a linear light value in 0..1 to sRGB
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308f32 {
+        v * 12.92f32
+    } else {
+        1.055f32 * v.powf(1f32 / 2.4f32) - 0.055f32
+    }
+}
+
+#[test]
+fn test_srgb_round_trip(){
+    for i in 0..256 {
+        let v = i as f32 / 255f32;
+        assert!((linear_to_srgb(srgb_to_linear(v)) - v).abs() < 0.0001f32);
+    }
+    assert_eq!(srgb_to_linear(0f32), 0f32);
+    assert!((srgb_to_linear(1f32) - 1f32).abs() < 0.0001f32);
+}
+
 #[test]
 fn test_color32(){
     assert_eq!(Color32(0xFFEEDDCC).to_aarrggbb_string(), "FFEEDDCC");
@@ -58,6 +58,30 @@ pub mod timeywimey{
         ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap().as_secs() / seconds_per_bucket % bucket_count
     }
     pub use chrono::prelude::Utc;
+
+    /// Nanoseconds of CPU time the calling thread has used; None where the platform doesn't track it
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn thread_cpu_ns() -> Option<u64> {
+        let mut t = ::libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        if unsafe { ::libc::clock_gettime(::libc::CLOCK_THREAD_CPUTIME_ID, &mut t) } == 0 {
+            Some(t.tv_sec as u64 * 1_000_000_000 + t.tv_nsec as u64)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn thread_cpu_ns() -> Option<u64> {
+        None
+    }
+
+    /// Nanoseconds of CPU time the calling thread has used since `start`, a value from `thread_cpu_ns`
+    pub fn thread_cpu_ns_since(start: Option<u64>) -> Option<u64> {
+        match (start, thread_cpu_ns()) {
+            (Some(start), Some(now)) => Some(now.saturating_sub(start)),
+            _ => None
+        }
+    }
 }
 
 #[cfg(test)]
@@ -93,3 +117,21 @@ fn test_file_macro_for_this_build(){
     assert!(file!().starts_with(env!("CARGO_PKG_NAME")))
 }
 
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_thread_cpu_time(){
+    let start = timeywimey::thread_cpu_ns();
+    assert!(start.is_some());
+    let mut x = 0u64;
+    for i in 0..10_000_000u64 {
+        x = x.wrapping_mul(31).wrapping_add(i);
+    }
+    assert!(x != 1);
+    // Sleeping uses no CPU time
+    let before_sleep = timeywimey::thread_cpu_ns_since(start).unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(50));
+    let after_sleep = timeywimey::thread_cpu_ns_since(start).unwrap();
+    assert!(before_sleep > 0);
+    assert!(after_sleep - before_sleep < 25_000_000);
+}
@@ -120,9 +120,12 @@ impl Ir4Layout{
         } else{
             self.i.mode.unwrap_or(FitMode::Pad)
         };
+        Self::steps_for(mode, self.i.scale.unwrap_or(ScaleMode::DownscaleOnly))
+    }
 
-
-        match (mode,self.i.scale.unwrap_or(ScaleMode::DownscaleOnly)){
+    /// The sizing steps IR4 uses for a given `mode` and `scale` combination
+    pub fn steps_for(mode: FitMode, scale: ScaleMode) -> Vec<Step>{
+        match (mode, scale){
             //Max is a misnomer. It scales up proportionally, as well. With scale=canvas, it produces padding.
             (FitMode::Max, ScaleMode::DownscaleOnly) => {
                 //scale to ibox, unless original is not larger than the box
@@ -239,8 +242,16 @@ impl Ir4Layout{
         let new_crop = layout.get_source_crop();
 
         let align = self.i.anchor.unwrap_or((Anchor1D::Center, Anchor1D::Center));
-        //align crop
-        let (inner_crop_x1, inner_crop_y1) = Self::align(align, new_crop, initial_size).expect("Outer box should never be smaller than inner box. All values must > 0");
+        //align crop, preferring c.focus over anchor
+        let (inner_crop_x1, inner_crop_y1) = match self.i.c_focus {
+            Some(CropFocus::Point(x, y)) => {
+                // c.focus is relative to the source, not to the manual crop window
+                let x = (x * precrop_w as f64 / 100f64 - initial_crop[0] as f64) * 100f64 / initial_size.width() as f64;
+                let y = (y * precrop_h as f64 / 100f64 - initial_crop[1] as f64) * 100f64 / initial_size.height() as f64;
+                sizing::FocalPointCropProvider::new(x, y).crop_origin(new_crop, initial_size)
+            },
+            _ => Self::align(align, new_crop, initial_size).ok()
+        }.expect("Outer box should never be smaller than inner box. All values must > 0");
         //add manual crop offset
         let (crop_x1, crop_y1) = ((initial_crop[0] + inner_crop_x1) as u32, (initial_crop[1] + inner_crop_y1) as u32);
 
@@ -269,7 +280,17 @@ impl Ir4Layout{
         let align = self.i.anchor.unwrap_or((Anchor1D::Center, Anchor1D::Center));
 
         if let Some(c) = crop{
-            b.add(s::Node::Crop { x1: c[0], y1: c[1], x2: c[2], y2: c[3] });
+            if self.i.c_focus == Some(CropFocus::Auto) {
+                //Apply the manual crop, then let the smart crop find the most detailed region within it
+                let (precrop_w, precrop_h) = self.get_precrop();
+                let window = self.get_initial_copy_window(precrop_w, precrop_h);
+                if window != [0, 0, precrop_w, precrop_h] {
+                    b.add(s::Node::Crop { x1: window[0] as u32, y1: window[1] as u32, x2: window[2] as u32, y2: window[3] as u32 });
+                }
+                b.add(s::Node::SmartCrop { w: new_crop.width() as u32, h: new_crop.height() as u32 });
+            } else {
+                b.add(s::Node::Crop { x1: c[0], y1: c[1], x2: c[2], y2: c[3] });
+            }
         }
 
         //Scale
@@ -298,6 +319,13 @@ impl Ir4Layout{
             });
         }
 
+        if let Some(true) = self.i.a_contrast {
+            b.add(s::Node::AutoLevels { threshold: None });
+        }
+        if let Some((black, white, gamma)) = self.i.a_levels {
+            b.add(s::Node::Levels { black: black, white: white, gamma: gamma as f32, channel: None });
+        }
+
         if let Some(c) = self.i.s_contrast {
             b.add(s::Node::ColorFilterSrgb(s::ColorFilterSrgb::Contrast(c as f32)));
         }
@@ -345,6 +373,18 @@ impl Ir4Layout{
         b.add_rotate(self.i.rotate);
         b.add_flip(self.i.flip);
 
+        if let Some(true) = self.i.dither {
+            b.add(s::Node::Dither {
+                palette: match (self.i.s_grayscale, self.i.colors) {
+                    (Some(_), levels) => s::DitherPalette::Grayscale { levels: levels.unwrap_or(2) },
+                    (None, None) => s::DitherPalette::WebSafe,
+                    // There's no palette of n colors to pick; the web-safe one would silently ignore the count
+                    (None, Some(colors)) => return Err(sizing::LayoutError::UnsupportedColorCount { colors: colors })
+                },
+                method: s::DitherMethod::FloydSteinberg,
+            });
+        }
+
         Ok(Ir4LayoutInfo {
             canvas: canvas
         })
@@ -362,7 +402,7 @@ impl Ir4Layout{
             })
         }
     }
-    fn align(alignment: (Anchor1D, Anchor1D), inner: AspectRatio, outer: AspectRatio) -> std::result::Result<(i32,i32),()>{
+    pub fn align(alignment: (Anchor1D, Anchor1D), inner: AspectRatio, outer: AspectRatio) -> std::result::Result<(i32,i32),()>{
         let (x,y) = alignment;
         Ok((Self::align1d(x,inner.width(), outer.width())?, Self::align1d(y, inner.height(), outer.height())?))
     }
@@ -457,6 +497,19 @@ fn test_crop_and_scale(){
 }
 
 
+#[test]
+fn test_crop_focus(){
+    let mut b = FramewiseBuilder::new();
+    let l  = Ir4Layout::new(Instructions{w: Some(100), h: Some(100), mode: Some(FitMode::Crop), c_focus: Some(CropFocus::Point(100f64, 50f64)), .. Default::default() }, 400, 200);
+    l.add_steps(&mut b).unwrap();
+    assert_eq!(b.steps[0], s::Node::Crop { x1: 200, y1: 0, x2: 400, y2: 200 });
+
+    let mut b = FramewiseBuilder::new();
+    let l  = Ir4Layout::new(Instructions{w: Some(100), h: Some(100), mode: Some(FitMode::Crop), c_focus: Some(CropFocus::Auto), .. Default::default() }, 400, 200);
+    l.add_steps(&mut b).unwrap();
+    assert_eq!(b.steps[0], s::Node::SmartCrop { w: 200, h: 200 });
+}
+
 #[test]
 fn test_scale(){
     let mut b = FramewiseBuilder::new();
@@ -469,3 +522,15 @@ fn test_scale(){
 
 
 }
+
+#[test]
+fn test_dither_colors(){
+    let dither = |query: &str| {
+        let (i, _) = parse_url(&::url::Url::from_str(&format!("https://fakeurl/img.jpg?{}", query)).unwrap());
+        let mut b = FramewiseBuilder::new();
+        Ir4Layout::new(i, 100, 100).add_steps(&mut b).map(|_| b.steps)
+    };
+    assert_eq!(dither("dither=true&colors=16&s.grayscale=true").unwrap().last(),
+               Some(&s::Node::Dither { palette: s::DitherPalette::Grayscale { levels: 16 }, method: s::DitherMethod::FloydSteinberg }));
+    assert_eq!(dither("dither=true&colors=16").err(), Some(sizing::LayoutError::UnsupportedColorCount { colors: 16 }));
+}
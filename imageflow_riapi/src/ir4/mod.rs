@@ -2,7 +2,7 @@ use imageflow_helpers::preludes::from_std::*;
 use ::imageflow_types as s;
 
 pub mod parsing;
-mod layout;
+pub mod layout;
 
 use ::sizing;
 use ::sizing::prelude::*;
@@ -153,7 +153,7 @@ pub enum ScalingColorspace {
 
 }
 
-pub static IR4_KEYS: [&'static str;61] = ["mode", "anchor", "flip", "sflip", "scale", "cache", "process",
+pub static IR4_KEYS: [&'static str;64] = ["mode", "anchor", "c.focus", "flip", "sflip", "scale", "cache", "process",
     "quality", "zoom", "crop", "cropxunits", "cropyunits",
     "w", "h", "width", "height", "maxwidth", "maxheight", "format", "thumbnail",
      "autorotate", "srotate", "rotate", "ignoreicc", //really? : "precise_scaling_ratio",
@@ -161,7 +161,7 @@ pub static IR4_KEYS: [&'static str;61] = ["mode", "anchor", "flip", "sflip", "sc
     "frame", "page", "subsampling", "colors", "f.sharpen", "down.colorspace",
     "404", "bgcolor", "paddingcolor", "bordercolor", "preset", "floatspace", "jpeg_idct_downscale_linear", "watermark",
     "s.invert", "s.sepia", "s.grayscale", "s.alpha", "s.brightness", "s.contrast", "s.saturation", /* "trim.threshold",
-    "trim.percentpadding",*/ "a.blur", "a.sharpen", "a.removenoise", "a.balancewhite", "dither","jpeg.progressive",
+    "trim.percentpadding",*/ "a.blur", "a.sharpen", "a.removenoise", "a.balancewhite", "a.contrast", "a.levels", "dither","jpeg.progressive",
     "encoder", "decoder", "builder", "s.roundcorners.", "paddingwidth", "paddingheight", "margin", "borderwidth", "decoder.min_precise_scaling_ratio"];
 
 
@@ -261,10 +261,14 @@ impl Instructions{
         add(&mut m, "s.saturation", self.s_saturation);
         add(&mut m, "s.sepia", self.s_sepia);
         add(&mut m, "jpeg.progressive", self.jpeg_progressive);
+        add(&mut m, "dither", self.dither);
+        add(&mut m, "colors", self.colors);
 
 
         add(&mut m, "s.grayscale", self.s_grayscale.map(|v| format!("{:?}", v).to_lowercase()));
         add(&mut m, "a.balancewhite", self.a_balance_white.map(|v| format!("{:?}", v).to_lowercase()));
+        add(&mut m, "a.contrast", self.a_contrast);
+        add(&mut m, "a.levels", self.a_levels.map(|(black, white, gamma)| format!("{},{},{}", black, white, gamma)));
         add(&mut m, "subsampling", self.jpeg_subsampling);
         add(&mut m, "bgcolor", self.bgcolor_srgb.and_then(|v| Some(v.to_rrggbbaa_string().to_lowercase())));
         add(&mut m, "f.sharpen", self.f_sharpen);
@@ -273,6 +277,10 @@ impl Instructions{
 
         add(&mut m, "crop", self.crop.map(|a| format!("{},{},{},{}", a[0],a[1],a[2],a[3])));
         add(&mut m, "anchor", self.anchor_string());
+        add(&mut m, "c.focus", self.c_focus.map(|v| match v {
+            CropFocus::Point(x, y) => format!("{},{}", x, y),
+            CropFocus::Auto => "auto".to_owned()
+        }));
 
 
         add(&mut m, "down.colorspace", self.down_colorspace.map(|v| format!("{:?}", v).to_lowercase()));
@@ -318,6 +326,7 @@ impl Instructions{
         i.jpeg_subsampling = p.parse_subsampling("subsampling");
 
         i.anchor = p.parse_anchor("anchor");
+        i.c_focus = p.parse_focus("c.focus");
 
 
         i.min_precise_scaling_ratio = p.parse_f64("decoder.min_precise_scaling_ratio");
@@ -342,12 +351,18 @@ impl Instructions{
             }
         };
 
+        i.a_contrast = p.parse_bool("a.contrast");
+        i.a_levels = p.parse_levels("a.levels");
+
         i.down_colorspace = p.parse_colorspace("down.colorspace");
 
 
         let _ = p.parse_test_pair("fastscale", "true");
         i.jpeg_progressive = p.parse_bool("jpeg.progressive");
 
+        i.dither = p.parse_bool("dither");
+        i.colors = p.parse_colors("colors");
+
         i
     }
 
@@ -483,6 +498,14 @@ impl<'a> Parser<'a>{
     }
 
 
+    fn parse_colors(&mut self, key: &'static str) -> Option<u32>{
+        self.parse(key, |s|
+            s.parse::<u32>().map_err(|_| ()).and_then(|v|
+                if v >= 2 && v <= 256 { Ok(v) } else { Err(()) }
+            )
+        )
+    }
+
     fn parse_subsampling(&mut self, key: &'static str) -> Option<i32>{
         self.parse(key, |s|
             s.parse::<i32>().map_err(|_| ()).and_then(|v|
@@ -614,6 +637,40 @@ fn parse_colorspace(&mut self, key: &'static str) -> Option<ScalingColorspace> {
         })
     }
 
+    /// black,white[,gamma]
+    fn parse_levels(&mut self, key: &'static str) -> Option<(u8, u8, f64)> {
+        self.parse(key, |value| {
+            let values = value.split(',').map(|v| v.trim()).collect::<Vec<&str>>();
+            if values.len() != 2 && values.len() != 3 {
+                return Err(());
+            }
+            let black = values[0].parse::<u8>().map_err(|_| ())?;
+            let white = values[1].parse::<u8>().map_err(|_| ())?;
+            let gamma = match values.get(2) {
+                Some(v) => v.parse::<f64>().map_err(|_| ())?,
+                None => 1f64
+            };
+            if white <= black || !(gamma > 0f64) {
+                Err(())
+            } else {
+                Ok((black, white, gamma))
+            }
+        })
+    }
+
+    fn parse_focus(&mut self, key: &'static str) -> Option<CropFocus> {
+        self.parse(key, |value| {
+            if value.eq_ignore_ascii_case("auto") {
+                return Ok(CropFocus::Auto)
+            }
+            let values = value.split(',').map(|v| v.trim().parse::<f64>()).collect::<Vec<std::result::Result<f64,::std::num::ParseFloatError>>>();
+            match (values.len(), values.get(0), values.get(1)) {
+                (2, Some(&Ok(x)), Some(&Ok(y))) => Ok(CropFocus::Point(x, y)),
+                _ => Err(())
+            }
+        })
+    }
+
 
 
 }
@@ -710,9 +767,13 @@ pub struct Instructions{
     pub bgcolor_srgb: Option<Color32>,
     pub jpeg_subsampling: Option<i32>,
     pub anchor: Option<(Anchor1D, Anchor1D)>,
+    pub c_focus: Option<CropFocus>,
     pub trim_whitespace_threshold: Option<i32>,
     pub trim_whitespace_padding_percent: Option<f64>,
     pub a_balance_white: Option<HistogramThresholdAlgorithm>,
+    pub a_contrast: Option<bool>,
+    /// black, white, gamma
+    pub a_levels: Option<(u8, u8, f64)>,
     pub s_alpha: Option<f64>,
     pub s_contrast: Option<f64>,
     pub s_saturation: Option<f64>,
@@ -722,6 +783,10 @@ pub struct Instructions{
     pub min_precise_scaling_ratio: Option<f64>,
     pub down_colorspace: Option<ScalingColorspace>,
     pub jpeg_progressive: Option<bool>,
+    /// Dither the result to a reduced palette: web-safe colors, or with s.grayscale, `colors` gray levels.
+    /// `colors` without s.grayscale fails the layout.
+    pub dither: Option<bool>,
+    pub colors: Option<u32>,
 }
 #[derive(Debug,Copy, Clone,PartialEq)]
 pub enum Anchor1D{
@@ -730,6 +795,15 @@ pub enum Anchor1D{
     Far
 }
 
+/// Where crops are positioned; overrides &amp;anchor when cropping
+#[derive(Debug,Copy, Clone,PartialEq)]
+pub enum CropFocus{
+    /// Center the crop on this point, given as percentages (0..100) of the source width and height
+    Point(f64, f64),
+    /// Crop to the most detailed region of the image
+    Auto
+}
+
 #[derive(Debug,Copy, Clone,PartialEq)]
 pub enum OutputFormat{
     Jpeg,
@@ -820,6 +894,8 @@ fn test_url_parsing() {
     t("thumbnail=exif", Instructions { format: Some(OutputFormat::Jpeg), ..Default::default() }, vec![]);
     t("cropxunits=2.3&cropyunits=100", Instructions { cropxunits: Some(2.3f64), cropyunits: Some(100f64), ..Default::default() }, vec![]);
     t("quality=85", Instructions { quality: Some(85), ..Default::default() }, vec![]);
+    t("dither=true&colors=16", Instructions { dither: Some(true), colors: Some(16), ..Default::default() }, vec![]);
+    t("colors=1", Instructions::default(), vec![ParseWarning::ValueInvalid(("colors", "1".to_owned())), ParseWarning::KeyNotSupported(("colors".to_owned(), "1".to_owned()))]);
     t("zoom=0.02", Instructions { zoom: Some(0.02f64), ..Default::default() }, vec![]);
 //    t("trim.threshold=80&trim.percentpadding=0.02", Instructions { trim_whitespace_threshold: Some(80),  trim_whitespace_padding_percent: Some(0.02f64), ..Default::default() }, vec![]);
     t("w=10&f.sharpen=80.5", Instructions { w: Some(10), f_sharpen: Some(80.5f64), ..Default::default() }, vec![]);
@@ -859,10 +935,14 @@ fn test_url_parsing() {
 
     t("crop=0,0,40,50", Instructions { crop: Some([0f64,0f64,40f64,50f64]), ..Default::default() }, vec![]);
     t("crop= 0, 0,40 ,  50", Instructions { crop: Some([0f64,0f64,40f64,50f64]), ..Default::default() }, vec![]);
+    t("c.focus=20,80.5", Instructions { c_focus: Some(CropFocus::Point(20f64, 80.5f64)), ..Default::default() }, vec![]);
+    t("c.focus=Auto", Instructions { c_focus: Some(CropFocus::Auto), ..Default::default() }, vec![]);
 
     t("a.balancewhite=true",  Instructions{a_balance_white: Some(HistogramThresholdAlgorithm::Area), ..Default::default()}, vec![]);
     t("a.balancewhite=area",  Instructions{a_balance_white: Some(HistogramThresholdAlgorithm::Area), ..Default::default()}, vec![]);
     t("down.colorspace=linear",  Instructions{down_colorspace: Some(ScalingColorspace::Linear), ..Default::default()}, vec![]);
+    t("a.contrast=true&a.levels=10,240",  Instructions{a_contrast: Some(true), a_levels: Some((10, 240, 1f64)), ..Default::default()}, vec![]);
+    t("a.levels=0, 200, 1.5",  Instructions{a_levels: Some((0, 200, 1.5f64)), ..Default::default()}, vec![]);
     t("down.colorspace=srgb",  Instructions{down_colorspace: Some(ScalingColorspace::Srgb), ..Default::default()}, vec![]);
 
     expect_warning("a.balancewhite","gimp",  Instructions{a_balance_white: Some(HistogramThresholdAlgorithm::Gimp), ..Default::default()});
@@ -870,6 +950,7 @@ fn test_url_parsing() {
     expect_warning("crop","(0,3,80, 90)",  Instructions { crop: Some([0f64,3f64,80f64,90f64]), ..Default::default() });
     expect_warning("crop","(0,3,happy, 90)",  Instructions { crop: Some([0f64,3f64,0f64,90f64]), ..Default::default() });
     expect_warning("crop","(  a0, 3, happy, 90)",  Instructions { crop: Some([0f64,3f64,0f64,90f64]), ..Default::default() });
+    t("c.focus=20", Instructions::new(), vec![ParseWarning::ValueInvalid(("c.focus", "20".to_owned())), ParseWarning::KeyNotSupported(("c.focus".to_owned(), "20".to_owned()))]);
 
 }
 
@@ -895,6 +976,8 @@ fn test_tostr(){
     t("cropxunits=2.3&cropyunits=100", Instructions { cropxunits: Some(2.3f64), cropyunits: Some(100f64), ..Default::default() });
     t("quality=85", Instructions { quality: Some(85), ..Default::default() });
     t("zoom=0.02", Instructions { zoom: Some(0.02f64), ..Default::default() });
+    t("c.focus=20,80.5", Instructions { c_focus: Some(CropFocus::Point(20f64, 80.5f64)), ..Default::default() });
+    t("a.contrast=true&a.levels=10,240,1.5", Instructions { a_contrast: Some(true), a_levels: Some((10, 240, 1.5f64)), ..Default::default() });
 //    t("trim.percentpadding=0.02&trim.threshold=80", Instructions { trim_whitespace_threshold: Some(80),  trim_whitespace_padding_percent: Some(0.02f64), ..Default::default() });
     t("bgcolor=ff0000ff", Instructions { bgcolor_srgb: Some(Color32(0xffff0000)), ..Default::default() });
     t("bgcolor=8fbc8bff", Instructions { bgcolor_srgb: Some(Color32(0xff8fbc8b)), ..Default::default() });
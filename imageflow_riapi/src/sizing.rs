@@ -4,7 +4,7 @@
 //! something a user tunes separately from alignment issues within the canvas. For face/region of interest cropping
 //! and careful cropping (resort to padding before cropping off a face, for example), it cannot know. Therefore it
 //! accepts a PartialCropProvider to determine how close to the desired crop the provider is willing to go.
-//! The provider is only used to adjust the layout sizes, but users should also have the provider handle alignment of crops.
+//! The provider adjusts the layout sizes, and may also position crops via PartialCropProvider::crop_origin.
 use imageflow_helpers::preludes::from_std::*;
 use ::std;
 
@@ -18,7 +18,7 @@ pub struct AspectRatio {
 }
 
 pub mod prelude{
-    pub use super::{AspectRatio, steps, BoxKind, LayoutError, PartialCropProvider, IdentityCropProvider, Cond, BoxParam, BoxTarget, Step, Step1D};
+    pub use super::{AspectRatio, steps, BoxKind, LayoutError, PartialCropProvider, IdentityCropProvider, FocalPointCropProvider, SalientCropProvider, Cond, BoxParam, BoxTarget, Step, Step1D};
 }
 
 impl AspectRatio {
@@ -215,7 +215,11 @@ pub enum LayoutError {
         invalid_result: f64
     },
     /// The result depends on the bitmap contents, and can't be calculated based on input size alone
-    ContentDependent
+    ContentDependent,
+    /// `colors` only counts gray levels, so dithering to it needs s.grayscale
+    UnsupportedColorCount {
+        colors: u32
+    }
 }
 
 pub type Result<T> = ::std::result::Result<T, LayoutError>;
@@ -403,6 +407,135 @@ impl PartialCropProvider for IdentityCropProvider{
 
 pub trait PartialCropProvider {
     fn crop_size(&self, lay: Layout, target: AspectRatio) -> Result<Layout>;
+
+    /// Positions a crop of size `inner` within `outer`. `None` leaves alignment to the caller's anchor.
+    fn crop_origin(&self, _inner: AspectRatio, _outer: AspectRatio) -> Option<(i32, i32)> {
+        None
+    }
+}
+
+/// Crops fully, like IdentityCropProvider, but keeps the crop centered on a focal point
+/// (given as percentages of the outer box) as far as the edges allow.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FocalPointCropProvider {
+    pub x: f64,
+    pub y: f64,
+}
+impl FocalPointCropProvider {
+    pub fn new(x_percent: f64, y_percent: f64) -> FocalPointCropProvider {
+        FocalPointCropProvider {
+            x: x_percent,
+            y: y_percent
+        }
+    }
+    fn origin_1d(percent: f64, inner: i32, outer: i32) -> i32 {
+        let focus = outer as f64 * percent.max(0f64).min(100f64) / 100f64;
+        let start = (focus - inner as f64 / 2f64).round() as i32;
+        cmp::max(0, cmp::min(outer - inner, start))
+    }
+}
+impl PartialCropProvider for FocalPointCropProvider {
+    fn crop_size(&self, lay: Layout, target: AspectRatio) -> Result<Layout> {
+        lay.crop(target)
+    }
+    fn crop_origin(&self, inner: AspectRatio, outer: AspectRatio) -> Option<(i32, i32)> {
+        if inner.exceeds_any(&outer) {
+            None
+        } else {
+            Some((Self::origin_1d(self.x, inner.w, outer.w), Self::origin_1d(self.y, inner.h, outer.h)))
+        }
+    }
+}
+
+/// Crops fully, placing the crop over the window with the greatest total weight.
+/// `weights` is a row-major `grid_w` x `grid_h` saliency map stretched over the outer box.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SalientCropProvider {
+    weights: Vec<f32>,
+    grid_w: usize,
+    grid_h: usize,
+}
+impl SalientCropProvider {
+    pub fn new(weights: Vec<f32>, grid_w: usize, grid_h: usize) -> SalientCropProvider {
+        assert_eq!(weights.len(), grid_w * grid_h);
+        SalientCropProvider {
+            weights: weights,
+            grid_w: grid_w,
+            grid_h: grid_h
+        }
+    }
+
+    /// Returns the (column, row) of the best `window_w` x `window_h` window of cells.
+    /// Ties go to the window closest to the center.
+    fn best_window(&self, window_w: usize, window_h: usize) -> (usize, usize) {
+        let stride = self.grid_w + 1;
+        let mut sums = vec![0f64; stride * (self.grid_h + 1)];
+        for y in 0..self.grid_h {
+            for x in 0..self.grid_w {
+                sums[(y + 1) * stride + x + 1] = self.weights[y * self.grid_w + x] as f64
+                    + sums[y * stride + x + 1] + sums[(y + 1) * stride + x] - sums[y * stride + x];
+            }
+        }
+        let (max_x, max_y) = (self.grid_w - window_w, self.grid_h - window_h);
+        let mut best = (max_x / 2, max_y / 2);
+        let mut best_score = ::std::f64::MIN;
+        let mut best_distance = ::std::usize::MAX;
+        for y in 0..(max_y + 1) {
+            for x in 0..(max_x + 1) {
+                let score = sums[(y + window_h) * stride + x + window_w] - sums[y * stride + x + window_w]
+                    - sums[(y + window_h) * stride + x] + sums[y * stride + x];
+                let distance = ((x as isize * 2 - max_x as isize).abs() + (y as isize * 2 - max_y as isize).abs()) as usize;
+                if score > best_score || (score == best_score && distance < best_distance) {
+                    best = (x, y);
+                    best_score = score;
+                    best_distance = distance;
+                }
+            }
+        }
+        best
+    }
+}
+impl PartialCropProvider for SalientCropProvider {
+    fn crop_size(&self, lay: Layout, target: AspectRatio) -> Result<Layout> {
+        lay.crop(target)
+    }
+    fn crop_origin(&self, inner: AspectRatio, outer: AspectRatio) -> Option<(i32, i32)> {
+        if inner.exceeds_any(&outer) || self.grid_w == 0 || self.grid_h == 0 {
+            return None;
+        }
+        let cells = |inner: i32, outer: i32, grid: usize| {
+            cmp::max(1, cmp::min(grid, (inner as f64 * grid as f64 / outer as f64).round() as usize))
+        };
+        let (window_w, window_h) = (cells(inner.w, outer.w, self.grid_w), cells(inner.h, outer.h, self.grid_h));
+        let (x, y) = self.best_window(window_w, window_h);
+        // Center the crop on the center of the winning window
+        let origin = |cell: usize, window: usize, grid: usize, inner: i32, outer: i32| {
+            let center = (cell as f64 + window as f64 / 2f64) * outer as f64 / grid as f64;
+            cmp::max(0, cmp::min(outer - inner, (center - inner as f64 / 2f64).round() as i32))
+        };
+        Some((origin(x, window_w, self.grid_w, inner.w, outer.w), origin(y, window_h, self.grid_h, inner.h, outer.h)))
+    }
+}
+
+#[test]
+fn test_crop_origin() {
+    fn ratio(w: i32, h: i32) -> AspectRatio {
+        AspectRatio::create(w, h).unwrap()
+    }
+    assert_eq!(IdentityCropProvider::new().crop_origin(ratio(10, 10), ratio(20, 10)), None);
+
+    let focal = FocalPointCropProvider::new(75f64, 50f64);
+    assert_eq!(focal.crop_origin(ratio(100, 100), ratio(400, 100)), Some((250, 0)));
+    // Clamped to the far edge
+    assert_eq!(FocalPointCropProvider::new(100f64, 0f64).crop_origin(ratio(100, 100), ratio(400, 100)), Some((300, 0)));
+    assert_eq!(focal.crop_origin(ratio(500, 100), ratio(400, 100)), None);
+
+    // All the weight is in the left quarter
+    let salient = SalientCropProvider::new(vec![1f32, 0f32, 0f32, 0f32], 4, 1);
+    assert_eq!(salient.crop_origin(ratio(100, 100), ratio(400, 100)), Some((0, 0)));
+    // No weight at all prefers the center
+    let flat = SalientCropProvider::new(vec![0f32; 4], 4, 1);
+    assert_eq!(flat.crop_origin(ratio(200, 100), ratio(400, 100)), Some((100, 0)));
 }
 
 /// After distortion, Crop/VirtualCanvas will undo stretching by cropping proportionally.
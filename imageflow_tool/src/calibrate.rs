@@ -0,0 +1,190 @@
+use fc;
+use s;
+use std::collections::HashMap;
+use std::ptr;
+
+const W: u32 = 1600;
+const H: u32 = 1200;
+/// Each job runs this many times, keeping the fastest time of each node
+const RUNS: usize = 3;
+
+/// Measures how long this machine takes to execute each kind of node, and derives the CostCoefficients
+/// that v0.1/estimate should use on it (pass them as `builder_config.cost_coefficients`)
+pub fn calibrate() -> Result<s::CostCoefficients, String> {
+    let pixels = (W * H) as f64;
+    let png = gradient_png(W, H)?;
+
+    let (tiny, _) = time_nodes(&[], s::Framewise::Steps(vec![
+        s::Node::CreateCanvas { w: 1, h: 1, format: s::PixelFormat::Bgra32, color: s::Color::Transparent },
+        s::Node::Encode { io_id: 1, preset: s::EncoderPreset::libpng32() },
+    ]))?;
+    let overhead = node_ns(&tiny, "create_canvas")?;
+    let per_pixel = |ns: f64| f64::max(0f64, ns - overhead) / pixels;
+
+    let (png_to_png, _) = time_nodes(&[(0, &png)], decode_encode(s::EncoderPreset::libpng32()))?;
+    let (png_to_jpeg, jpeg) = time_nodes(&[(0, &png)], decode_encode(s::EncoderPreset::LibjpegTurbo { quality: Some(90), progressive: None, optimize_huffman_coding: None }))?;
+    let (jpeg_to_gif, gif) = time_nodes(&[(0, &jpeg)], decode_encode(s::EncoderPreset::Gif))?;
+    let (gif_to_png, _) = time_nodes(&[(0, &gif)], decode_encode(s::EncoderPreset::libpng32()))?;
+
+    let (canvas, _) = time_nodes(&[], s::Framewise::Steps(vec![
+        s::Node::CreateCanvas { w: W as usize, h: H as usize, format: s::PixelFormat::Bgra32, color: s::Color::Transparent },
+        s::Node::Encode { io_id: 1, preset: s::EncoderPreset::libpng32() },
+    ]))?;
+
+    // Two filters with different windows at the same size separate the per-pixel and per-tap costs
+    let (half_w, half_h) = (W / 2, H / 2);
+    let scale_pixels = pixels + (half_w * half_h) as f64;
+    let taps = |filter: s::Filter| resample_taps(W, H, half_w, half_h, filter);
+    let (narrow, _) = time_nodes(&[(0, &png)], decode_resample_encode(half_w, half_h, s::Filter::Box))?;
+    let (wide, _) = time_nodes(&[(0, &png)], decode_resample_encode(half_w, half_h, s::Filter::Lanczos))?;
+    let narrow_ns = f64::max(0f64, node_ns(&narrow, "scale_2d_to_canvas")? - overhead);
+    let wide_ns = f64::max(0f64, node_ns(&wide, "scale_2d_to_canvas")? - overhead);
+    let ns_per_tap = f64::max(0f64, (wide_ns - narrow_ns) / (taps(s::Filter::Lanczos) - taps(s::Filter::Box)));
+    let scale_ns_per_pixel = f64::max(0f64, (narrow_ns - ns_per_tap * taps(s::Filter::Box)) / scale_pixels);
+
+    // Drawing at 1:1 with blending; whatever the resample doesn't account for is the blend
+    let (draw, _) = time_nodes(&[(0, &png)], decode_draw_encode())?;
+    let draw_resample_ns = scale_ns_per_pixel * pixels * 2f64 + ns_per_tap * resample_taps(W, H, W, H, s::Filter::Box);
+    let blend_ns_per_pixel = f64::max(0f64, node_ns(&draw, "draw_image_to_canvas")? - overhead - draw_resample_ns) / pixels;
+
+    Ok(s::CostCoefficients {
+        node_overhead_ns: overhead,
+        copy_ns_per_pixel: per_pixel(node_ns(&canvas, "create_canvas")?),
+        blend_ns_per_pixel: blend_ns_per_pixel,
+        scale_ns_per_pixel: scale_ns_per_pixel,
+        scale_ns_per_tap: ns_per_tap,
+        decode_jpeg_ns_per_pixel: per_pixel(node_ns(&jpeg_to_gif, "primitive_decoder")?),
+        decode_png_ns_per_pixel: per_pixel(node_ns(&png_to_png, "primitive_decoder")?),
+        decode_gif_ns_per_pixel: per_pixel(node_ns(&gif_to_png, "primitive_decoder")?),
+        encode_jpeg_ns_per_pixel: per_pixel(node_ns(&png_to_jpeg, "primitive_encoder")?),
+        encode_png_ns_per_pixel: per_pixel(node_ns(&png_to_png, "primitive_encoder")?),
+        encode_gif_ns_per_pixel: per_pixel(node_ns(&jpeg_to_gif, "primitive_encoder")?),
+    })
+}
+
+/// The time a job spent in nodes named `name`; an error if it had none, as when a node is renamed
+fn node_ns(times: &HashMap<String, f64>, name: &str) -> Result<f64, String> {
+    times.get(name).cloned().ok_or_else(|| format!("The calibration job ran no {} node; it ran {:?}", name, times.keys().collect::<Vec<&String>>()))
+}
+
+/// Taps summed over a whole resample, counted the way flow::cost::resample_cost counts them
+fn resample_taps(from_w: u32, from_h: u32, to_w: u32, to_h: u32, filter: s::Filter) -> f64 {
+    let window = fc::filter_window(filter);
+    let taps_x = fc::taps_per_pixel(from_w, to_w, window);
+    let taps_y = fc::taps_per_pixel(from_h, to_h, window);
+    (to_h as u64 * from_w as u64 * taps_y + to_w as u64 * to_h as u64 * taps_x) as f64
+}
+
+fn decode_encode(preset: s::EncoderPreset) -> s::Framewise {
+    s::Framewise::Steps(vec![
+        s::Node::Decode { io_id: 0, commands: None },
+        s::Node::Encode { io_id: 1, preset: preset },
+    ])
+}
+
+fn decode_resample_encode(w: u32, h: u32, filter: s::Filter) -> s::Framewise {
+    s::Framewise::Steps(vec![
+        s::Node::Decode { io_id: 0, commands: None },
+        s::Node::Resample2D { w: w, h: h, down_filter: Some(filter), up_filter: Some(filter), scaling_colorspace: None, hints: None },
+        s::Node::Encode { io_id: 1, preset: s::EncoderPreset::libpng32() },
+    ])
+}
+
+fn decode_draw_encode() -> s::Framewise {
+    let mut nodes = HashMap::new();
+    nodes.insert("0".to_owned(), s::Node::Decode { io_id: 0, commands: None });
+    nodes.insert("1".to_owned(), s::Node::CreateCanvas { w: W as usize, h: H as usize, format: s::PixelFormat::Bgra32, color: s::Color::Transparent });
+    nodes.insert("2".to_owned(), s::Node::DrawImageExact {
+        x: 0,
+        y: 0,
+        w: W,
+        h: H,
+        blend: Some(s::CompositingMode::Compose),
+        hints: Some(s::ConstraintResamplingHints::with(Some(s::Filter::Box), None)),
+    });
+    nodes.insert("3".to_owned(), s::Node::Encode { io_id: 1, preset: s::EncoderPreset::libpng32() });
+    s::Framewise::Graph(s::Graph {
+        nodes: nodes,
+        edges: vec![
+            s::Edge { from: 0, to: 2, kind: s::EdgeKind::Input },
+            s::Edge { from: 1, to: 2, kind: s::EdgeKind::Canvas },
+            s::Edge { from: 2, to: 3, kind: s::EdgeKind::Input },
+        ],
+    })
+}
+
+/// Runs a job RUNS times. Returns the fastest nanoseconds spent in each kind of node (summed within a run),
+/// and the bytes encoded to io_id 1.
+fn time_nodes(inputs: &[(i32, &Vec<u8>)], framewise: s::Framewise) -> Result<(HashMap<String, f64>, Vec<u8>), String> {
+    let mut fastest: HashMap<String, f64> = HashMap::new();
+    let mut output = Vec::new();
+    for _ in 0..RUNS {
+        let mut io = inputs.iter().map(|&(io_id, bytes)| s::IoObject {
+            io_id: io_id,
+            direction: s::IoDirection::In,
+            io: s::IoEnum::ByteArray(bytes.clone()),
+        }).collect::<Vec<s::IoObject>>();
+        io.push(s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer });
+
+        let mut context = fc::Context::create().map_err(|e| format!("{}", e))?;
+        let result = context.build_1(s::Build001 { builder_config: None, io: io, framewise: framewise.clone() })
+            .map_err(|e| format!("Calibration job failed: {}", e))?;
+        let mut run: HashMap<String, f64> = HashMap::new();
+        if let s::ResponsePayload::BuildResult(s::JobResult { performance: Some(perf), .. }) = result {
+            for node in perf.frames.into_iter().flat_map(|f| f.nodes.into_iter()) {
+                *run.entry(node.name).or_insert(0f64) += node.wall_microseconds as f64 * 1000f64;
+            }
+        }
+        for (name, ns) in run {
+            let best = fastest.entry(name).or_insert(ns);
+            if ns < *best {
+                *best = ns;
+            }
+        }
+        output = context.get_output_buffer_slice(1).map_err(|e| format!("Calibration job wrote no output: {}", e))?.to_vec();
+    }
+    Ok((fastest, output))
+}
+
+/// A gradient with grain, so codecs see neither flat color nor pure noise. Encoded by imageflow as a PNG
+/// without compression.
+fn gradient_png(w: u32, h: u32) -> Result<Vec<u8>, String> {
+    let mut context = fc::Context::create().map_err(|e| format!("{}", e))?;
+    // Create a canvas to draw the gradient on by hand
+    let mut bitmap: *mut fc::ffi::BitmapBgra = ptr::null_mut();
+    let bitmap_ptr = &mut bitmap as *mut *mut fc::ffi::BitmapBgra as usize;
+    context.build_1(s::Build001 {
+        builder_config: None,
+        io: vec![],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::CreateCanvas { w: w as usize, h: h as usize, format: s::PixelFormat::Bgra32, color: s::Color::Transparent },
+            s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: bitmap_ptr },
+        ])
+    }).map_err(|e| format!("Failed to create the calibration image: {}", e))?;
+    let mut seed = 0x2545F491u32;
+    for y in 0..h {
+        for x in 0..w {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let grain = (seed & 31) as u32;
+            unsafe {
+                let pixel = (*bitmap).pixels.offset((y * (*bitmap).stride + x * 4) as isize);
+                *pixel = (((x + y) * 100 / (w + h)) + grain) as u8;
+                *pixel.offset(1) = ((y * 200 / h) + grain) as u8;
+                *pixel.offset(2) = ((x * 200 / w) + grain) as u8;
+                *pixel.offset(3) = 255;
+            }
+        }
+    }
+    // The bitmap is the context's until it is dropped, so a second job can encode it
+    context.build_1(s::Build001 {
+        builder_config: None,
+        io: vec![s::IoObject { io_id: 1, direction: s::IoDirection::Out, io: s::IoEnum::OutputBuffer }],
+        framewise: s::Framewise::Steps(vec![
+            s::Node::FlowBitmapBgraPtr { ptr_to_flow_bitmap_bgra_ptr: bitmap_ptr },
+            s::Node::Encode { io_id: 1, preset: s::EncoderPreset::Libpng { depth: Some(s::PngBitDepth::Png32), matte: None, zlib_compression: Some(0) } },
+        ])
+    }).map_err(|e| format!("Failed to encode the calibration image: {}", e))?;
+    context.get_output_buffer_slice(1).map(|bytes| bytes.to_vec()).map_err(|e| format!("{}", e))
+}
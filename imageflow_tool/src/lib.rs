@@ -13,6 +13,7 @@ use imageflow_helpers as hlp;
 
 use std::path::{Path,PathBuf};
 mod cmd_build;
+mod calibrate;
 pub mod self_test;
 
 
@@ -49,6 +50,10 @@ pub fn main_with_exit_code() -> i32 {
                     Arg::with_name("call-panic").long("call-panic")
                         .help("Triggers a Rust panic (so you can observe failure/backtrace behavior)")
                 )
+                .arg(
+                    Arg::with_name("calibrate-costs").long("calibrate-costs")
+                        .help("Benchmarks this machine and prints the cost_coefficients v0.1/estimate should use on it (builder_config.cost_coefficients)")
+                )
         )
         .subcommand(
             SubCommand::with_name("examples")
@@ -205,6 +210,19 @@ pub fn main_with_exit_code() -> i32 {
         if m.is_present("call-panic") {
             panic!("Panicking on command");
         }
+        if m.is_present("calibrate-costs") {
+            match calibrate::calibrate() {
+                Ok(coefficients) => {
+                    println!("{}", serde_json::to_string_pretty(&coefficients).unwrap());
+                    return 0;
+                }
+                Err(message) => {
+                    use std::io::Write;
+                    writeln!(&mut std::io::stderr(), "Calibration failed: {}", message).unwrap();
+                    return 1;
+                }
+            }
+        }
     }
     if let Some(ref matches) = matches.subcommand_matches("examples") {
         let m: &&clap::ArgMatches = matches;
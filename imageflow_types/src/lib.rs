@@ -186,6 +186,14 @@ pub enum EncoderPreset {
 }
 
 impl EncoderPreset {
+    /// The mime type and file extension of what this preset encodes
+    pub fn mime_type_and_extension(&self) -> (&'static str, &'static str) {
+        match *self {
+            EncoderPreset::Libpng { .. } => ("image/png", "png"),
+            EncoderPreset::LibjpegTurbo { .. } => ("image/jpeg", "jpg"),
+            EncoderPreset::Gif => ("image/gif", "gif"),
+        }
+    }
     pub fn libpng32() -> EncoderPreset {
         EncoderPreset::Libpng {
             depth: Some(PngBitDepth::Png32),
@@ -316,11 +324,83 @@ pub enum CommandStringKind{
     ImageResizer4
 }
 
+/// When a constraint is permitted to change the image size. Mirrors IR4's `scale=` values.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ConstraintScaling {
+    /// Only shrink images; never enlarge (the default)
+    #[serde(rename="down")]
+    DownscaleOnly,
+    /// Only enlarge images; never shrink
+    #[serde(rename="up")]
+    UpscaleOnly,
+    #[serde(rename="both")]
+    Both,
+    /// Never enlarge the image, but pad the canvas up to the requested size
+    #[serde(rename="canvas")]
+    UpscaleCanvas,
+}
+
+/// Which part of the image is kept when cropping, or where it is placed when padding
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ConstraintGravity {
+    #[serde(rename="top_left")]
+    TopLeft,
+    #[serde(rename="top")]
+    Top,
+    #[serde(rename="top_right")]
+    TopRight,
+    #[serde(rename="left")]
+    Left,
+    #[serde(rename="center")]
+    Center,
+    #[serde(rename="right")]
+    Right,
+    #[serde(rename="bottom_left")]
+    BottomLeft,
+    #[serde(rename="bottom")]
+    Bottom,
+    #[serde(rename="bottom_right")]
+    BottomRight,
+    /// Keep the crop centered on a point, given as percentages (0..100) of the image width and height
+    #[serde(rename="focal_point")]
+    FocalPoint { x: f32, y: f32 },
+    /// Crop to the most detailed region of the image (by edge energy and entropy). Pads like `center`.
+    #[serde(rename="auto")]
+    Auto,
+}
+
+/// The target box for fit/pad/crop/distort constraints. If only one dimension is given,
+/// the other is derived from the image's aspect ratio.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ConstraintBox {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub scale: Option<ConstraintScaling>,
+    pub gravity: Option<ConstraintGravity>,
+    /// Padding color; defaults to transparent white
+    pub background: Option<Color>,
+    pub hints: Option<ConstraintResamplingHints>,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Constraint {
     #[serde(rename="within")]
-    Within{w: Option<u32>, h: Option<u32>, hints: Option<ConstraintResamplingHints>}
-    //max * {down, up, both, canvas}
+    Within{w: Option<u32>, h: Option<u32>, hints: Option<ConstraintResamplingHints>},
+    /// Scale proportionally to fit within the box (IR4 `mode=max`)
+    #[serde(rename="fit")]
+    Fit(ConstraintBox),
+    /// Scale proportionally to fit within the box, then pad to the exact box size (IR4 `mode=pad`)
+    #[serde(rename="fit_pad")]
+    FitPad(ConstraintBox),
+    /// Scale proportionally to fill the box, then crop to the exact box size (IR4 `mode=crop`)
+    #[serde(rename="fit_crop")]
+    FitCrop(ConstraintBox),
+    /// Scale to the exact box size, ignoring aspect ratio (IR4 `mode=stretch`)
+    #[serde(rename="distort")]
+    Distort(ConstraintBox),
+    /// Crop to the aspect ratio of w:h without scaling
+    #[serde(rename="aspect_crop")]
+    AspectCrop{w: u32, h: u32, gravity: Option<ConstraintGravity>},
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
@@ -328,7 +408,16 @@ pub enum CompositingMode {
     #[serde(rename="compose")]
     Compose,
     #[serde(rename="overwrite")]
-    Overwrite
+    Overwrite,
+    /// Darkens; white is neutral
+    #[serde(rename="multiply")]
+    Multiply,
+    /// Lightens; black is neutral
+    #[serde(rename="screen")]
+    Screen,
+    /// Multiplies dark areas of the canvas and screens light areas, increasing contrast
+    #[serde(rename="overlay")]
+    Overlay,
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -339,6 +428,9 @@ pub enum Node {
     FlipH,
     #[serde(rename="crop")]
     Crop { x1: u32, y1: u32, x2: u32, y2: u32 },
+    /// Crops to exactly w x h, positioning the crop over the most detailed region of the image
+    #[serde(rename="smart_crop")]
+    SmartCrop { w: u32, h: u32 },
     // #[serde(rename="crop_whitespace")]
     // CropWhitespace { threshold: u32, percent_padding: f32 },
     #[serde(rename="create_canvas")]
@@ -400,6 +492,16 @@ pub enum Node {
     Rotate180,
     #[serde(rename="rotate_270")]
     Rotate270,
+    /// Rotates counter-clockwise by an arbitrary angle (matching the direction of rotate_90).
+    /// Uncovered areas are filled with `background`. When `expand_canvas` is false, the output
+    /// keeps the input dimensions and the corners are clipped.
+    #[serde(rename="rotate")]
+    Rotate {
+        degrees: f32,
+        background: Color,
+        filter: Option<Filter>,
+        expand_canvas: bool,
+    },
     #[serde(rename="apply_orientation")]
     ApplyOrientation { flag: i32 },
     #[serde(rename="resample_2d")]
@@ -431,12 +533,150 @@ pub enum Node {
     WhiteBalanceHistogramAreaThresholdSrgb{
         threshold: Option<f32>
     },
+    /// Maps `black`..`white` to the full 0..255 range with `gamma` applied in between (values above 1 brighten).
+    /// `channel` limits the adjustment to one channel; chain several nodes for per-channel levels.
+    #[serde(rename="levels")]
+    Levels {
+        black: u8,
+        white: u8,
+        gamma: f32,
+        channel: Option<ColorChannel>,
+    },
+    /// Maps values through a smooth monotone curve passing through the given (input, output) points.
+    /// Points must be sorted by input value; inputs outside the first and last points are clamped.
+    #[serde(rename="curves")]
+    Curves {
+        points: Vec<(u8, u8)>,
+        channel: Option<ColorChannel>,
+    },
+    /// Stretches contrast so that `threshold` (default 0.006) of pixels are clipped at each end of the
+    /// combined histogram. Unlike white balancing, every channel receives the same mapping.
+    #[serde(rename="auto_levels")]
+    AutoLevels {
+        threshold: Option<f32>
+    },
     #[serde(rename="color_matrix_srgb")]
     ColorMatrixSrgb{
         matrix: [[f32;5];5]
     },
     #[serde(rename="color_matrix_srgb")]
     ColorFilterSrgb (ColorFilterSrgb),
+    /// Like `color_matrix_srgb`, but applied to linear light (values 0..1, alpha not premultiplied)
+    #[serde(rename="color_matrix_linear")]
+    ColorMatrixLinear{
+        matrix: [[f32;5];5]
+    },
+    /// Applies `filter` in the given color space (default `srgb`, which matches the `ColorFilterSrgb` node)
+    #[serde(rename="color_filter")]
+    ColorFilter {
+        filter: ColorFilterSrgb,
+        space: Option<ColorFilterSpace>,
+    },
+    /// Reports a BlurHash, a tiny preview and the dominant colors of its input into `JobResult.analyses`.
+    /// Like `encode`, it must be the last node on its branch.
+    #[serde(rename="analyze_colors")]
+    AnalyzeColors {
+        /// 1..9, default 4
+        blurhash_x_components: Option<u32>,
+        /// 1..9, default 3
+        blurhash_y_components: Option<u32>,
+        /// How many dominant colors to report, default 5
+        dominant_colors: Option<u32>,
+    },
+    /// Reports a perceptual hash of its input into `JobResult.analyses`. Must be the last node on its branch.
+    /// Decoding applies EXIF orientation first, so rotated duplicates hash alike.
+    #[serde(rename="perceptual_hash")]
+    PerceptualHash {
+        algorithm: PerceptualHashAlgorithm,
+        /// 64 (default) or 256
+        bits: Option<u32>,
+    },
+    /// Compares its input against its canvas (the expected image) and reports the difference into `JobResult.analyses`.
+    /// Both must have the same dimensions. Must be the last node on its branch.
+    #[serde(rename="compare")]
+    Compare {
+        /// If set, a heat map of the differing pixels is encoded to this io_id as PNG
+        diff_io_id: Option<i32>,
+    },
+    /// Reports histograms and statistics of its input into `JobResult.analyses`. Must be the last node on its branch.
+    #[serde(rename="histogram")]
+    Histogram,
+    /// Obscures rectangles given in the pixel grid of the source image as stored (before EXIF or other
+    /// orientation changes); flips, transposes and quarter rotations applied before this node, and downscaling
+    /// the decoder was told to do, are accounted for.
+    #[serde(rename="redact_regions")]
+    RedactRegions {
+        regions: Vec<RedactRegion>,
+        style: RedactionStyle,
+    },
+    /// Draws each of its inputs into a cell of a single canvas, filling rows left to right. Inputs are placed in
+    /// the order their edges are listed in the graph. The position of each is reported in `JobResult.analyses`.
+    #[serde(rename="grid")]
+    Grid {
+        columns: u32,
+        cell_w: u32,
+        cell_h: u32,
+        /// Space between cells; defaults to 0
+        gap: Option<u32>,
+        /// Defaults to transparent
+        background: Option<Color>,
+        /// How images are sized into cells; defaults to `fit_pad`. Images are upscaled when smaller than a cell.
+        fit: Option<GridFit>,
+    },
+    /// Uses its input (the mask) to set the transparency of its canvas (the image), which must be the same size.
+    /// Existing transparency is kept; the mask can only make pixels more transparent.
+    #[serde(rename="apply_mask")]
+    ApplyMask {
+        channel: MaskChannel,
+        /// Use 255 minus the mask's value
+        invert: Option<bool>,
+    },
+    /// Makes pixels near `color` transparent. Distance is measured in chroma (Cb/Cr, 0..255), ignoring brightness.
+    #[serde(rename="chroma_key")]
+    ChromaKey {
+        color: Color,
+        /// Pixels within this distance of `color` become fully transparent
+        tolerance: f32,
+        /// Transparency fades out over this much further distance; defaults to 0
+        feather: Option<f32>,
+    },
+    /// Draws anti-aliased text over its input, composited like `draw_image_exact`
+    #[serde(rename="draw_text")]
+    DrawText {
+        text: String,
+        /// Defaults to the bundled DejaVu Sans
+        font: Option<TextFont>,
+        /// The em size in pixels
+        size: f32,
+        color: Color,
+        /// Where the block of text sits on the image, and how its lines are aligned; defaults to `top_left`.
+        /// `focal_point` centers the text on a point; `auto` is not supported.
+        anchor: Option<ConstraintGravity>,
+        /// The widest a line may be, in pixels; defaults to the image width
+        max_width: Option<u32>,
+        /// Break lines between words to fit `max_width` (the default); otherwise the text is shrunk to fit
+        wrap: Option<bool>,
+    },
+    /// Writes a zoomable tile pyramid of its input. The descriptor (a .dzi file or IIIF info.json) is written
+    /// to `io_id`, which must be a `Filename` output; tiles are written to files beside it, as the layout requires.
+    /// Must be the last node on its branch.
+    #[serde(rename="tile_pyramid")]
+    TilePyramid {
+        io_id: i32,
+        layout: TileLayout,
+        /// Defaults to 254 for DeepZoom and 512 for IIIF
+        tile_size: Option<u32>,
+        /// Pixels each tile shares with its neighbors. Defaults to 1 for DeepZoom; IIIF tiles cannot overlap.
+        overlap: Option<u32>,
+        preset: EncoderPreset,
+    },
+    /// Reduces its input to the colors of `palette`, using `method` to spread the difference across pixels.
+    /// Transparency is kept as-is.
+    #[serde(rename="dither")]
+    Dither {
+        palette: DitherPalette,
+        method: DitherMethod,
+    },
     // TODO: Block use except from FFI/unit test use
     #[serde(rename="flow_bitmap_bgra_ptr")]
     FlowBitmapBgraPtr {
@@ -444,6 +684,134 @@ pub enum Node {
     },
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum ColorChannel {
+    #[serde(rename="red")]
+    Red,
+    #[serde(rename="green")]
+    Green,
+    #[serde(rename="blue")]
+    Blue,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct RedactRegion {
+    pub x1: u32,
+    pub y1: u32,
+    pub x2: u32,
+    pub y2: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum RedactionStyle {
+    /// Replaces each block x block square with its average color
+    #[serde(rename="pixelate")]
+    Pixelate { block: u32 },
+    /// Gaussian blur confined to the region
+    #[serde(rename="blur")]
+    Blur { sigma: f32 },
+    #[serde(rename="fill")]
+    Fill { color: Color },
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum MaskChannel {
+    /// Rec. 601 luma; transparent areas of the mask count as black
+    #[serde(rename="luminance")]
+    Luminance,
+    #[serde(rename="alpha")]
+    Alpha,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum DitherPalette {
+    /// The 216 colors with each channel a multiple of 51
+    #[serde(rename="web_safe")]
+    WebSafe,
+    /// `levels` evenly spaced grays from black to white (2..256)
+    #[serde(rename="grayscale")]
+    Grayscale { levels: u32 },
+    /// Up to 256 colors; their alpha is ignored
+    #[serde(rename="custom")]
+    Custom { colors: Vec<Color> },
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum DitherMethod {
+    #[serde(rename="floyd_steinberg")]
+    FloydSteinberg,
+    /// Diffuses only 3/4 of the error, keeping more contrast
+    #[serde(rename="atkinson")]
+    Atkinson,
+    /// Ordered dithering with a `size` x `size` threshold matrix; size must be 2, 4 or 8
+    #[serde(rename="bayer")]
+    Bayer { size: u32 },
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum TextFont {
+    #[serde(rename="bundled")]
+    Bundled,
+    /// A TrueType or OpenType font provided as an input
+    #[serde(rename="io")]
+    Io { io_id: i32 },
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum GridFit {
+    /// Scale proportionally to fit within the cell (IR4 `mode=max`)
+    #[serde(rename="fit")]
+    Fit,
+    /// Scale proportionally to fit within the cell, centered (IR4 `mode=pad`)
+    #[serde(rename="fit_pad")]
+    FitPad,
+    /// Scale proportionally to fill the cell, cropping the excess (IR4 `mode=crop`)
+    #[serde(rename="fit_crop")]
+    FitCrop,
+    /// Scale to the cell size, ignoring aspect ratio (IR4 `mode=stretch`)
+    #[serde(rename="distort")]
+    Distort,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum TileLayout {
+    /// `name.dzi` with tiles at `name_files/{level}/{column}_{row}.{ext}`
+    #[serde(rename="dzi")]
+    Dzi,
+    /// A static (level 0) IIIF Image API 2 layout: `info.json` with tiles at `{region}/{size}/0/default.{ext}`
+    /// in the same directory. `id` is the URI the directory will be served from.
+    #[serde(rename="iiif")]
+    Iiif { id: String },
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum PerceptualHashAlgorithm {
+    /// Each bit is whether a cell is brighter than the mean
+    #[serde(rename="ahash")]
+    AHash,
+    /// Each bit is whether a cell is brighter than its right neighbor
+    #[serde(rename="dhash")]
+    DHash,
+    /// Each bit is whether a low-frequency DCT coefficient is above the median; the most robust to edits
+    #[serde(rename="phash")]
+    PHash,
+}
+
+/// The color space a filter is evaluated in
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum ColorFilterSpace {
+    /// Gamma-encoded sRGB bytes; fast, but darkens and muddies colors
+    #[serde(rename="srgb")]
+    Srgb,
+    /// Linear light
+    #[serde(rename="linear")]
+    Linear,
+    /// CIE L*u*v*. Brightness, contrast, saturation and grayscale act on lightness and chroma;
+    /// other filters are applied in linear light.
+    #[serde(rename="luv")]
+    Luv,
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
 pub enum ColorFilterSrgb {
     #[serde(rename="grayscale_ntsc")]
@@ -605,11 +973,68 @@ impl Build001GraphRecording {
 }
 
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
 pub struct Build001Config {
     // pub process_all_gif_frames: Option<bool>,
     pub graph_recording: Option<Build001GraphRecording>,
+    /// Fails the job with an OperationTimedOut error once it has run this many milliseconds
+    pub job_timeout_ms: Option<u64>,
+    pub limits: Option<JobLimits>,
+    /// Records a Chrome trace of graph passes, phases, nodes and codec I/O
+    pub trace: Option<TraceDestination>,
+    /// Enables, resizes or (with max_bytes 0) disables the context's cache of decoded and resampled frames
+    pub frame_cache: Option<FrameCacheConfig>,
+    /// Lets JPEG decoders feeding a large downscale scale during IDCT; faster, but changes output pixels. Off by default.
+    pub enable_jpeg_block_scaling: Option<bool>,
+    /// How many independent nodes may execute at once on this and later builds of the context; 1 (the default) executes serially
+    pub max_parallelism: Option<u32>,
+    /// How many threads one resample may split its output rows across, on this and later builds of the context;
+    /// 1 (the default) stays on the calling thread
+    pub max_resample_threads: Option<u32>,
+    /// Replaces the context's cost coefficients for this and later builds and estimates, such as with the output of
+    /// `imageflow_tool diagnose --calibrate-costs`. An estimate's apply to that estimate alone.
+    pub cost_coefficients: Option<CostCoefficients>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum TraceDestination {
+    /// Returns the events in the job result
+    #[serde(rename="response")]
+    Response,
+    /// Writes the events as a JSON array to the given path
+    #[serde(rename="file")]
+    File(String),
+}
 
+/// Lets later builds on the same context reuse the frames of Decode nodes, and of Resample2D nodes fed directly by
+/// them, when the input bytes and node parameters match
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct FrameCacheConfig {
+    /// The least recently used frames are evicted to stay within this many bytes; 0 disables and empties the cache
+    pub max_bytes: u64,
+}
+
+/// Counts since the cache was enabled
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct FrameCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Frames held now
+    pub entries: u64,
+    /// Bytes of the frames held now
+    pub bytes: u64,
+}
+
+/// Sizes past which a job fails with a SizeLimitExceeded error, rather than exhaust the host on a decompression bomb
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct JobLimits {
+    /// The most pixels a source image may have, checked before it is decoded
+    pub max_input_pixels: Option<u64>,
+    /// The most pixels an encoded image may have
+    pub max_output_pixels: Option<u64>,
+    /// The most memory bitmaps may occupy at once
+    pub max_bitmap_bytes: Option<u64>,
 }
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct Build001 {
@@ -753,6 +1178,20 @@ impl Framewise {
                               Node::Rotate180,
                               Node::Rotate270,
                               Node::Transpose,
+                              Node::Rotate {
+                                  degrees: 2.5f32,
+                                  background: Color::Srgb(ColorSrgb::Hex("FFFFFFFF".to_owned())),
+                                  filter: Some(Filter::Robidoux),
+                                  expand_canvas: true,
+                              },
+                              Node::Constrain(Constraint::FitPad(ConstraintBox {
+                                  w: Some(400),
+                                  h: Some(400),
+                                  scale: Some(ConstraintScaling::DownscaleOnly),
+                                  gravity: Some(ConstraintGravity::Center),
+                                  background: Some(Color::Srgb(ColorSrgb::Hex("FFFFFF00".to_owned()))),
+                                  hints: None,
+                              })),
                               Node::Resample2D {
                                   w: 100,
                                   h: 75,
@@ -772,6 +1211,12 @@ impl Framewise {
                                   scaling_colorspace: Some(ScalingFloatspace::Srgb),
                                   hints: None,
                               },
+                              Node::Levels {
+                                  black: 10,
+                                  white: 240,
+                                  gamma: 1.2f32,
+                                  channel: None,
+                              },
                               Node::Encode {
                                   io_id: 1,
                                   preset: EncoderPreset::LibjpegTurbo { quality: Some(90), optimize_huffman_coding: Some(true), progressive: Some(true)},
@@ -950,9 +1395,91 @@ pub struct EncodeResult {
     pub bytes: ResultBytes,
 }
 
+/// The time units of work take on a given machine, from which node costs are estimated.
+/// `imageflow_tool diagnose --calibrate-costs` measures them for the host it runs on.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CostCoefficients {
+    /// Fixed cost of executing any node
+    pub node_overhead_ns: f64,
+    /// Filling, copying, flipping or otherwise touching a pixel once
+    pub copy_ns_per_pixel: f64,
+    /// Blending a pixel onto a canvas
+    pub blend_ns_per_pixel: f64,
+    /// Converting a source pixel to and from the scaling colorspace
+    pub scale_ns_per_pixel: f64,
+    /// One filter weight applied to one channel set during resampling
+    pub scale_ns_per_tap: f64,
+    pub decode_jpeg_ns_per_pixel: f64,
+    pub decode_png_ns_per_pixel: f64,
+    pub decode_gif_ns_per_pixel: f64,
+    pub encode_jpeg_ns_per_pixel: f64,
+    pub encode_png_ns_per_pixel: f64,
+    pub encode_gif_ns_per_pixel: f64,
+}
+
+impl Default for CostCoefficients {
+    /// Rough figures for a recent x86-64 core
+    fn default() -> CostCoefficients {
+        CostCoefficients {
+            node_overhead_ns: 10000f64,
+            copy_ns_per_pixel: 0.5f64,
+            blend_ns_per_pixel: 3f64,
+            scale_ns_per_pixel: 4f64,
+            scale_ns_per_tap: 0.7f64,
+            decode_jpeg_ns_per_pixel: 6f64,
+            decode_png_ns_per_pixel: 10f64,
+            decode_gif_ns_per_pixel: 8f64,
+            encode_jpeg_ns_per_pixel: 8f64,
+            encode_png_ns_per_pixel: 25f64,
+            encode_gif_ns_per_pixel: 40f64,
+        }
+    }
+}
+
+/// What an encode will produce, predicted without decoding any pixels
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EncodeEstimate {
+    pub preferred_mime_type: String,
+    pub preferred_extension: String,
+    pub io_id: i32,
+    /// None when the size depends on pixel data, such as when trimming whitespace
+    pub w: Option<i32>,
+    pub h: Option<i32>,
+}
+
+/// The response to v0.1/estimate
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct JobEstimate {
+    pub encodes: Vec<EncodeEstimate>,
+    /// Approximate time to execute every node on one thread
+    pub estimated_wall_microseconds: u64,
+    /// Approximate bitmap memory the job will allocate
+    pub estimated_heap_bytes: u64,
+    /// False if some nodes could not be estimated, making the totals a lower bound
+    pub complete: bool,
+}
+
+/// A complete event ("ph": "X") in Chrome's trace_event format; a list of them opens in chrome://tracing or Perfetto
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TraceEvent {
+    pub name: String,
+    /// "frame", "pass", "phase", "expand", "node" or "codec"
+    pub cat: String,
+    pub ph: String,
+    /// Microseconds since the job started
+    pub ts: f64,
+    pub dur: f64,
+    pub pid: u32,
+    /// 0 for the thread running the job; nodes run in parallel get a lane each
+    pub tid: u32,
+    pub args: std::collections::BTreeMap<String, String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct NodePerf{
     pub wall_microseconds: u64,
+    /// Thread time summed over every thread that worked on the node; exceeds wall time when it was split across threads
+    pub cpu_microseconds: Option<u64>,
     pub name: String
 }
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -963,7 +1490,153 @@ pub struct BuildPerformance{
 pub struct FramePerformance{
     pub nodes: Vec<NodePerf>,
     pub wall_microseconds: u64,
-    pub overhead_microseconds: i64
+    pub overhead_microseconds: i64,
+    /// The most bytes of bitmap pixels allocated at once while executing the frame; 0 from older servers
+    #[serde(default)]
+    pub peak_bitmap_bytes: u64
+}
+
+/// Data reported by an analysis node
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum AnalysisResult {
+    #[serde(rename="colors")]
+    Colors(ColorAnalysis),
+    #[serde(rename="perceptual_hash")]
+    PerceptualHash(PerceptualHashResult),
+    #[serde(rename="comparison")]
+    Comparison(ComparisonResult),
+    #[serde(rename="image_stats")]
+    ImageStats(ImageStats),
+    #[serde(rename="grid_layout")]
+    GridLayout(GridLayout),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GridLayout {
+    pub w: u32,
+    pub h: u32,
+    /// In the order the inputs were wired, which is also placement order: left to right, then top to bottom
+    pub cells: Vec<GridCell>,
+}
+
+/// Where one input was drawn on a grid
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GridCell {
+    /// The io_id of the image this input was decoded from, if any
+    pub io_id: Option<i32>,
+    pub column: u32,
+    pub row: u32,
+    /// The rectangle the image occupies (which may be smaller than the cell)
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ChannelStats {
+    /// 256 bins
+    pub histogram: Vec<u64>,
+    pub mean: f64,
+    pub stddev: f64,
+    pub min: u8,
+    pub max: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ImageStats {
+    pub w: u32,
+    pub h: u32,
+    pub red: ChannelStats,
+    pub green: ChannelStats,
+    pub blue: ChannelStats,
+    /// Always 255 for formats without alpha
+    pub alpha: ChannelStats,
+    /// The fraction (0..1) of pixels that aren't fully transparent
+    pub alpha_coverage: f64,
+    /// Every pixel is (nearly) the same color
+    pub solid: bool,
+    /// Fully transparent, or solid white or black
+    pub blank: bool,
+    /// No pixel has (noticeably) different red, green and blue values
+    pub grayscale: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ComparisonResult {
+    /// Structural dissimilarity of luma, as 1/SSIM - 1; 0 means identical
+    pub dssim: f64,
+    /// Peak signal-to-noise ratio in decibels across all channels; `None` when the images are identical
+    pub psnr: Option<f64>,
+    /// The largest difference (0..255) in any single channel of any pixel
+    pub max_channel_delta: u8,
+    /// The number of pixels with any difference
+    pub pixels_different: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PerceptualHashResult {
+    pub algorithm: PerceptualHashAlgorithm,
+    pub bits: u32,
+    /// Lowercase hex, most significant bit first
+    pub hash: String,
+}
+
+impl PerceptualHashResult {
+    /// The number of differing bits; 0 means identical. `None` if the hashes aren't comparable.
+    pub fn hamming_distance(&self, other: &PerceptualHashResult) -> Option<u32> {
+        if self.algorithm != other.algorithm || self.bits != other.bits {
+            None
+        } else {
+            hamming_distance(&self.hash, &other.hash)
+        }
+    }
+}
+
+/// The number of differing bits between two equal-length hex strings, or `None` if they aren't both valid hex of the same length
+pub fn hamming_distance(a: &str, b: &str) -> Option<u32> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let mut distance = 0;
+    for (x, y) in a.chars().zip(b.chars()) {
+        match (x.to_digit(16), y.to_digit(16)) {
+            (Some(x), Some(y)) => distance += (x ^ y).count_ones(),
+            _ => return None
+        }
+    }
+    Some(distance)
+}
+
+#[test]
+fn test_hamming_distance() {
+    assert_eq!(hamming_distance("00ff", "00ff"), Some(0));
+    assert_eq!(hamming_distance("00ff", "01fE"), Some(2));
+    assert_eq!(hamming_distance("00ff", "00f"), None);
+    assert_eq!(hamming_distance("00fg", "00ff"), None);
+
+    let a = PerceptualHashResult { algorithm: PerceptualHashAlgorithm::DHash, bits: 64, hash: "8f0c3e1a00ff7711".to_owned() };
+    let b = PerceptualHashResult { hash: "8f0c3e1a00ff7710".to_owned(), ..a.clone() };
+    assert_eq!(a.hamming_distance(&b), Some(1));
+    assert_eq!(a.hamming_distance(&PerceptualHashResult { algorithm: PerceptualHashAlgorithm::AHash, ..b }), None);
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ColorAnalysis {
+    pub blurhash: String,
+    /// A PNG at most 16x16 pixels (aspect ratio preserved), base64-encoded
+    pub preview_png_base64: String,
+    pub preview_w: u32,
+    pub preview_h: u32,
+    /// Most common colors first
+    pub dominant_colors: Vec<DominantColor>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DominantColor {
+    pub color: Color,
+    /// The share (0..1) of opaque pixels close to this color
+    pub fraction: f32,
 }
 
 //pub struct JobDebugInfo{
@@ -974,7 +1647,14 @@ pub struct FramePerformance{
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct JobResult {
     pub encodes: Vec<EncodeResult>,
-    pub performance: Option<BuildPerformance>
+    /// Results of analysis nodes, in graph order. Absent from responses of servers that predate analysis nodes.
+    #[serde(default)]
+    pub analyses: Vec<AnalysisResult>,
+    pub performance: Option<BuildPerformance>,
+    /// Present when the job was built with `trace: "response"`
+    pub trace: Option<Vec<TraceEvent>>,
+    /// Present while the context's frame cache is enabled
+    pub frame_cache: Option<FrameCacheStats>,
 }
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum ResponsePayload {
@@ -984,6 +1664,8 @@ pub enum ResponsePayload {
     JobResult(JobResult),
     #[serde(rename="build_result")]
     BuildResult(JobResult),
+    #[serde(rename="job_estimate")]
+    JobEstimate(JobEstimate),
     #[serde(rename="none")]
     None,
 }
@@ -1020,7 +1702,7 @@ impl Response001 {
                                       ext: &'static str)
                                       -> Response001 {
 
-        let frame_perf = FramePerformance{ nodes: vec![ NodePerf {wall_microseconds: 30000, name: "decode".to_owned()}], overhead_microseconds: 100, wall_microseconds: 30100};
+        let frame_perf = FramePerformance{ nodes: vec![ NodePerf {wall_microseconds: 30000, cpu_microseconds: Some(30000), name: "decode".to_owned()}], overhead_microseconds: 100, wall_microseconds: 30100, peak_bitmap_bytes: 4915200};
         Response001 {
             code: 200,
             success: true,
@@ -1034,9 +1716,32 @@ impl Response001 {
                                   preferred_extension: ext.to_owned(),
                                   bytes: ResultBytes::Elsewhere,
                               }],
+             analyses: vec![],
              performance: Some(BuildPerformance{
                  frames: vec![frame_perf]
              }),
+             trace: None,
+             frame_cache: None,
+            }),
+        }
+    }
+
+    pub fn example_job_estimate() -> Response001 {
+        Response001 {
+            code: 200,
+            success: true,
+            message: None,
+            data: ResponsePayload::JobEstimate(JobEstimate {
+                encodes: vec![EncodeEstimate {
+                    io_id: 1,
+                    w: Some(200),
+                    h: Some(200),
+                    preferred_mime_type: "image/png".to_owned(),
+                    preferred_extension: "png".to_owned(),
+                }],
+                estimated_wall_microseconds: 12000,
+                estimated_heap_bytes: 1440000,
+                complete: true,
             }),
         }
     }
@@ -1078,6 +1783,16 @@ fn roundtrip_example_responses() {
     assert_eq!(0, failures);
 }
 
+#[test]
+fn decode_job_result_from_older_servers() {
+    let text = r#"{"encodes": [], "performance": null}"#;
+    let result: JobResult = serde_json::from_str(text).unwrap();
+    assert!(result.analyses.is_empty());
+    let text = r#"{"encodes": [], "performance": {"frames": [{"nodes": [], "wall_microseconds": 10, "overhead_microseconds": 1}]}}"#;
+    let result: JobResult = serde_json::from_str(text).unwrap();
+    assert_eq!(result.performance.unwrap().frames[0].peak_bitmap_bytes, 0);
+}
+
 
 #[allow(unused_macros)]
 macro_rules! hashmap {